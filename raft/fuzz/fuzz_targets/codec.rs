@@ -0,0 +1,83 @@
+// UDP payloads as decoded by userspace: election message bodies, their MAC trailer and
+// key-value keys and values.
+#![no_main]
+// SipHasher (SipHash-2-4, hashing words as little-endian bytes on x86) is the reference for the MAC.
+#![allow(deprecated)]
+
+use libfuzzer_sys::fuzz_target;
+use raft_main_common::auth::{message_mac, message_mac_bytes, MacKey};
+use raft_main_common::handler::{decode_body, encode_body};
+use raft_main_common::kv::{KvKey, KvValue, KV_KEY_LEN, KV_VALUE_LEN, KV_VALUE_TOO_LARGE};
+use raft_main_common::{TERM_LEN, VOTE_REQUEST_PORT};
+use std::hash::{Hasher, SipHasher};
+
+const KEY: [u8; 16] = *b"raft-fuzzing-key";
+const SOURCE_ADDR: u32 = 0x0a00_0002;
+
+fuzz_target!(|payload: &[u8]| {
+    check_body::<1>(payload);
+    check_body::<3>(payload);
+
+    let key = MacKey::from_bytes(KEY);
+    if let (Some(body), Some([seq])) = (
+        decode_body::<3>(payload),
+        payload.get(3 * TERM_LEN..).and_then(decode_body::<1>),
+    ) {
+        assert_eq!(
+            message_mac(&key, SOURCE_ADDR, VOTE_REQUEST_PORT, &body, seq),
+            reference_mac(&key, &[body[0], body[1], body[2], seq]),
+        );
+    }
+
+    // Userspace channels hash the length, then zero-padded little-endian words.
+    let mut words = vec![payload.len() as u64];
+    words.extend(payload.chunks(8).map(|chunk| {
+        let mut word = [0u8; 8];
+        word[..chunk.len()].copy_from_slice(chunk);
+        u64::from_le_bytes(word)
+    }));
+    assert_eq!(
+        message_mac_bytes(&key, SOURCE_ADDR, VOTE_REQUEST_PORT, payload),
+        reference_mac(&key, &words)
+    );
+
+    match KvKey::from_bytes(payload) {
+        Some(key) => {
+            assert_eq!(&key.bytes[..payload.len()], payload);
+            assert!(key.bytes[payload.len()..].iter().all(|byte| *byte == 0));
+        }
+        None => assert!(payload.len() > KV_KEY_LEN),
+    }
+
+    let value = KvValue::from_bytes(payload);
+    if payload.len() > KV_VALUE_LEN {
+        assert_eq!(value.len, KV_VALUE_TOO_LARGE);
+    } else {
+        assert_eq!(&value.bytes[..value.len as usize], payload);
+    }
+});
+
+// Bodies re-encode to the bytes they were decoded from; shorter payloads are rejected.
+fn check_body<const N: usize>(payload: &[u8]) {
+    let body = match decode_body::<N>(payload) {
+        Some(body) => body,
+        None => {
+            assert!(payload.len() < N * TERM_LEN);
+            return;
+        }
+    };
+
+    let mut encoded = [0u8; 3 * TERM_LEN];
+    let len = encode_body(&body, &mut encoded).unwrap();
+    assert_eq!(&encoded[..len], &payload[..N * TERM_LEN]);
+}
+
+// MAC of a message from SOURCE_ADDR to VOTE_REQUEST_PORT, computed with the standard library.
+fn reference_mac(key: &MacKey, words: &[u64]) -> u64 {
+    let mut hasher = SipHasher::new_with_keys(key.k0, key.k1);
+    hasher.write_u64(((SOURCE_ADDR as u64) << 16) | VOTE_REQUEST_PORT as u64);
+    for word in words {
+        hasher.write_u64(*word);
+    }
+    hasher.finish()
+}
@@ -0,0 +1,339 @@
+// Sequences of election messages with arbitrary bodies, handled by the code shared by the XDP
+// program and userspace, interleaved with the transitions userspace makes. A node must never
+// grant two votes in a term, nor grant one while leading, for a term not above its own or above
+// its vote ceiling.
+#![no_main]
+
+use libfuzzer_sys::arbitrary::{self, Arbitrary};
+use libfuzzer_sys::fuzz_target;
+use raft_main_common::handler::{self, NodeMaps, VoteDecision};
+use raft_main_common::{
+    CurrentNode, HeartbeatAck, LeaderNode, LogState, NodeState, Vote, LEADER_LEASE_GUARD_NS,
+};
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+
+const PEERS: [u32; 2] = [0x0a00_0002, 0x0a00_0003];
+
+#[derive(Debug, Arbitrary)]
+enum Event {
+    VoteRequest {
+        peer: bool,
+        body: [u64; 3],
+    },
+    VoteResponse {
+        peer: bool,
+        granted: bool,
+        body: [u64; 3],
+    },
+    Heartbeat {
+        peer: bool,
+        body: [u64; 3],
+    },
+    HeartbeatResponse {
+        peer: bool,
+        body: [u64; 3],
+    },
+    StepDown {
+        peer: bool,
+    },
+    Elapse {
+        ns: u32,
+    },
+    // Userspace.
+    ApplyLeaderHeartbeats,
+    ApplyLaterTerms,
+    StandAsCandidate,
+    BecomeLeader,
+    AppendToLog {
+        last_index: u64,
+        last_term: u64,
+    },
+    SendHeartbeat {
+        peer: bool,
+    },
+}
+
+fuzz_target!(|events: Vec<Event>| {
+    let maps = FuzzMaps::new();
+    let mut granted_terms = HashSet::new();
+
+    for event in events {
+        match event {
+            Event::VoteRequest { peer, body } => {
+                let state = handler::node_state(&maps);
+                let term = handler::current_term(&maps);
+                let voted = maps.voted_for_term(body[0]);
+
+                let mut decision = handler::handle_vote_request(&maps, &body);
+                if decision == VoteDecision::NotDurable {
+                    assert!(body[0] > maps.vote_ceiling.get());
+                    assert!(!maps.voted_for_term(body[0]));
+                    // As the userspace listener, which raises the ceiling on disk first.
+                    maps.raise_vote_ceiling(body[0]);
+                    decision = handler::handle_vote_request(&maps, &body);
+                }
+                if decision == VoteDecision::Granted {
+                    assert!(body[0] <= maps.vote_ceiling.get());
+                    assert_ne!(state, NodeState::Leader);
+                    assert!(
+                        body[0] > term && !voted,
+                        "vote granted for term {} in term {} ({:?})",
+                        body[0],
+                        term,
+                        PEERS[peer as usize]
+                    );
+                    assert!(
+                        granted_terms.insert(body[0]),
+                        "second vote granted in term {}",
+                        body[0]
+                    );
+                    assert!(maps.voted_for_term(body[0]));
+                    assert!(handler::current_term(&maps) >= body[0]);
+                }
+            }
+            Event::VoteResponse {
+                peer,
+                granted,
+                body,
+            } => {
+                let counts =
+                    handler::handle_vote_response(&maps, PEERS[peer as usize], granted, &body);
+                assert!(
+                    !counts
+                        || (handler::node_state(&maps) == NodeState::Candidate
+                            && body[0] == handler::current_term(&maps))
+                );
+            }
+            Event::Heartbeat { peer, body } => {
+                let term = handler::current_term(&maps);
+                let generation = maps.leader.get().generation;
+
+                if let Ok(response) = handler::handle_heartbeat(&maps, PEERS[peer as usize], &body)
+                {
+                    assert_eq!(response[0], body[0].max(term));
+                    assert_eq!(response[1], body[1]);
+                    // Heartbeats of deposed leaders are not recorded.
+                    assert!(body[0] >= term || maps.leader.get().generation == generation);
+                }
+            }
+            Event::HeartbeatResponse { peer, body } => {
+                handler::handle_heartbeat_response(&maps, PEERS[peer as usize], &body)
+            }
+            Event::StepDown { peer } => {
+                let _ = handler::handle_step_down(&maps, PEERS[peer as usize]);
+            }
+            Event::Elapse { ns } => maps.now_ns.set(maps.now_ns.get() + ns as u64),
+            Event::ApplyLeaderHeartbeats => maps.apply_leader_heartbeats(),
+            Event::ApplyLaterTerms => maps.apply_later_terms(),
+            Event::StandAsCandidate => {
+                let mut node = maps.node.get();
+                if node.state == NodeState::Leader || node.term == u64::MAX {
+                    continue;
+                }
+                // Userspace votes for itself, so the XDP program cannot grant the term away.
+                node.term += 1;
+                maps.raise_vote_ceiling(node.term);
+                if maps.insert_vote_term(node.term).is_err() {
+                    continue;
+                }
+                maps.raise_voted_term(node.term).unwrap();
+                node.state = NodeState::Candidate;
+                maps.node.set(node);
+                maps.vote_results.borrow_mut().clear();
+            }
+            Event::BecomeLeader => {
+                let mut node = maps.node.get();
+                if node.state == NodeState::Candidate
+                    && maps
+                        .vote_results
+                        .borrow()
+                        .values()
+                        .any(|granted| *granted == 1)
+                {
+                    node.state = NodeState::Leader;
+                    maps.node.set(node);
+                }
+            }
+            Event::AppendToLog {
+                last_index,
+                last_term,
+            } => maps.log_state.set(LogState {
+                last_index,
+                last_term,
+            }),
+            Event::SendHeartbeat { peer } => {
+                maps.heartbeats_sent
+                    .borrow_mut()
+                    .insert(PEERS[peer as usize], maps.now_ns());
+            }
+        }
+    }
+});
+
+struct FuzzMaps {
+    now_ns: Cell<u64>,
+    node: Cell<CurrentNode>,
+    leader: Cell<LeaderNode>,
+    applied_generation: Cell<u64>,
+    log_state: Cell<LogState>,
+    vote_terms: RefCell<HashSet<u64>>,
+    voted_term: Cell<u64>,
+    vote_ceiling: Cell<u64>,
+    vote_results: RefCell<HashMap<u32, u64>>,
+    acks: RefCell<HashMap<u32, HeartbeatAck>>,
+    heartbeats_sent: RefCell<HashMap<u32, u64>>,
+}
+
+impl FuzzMaps {
+    fn new() -> FuzzMaps {
+        FuzzMaps {
+            now_ns: Cell::new(10 * LEADER_LEASE_GUARD_NS),
+            node: Cell::new(CurrentNode {
+                state: NodeState::Follower,
+                term: 0,
+                peers: PEERS,
+                vote: Vote {
+                    in_progress: false,
+                    started_ts: 0,
+                    ended_ts: 0,
+                    election_timeout: 0,
+                },
+            }),
+            leader: Cell::new(LeaderNode {
+                last_seen: 0,
+                source_addr_raw: 0,
+                term_id: 0,
+                generation: 0,
+            }),
+            applied_generation: Cell::new(0),
+            log_state: Cell::new(LogState::default()),
+            vote_terms: RefCell::new(HashSet::new()),
+            voted_term: Cell::new(0),
+            vote_ceiling: Cell::new(0),
+            vote_results: RefCell::new(HashMap::new()),
+            acks: RefCell::new(HashMap::new()),
+            heartbeats_sent: RefCell::new(HashMap::new()),
+        }
+    }
+
+    // As AppState::raise_vote_ceiling.
+    fn raise_vote_ceiling(&self, term: u64) {
+        if self.vote_ceiling.get() < term {
+            self.vote_ceiling.set(term.saturating_add(10));
+        }
+    }
+
+    // As Election::apply_leader_heartbeats.
+    fn apply_leader_heartbeats(&self) {
+        let leader = self.leader.get();
+        if leader.generation == self.applied_generation.get() {
+            return;
+        }
+
+        let mut node = self.node.get();
+        if leader.term_id >= node.term.max(self.voted_term.get()) {
+            node.state = NodeState::Follower;
+            node.term = leader.term_id;
+            self.node.set(node);
+        }
+        self.applied_generation.set(leader.generation);
+    }
+
+    // As Election::apply_later_terms.
+    fn apply_later_terms(&self) {
+        let response_term = self.acks.borrow().values().map(|ack| ack.term).max();
+        let term = self.voted_term.get().max(response_term.unwrap_or_default());
+
+        let mut node = self.node.get();
+        if term > node.term {
+            node.state = NodeState::Follower;
+            node.term = term;
+            self.node.set(node);
+        }
+    }
+}
+
+impl NodeMaps for FuzzMaps {
+    fn now_ns(&self) -> u64 {
+        self.now_ns.get()
+    }
+
+    fn current_node(&self) -> Option<CurrentNode> {
+        Some(self.node.get())
+    }
+
+    fn leader_node(&self) -> Option<LeaderNode> {
+        Some(self.leader.get())
+    }
+
+    fn record_leader(&self, source_addr: u32, term: u64) -> Result<(), ()> {
+        let mut leader = self.leader.get();
+        leader.last_seen = self.now_ns();
+        leader.source_addr_raw = source_addr;
+        leader.term_id = term;
+        leader.generation += 1;
+        self.leader.set(leader);
+        Ok(())
+    }
+
+    fn expire_leader(&self) -> Result<(), ()> {
+        let mut leader = self.leader.get();
+        leader.last_seen = 0;
+        self.leader.set(leader);
+        Ok(())
+    }
+
+    fn log_state(&self) -> LogState {
+        self.log_state.get()
+    }
+
+    fn voted_for_term(&self, term: u64) -> bool {
+        self.vote_terms.borrow().contains(&term)
+    }
+
+    fn insert_vote_term(&self, term: u64) -> Result<(), ()> {
+        if self.vote_terms.borrow_mut().insert(term) {
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+
+    fn voted_term(&self) -> u64 {
+        self.voted_term.get()
+    }
+
+    fn raise_voted_term(&self, term: u64) -> Result<(), ()> {
+        self.voted_term.set(self.voted_term.get().max(term));
+        Ok(())
+    }
+
+    fn vote_ceiling(&self) -> u64 {
+        self.vote_ceiling.get()
+    }
+
+    fn insert_vote_result(&self, source_addr: u32, granted: u64) -> Result<(), ()> {
+        self.vote_results.borrow_mut().insert(source_addr, granted);
+        Ok(())
+    }
+
+    fn heartbeat_ack(&self, source_addr: u32) -> Option<HeartbeatAck> {
+        self.acks.borrow().get(&source_addr).copied()
+    }
+
+    fn insert_heartbeat_ack(&self, source_addr: u32, ack: &HeartbeatAck) -> Result<(), ()> {
+        self.acks.borrow_mut().insert(source_addr, *ack);
+        Ok(())
+    }
+
+    fn heartbeat_sent_ns(&self, source_addr: u32) -> Option<u64> {
+        self.heartbeats_sent.borrow().get(&source_addr).copied()
+    }
+
+    fn insert_heartbeat_latency(&self, _source_addr: u32, _latency_ns: u64) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn increment_counter(&self, _index: u32) {}
+}
@@ -0,0 +1,58 @@
+// Frames as received by the XDP program: header parsing, then the payload checks of the
+// election message ports, with and without the MAC trailer.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use raft_main_common::auth::{MAC_LEN, SEQ_LEN};
+use raft_main_common::packet::{self, Protocol, ETH_HDR_LEN, IPV4_HDR_LEN, PAYLOAD_OFFSET};
+use raft_main_common::{HEARTBEAT_WORDS, TERM_LEN, VOTE_REQUEST_WORDS};
+
+fuzz_target!(|frame: &[u8]| {
+    let header = match packet::parse_header(frame) {
+        Ok(Some(header)) => header,
+        Ok(None) => return,
+        Err(()) => {
+            assert!(frame.len() < PAYLOAD_OFFSET);
+            return;
+        }
+    };
+
+    // The fields are those the program rewrites in its responses.
+    assert!(frame.len() >= PAYLOAD_OFFSET);
+    assert_eq!(
+        header.source_addr.to_be_bytes(),
+        frame[ETH_HDR_LEN + 12..ETH_HDR_LEN + 16]
+    );
+    assert_eq!(
+        header.dest_port.to_be_bytes(),
+        frame[ETH_HDR_LEN + IPV4_HDR_LEN + 2..ETH_HDR_LEN + IPV4_HDR_LEN + 4]
+    );
+
+    if header.protocol != Protocol::Udp {
+        return;
+    }
+
+    for trailer_len in [0, SEQ_LEN + MAC_LEN] {
+        check_message::<1>(frame, trailer_len); // Step down.
+        check_message::<VOTE_REQUEST_WORDS>(frame, trailer_len);
+        check_message::<HEARTBEAT_WORDS>(frame, trailer_len);
+    }
+});
+
+// A message is accepted only if it fills the payload exactly, and then all of it can be read.
+fn check_message<const N: usize>(frame: &[u8], trailer_len: usize) {
+    let exact = frame.len() == PAYLOAD_OFFSET + N * TERM_LEN + trailer_len;
+    assert_eq!(packet::is_message_in_payload(frame, trailer_len, N), exact);
+
+    if !exact {
+        return;
+    }
+
+    let body = packet::parse_body::<_, N>(frame).unwrap();
+    assert_eq!(Ok(body[0]), packet::parse_term_in_payload(frame));
+    if trailer_len > 0 {
+        packet::parse_u64_in_payload(frame, N * TERM_LEN).unwrap();
+        packet::parse_u64_in_payload(frame, N * TERM_LEN + SEQ_LEN).unwrap();
+    }
+    assert!(packet::parse_u64_in_payload(frame, N * TERM_LEN + trailer_len).is_err());
+}
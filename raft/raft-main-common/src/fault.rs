@@ -0,0 +1,142 @@
+// Fault injection for chaos testing.
+//
+// The FAULTS map holds a Fault for each peer address, and one for FAULT_ALL_PEERS applying to
+// every peer. The XDP program drops Raft messages received from a peer that match either.
+// Faults only act on received packets: to cut the link between two nodes in both directions,
+// set a fault on each of them.
+
+use crate::packet::Protocol;
+use crate::{
+    APPEND_ENTRIES_PORT, APPEND_ENTRIES_RESPONSE_PORT, HEARTBEAT_REQUEST_PORT,
+    HEARTBEAT_RESPONSE_PORT, LEADER_STEP_DOWN_PORT, SNAPSHOT_PORT, VOTE_REQUEST_PORT,
+    VOTE_RESPONSE_PORT_NO, VOTE_RESPONSE_PORT_YES,
+};
+
+// FAULTS key of the fault applying to all peers (0.0.0.0 is never a member).
+pub const FAULT_ALL_PEERS: u32 = 0;
+
+// Drop probabilities are in parts per million.
+pub const FAULT_DROP_PPM_MAX: u32 = 1_000_000;
+
+// message types (bits of Fault::drop_messages)
+pub const FAULT_VOTE_REQUEST: u32 = 1 << 0;
+pub const FAULT_VOTE_RESPONSE: u32 = 1 << 1;
+pub const FAULT_HEARTBEAT_REQUEST: u32 = 1 << 2;
+pub const FAULT_HEARTBEAT_RESPONSE: u32 = 1 << 3;
+pub const FAULT_LEADER_STEP_DOWN: u32 = 1 << 4;
+pub const FAULT_APPEND_ENTRIES: u32 = 1 << 5;
+pub const FAULT_APPEND_ENTRIES_RESPONSE: u32 = 1 << 6;
+pub const FAULT_SNAPSHOT: u32 = 1 << 7;
+
+// Names of the message types, as used by the HTTP API.
+pub const FAULT_MESSAGE_NAMES: [(&str, u32); 8] = [
+    ("vote_request", FAULT_VOTE_REQUEST),
+    ("vote_response", FAULT_VOTE_RESPONSE),
+    ("heartbeat_request", FAULT_HEARTBEAT_REQUEST),
+    ("heartbeat_response", FAULT_HEARTBEAT_RESPONSE),
+    ("leader_step_down", FAULT_LEADER_STEP_DOWN),
+    ("append_entries", FAULT_APPEND_ENTRIES),
+    ("append_entries_response", FAULT_APPEND_ENTRIES_RESPONSE),
+    ("snapshot", FAULT_SNAPSHOT),
+];
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct Fault {
+    pub isolated: u32,      // Drop every Raft message.
+    pub drop_ppm: u32,      // Drop each Raft message with this probability.
+    pub drop_messages: u32, // Drop these message types (FAULT_* bits).
+}
+
+impl Fault {
+    // Check if a message of the given type is dropped, given a uniformly random number.
+    #[inline(always)]
+    pub fn drops(&self, message_type: u32, random: u32) -> bool {
+        self.isolated != 0
+            || self.drop_messages & message_type != 0
+            || random % FAULT_DROP_PPM_MAX < self.drop_ppm
+    }
+}
+
+// Message type of a packet, or 0 if it is not a Raft message.
+#[inline(always)]
+pub fn message_type(protocol: Protocol, dest_port: u16) -> u32 {
+    match (protocol, dest_port) {
+        (Protocol::Udp, VOTE_REQUEST_PORT) => FAULT_VOTE_REQUEST,
+        (Protocol::Udp, VOTE_RESPONSE_PORT_NO | VOTE_RESPONSE_PORT_YES) => FAULT_VOTE_RESPONSE,
+        (Protocol::Udp, HEARTBEAT_REQUEST_PORT) => FAULT_HEARTBEAT_REQUEST,
+        (Protocol::Udp, HEARTBEAT_RESPONSE_PORT) => FAULT_HEARTBEAT_RESPONSE,
+        (Protocol::Udp, LEADER_STEP_DOWN_PORT) => FAULT_LEADER_STEP_DOWN,
+        (Protocol::Udp, APPEND_ENTRIES_PORT) => FAULT_APPEND_ENTRIES,
+        (Protocol::Udp, APPEND_ENTRIES_RESPONSE_PORT) => FAULT_APPEND_ENTRIES_RESPONSE,
+        (Protocol::Tcp, SNAPSHOT_PORT) => FAULT_SNAPSHOT,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_fault_drops_nothing() {
+        let fault = Fault::default();
+
+        assert!(!fault.drops(FAULT_VOTE_REQUEST, 0));
+        assert!(!fault.drops(FAULT_APPEND_ENTRIES, u32::MAX));
+    }
+
+    #[test]
+    fn isolation_drops_every_message() {
+        let fault = Fault {
+            isolated: 1,
+            ..Fault::default()
+        };
+
+        for (_, message_type) in FAULT_MESSAGE_NAMES {
+            assert!(fault.drops(message_type, 999_999));
+        }
+    }
+
+    #[test]
+    fn message_types_are_dropped_selectively() {
+        let fault = Fault {
+            drop_messages: FAULT_HEARTBEAT_REQUEST | FAULT_APPEND_ENTRIES,
+            ..Fault::default()
+        };
+
+        assert!(fault.drops(FAULT_HEARTBEAT_REQUEST, 0));
+        assert!(fault.drops(FAULT_APPEND_ENTRIES, 0));
+        assert!(!fault.drops(FAULT_HEARTBEAT_RESPONSE, 0));
+        assert!(!fault.drops(FAULT_VOTE_REQUEST, 0));
+    }
+
+    #[test]
+    fn drop_probability_is_in_parts_per_million() {
+        let fault = Fault {
+            drop_ppm: 250_000,
+            ..Fault::default()
+        };
+
+        assert!(fault.drops(FAULT_VOTE_REQUEST, 249_999));
+        assert!(!fault.drops(FAULT_VOTE_REQUEST, 250_000));
+        assert!(fault.drops(FAULT_VOTE_REQUEST, 1_000_000)); // Wraps around.
+
+        let always = Fault {
+            drop_ppm: FAULT_DROP_PPM_MAX,
+            ..Fault::default()
+        };
+        assert!(always.drops(FAULT_VOTE_REQUEST, 999_999));
+    }
+
+    #[test]
+    fn only_raft_ports_are_message_types() {
+        assert_eq!(
+            message_type(Protocol::Udp, VOTE_RESPONSE_PORT_YES),
+            FAULT_VOTE_RESPONSE
+        );
+        assert_eq!(message_type(Protocol::Tcp, SNAPSHOT_PORT), FAULT_SNAPSHOT);
+        assert_eq!(message_type(Protocol::Tcp, APPEND_ENTRIES_PORT), 0);
+        assert_eq!(message_type(Protocol::Udp, 53), 0);
+    }
+}
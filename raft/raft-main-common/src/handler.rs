@@ -0,0 +1,716 @@
+// Handling of election messages, shared by the XDP program and userspace.
+//
+// The XDP program parses and authenticates messages, then hands their body to the functions
+// below, which decide what to record and how to answer. They reach node state only through
+// NodeMaps, which the XDP program implements with its BPF maps and userspace with its own
+// storage, so the decisions can be exercised outside the kernel.
+//
+// Message bodies are big-endian u64 words (see VOTE_REQUEST_WORDS and HEARTBEAT_WORDS),
+// followed by the MAC trailer when authentication is enabled (see auth).
+
+// Errors are unit, like those of the BPF map helpers the XDP program implements NodeMaps with.
+#![allow(clippy::result_unit_err)]
+
+use crate::{
+    CurrentNode, HeartbeatAck, LeaderNode, LogState, NodeState, COUNTER_MAP_INSERT_FAILURES,
+    HEARTBEAT_WORDS, LEADER_LEASE_GUARD_NS, TERM_LEN, VOTE_REQUEST_WORDS, VOTE_RESPONSE_PORT_NO,
+    VOTE_RESPONSE_PORT_YES,
+};
+
+// Node state used to handle election messages: CURRENT_NODE, LEADER_NODE, LOG_STATE,
+// VOTE_TERMS, VOTED_TERM, VOTE_CEILING, VOTE_RESULTS, HEARTBEAT_ACKS, FOLLOWERS,
+// HEARTBEAT_LATENCY and COUNTERS.
+pub trait NodeMaps {
+    // Monotonic clock, in nanoseconds.
+    fn now_ns(&self) -> u64;
+
+    fn current_node(&self) -> Option<CurrentNode>;
+
+    fn leader_node(&self) -> Option<LeaderNode>;
+
+    // Record a heartbeat from `source_addr` in `term` as seen now, and bump the generation.
+    fn record_leader(&self, source_addr: u32, term: u64) -> Result<(), ()>;
+
+    // Mark the leader as not seen.
+    fn expire_leader(&self) -> Result<(), ()>;
+
+    fn log_state(&self) -> LogState;
+
+    fn voted_for_term(&self, term: u64) -> bool;
+
+    // Record a vote in `term`; fails if a vote in `term` is already recorded.
+    fn insert_vote_term(&self, term: u64) -> Result<(), ()>;
+
+    // Highest term in which this node granted its vote, stood as a candidate or refused a
+    // candidate with an outdated log.
+    fn voted_term(&self) -> u64;
+
+    // Raise the highest voted term to `term`, unless it is higher already.
+    fn raise_voted_term(&self, term: u64) -> Result<(), ()>;
+
+    // Highest term in which a vote may be granted. Userspace records it on disk before raising it,
+    // so a restarted node never votes again in a term it may have voted in.
+    fn vote_ceiling(&self) -> u64;
+
+    fn insert_vote_result(&self, source_addr: u32, granted: u64) -> Result<(), ()>;
+
+    fn heartbeat_ack(&self, source_addr: u32) -> Option<HeartbeatAck>;
+
+    fn insert_heartbeat_ack(&self, source_addr: u32, ack: &HeartbeatAck) -> Result<(), ()>;
+
+    // Time the last heartbeat was sent to `source_addr`.
+    fn heartbeat_sent_ns(&self, source_addr: u32) -> Option<u64>;
+
+    fn insert_heartbeat_latency(&self, source_addr: u32, latency_ns: u64) -> Result<(), ()>;
+
+    fn increment_counter(&self, index: u32);
+}
+
+// Outcome of a vote request.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum VoteDecision {
+    // Dropped without an answer.
+    Leader,       // We are the leader.
+    LeaderSeen,   // Our leader was seen recently, so it can rely on its lease.
+    AlreadyVoted, // We voted in the requested term.
+    NotRecorded,  // The vote could not be recorded, so granting it could grant two in the term.
+    NotDurable,   // The term is above the vote ceiling; userspace raises it and decides.
+    // Answered.
+    LogBehind, // The candidate's log is behind ours.
+    StaleTerm, // The requested term is not above ours.
+    Granted,
+}
+
+impl VoteDecision {
+    // Port the request is answered on, if any.
+    #[inline(always)]
+    pub fn response_port(&self) -> Option<u16> {
+        match self {
+            VoteDecision::Granted => Some(VOTE_RESPONSE_PORT_YES),
+            VoteDecision::LogBehind | VoteDecision::StaleTerm => Some(VOTE_RESPONSE_PORT_NO),
+            _ => None,
+        }
+    }
+}
+
+// Get current node state.
+#[inline(always)]
+pub fn node_state<M: NodeMaps>(maps: &M) -> NodeState {
+    match maps.current_node() {
+        Some(node) => node.state,
+        None => NodeState::Follower,
+    }
+}
+
+// Get current node term.
+// Includes the term of heartbeats and granted votes which userspace has not applied yet.
+#[inline(always)]
+pub fn current_term<M: NodeMaps>(maps: &M) -> u64 {
+    let node = match maps.current_node() {
+        Some(node) => node,
+        None => return 0,
+    };
+
+    let leader_term = match maps.leader_node() {
+        Some(leader) => leader.term_id,
+        None => 0,
+    };
+
+    node.term.max(leader_term).max(maps.voted_term())
+}
+
+// Check if a heartbeat from the leader arrived less than LEADER_LEASE_GUARD_NS ago.
+// Votes are not granted meanwhile, which is what leader leases rely on.
+#[inline(always)]
+pub fn leader_recently_seen<M: NodeMaps>(maps: &M) -> bool {
+    let last_seen = match maps.leader_node() {
+        Some(leader) => leader.last_seen,
+        None => 0,
+    };
+
+    maps.now_ns().saturating_sub(last_seen) < LEADER_LEASE_GUARD_NS
+}
+
+// Decide on a vote request (term, last log index, last log term) and record the vote.
+// A vote granted or refused for an outdated log raises the voted term, which userspace adopts as
+// its own (see Election::apply_later_terms). Only granted votes are recorded in the term, so a
+// refusal leaves later candidates of the term to be answered. Answers echo the request.
+#[inline(always)]
+pub fn handle_vote_request<M: NodeMaps>(
+    maps: &M,
+    request: &[u64; VOTE_REQUEST_WORDS],
+) -> VoteDecision {
+    if node_state(maps) == NodeState::Leader {
+        return VoteDecision::Leader;
+    }
+
+    if leader_recently_seen(maps) {
+        return VoteDecision::LeaderSeen;
+    }
+
+    let [term, last_log_index, last_log_term] = *request;
+    if maps.voted_for_term(term) {
+        return VoteDecision::AlreadyVoted;
+    }
+
+    let decision = if term <= current_term(maps) {
+        VoteDecision::StaleTerm
+    } else if !maps
+        .log_state()
+        .is_up_to_date(last_log_index, last_log_term)
+    {
+        VoteDecision::LogBehind
+    } else {
+        VoteDecision::Granted
+    };
+
+    if decision == VoteDecision::StaleTerm {
+        return decision;
+    }
+
+    if decision == VoteDecision::Granted && term > maps.vote_ceiling() {
+        return VoteDecision::NotDurable;
+    }
+
+    // The insert fails if the term was recorded meanwhile, e.g. by userspace standing as a candidate in it.
+    if decision == VoteDecision::Granted && maps.insert_vote_term(term).is_err() {
+        maps.increment_counter(COUNTER_MAP_INSERT_FAILURES);
+        return VoteDecision::NotRecorded;
+    }
+
+    // Leaders of earlier terms are refused from now on, so the candidate is elected with our log as it is.
+    // A candidate with an outdated log started a later term too, which we adopt without voting.
+    if maps.raise_voted_term(term).is_err() {
+        maps.increment_counter(COUNTER_MAP_INSERT_FAILURES);
+        if decision == VoteDecision::Granted {
+            return VoteDecision::NotRecorded;
+        }
+    }
+
+    decision
+}
+
+// Record a vote response, which echoes our request. Returns whether it counts.
+// Responses to requests of previous elections are ignored.
+#[inline(always)]
+pub fn handle_vote_response<M: NodeMaps>(
+    maps: &M,
+    source_addr: u32,
+    granted: bool,
+    request: &[u64; VOTE_REQUEST_WORDS],
+) -> bool {
+    if node_state(maps) != NodeState::Candidate || request[0] != current_term(maps) {
+        return false;
+    }
+
+    if maps
+        .insert_vote_result(source_addr, granted as u64)
+        .is_err()
+    {
+        maps.increment_counter(COUNTER_MAP_INSERT_FAILURES);
+        return false;
+    }
+
+    true
+}
+
+// Record a heartbeat (term, leader's send time, unused) from the leader and build the response,
+// which carries our term and our clock in the last word.
+// Heartbeats of a term below ours, including terms we voted in, come from a deposed leader: they
+// are not recorded, and the response tells the leader about the later term.
+// Userspace transitions to follower state and updates the term (see Election::apply_leader_heartbeats).
+#[inline(always)]
+pub fn handle_heartbeat<M: NodeMaps>(
+    maps: &M,
+    source_addr: u32,
+    heartbeat: &[u64; HEARTBEAT_WORDS],
+) -> Result<[u64; HEARTBEAT_WORDS], ()> {
+    let term = current_term(maps);
+    if heartbeat[0] < term {
+        return Ok([term, heartbeat[1], maps.now_ns()]);
+    }
+
+    maps.record_leader(source_addr, heartbeat[0])?;
+    Ok([heartbeat[0], heartbeat[1], maps.now_ns()])
+}
+
+// Record a heartbeat response from a follower, which carries the follower's term.
+// Acks of the current term extend the leader lease. A later term means the follower voted for
+// another candidate: the response is recorded too, so userspace steps down (see
+// Election::apply_later_terms), but never counts as an ack.
+#[inline(always)]
+pub fn handle_heartbeat_response<M: NodeMaps>(
+    maps: &M,
+    source_addr: u32,
+    heartbeat: &[u64; HEARTBEAT_WORDS],
+) {
+    if node_state(maps) == NodeState::Leader
+        && heartbeat[0] >= current_term(maps)
+        && record_heartbeat_ack(maps, source_addr, heartbeat[0], heartbeat[1], heartbeat[2])
+            .is_err()
+    {
+        maps.increment_counter(COUNTER_MAP_INSERT_FAILURES);
+    }
+
+    if let Some(sent_ns) = maps.heartbeat_sent_ns(source_addr) {
+        let latency_ns = maps.now_ns().saturating_sub(sent_ns);
+        if maps
+            .insert_heartbeat_latency(source_addr, latency_ns)
+            .is_err()
+        {
+            maps.increment_counter(COUNTER_MAP_INSERT_FAILURES);
+        }
+    }
+}
+
+// Record a follower's acknowledgement of a heartbeat sent at `sent_ns`, answered at `peer_ns`
+// on the follower's clock.
+#[inline(always)]
+pub fn record_heartbeat_ack<M: NodeMaps>(
+    maps: &M,
+    source_addr: u32,
+    term: u64,
+    sent_ns: u64,
+    peer_ns: u64,
+) -> Result<(), ()> {
+    let mut ack = HeartbeatAck {
+        term,
+        sent_ns,
+        peer_ns,
+        baseline_sent_ns: sent_ns,
+        baseline_peer_ns: peer_ns,
+    };
+
+    if let Some(previous) = maps.heartbeat_ack(source_addr) {
+        if previous.term == term {
+            // Acks may arrive out of order.
+            if previous.sent_ns > sent_ns {
+                return Ok(());
+            }
+            ack.baseline_sent_ns = previous.baseline_sent_ns;
+            ack.baseline_peer_ns = previous.baseline_peer_ns;
+        }
+    }
+
+    // Start measuring again if the drift is out of bounds, e.g. after the follower restarted.
+    if ack.drift_ppm().is_some() && !ack.drift_within_bounds() {
+        ack.baseline_sent_ns = sent_ns;
+        ack.baseline_peer_ns = peer_ns;
+    }
+
+    maps.insert_heartbeat_ack(source_addr, &ack)
+}
+
+// Handle the leader relinquishing leadership, so followers start an election without waiting
+// for the leader timeout. Returns whether `source_addr` was our leader.
+#[inline(always)]
+pub fn handle_step_down<M: NodeMaps>(maps: &M, source_addr: u32) -> Result<bool, ()> {
+    match maps.leader_node() {
+        Some(leader) if leader.source_addr_raw == source_addr => {}
+        Some(_) => return Ok(false),
+        None => return Err(()),
+    }
+
+    maps.expire_leader()?;
+    Ok(true)
+}
+
+// Parse a message body of N words from the start of a UDP payload.
+pub fn decode_body<const N: usize>(payload: &[u8]) -> Option<[u64; N]> {
+    if payload.len() < N * TERM_LEN {
+        return None;
+    }
+
+    let mut body = [0u64; N];
+    for (word, bytes) in body.iter_mut().zip(payload.chunks_exact(TERM_LEN)) {
+        *word = u64::from_be_bytes(bytes.try_into().ok()?);
+    }
+    Some(body)
+}
+
+// Write a message body of N words to the start of a UDP payload; returns the length written.
+pub fn encode_body<const N: usize>(body: &[u64; N], payload: &mut [u8]) -> Option<usize> {
+    if payload.len() < N * TERM_LEN {
+        return None;
+    }
+
+    for (word, bytes) in body.iter().zip(payload.chunks_exact_mut(TERM_LEN)) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    Some(N * TERM_LEN)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::Vote;
+    use core::cell::{Cell, RefCell};
+    use std::collections::{HashMap, HashSet};
+
+    const PEER: u32 = 0x0a00_0002;
+
+    struct FakeMaps {
+        now_ns: Cell<u64>,
+        node: Cell<CurrentNode>,
+        leader: Cell<LeaderNode>,
+        log_state: Cell<LogState>,
+        vote_terms: RefCell<HashSet<u64>>,
+        vote_terms_full: Cell<bool>,
+        voted_term: Cell<u64>,
+        vote_ceiling: Cell<u64>,
+        vote_results: RefCell<HashMap<u32, u64>>,
+        acks: RefCell<HashMap<u32, HeartbeatAck>>,
+        counters: RefCell<HashMap<u32, u64>>,
+    }
+
+    impl FakeMaps {
+        fn new(state: NodeState, term: u64) -> FakeMaps {
+            FakeMaps {
+                now_ns: Cell::new(10 * LEADER_LEASE_GUARD_NS),
+                node: Cell::new(CurrentNode {
+                    state,
+                    term,
+                    peers: [PEER, 0],
+                    vote: Vote {
+                        in_progress: false,
+                        started_ts: 0,
+                        ended_ts: 0,
+                        election_timeout: 0,
+                    },
+                }),
+                leader: Cell::new(LeaderNode {
+                    last_seen: 0,
+                    source_addr_raw: 0,
+                    term_id: 0,
+                    generation: 0,
+                }),
+                log_state: Cell::new(LogState::default()),
+                vote_terms: RefCell::new(HashSet::new()),
+                vote_terms_full: Cell::new(false),
+                voted_term: Cell::new(0),
+                vote_ceiling: Cell::new(u64::MAX),
+                vote_results: RefCell::new(HashMap::new()),
+                acks: RefCell::new(HashMap::new()),
+                counters: RefCell::new(HashMap::new()),
+            }
+        }
+    }
+
+    impl NodeMaps for FakeMaps {
+        fn now_ns(&self) -> u64 {
+            self.now_ns.get()
+        }
+
+        fn current_node(&self) -> Option<CurrentNode> {
+            Some(self.node.get())
+        }
+
+        fn leader_node(&self) -> Option<LeaderNode> {
+            Some(self.leader.get())
+        }
+
+        fn record_leader(&self, source_addr: u32, term: u64) -> Result<(), ()> {
+            let mut leader = self.leader.get();
+            leader.last_seen = self.now_ns();
+            leader.source_addr_raw = source_addr;
+            leader.term_id = term;
+            leader.generation += 1;
+            self.leader.set(leader);
+            Ok(())
+        }
+
+        fn expire_leader(&self) -> Result<(), ()> {
+            let mut leader = self.leader.get();
+            leader.last_seen = 0;
+            self.leader.set(leader);
+            Ok(())
+        }
+
+        fn log_state(&self) -> LogState {
+            self.log_state.get()
+        }
+
+        fn voted_for_term(&self, term: u64) -> bool {
+            self.vote_terms.borrow().contains(&term)
+        }
+
+        fn insert_vote_term(&self, term: u64) -> Result<(), ()> {
+            if self.vote_terms_full.get() || !self.vote_terms.borrow_mut().insert(term) {
+                return Err(());
+            }
+            Ok(())
+        }
+
+        fn voted_term(&self) -> u64 {
+            self.voted_term.get()
+        }
+
+        fn raise_voted_term(&self, term: u64) -> Result<(), ()> {
+            self.voted_term.set(self.voted_term.get().max(term));
+            Ok(())
+        }
+
+        fn vote_ceiling(&self) -> u64 {
+            self.vote_ceiling.get()
+        }
+
+        fn insert_vote_result(&self, source_addr: u32, granted: u64) -> Result<(), ()> {
+            self.vote_results.borrow_mut().insert(source_addr, granted);
+            Ok(())
+        }
+
+        fn heartbeat_ack(&self, source_addr: u32) -> Option<HeartbeatAck> {
+            self.acks.borrow().get(&source_addr).copied()
+        }
+
+        fn insert_heartbeat_ack(&self, source_addr: u32, ack: &HeartbeatAck) -> Result<(), ()> {
+            self.acks.borrow_mut().insert(source_addr, *ack);
+            Ok(())
+        }
+
+        fn heartbeat_sent_ns(&self, _source_addr: u32) -> Option<u64> {
+            None
+        }
+
+        fn insert_heartbeat_latency(&self, _source_addr: u32, _latency_ns: u64) -> Result<(), ()> {
+            Ok(())
+        }
+
+        fn increment_counter(&self, index: u32) {
+            *self.counters.borrow_mut().entry(index).or_default() += 1;
+        }
+    }
+
+    #[test]
+    fn vote_granted_for_higher_term_and_recorded() {
+        let maps = FakeMaps::new(NodeState::Follower, 4);
+        assert_eq!(
+            handle_vote_request(&maps, &[5, 0, 0]),
+            VoteDecision::Granted
+        );
+        assert!(maps.voted_for_term(5));
+        assert_eq!(
+            handle_vote_request(&maps, &[5, 0, 0]),
+            VoteDecision::AlreadyVoted
+        );
+    }
+
+    #[test]
+    fn granted_vote_raises_term() {
+        let maps = FakeMaps::new(NodeState::Follower, 4);
+        assert_eq!(
+            handle_vote_request(&maps, &[7, 0, 0]),
+            VoteDecision::Granted
+        );
+        assert_eq!((maps.voted_term(), current_term(&maps)), (7, 7));
+
+        // Refusals for a stale term do not.
+        assert_eq!(
+            handle_vote_request(&maps, &[6, 0, 0]),
+            VoteDecision::StaleTerm
+        );
+        assert_eq!(maps.voted_term(), 7);
+
+        // Refusals for an outdated log do, without recording a vote.
+        maps.log_state.set(LogState {
+            last_index: 10,
+            last_term: 4,
+        });
+        assert_eq!(
+            handle_vote_request(&maps, &[9, 1, 1]),
+            VoteDecision::LogBehind
+        );
+        assert_eq!((maps.voted_term(), current_term(&maps)), (9, 9));
+        assert!(!maps.voted_for_term(9));
+    }
+
+    #[test]
+    fn vote_refused_for_stale_term() {
+        let maps = FakeMaps::new(NodeState::Follower, 5);
+        assert_eq!(
+            handle_vote_request(&maps, &[5, 0, 0]),
+            VoteDecision::StaleTerm
+        );
+
+        // Heartbeats not yet applied by userspace count too.
+        let maps = FakeMaps::new(NodeState::Follower, 4);
+        maps.record_leader(PEER, 6).unwrap();
+        maps.expire_leader().unwrap();
+        assert_eq!(
+            handle_vote_request(&maps, &[6, 0, 0]),
+            VoteDecision::StaleTerm
+        );
+    }
+
+    #[test]
+    fn vote_refused_for_log_behind() {
+        let maps = FakeMaps::new(NodeState::Follower, 4);
+        maps.log_state.set(LogState {
+            last_index: 10,
+            last_term: 4,
+        });
+        assert_eq!(
+            handle_vote_request(&maps, &[5, 9, 4]),
+            VoteDecision::LogBehind
+        );
+        assert!(!maps.voted_for_term(5));
+
+        // Other candidates of the term are answered rather than dropped as already voted for.
+        assert_eq!(
+            handle_vote_request(&maps, &[5, 10, 4]),
+            VoteDecision::StaleTerm
+        );
+        assert_eq!(
+            handle_vote_request(&maps, &[6, 1, 5]),
+            VoteDecision::Granted
+        );
+    }
+
+    #[test]
+    fn vote_request_dropped_by_leader_and_while_leader_seen() {
+        let maps = FakeMaps::new(NodeState::Leader, 4);
+        assert_eq!(handle_vote_request(&maps, &[5, 0, 0]), VoteDecision::Leader);
+
+        let maps = FakeMaps::new(NodeState::Follower, 4);
+        maps.record_leader(PEER, 4).unwrap();
+        assert_eq!(
+            handle_vote_request(&maps, &[5, 0, 0]),
+            VoteDecision::LeaderSeen
+        );
+        assert!(!maps.voted_for_term(5));
+    }
+
+    #[test]
+    fn vote_not_granted_unless_recorded() {
+        let maps = FakeMaps::new(NodeState::Follower, 4);
+        maps.vote_terms_full.set(true);
+        assert_eq!(
+            handle_vote_request(&maps, &[5, 0, 0]),
+            VoteDecision::NotRecorded
+        );
+        assert_eq!(maps.counters.borrow()[&COUNTER_MAP_INSERT_FAILURES], 1);
+    }
+
+    #[test]
+    fn vote_above_ceiling_left_to_userspace() {
+        let maps = FakeMaps::new(NodeState::Follower, 4);
+        maps.vote_ceiling.set(5);
+        assert_eq!(
+            handle_vote_request(&maps, &[6, 0, 0]),
+            VoteDecision::NotDurable
+        );
+        assert!(!maps.voted_for_term(6));
+        assert_eq!(maps.voted_term(), 0);
+
+        // Refusals are not votes, so they are answered regardless.
+        maps.log_state.set(LogState {
+            last_index: 1,
+            last_term: 1,
+        });
+        assert_eq!(
+            handle_vote_request(&maps, &[6, 0, 0]),
+            VoteDecision::LogBehind
+        );
+
+        maps.vote_ceiling.set(7);
+        assert_eq!(
+            handle_vote_request(&maps, &[7, 1, 1]),
+            VoteDecision::Granted
+        );
+    }
+
+    #[test]
+    fn vote_responses_of_previous_terms_ignored() {
+        let maps = FakeMaps::new(NodeState::Candidate, 5);
+        assert!(!handle_vote_response(&maps, PEER, true, &[4, 0, 0]));
+        assert!(handle_vote_response(&maps, PEER, true, &[5, 0, 0]));
+        assert_eq!(maps.vote_results.borrow()[&PEER], 1);
+
+        let maps = FakeMaps::new(NodeState::Follower, 5);
+        assert!(!handle_vote_response(&maps, PEER, true, &[5, 0, 0]));
+    }
+
+    #[test]
+    fn heartbeat_recorded_and_answered_with_our_clock() {
+        let maps = FakeMaps::new(NodeState::Candidate, 4);
+        let response = handle_heartbeat(&maps, PEER, &[5, 123, 0]).unwrap();
+        assert_eq!(response, [5, 123, maps.now_ns()]);
+
+        let leader = maps.leader_node().unwrap();
+        assert_eq!(
+            (leader.source_addr_raw, leader.term_id, leader.generation),
+            (PEER, 5, 1)
+        );
+        assert_eq!(current_term(&maps), 5);
+    }
+
+    #[test]
+    fn stale_heartbeat_answered_with_our_term_and_not_recorded() {
+        let maps = FakeMaps::new(NodeState::Follower, 4);
+        let response = handle_heartbeat(&maps, PEER, &[3, 123, 0]).unwrap();
+        assert_eq!(response, [4, 123, maps.now_ns()]);
+        assert_eq!(maps.leader_node().unwrap().generation, 0);
+        assert!(!leader_recently_seen(&maps));
+
+        // Nor are heartbeats of a term below one we voted in.
+        assert_eq!(
+            handle_vote_request(&maps, &[6, 0, 0]),
+            VoteDecision::Granted
+        );
+        assert_eq!(handle_heartbeat(&maps, PEER, &[5, 123, 0]).unwrap()[0], 6);
+        assert_eq!(maps.leader_node().unwrap().generation, 0);
+
+        assert_eq!(handle_heartbeat(&maps, PEER, &[6, 123, 0]).unwrap()[0], 6);
+        assert_eq!(maps.leader_node().unwrap().term_id, 6);
+    }
+
+    #[test]
+    fn heartbeat_acks_of_current_term_recorded_by_leader() {
+        let maps = FakeMaps::new(NodeState::Leader, 5);
+        handle_heartbeat_response(&maps, PEER, &[4, 100, 200]);
+        assert!(maps.heartbeat_ack(PEER).is_none());
+
+        handle_heartbeat_response(&maps, PEER, &[5, 100, 200]);
+        handle_heartbeat_response(&maps, PEER, &[5, 300, 400]);
+        handle_heartbeat_response(&maps, PEER, &[5, 200, 300]); // Reordered.
+        let ack = maps.heartbeat_ack(PEER).unwrap();
+        assert_eq!(
+            (
+                ack.sent_ns,
+                ack.peer_ns,
+                ack.baseline_sent_ns,
+                ack.baseline_peer_ns
+            ),
+            (300, 400, 100, 200)
+        );
+
+        // A follower of a later term is recorded with its term, for userspace to step down.
+        handle_heartbeat_response(&maps, PEER, &[6, 500, 600]);
+        let ack = maps.heartbeat_ack(PEER).unwrap();
+        assert_eq!((ack.term, ack.sent_ns, ack.baseline_sent_ns), (6, 500, 500));
+    }
+
+    #[test]
+    fn step_down_only_from_leader() {
+        let maps = FakeMaps::new(NodeState::Follower, 5);
+        maps.record_leader(PEER, 5).unwrap();
+        assert_eq!(handle_step_down(&maps, PEER + 1), Ok(false));
+        assert!(leader_recently_seen(&maps));
+        assert_eq!(handle_step_down(&maps, PEER), Ok(true));
+        assert!(!leader_recently_seen(&maps));
+    }
+
+    #[test]
+    fn bodies_round_trip() {
+        let mut payload = [0u8; 3 * TERM_LEN + 1];
+        assert_eq!(
+            encode_body(&[1, u64::MAX, 3], &mut payload),
+            Some(3 * TERM_LEN)
+        );
+        assert_eq!(decode_body::<3>(&payload), Some([1, u64::MAX, 3]));
+        assert_eq!(decode_body::<3>(&payload[..3 * TERM_LEN - 1]), None);
+        assert_eq!(encode_body(&[1, 2, 3], &mut payload[..8]), None);
+    }
+}
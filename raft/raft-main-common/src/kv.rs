@@ -0,0 +1,85 @@
+// Key-value reads served by the XDP program on KV_GET_PORT.
+//
+// Requests and responses are a single KvMessage (KV_MESSAGE_LEN bytes), which the leader
+// answers in place from the KV_STORE map, a mirror of its applied key-value state.
+// Keys are zero-padded to KV_KEY_LEN bytes.
+
+pub const KV_KEY_LEN: usize = 32;
+pub const KV_VALUE_LEN: usize = 64;
+pub const KV_MESSAGE_LEN: usize = core::mem::size_of::<KvMessage>();
+
+// message status
+pub const KV_STATUS_REQUEST: u8 = 0;
+pub const KV_STATUS_FOUND: u8 = 1;
+pub const KV_STATUS_NOT_FOUND: u8 = 2;
+pub const KV_STATUS_NOT_LEADER: u8 = 3;
+pub const KV_STATUS_NO_LEASE: u8 = 4; // Leader cannot guarantee the read is up-to-date; retry or use HTTP.
+pub const KV_STATUS_TOO_LARGE: u8 = 5; // Value does not fit in KV_VALUE_LEN; use HTTP.
+pub const KV_STATUS_UNAVAILABLE: u8 = 6; // Mirror is incomplete, so missing keys are not authoritative; use HTTP.
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[repr(C)]
+pub struct KvKey {
+    pub bytes: [u8; KV_KEY_LEN],
+}
+
+impl KvKey {
+    // Keys longer than KV_KEY_LEN cannot be read through XDP.
+    pub fn from_bytes(key: &[u8]) -> Option<KvKey> {
+        if key.len() > KV_KEY_LEN {
+            return None;
+        }
+
+        let mut bytes = [0u8; KV_KEY_LEN];
+        bytes[..key.len()].copy_from_slice(key);
+        Some(KvKey { bytes })
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct KvValue {
+    pub len: u32, // KV_VALUE_TOO_LARGE if the value is only available through HTTP.
+    pub bytes: [u8; KV_VALUE_LEN],
+}
+
+pub const KV_VALUE_TOO_LARGE: u32 = u32::MAX;
+
+impl KvValue {
+    pub fn from_bytes(value: &[u8]) -> KvValue {
+        let mut bytes = [0u8; KV_VALUE_LEN];
+
+        if value.len() > KV_VALUE_LEN {
+            return KvValue {
+                len: KV_VALUE_TOO_LARGE,
+                bytes,
+            };
+        }
+
+        bytes[..value.len()].copy_from_slice(value);
+        KvValue {
+            len: value.len() as u32,
+            bytes,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct KvMessage {
+    pub status: u8,
+    pub value_len: u8,
+    pub key: KvKey,
+    pub value: [u8; KV_VALUE_LEN],
+}
+
+// State of the KV_STORE mirror, published by userspace after applying log entries.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct KvMirrorState {
+    // Term of the last applied entry. Reads are only served once the leader has applied an
+    // entry of its own term, as all entries committed by earlier leaders precede it.
+    pub applied_term: u64,
+    // Cleared while the latest value of some key could not be mirrored (e.g. KV_STORE is full).
+    pub complete: u64,
+}
@@ -1,11 +1,18 @@
 #![no_std]
 
-#[derive(Copy, Clone, Debug)]
+pub mod auth;
+pub mod fault;
+pub mod handler;
+pub mod kv;
+pub mod packet;
+
+#[derive(Copy, Clone, Debug, Default)]
 #[repr(C)]
 pub struct LeaderNode {
     pub last_seen: u64,
     pub source_addr_raw: u32,
     pub term_id: u64,
+    pub generation: u64, // Incremented by the eBPF program on every heartbeat.
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -36,15 +43,122 @@ pub struct Vote {
     pub election_timeout: u64,
 }
 
+// Last entry of the node's Raft log, published by userspace for vote decisions in XDP.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct LogState {
+    pub last_index: u64,
+    pub last_term: u64,
+}
+
+impl LogState {
+    // Raft election restriction: a candidate's log must be at least as up-to-date as ours.
+    #[inline(always)]
+    pub fn is_up_to_date(&self, candidate_last_index: u64, candidate_last_term: u64) -> bool {
+        candidate_last_term > self.last_term
+            || (candidate_last_term == self.last_term && candidate_last_index >= self.last_index)
+    }
+}
+
+// Latest heartbeat of the current term acknowledged by a follower, recorded by the leader's eBPF program.
+// The baseline is the first ack of the term; comparing it with the latest ack shows how fast the
+// follower's clock runs relative to ours.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct HeartbeatAck {
+    pub term: u64,
+    pub sent_ns: u64, // Leader's clock when the heartbeat was sent.
+    pub peer_ns: u64, // Follower's clock when it answered.
+    pub baseline_sent_ns: u64,
+    pub baseline_peer_ns: u64,
+}
+
+impl HeartbeatAck {
+    // Relative clock drift between the follower and us in parts per million, once the
+    // baseline is at least CLOCK_DRIFT_BASELINE_NS old.
+    #[inline(always)]
+    pub fn drift_ppm(&self) -> Option<u64> {
+        if self.sent_ns < self.baseline_sent_ns || self.peer_ns < self.baseline_peer_ns {
+            return Some(u64::MAX); // Follower restarted or acks were reordered.
+        }
+
+        let elapsed = self.sent_ns - self.baseline_sent_ns;
+        if elapsed < CLOCK_DRIFT_BASELINE_NS {
+            return None;
+        }
+
+        let peer_elapsed = self.peer_ns - self.baseline_peer_ns;
+        Some(peer_elapsed.abs_diff(elapsed) / (elapsed / 1_000_000))
+    }
+
+    // Check if the follower's clock is known to drift less than MAX_CLOCK_DRIFT_PPM.
+    #[inline(always)]
+    pub fn drift_within_bounds(&self) -> bool {
+        matches!(self.drift_ppm(), Some(ppm) if ppm <= MAX_CLOCK_DRIFT_PPM)
+    }
+}
+
 #[cfg(feature = "user")]
 unsafe impl aya::Pod for LeaderNode {}
 
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for HeartbeatAck {}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for kv::KvKey {}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for kv::KvValue {}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for kv::KvMirrorState {}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for LogState {}
+
 #[cfg(feature = "user")]
 unsafe impl aya::Pod for CurrentNode {}
 
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for auth::MacKey {}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for fault::Fault {}
+
 // ports
 pub const VOTE_REQUEST_PORT: u16 = 28000;
 pub const VOTE_RESPONSE_PORT_NO: u16 = 29000;
 pub const VOTE_RESPONSE_PORT_YES: u16 = 29001;
 pub const HEARTBEAT_REQUEST_PORT: u16 = 27001;
 pub const HEARTBEAT_RESPONSE_PORT: u16 = 27000;
+pub const LEADER_STEP_DOWN_PORT: u16 = 27002;
+pub const APPEND_ENTRIES_PORT: u16 = 27100; // Log replication, handled in userspace.
+pub const APPEND_ENTRIES_RESPONSE_PORT: u16 = 27101;
+pub const SNAPSHOT_PORT: u16 = 27102; // InstallSnapshot over TCP, handled in userspace.
+pub const KV_GET_PORT: u16 = 27200; // Key-value reads answered by XDP (see kv).
+
+// message layout
+pub const TERM_LEN: usize = 8;
+pub const VOTE_REQUEST_WORDS: usize = 3; // term, last log index, last log term
+pub const HEARTBEAT_WORDS: usize = 3; // term, leader's send time, follower's clock (set in responses)
+
+// leader lease
+// Followers ignore vote requests for LEADER_LEASE_GUARD_NS after a heartbeat from their leader.
+// A leader holding acks of heartbeats sent less than LEADER_LEASE_NS ago from LEASE_FOLLOWER_ACKS
+// followers (a quorum together with itself) therefore knows no other leader can exist yet.
+// Acks only count while the follower's clock drift is measured to be within MAX_CLOCK_DRIFT_PPM,
+// which the margin between the two durations covers.
+pub const LEADER_LEASE_GUARD_NS: u64 = 100_000_000; // 100 ms, the leader communication timeout.
+pub const MAX_CLOCK_DRIFT_PPM: u64 = 100_000; // 10%
+pub const LEADER_LEASE_NS: u64 =
+    LEADER_LEASE_GUARD_NS - LEADER_LEASE_GUARD_NS / 1_000_000 * MAX_CLOCK_DRIFT_PPM;
+pub const CLOCK_DRIFT_BASELINE_NS: u64 = 1_000_000_000; // Drift is measured over at least 1 s.
+pub const LEASE_FOLLOWER_ACKS: u32 = 1; // QUORUM - 1
+
+// counters (indices into the COUNTERS map)
+pub const COUNTERS_MAX_ENTRIES: u32 = 16;
+pub const COUNTER_NON_MEMBER_DROPS: u32 = 0;
+pub const COUNTER_MAC_FAILURES: u32 = 1;
+pub const COUNTER_REPLAYS: u32 = 2;
+pub const COUNTER_MAP_INSERT_FAILURES: u32 = 3;
+pub const COUNTER_FAULT_DROPS: u32 = 4; // Messages dropped by injected faults (see fault).
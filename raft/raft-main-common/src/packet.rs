@@ -0,0 +1,223 @@
+// Parsing of Ethernet/IPv4/UDP frames carrying Raft messages, shared by the XDP program and
+// userspace.
+//
+// The XDP program reads frames through bounds-checked pointers into the packet, userspace
+// (tests and fuzz targets) through byte slices; both implement Packet, so the same code
+// decides what a frame is and what its payload holds.
+
+// Errors are unit, like those of the XDP helpers the program implements Packet with.
+#![allow(clippy::result_unit_err)]
+
+use crate::TERM_LEN;
+
+pub const ETH_HDR_LEN: usize = 14;
+pub const IPV4_HDR_LEN: usize = 20; // Without options.
+pub const UDP_HDR_LEN: usize = 8;
+pub const PAYLOAD_OFFSET: usize = ETH_HDR_LEN + IPV4_HDR_LEN + UDP_HDR_LEN;
+
+const ETHER_TYPE_IPV4: u16 = 0x0800;
+const IPV4_VERSION_IHL: u8 = 0x45; // Version 4, header of 5 words.
+const IPV4_FRAGMENT_MASK: u16 = 0x3fff; // More fragments flag and fragment offset.
+const IP_PROTO_TCP: u8 = 6;
+const IP_PROTO_UDP: u8 = 17;
+
+// Bytes of a frame, starting with the Ethernet header.
+pub trait Packet {
+    // Copy N bytes at `offset`, if the frame holds them.
+    fn read<const N: usize>(&self, offset: usize) -> Option<[u8; N]>;
+
+    // Check if the frame holds `len` bytes at `offset`.
+    fn has_bytes(&self, offset: usize, len: usize) -> bool;
+}
+
+impl Packet for [u8] {
+    fn read<const N: usize>(&self, offset: usize) -> Option<[u8; N]> {
+        self.get(offset..offset.checked_add(N)?)?.try_into().ok()
+    }
+
+    fn has_bytes(&self, offset: usize, len: usize) -> bool {
+        matches!(offset.checked_add(len), Some(end) if end <= self.len())
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+// Fields of the IPv4 and transport headers the program dispatches on.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Header {
+    pub source_addr: u32,
+    pub protocol: Protocol,
+    pub dest_port: u16,
+}
+
+// Parse the headers of a frame. Frames which cannot carry Raft messages (other ether types and
+// protocols, IPv4 options, fragments) are Ok(None) and passed to the kernel; frames too short for
+// their headers are errors.
+#[inline(always)]
+pub fn parse_header<P: Packet + ?Sized>(packet: &P) -> Result<Option<Header>, ()> {
+    let ether_type = u16::from_be_bytes(packet.read::<2>(12).ok_or(())?);
+    if ether_type != ETHER_TYPE_IPV4 {
+        return Ok(None);
+    }
+
+    // The transport header must follow, as it does in every Raft message.
+    if !packet.has_bytes(ETH_HDR_LEN, IPV4_HDR_LEN + UDP_HDR_LEN) {
+        return Err(());
+    }
+
+    let [version_ihl] = packet.read::<1>(ETH_HDR_LEN).ok_or(())?;
+    let fragment = u16::from_be_bytes(packet.read::<2>(ETH_HDR_LEN + 6).ok_or(())?);
+    if version_ihl != IPV4_VERSION_IHL || fragment & IPV4_FRAGMENT_MASK != 0 {
+        return Ok(None);
+    }
+
+    let protocol = match packet.read::<1>(ETH_HDR_LEN + 9).ok_or(())? {
+        [IP_PROTO_TCP] => Protocol::Tcp,
+        [IP_PROTO_UDP] => Protocol::Udp,
+        _ => return Ok(None),
+    };
+
+    // TCP and UDP both start with the source and destination ports.
+    Ok(Some(Header {
+        source_addr: u32::from_be_bytes(packet.read::<4>(ETH_HDR_LEN + 12).ok_or(())?),
+        protocol,
+        dest_port: u16::from_be_bytes(packet.read::<2>(ETH_HDR_LEN + IPV4_HDR_LEN + 2).ok_or(())?),
+    }))
+}
+
+// Calculate if Raft term number (u64) and, if enabled, its MAC trailer are exactly the payload.
+#[inline(always)]
+pub fn is_term_in_payload<P: Packet + ?Sized>(packet: &P, trailer_len: usize) -> bool {
+    is_message_in_payload(packet, trailer_len, 1)
+}
+
+// Calculate if a message of `words` u64s and, if enabled, its MAC trailer are exactly the payload.
+#[inline(always)]
+pub fn is_message_in_payload<P: Packet + ?Sized>(
+    packet: &P,
+    trailer_len: usize,
+    words: usize,
+) -> bool {
+    let message_len = words * TERM_LEN + trailer_len;
+    if message_len < TERM_LEN {
+        return false;
+    }
+
+    // If there is data after the message, it is not a Raft message.
+    if packet.has_bytes(PAYLOAD_OFFSET + message_len, 1) {
+        return false;
+    }
+
+    packet.has_bytes(PAYLOAD_OFFSET + message_len - TERM_LEN, TERM_LEN)
+}
+
+#[inline(always)]
+pub fn parse_term_in_payload<P: Packet + ?Sized>(packet: &P) -> Result<u64, ()> {
+    parse_u64_in_payload(packet, 0)
+}
+
+// Parse big-endian u64 at the given offset of the UDP payload.
+#[inline(always)]
+pub fn parse_u64_in_payload<P: Packet + ?Sized>(packet: &P, offset: usize) -> Result<u64, ()> {
+    packet
+        .read::<TERM_LEN>(PAYLOAD_OFFSET + offset)
+        .map(u64::from_be_bytes)
+        .ok_or(())
+}
+
+// Parse a message body of N words from the start of the UDP payload.
+#[inline(always)]
+pub fn parse_body<P: Packet + ?Sized, const N: usize>(packet: &P) -> Result<[u64; N], ()> {
+    let mut body = [0u64; N];
+    let mut i = 0;
+    while i < N {
+        body[i] = parse_u64_in_payload(packet, i * TERM_LEN)?;
+        i += 1;
+    }
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::VOTE_REQUEST_PORT;
+    use std::vec::Vec;
+
+    const PEER_ADDR: u32 = 0x0a00_0002;
+
+    fn udp_frame(dest_port: u16, payload: &[u8]) -> Vec<u8> {
+        let mut frame = std::vec![0u8; PAYLOAD_OFFSET];
+        frame[12..14].copy_from_slice(&ETHER_TYPE_IPV4.to_be_bytes());
+        frame[ETH_HDR_LEN] = IPV4_VERSION_IHL;
+        frame[ETH_HDR_LEN + 9] = IP_PROTO_UDP;
+        frame[ETH_HDR_LEN + 12..ETH_HDR_LEN + 16].copy_from_slice(&PEER_ADDR.to_be_bytes());
+        frame[ETH_HDR_LEN + IPV4_HDR_LEN + 2..ETH_HDR_LEN + IPV4_HDR_LEN + 4]
+            .copy_from_slice(&dest_port.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn words(body: &[u64]) -> Vec<u8> {
+        body.iter().flat_map(|word| word.to_be_bytes()).collect()
+    }
+
+    #[test]
+    fn udp_header_parsed() {
+        let frame = udp_frame(VOTE_REQUEST_PORT, &words(&[5, 0, 0]));
+
+        assert_eq!(
+            parse_header(frame.as_slice()),
+            Ok(Some(Header {
+                source_addr: PEER_ADDR,
+                protocol: Protocol::Udp,
+                dest_port: VOTE_REQUEST_PORT
+            }))
+        );
+    }
+
+    #[test]
+    fn frames_without_raft_messages_passed() {
+        let mut arp = udp_frame(VOTE_REQUEST_PORT, &[]);
+        arp[12..14].copy_from_slice(&0x0806u16.to_be_bytes());
+        assert_eq!(parse_header(arp.as_slice()), Ok(None));
+
+        let mut icmp = udp_frame(VOTE_REQUEST_PORT, &[]);
+        icmp[ETH_HDR_LEN + 9] = 1;
+        assert_eq!(parse_header(icmp.as_slice()), Ok(None));
+
+        let mut options = udp_frame(VOTE_REQUEST_PORT, &[]);
+        options[ETH_HDR_LEN] = 0x46;
+        assert_eq!(parse_header(options.as_slice()), Ok(None));
+
+        let mut fragment = udp_frame(VOTE_REQUEST_PORT, &[]);
+        fragment[ETH_HDR_LEN + 7] = 1;
+        assert_eq!(parse_header(fragment.as_slice()), Ok(None));
+    }
+
+    #[test]
+    fn truncated_frames_rejected() {
+        let frame = udp_frame(VOTE_REQUEST_PORT, &[]);
+
+        assert_eq!(parse_header(&frame[..PAYLOAD_OFFSET - 1]), Err(()));
+        assert_eq!(parse_header(&frame[..ETH_HDR_LEN - 1]), Err(()));
+    }
+
+    #[test]
+    fn message_must_fill_payload() {
+        let frame = udp_frame(VOTE_REQUEST_PORT, &words(&[5, 6, 7]));
+
+        assert!(is_message_in_payload(frame.as_slice(), 0, 3));
+        assert!(!is_message_in_payload(frame.as_slice(), 0, 2));
+        assert!(!is_message_in_payload(frame.as_slice(), 0, 4));
+        assert!(!is_message_in_payload(frame.as_slice(), 16, 3));
+        assert!(!is_message_in_payload(frame.as_slice(), 0, 0));
+        assert_eq!(parse_body::<_, 3>(frame.as_slice()), Ok([5, 6, 7]));
+        assert_eq!(parse_body::<_, 4>(frame.as_slice()), Err(()));
+    }
+}
@@ -1,132 +1,382 @@
-use aya_bpf::programs::XdpContext;
-use network_types::{
-    eth::EthHdr,
-    ip::Ipv4Hdr,
-    udp::UdpHdr,
+use aya_bpf::{bindings::BPF_NOEXIST, programs::XdpContext};
+use network_types::udp::UdpHdr;
+use aya_bpf::helpers::{bpf_get_prandom_u32, bpf_ktime_get_ns};
+use raft_main_common::{
+    auth::{is_fresh, message_mac, replay_window_key, MacKey, SEQ_LEN},
+    fault::FAULT_ALL_PEERS,
+    handler::NodeMaps,
+    kv::{
+        KvMessage,
+        KvMirrorState,
+        KV_VALUE_LEN,
+        KV_STATUS_FOUND,
+        KV_STATUS_NOT_FOUND,
+        KV_STATUS_NOT_LEADER,
+        KV_STATUS_NO_LEASE,
+        KV_STATUS_TOO_LARGE,
+        KV_STATUS_UNAVAILABLE
+    },
+    packet::{parse_u64_in_payload, PAYLOAD_OFFSET},
+    CurrentNode,
+    HeartbeatAck,
+    NodeState,
+    LeaderNode,
+    LogState,
+    TERM_LEN,
+    LEADER_LEASE_NS,
+    LEASE_FOLLOWER_ACKS,
+    COUNTER_MAC_FAILURES,
+    COUNTER_REPLAYS
 };
-use aya_bpf::helpers::bpf_ktime_get_ns;
-use raft_main_common::{CurrentNode, NodeState, LeaderNode};
-use crate::helpers_xdp;
+use core::ptr::addr_of_mut;
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::helpers_xdp::{self, XdpPacket};
 use crate::maps;
 
-// Calculate if Raft term number (u64) is present in the payload.
+// Attempts at raising a replay window raced by other CPUs before a message is dropped.
+const REPLAY_WINDOW_RETRIES: u32 = 4;
+// Attempts at raising VOTED_TERM raced by other CPUs before the vote is not recorded.
+const VOTED_TERM_RETRIES: u32 = 4;
+
+// Get message authentication key. MACs are disabled unless userspace has set a key.
+#[inline(always)]
+pub fn mac_key() -> MacKey {
+    match maps::MAC_KEY.get(0) {
+        Some(value) => *value,
+        None => MacKey::default(),
+    }
+}
+
+// Get own sequence number (see raft_main_common::auth).
+#[inline(always)]
+pub fn sequence_number() -> u64 {
+    let base: u64 = match maps::SEQUENCE_BASE.get(0) {
+        Some(value) => *value,
+        None => 0,
+    };
+    base + unsafe { bpf_ktime_get_ns() }
+}
+
+// Verify MAC and sequence number trailing the message body; always succeeds when authentication is disabled.
 #[inline(always)]
-pub fn is_term_in_payload(ctx: &XdpContext) -> bool {
-    // If there is data after 8 bytes (u64 size), it is not u64 term number.
-    if helpers_xdp::ptr_exists::<[u8; 1]>(ctx, EthHdr::LEN + Ipv4Hdr::LEN + UdpHdr::LEN + 8) {
+pub fn is_authentic<const N: usize>(ctx: &XdpContext, mac_key: &MacKey, source_addr: u32, port: u16, body: &[u64; N]) -> bool {
+    if !mac_key.is_enabled() {
+        return true;
+    }
+
+    let body_len = N * TERM_LEN;
+    let (seq, mac) = match (parse_u64_in_payload(&XdpPacket(ctx), body_len), parse_u64_in_payload(&XdpPacket(ctx), body_len + SEQ_LEN)) {
+        (Ok(seq), Ok(mac)) => (seq, mac),
+        _ => {
+            increment_counter(COUNTER_MAC_FAILURES);
+            return false;
+        }
+    };
+
+    if mac != message_mac(mac_key, source_addr, port, body, seq) {
+        increment_counter(COUNTER_MAC_FAILURES);
         return false;
     }
 
-    // If size matches that of u64, it _must_ be the term number.
-    if helpers_xdp::ptr_exists::<[u8;8]>(ctx, EthHdr::LEN + Ipv4Hdr::LEN + UdpHdr::LEN) {
-        return true 
+    // Only authentic messages may advance the sender's high-water mark.
+    if !advance_replay_window(replay_window_key(source_addr, port), seq) {
+        increment_counter(COUNTER_REPLAYS);
+        return false;
     }
 
-    return false
+    true
 }
 
-
+// Raise the high-water mark of a sender to `seq`, if `seq` is fresh. The same message may be
+// processed on several CPUs at once, so the mark is raised with compare-and-swap, and each
+// sequence number is accepted at most once.
 #[inline(always)]
-pub fn parse_term_in_payload(ctx: &XdpContext) -> Result<u64, ()> {
-    let term_bytes: *mut [u8; 8] = match helpers_xdp::ptr_at(&ctx, EthHdr::LEN + Ipv4Hdr::LEN + UdpHdr::LEN) {
-        Ok(x) => {
-            x as *mut [u8; 8]
+fn advance_replay_window(window_key: u64, seq: u64) -> bool {
+    let now = sequence_number();
+
+    // The first message from a sender creates its window; a concurrent one finds it created.
+    if maps::REPLAY_WINDOWS.get_ptr_mut(&window_key).is_none() {
+        if !is_fresh(seq, 0, now) {
+            return false;
         }
-        Err(_) => {
-            return Err(());
+        if maps::REPLAY_WINDOWS.insert(&window_key, &seq, BPF_NOEXIST as u64).is_ok() {
+            return true;
         }
+    }
+
+    let highest_seen = match maps::REPLAY_WINDOWS.get_ptr_mut(&window_key) {
+        Some(value) => unsafe { &*(value as *const AtomicU64) },
+        None => return false,
     };
 
-    return unsafe 
-     { 
-        Ok(
-        (u64::from((*term_bytes)[0]) << 56) + 
-        (u64::from((*term_bytes)[1]) << 48) + 
-        (u64::from((*term_bytes)[2]) << 40) + 
-        (u64::from((*term_bytes)[3]) << 32) + 
-        (u64::from((*term_bytes)[4]) << 24) + 
-        (u64::from((*term_bytes)[5]) << 16) + 
-        (u64::from((*term_bytes)[6]) << 8) + 
-        u64::from((*term_bytes)[7])
-    )
+    // Retried while other messages from the sender raise the mark; bounded for the verifier.
+    let mut current = highest_seen.load(Ordering::SeqCst);
+    for _ in 0..REPLAY_WINDOW_RETRIES {
+        if !is_fresh(seq, current, now) {
+            return false;
+        }
+        match highest_seen.compare_exchange(current, seq, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => return true,
+            Err(actual) => current = actual,
+        }
     }
+
+    false
 }
 
-// Get current node state.
-pub fn get_current_node_state() -> Result<NodeState, ()> {
-    let current_node: CurrentNode = match maps::CURRENT_NODE.get(0) {
-        Some(value) => *value,
-        None => { 
-            return Ok(NodeState::Follower);
-        }
-    };
-    Ok(current_node.state)
+// Re-sign a rewritten message sent from `source_addr` to `port` with a new sequence number.
+// UDP checksum is cleared as the payload changes.
+#[inline(always)]
+pub fn sign_payload<const N: usize>(ctx: &XdpContext, udphdr: *mut UdpHdr, mac_key: &MacKey, source_addr: u32, port: u16, body: &[u64; N]) -> Result<(), ()> {
+    if !mac_key.is_enabled() {
+        return Ok(());
+    }
+
+    let body_len = N * TERM_LEN;
+    let seq = sequence_number();
+    let seq_bytes: *mut [u8; 8] = helpers_xdp::ptr_at(&ctx, PAYLOAD_OFFSET + body_len)?;
+    let mac_bytes: *mut [u8; 8] = helpers_xdp::ptr_at(&ctx, PAYLOAD_OFFSET + body_len + SEQ_LEN)?;
+    unsafe {
+        *seq_bytes = seq.to_be_bytes();
+        *mac_bytes = message_mac(mac_key, source_addr, port, body, seq).to_be_bytes();
+        (*udphdr).check = 0;
+    }
+    Ok(())
 }
 
-// Get current node term.
-pub fn current_node_term() -> Result<u64, ()> {
-    let current_node: CurrentNode = match maps::CURRENT_NODE.get(0) {
+// Node state in BPF maps, for the election message handlers shared with userspace.
+pub struct XdpMaps;
+
+impl NodeMaps for XdpMaps {
+    #[inline(always)]
+    fn now_ns(&self) -> u64 {
+        unsafe { bpf_ktime_get_ns() }
+    }
+
+    #[inline(always)]
+    fn current_node(&self) -> Option<CurrentNode> {
+        maps::CURRENT_NODE.get(0).copied()
+    }
+
+    #[inline(always)]
+    fn leader_node(&self) -> Option<LeaderNode> {
+        maps::LEADER_NODE.get(0).copied()
+    }
+
+    // CURRENT_NODE is only ever written by userspace, which applies the transition to follower
+    // state and the term update once it observes the new generation (see Election::apply_leader_heartbeats).
+    #[inline(always)]
+    fn record_leader(&self, source_addr: u32, term: u64) -> Result<(), ()> {
+        unsafe {
+            let leader_node: *mut LeaderNode = match maps::LEADER_NODE.get_ptr_mut(0) {
+                Some(value) => value,
+                None => { return Err(());}
+            };
+
+            (*leader_node).last_seen = bpf_ktime_get_ns();
+            (*leader_node).source_addr_raw = source_addr;
+            (*leader_node).term_id = term;
+
+            // Bumped last and atomically, so concurrent heartbeats on other CPUs are never lost.
+            let generation = &*(addr_of_mut!((*leader_node).generation) as *const AtomicU64);
+            generation.fetch_add(1, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn expire_leader(&self) -> Result<(), ()> {
+        match maps::LEADER_NODE.get_ptr_mut(0) {
+            Some(leader_node) => unsafe { (*leader_node).last_seen = 0 },
+            None => return Err(()),
+        }
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn log_state(&self) -> LogState {
+        match maps::LOG_STATE.get(0) {
             Some(value) => *value,
-            None => { 
-                return Err(());
+            None => LogState::default(),
+        }
+    }
+
+    #[inline(always)]
+    fn voted_for_term(&self, term: u64) -> bool {
+        unsafe {
+            match maps::VOTE_TERMS.get(&term) {
+                Some(value) => *value,
+                None => false,
             }
-    };
+        }
+    }
 
-    Ok(current_node.term)
-}
+    #[inline(always)]
+    fn insert_vote_term(&self, term: u64) -> Result<(), ()> {
+        maps::VOTE_TERMS.insert(&term, &true, BPF_NOEXIST as u64).map_err(|_| ())
+    }
 
-// Check if already voted for a given term.
-pub fn voted_for_term(term: u64) -> Result<bool, ()> {
-    unsafe {
-        let voted: bool = match maps::VOTE_TERMS.get(&term) {
+    #[inline(always)]
+    fn voted_term(&self) -> u64 {
+        match maps::VOTED_TERM.get(0) {
             Some(value) => *value,
-            None => {
-                false
-            }
-        };
-        return Ok(voted)
+            None => 0,
+        }
     }
-}
 
-// Become follower.
-pub fn become_follower() -> Result<(), ()> {
-    unsafe {
-        let current_node: *mut CurrentNode = match maps::CURRENT_NODE.get_ptr_mut(0) {
-                Some(value) => value,
-                None => { return Err(());}
+    // Raised with compare-and-swap, so a vote for a lower term granted on another CPU at the same
+    // instant cannot overwrite a higher one.
+    #[inline(always)]
+    fn raise_voted_term(&self, term: u64) -> Result<(), ()> {
+        let voted_term = match maps::VOTED_TERM.get_ptr_mut(0) {
+            Some(value) => unsafe { &*(value as *const AtomicU64) },
+            None => return Err(()),
         };
-        (*current_node).state = NodeState::Follower;
-        (*current_node).vote.in_progress = false; 
-        (*current_node).vote.started_ts = 0; 
-        (*current_node).vote.ended_ts = 0; 
-        (*current_node).vote.election_timeout = 0; 
+
+        let mut current = voted_term.load(Ordering::SeqCst);
+        for _ in 0..VOTED_TERM_RETRIES {
+            if current >= term {
+                return Ok(());
+            }
+            match voted_term.compare_exchange(current, term, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => return Ok(()),
+                Err(actual) => current = actual,
+            }
+        }
+        Err(())
+    }
+
+    // Unset until userspace has read it from disk, so no vote is granted before.
+    #[inline(always)]
+    fn vote_ceiling(&self) -> u64 {
+        match maps::VOTE_CEILING.get(0) {
+            Some(value) => *value,
+            None => 0,
+        }
+    }
+
+    #[inline(always)]
+    fn insert_vote_result(&self, source_addr: u32, granted: u64) -> Result<(), ()> {
+        maps::VOTE_RESULTS.insert(&source_addr, &granted, 0).map_err(|_| ())
+    }
+
+    #[inline(always)]
+    fn heartbeat_ack(&self, source_addr: u32) -> Option<HeartbeatAck> {
+        unsafe { maps::HEARTBEAT_ACKS.get(&source_addr).copied() }
+    }
+
+    #[inline(always)]
+    fn insert_heartbeat_ack(&self, source_addr: u32, ack: &HeartbeatAck) -> Result<(), ()> {
+        maps::HEARTBEAT_ACKS.insert(&source_addr, ack, 0).map_err(|_| ())
+    }
+
+    #[inline(always)]
+    fn heartbeat_sent_ns(&self, source_addr: u32) -> Option<u64> {
+        unsafe { maps::FOLLOWERS.get(&source_addr).copied() }
+    }
+
+    #[inline(always)]
+    fn insert_heartbeat_latency(&self, source_addr: u32, latency_ns: u64) -> Result<(), ()> {
+        maps::HEARTBEAT_LATENCY.insert(&source_addr, &latency_ns, 0).map_err(|_| ())
+    }
+
+    #[inline(always)]
+    fn increment_counter(&self, index: u32) {
+        increment_counter(index)
     }
-    Ok(())
 }
 
-// Update leader metadata.
-pub fn update_leader_metadata(source_addr: u32, term: u64) -> Result<(), ()> {
-    unsafe {
-        let leader_node: *mut LeaderNode = match maps::LEADER_NODE.get_ptr_mut(0) {
-            Some(value) => value,
-            None => { return Err(());}
+// Check if the leader lease holds: enough followers to form a quorum acknowledged a heartbeat
+// of the current term sent less than LEADER_LEASE_NS ago, and their clocks drift within bounds.
+// A later term, voted in by this node or answered by a follower, ends the lease before
+// userspace steps down.
+pub fn holds_lease(node: &CurrentNode) -> bool {
+    let now = unsafe { bpf_ktime_get_ns() };
+    let mut acks: u32 = 0;
+
+    if XdpMaps.voted_term() > node.term {
+        return false;
+    }
+
+    for peer in node.peers {
+        if peer == 0 {
+            continue;
+        }
+
+        let ack: HeartbeatAck = match unsafe { maps::HEARTBEAT_ACKS.get(&peer) } {
+            Some(value) => *value,
+            None => continue,
         };
 
-        (*leader_node).last_seen = bpf_ktime_get_ns();
-        (*leader_node).source_addr_raw = source_addr;
-        (*leader_node).term_id = term;
+        if ack.term > node.term {
+            return false;
+        }
+        if ack.term == node.term && ack.sent_ns <= now && now - ack.sent_ns < LEADER_LEASE_NS && ack.drift_within_bounds() {
+            acks += 1;
+        }
     }
-    Ok(())
+
+    acks >= LEASE_FOLLOWER_ACKS
 }
 
-// Update node term.
-pub fn update_node_term(incoming_term: u64) -> Result<(), ()>  {
+// Answer a key-value read in place.
+// Only the leader answers from the mirror, while its lease holds and once it has applied an
+// entry of its own term; otherwise the client is told to retry elsewhere.
+pub fn answer_kv_read(message: *mut KvMessage) -> Result<(), ()> {
+    let node: CurrentNode = match maps::CURRENT_NODE.get(0) {
+        Some(value) => *value,
+        None => return Err(()),
+    };
+
+    let mirror: KvMirrorState = match maps::KV_MIRROR.get(0) {
+        Some(value) => *value,
+        None => KvMirrorState::default(),
+    };
+
     unsafe {
-        let current_node: *mut CurrentNode = match maps::CURRENT_NODE.get_ptr_mut(0) {
-                Some(value) => value,
-                None => { return Err(());}
+        if node.state != NodeState::Leader {
+            (*message).status = KV_STATUS_NOT_LEADER;
+            return Ok(());
+        }
+
+        if mirror.applied_term != node.term || !holds_lease(&node) {
+            (*message).status = KV_STATUS_NO_LEASE;
+            return Ok(());
+        }
+
+        (*message).status = match maps::KV_STORE.get(&(*message).key) {
+            Some(value) if value.len as usize <= KV_VALUE_LEN => {
+                (*message).value = value.bytes;
+                (*message).value_len = value.len as u8;
+                KV_STATUS_FOUND
+            }
+            Some(_) => KV_STATUS_TOO_LARGE,
+            None if mirror.complete != 0 => KV_STATUS_NOT_FOUND,
+            None => KV_STATUS_UNAVAILABLE,
         };
-        (*current_node).term = incoming_term
     }
     Ok(())
+}
+
+// Check if source address is a member of the cluster.
+pub fn is_member(source_addr: u32) -> bool {
+    unsafe { maps::MEMBERS.get(&source_addr).is_some() }
+}
+
+// Check if an injected fault drops a Raft message from the source address (see raft_main_common::fault).
+#[inline(always)]
+pub fn is_dropped_by_fault(source_addr: u32, message_type: u32) -> bool {
+    let random = unsafe { bpf_get_prandom_u32() };
+    let peer = unsafe { maps::FAULTS.get(&source_addr) };
+    let all_peers = unsafe { maps::FAULTS.get(&FAULT_ALL_PEERS) };
+
+    matches!(peer, Some(fault) if fault.drops(message_type, random))
+        || matches!(all_peers, Some(fault) if fault.drops(message_type, random))
+}
+
+// Increment per-CPU counter.
+pub fn increment_counter(index: u32) {
+    if let Some(counter) = maps::COUNTERS.get_ptr_mut(index) {
+        unsafe { *counter += 1 }
+    }
 }
\ No newline at end of file
@@ -1,5 +1,6 @@
 use aya_bpf::programs::XdpContext;
 use core::mem;
+use raft_main_common::packet::Packet;
 
 #[inline(always)]
 pub fn ptr_at<T>(ctx: &XdpContext, offset: usize) -> Result<*mut T, ()> {
@@ -25,4 +26,19 @@ pub fn ptr_exists<T>(ctx: &XdpContext, offset: usize) -> bool {
     }
 
     return true
-}
\ No newline at end of file
+}
+// Frame of the XDP context, for the parsing shared with userspace.
+pub struct XdpPacket<'a>(pub &'a XdpContext);
+
+impl Packet for XdpPacket<'_> {
+    #[inline(always)]
+    fn read<const N: usize>(&self, offset: usize) -> Option<[u8; N]> {
+        let bytes: *const [u8; N] = ptr_at(self.0, offset).ok()?;
+        Some(unsafe { *bytes })
+    }
+
+    #[inline(always)]
+    fn has_bytes(&self, offset: usize, len: usize) -> bool {
+        self.0.data() + offset + len <= self.0.data_end()
+    }
+}
@@ -8,26 +8,39 @@ use aya_bpf::{
     helpers::bpf_ktime_get_ns,
 };
 use aya_log_ebpf::{debug, warn, info};
+use core::mem;
 use network_types::{
-    eth::{EthHdr, EtherType},
-    ip::{IpProto, Ipv4Hdr},
-    tcp::TcpHdr,
+    eth::EthHdr,
+    ip::Ipv4Hdr,
     udp::UdpHdr,
 };
 
 use raft_main_common::{
-    NodeState, 
+    fault,
+    handler::{self, VoteDecision},
+    packet::{self, Protocol, PAYLOAD_OFFSET},
     VOTE_REQUEST_PORT, 
     VOTE_RESPONSE_PORT_NO, 
     VOTE_RESPONSE_PORT_YES, 
     HEARTBEAT_REQUEST_PORT, 
-    HEARTBEAT_RESPONSE_PORT
+    HEARTBEAT_RESPONSE_PORT,
+    LEADER_STEP_DOWN_PORT,
+    KV_GET_PORT,
+    TERM_LEN,
+    VOTE_REQUEST_WORDS,
+    HEARTBEAT_WORDS,
+    kv::{KvMessage, KV_STATUS_REQUEST},
+    COUNTER_FAULT_DROPS,
+    COUNTER_NON_MEMBER_DROPS
 };
 
 mod helpers_raft;
 mod helpers_xdp;
 mod maps;
 
+use helpers_raft::XdpMaps;
+use helpers_xdp::XdpPacket;
+
 #[xdp]
 pub fn raft_main(ctx: XdpContext) -> u32 {
     match try_raft_main(ctx) {
@@ -37,88 +50,83 @@ pub fn raft_main(ctx: XdpContext) -> u32 {
 }
 
 fn try_raft_main(ctx: XdpContext) -> Result<u32, ()> {
-    let ethhdr: *mut EthHdr = helpers_xdp::ptr_at(&ctx, 0)?;
-    match unsafe { (*ethhdr).ether_type } {
-        EtherType::Ipv4 => {}
-        _ => return Ok(xdp_action::XDP_PASS),
-    }
+    let packet = XdpPacket(&ctx);
 
+    // Other ether types and protocols, IPv4 options and fragments are left to the kernel.
+    let header = match packet::parse_header(&packet)? {
+        Some(header) => header,
+        None => return Ok(xdp_action::XDP_PASS),
+    };
+    let (source_addr, protocol, dest_port) = (header.source_addr, header.protocol, header.dest_port);
+
+    // Headers of responses sent with XDP_TX are rewritten in place.
+    let ethhdr: *mut EthHdr = helpers_xdp::ptr_at(&ctx, 0)?;
     let ipv4hdr: *mut Ipv4Hdr = helpers_xdp::ptr_at(&ctx, EthHdr::LEN)?;
     let udphdr: *mut UdpHdr = helpers_xdp::ptr_at(&ctx, EthHdr::LEN + Ipv4Hdr::LEN)?;
 
-    let source_addr = u32::from_be(unsafe { (*ipv4hdr).src_addr });
+    // Log prefix
+    let execution_id = unsafe{bpf_ktime_get_ns()};
 
-    let protocol: IpProto = match unsafe { (*ipv4hdr).proto } {
-        IpProto::Tcp => IpProto::Tcp,
-        IpProto::Udp => IpProto::Udp,
-        _ => return Err(()),
-    };
+    // Drop Raft messages as set up by fault injection.
+    let message_type = fault::message_type(protocol, dest_port);
+    if message_type != 0 && helpers_raft::is_dropped_by_fault(source_addr, message_type) {
+        helpers_raft::increment_counter(COUNTER_FAULT_DROPS);
+        debug!(&ctx, "[XDP] [{}] [->] Dropping packet on port {} from '{}' by injected fault.", execution_id, dest_port, source_addr);
+        return Ok(xdp_action::XDP_DROP);
+    }
 
-    let dest_port = match unsafe { (*ipv4hdr).proto } {
-        IpProto::Tcp => {
-            let tcphdr: *const TcpHdr =
-                helpers_xdp::ptr_at(&ctx, EthHdr::LEN + Ipv4Hdr::LEN)?;
-            u16::from_be(unsafe { (*tcphdr).dest })
-        }
-        IpProto::Udp => {
-            let udphdr: *const UdpHdr =
-                helpers_xdp::ptr_at(&ctx, EthHdr::LEN + Ipv4Hdr::LEN)?;
-            u16::from_be(unsafe { (*udphdr).dest })
-        }
-        _ => return Err(()),
-    };
+    // Drop Raft traffic from addresses which are not members of the cluster, including log
+    // replication and snapshots. Their bodies are variable-length, so userspace checks their MAC.
+    if message_type != 0 && !helpers_raft::is_member(source_addr) {
+        helpers_raft::increment_counter(COUNTER_NON_MEMBER_DROPS);
+        debug!(&ctx, "[XDP] [{}] [->] Received packet on port {} from non-member '{}'; dropping.", execution_id, dest_port, source_addr);
+        return Ok(xdp_action::XDP_DROP);
+    }
 
-    // Log prefix
-    let execution_id = unsafe{bpf_ktime_get_ns()};
+    let mac_key = helpers_raft::mac_key();
 
     match (protocol, dest_port) {
-        (IpProto::Udp, VOTE_REQUEST_PORT) => {
-
-            // Drop vote requests if currently in a Leader state.
-            if helpers_raft::get_current_node_state().unwrap() == NodeState::Leader {
-                debug!(&ctx, "[XDP] [{}] [->] Received vote request with term from '{}', but I'm a leader; dropping.", execution_id, source_addr);
+        (Protocol::Udp, VOTE_REQUEST_PORT) => {
+            if !packet::is_message_in_payload(&packet, mac_key.trailer_len(), VOTE_REQUEST_WORDS) {
                 return Ok(xdp_action::XDP_DROP);
             }
 
-            let incoming_term_number: u64 = match helpers_raft::parse_term_in_payload(&ctx) {
-                Ok(x) => x,
+            // Vote requests carry the candidate's term and its last log index and term.
+            let vote_request = match packet::parse_body(&packet) {
+                Ok(body) => body,
                 Err(_) => return Ok(xdp_action::XDP_DROP)
             };
+            let [incoming_term_number, last_log_index, last_log_term] = vote_request;
 
-            // Drop vote requests for terms already voted for.
-            if helpers_raft::voted_for_term(incoming_term_number).unwrap_or_default() {
-                debug!(&ctx, "[XDP] [{}] [->] I already voted for term '{}' from '{}'; dropping.", execution_id, incoming_term_number, source_addr);
-                return Ok(xdp_action::XDP_DROP)
-            } 
-
-
-            let current_node_term = helpers_raft::current_node_term().unwrap_or_default();
-            // Default response
-            let mut vote_response: u16 = VOTE_RESPONSE_PORT_NO;
+            if !helpers_raft::is_authentic(&ctx, &mac_key, source_addr, dest_port, &vote_request) {
+                warn!(&ctx, "[XDP] [{}] [->] Received vote request from '{}' with invalid MAC; dropping.", execution_id, source_addr);
+                return Ok(xdp_action::XDP_DROP);
+            }
 
-            if incoming_term_number > current_node_term {
-                debug!(&ctx, "[XDP] [{}] [->] Received vote from '{}' with higher term number than mine ({} vs {}). Voting YES.", execution_id, source_addr, incoming_term_number, current_node_term);
-                vote_response = VOTE_RESPONSE_PORT_YES;
-            } else {
-                debug!(&ctx, "[XDP] [{}] [->] Received vote from '{}' with lower term number than mine ({} vs {}). Voting NO.", execution_id, source_addr, incoming_term_number, current_node_term);
-                vote_response = VOTE_RESPONSE_PORT_NO;
+            let current_node_term = handler::current_term(&XdpMaps);
+
+            let decision = handler::handle_vote_request(&XdpMaps, &vote_request);
+            match decision {
+                VoteDecision::Leader => debug!(&ctx, "[XDP] [{}] [->] Received vote request with term from '{}', but I'm a leader; dropping.", execution_id, source_addr),
+                VoteDecision::LeaderSeen => debug!(&ctx, "[XDP] [{}] [->] Received vote request from '{}', but leader was seen recently; dropping.", execution_id, source_addr),
+                VoteDecision::AlreadyVoted => debug!(&ctx, "[XDP] [{}] [->] I already voted for term '{}' from '{}'; dropping.", execution_id, incoming_term_number, source_addr),
+                VoteDecision::NotRecorded => warn!(&ctx, "[XDP] [{}] [->] Unable to record vote for term '{}' from '{}'; dropping.", execution_id, incoming_term_number, source_addr),
+                VoteDecision::NotDurable => debug!(&ctx, "[XDP] [{}] [->] Received vote request from '{}' for term '{}' above the vote ceiling; passing to userspace.", execution_id, source_addr, incoming_term_number),
+                VoteDecision::LogBehind => debug!(&ctx, "[XDP] [{}] [->] Received vote from '{}' whose log is behind mine (last entry {} in term {}). Voting NO.", execution_id, source_addr, last_log_index, last_log_term),
+                VoteDecision::StaleTerm => debug!(&ctx, "[XDP] [{}] [->] Received vote from '{}' with lower term number than mine ({} vs {}). Voting NO.", execution_id, source_addr, incoming_term_number, current_node_term),
+                VoteDecision::Granted => debug!(&ctx, "[XDP] [{}] [->] Received vote from '{}' with higher term number than mine ({} vs {}). Voting YES.", execution_id, source_addr, incoming_term_number, current_node_term),
             }
 
-            // Record voting for incoming term.
-            match maps::VOTE_TERMS.insert(&incoming_term_number, &true, 0) {
-                    Ok(()) => {},
-                    Err(_) => todo!()
-                }
+            // Userspace records a higher ceiling on disk before deciding (see udp::listener).
+            if decision == VoteDecision::NotDurable {
+                return Ok(xdp_action::XDP_PASS);
+            }
 
-            let decision = match vote_response {
-                VOTE_RESPONSE_PORT_YES => "YES",
-                VOTE_RESPONSE_PORT_NO => "NO",
-                _     => "unknown",
+            let vote_response = match decision.response_port() {
+                Some(port) => port,
+                None => return Ok(xdp_action::XDP_DROP),
             };
 
-
-            debug!(&ctx, "[XDP] [{}] [->] Final vote: '{}' to '{}' (incoming {} vs my term {})", execution_id, decision, source_addr, incoming_term_number, current_node_term);
-
             unsafe {
                 let src_addr = (*ipv4hdr).src_addr;
                 let dst_addr = (*ipv4hdr).dst_addr;
@@ -131,79 +139,77 @@ fn try_raft_main(ctx: XdpContext) -> Result<u32, ()> {
                 (*ethhdr).src_addr = dst_mac;
             }
 
+            let own_addr = u32::from_be(unsafe { (*ipv4hdr).src_addr });
+            helpers_raft::sign_payload(&ctx, udphdr, &mac_key, own_addr, vote_response, &vote_request)?;
+
             return Ok(xdp_action::XDP_TX);
         },
 
-        // Vote response port.
-        (IpProto::Udp, VOTE_RESPONSE_PORT_YES) => {
-            if helpers_raft::get_current_node_state().unwrap() == NodeState::Candidate {
-                let current_node_term = helpers_raft::current_node_term().unwrap_or_default();
-
-                match maps::VOTE_RESULTS.insert(&source_addr, &1, 0) {
-                    Ok(()) => {
-                        debug!(&ctx, "[XDP] [{}] [<-] Received 'NO' from {} for term {}", execution_id, source_addr, current_node_term);
-                    },
-                    Err(_) => todo!()
-                }
+        // Vote response ports.
+        (Protocol::Udp, VOTE_RESPONSE_PORT_YES | VOTE_RESPONSE_PORT_NO) => {
+            if !packet::is_message_in_payload(&packet, mac_key.trailer_len(), VOTE_REQUEST_WORDS) {
+                return Ok(xdp_action::XDP_DROP);
             }
 
-            return Ok(xdp_action::XDP_DROP);
-        },
+            // Vote responses echo the body of the vote request.
+            let vote_request = match packet::parse_body(&packet) {
+                Ok(body) => body,
+                Err(_) => return Ok(xdp_action::XDP_DROP)
+            };
 
-        // Vote response port.
-        (IpProto::Udp, VOTE_RESPONSE_PORT_NO) => {
-            if helpers_raft::get_current_node_state().unwrap() == NodeState::Candidate {
-                let current_node_term = helpers_raft::current_node_term().unwrap_or_default();
+            if !helpers_raft::is_authentic(&ctx, &mac_key, source_addr, dest_port, &vote_request) {
+                warn!(&ctx, "[XDP] [{}] [<-] Received vote response from '{}' with invalid MAC; dropping.", execution_id, source_addr);
+                return Ok(xdp_action::XDP_DROP);
+            }
 
-                match maps::VOTE_RESULTS.insert(&source_addr, &0, 0) {
-                    Ok(()) => {
-                        debug!(&ctx, "[XDP] [{}] [<-] Received 'NO' from {} for term {}", execution_id, source_addr, current_node_term);
-                    },
-                    Err(_) => todo!()
-                }
+            let granted = dest_port == VOTE_RESPONSE_PORT_YES;
+            if handler::handle_vote_response(&XdpMaps, source_addr, granted, &vote_request) {
+                debug!(&ctx, "[XDP] [{}] [<-] Received '{}' from {} for term {}", execution_id, if granted { "YES" } else { "NO" }, source_addr, vote_request[0]);
             }
 
             return Ok(xdp_action::XDP_DROP);
         },
 
-
         // Heartbeat request packets handled by nodes receiving heartbeat packets from the leader.
-        (IpProto::Udp, HEARTBEAT_REQUEST_PORT) => {
-            if !helpers_raft::is_term_in_payload(&ctx) {
+        (Protocol::Udp, HEARTBEAT_REQUEST_PORT) => {
+            if !packet::is_message_in_payload(&packet, mac_key.trailer_len(), HEARTBEAT_WORDS) {
                 warn!(&ctx, "[XDP] [{}]: Received a healthcheck packet, but Raft term is not present. Ignorning.", dest_port);
                 return Ok(xdp_action::XDP_PASS);
             };
 
-            let incoming_term_number: u64 = match helpers_raft::parse_term_in_payload(&ctx) {
-                Ok(x) => x,
+            // Heartbeats carry the term and the leader's send time, echoed in the response along with our clock.
+            let heartbeat = match packet::parse_body(&packet) {
+                Ok(body) => body,
                 Err(_) => {
                     warn!(&ctx, "[XDP] [{}]: Unable to parse Raft term number, ignoring.", dest_port);
                     return Ok(xdp_action::XDP_PASS)
                 }
             };
 
-            // Transition to follower state, if current state is candidate.
-            if helpers_raft::get_current_node_state().unwrap() != NodeState::Follower {
-                match helpers_raft::become_follower() {
-                    Ok(_) => info!(&ctx, "[XDP] [{}] Received a new heartbeat from leader '{}' with term '{}', transitioned to Follower state.", execution_id, source_addr, incoming_term_number),
-                    Err(_) => return Ok(xdp_action::XDP_DROP)
-                };
+            if !helpers_raft::is_authentic(&ctx, &mac_key, source_addr, dest_port, &heartbeat) {
+                warn!(&ctx, "[XDP] [{}] Received heartbeat from '{}' with invalid MAC; dropping.", execution_id, source_addr);
+                return Ok(xdp_action::XDP_DROP);
             }
 
-            let current_node_term = helpers_raft::current_node_term().unwrap_or_default();
+            let current_node_term = handler::current_term(&XdpMaps);
 
-            match helpers_raft::update_node_term(incoming_term_number) {
-                Ok(_) => info!(&ctx, "[XDP] [{}] Updated node term to {} (mine was {}).", execution_id, incoming_term_number, current_node_term),
+            let response = match handler::handle_heartbeat(&XdpMaps, source_addr, &heartbeat) {
+                Ok(response) => {
+                    info!(&ctx, "[XDP] [{}] Received heartbeat from leader '{}' with term {} (mine was {}).", execution_id, source_addr, heartbeat[0], current_node_term);
+                    response
+                },
                 Err(_) => return Ok(xdp_action::XDP_DROP)
             };
 
-            match helpers_raft::update_leader_metadata(source_addr, incoming_term_number) {
-                Ok(_) => debug!(&ctx, "[XDP] [{}] Updated leader metadata.", execution_id),
-                Err(_) => return Ok(xdp_action::XDP_DROP)
-            };
-            
+            // Send heartbeat response, with our term and clock.
+            let term: *mut [u8; 8] = helpers_xdp::ptr_at(&ctx, PAYLOAD_OFFSET)?;
+            let peer_ns: *mut [u8; 8] = helpers_xdp::ptr_at(&ctx, PAYLOAD_OFFSET + 2 * TERM_LEN)?;
+            unsafe {
+                *term = response[0].to_be_bytes();
+                *peer_ns = response[2].to_be_bytes();
+                (*udphdr).check = 0; // Payload changed.
+            }
 
-            // Send heartbeat response.
             unsafe {
                 let src_addr = (*ipv4hdr).src_addr;
                 let dst_addr = (*ipv4hdr).dst_addr;
@@ -216,34 +222,92 @@ fn try_raft_main(ctx: XdpContext) -> Result<u32, ()> {
                 (*ethhdr).src_addr = dst_mac;
             }
 
+            let own_addr = u32::from_be(unsafe { (*ipv4hdr).src_addr });
+            helpers_raft::sign_payload(&ctx, udphdr, &mac_key, own_addr, HEARTBEAT_RESPONSE_PORT, &response)?;
+
             return Ok(xdp_action::XDP_TX)
         },
 
         // Heartbeat response packets handled by the leader.
-        (IpProto::Udp, HEARTBEAT_RESPONSE_PORT) => {
-            unsafe {
-                if maps::FOLLOWERS.get(&source_addr).is_some() {
-                    let heartbeat_request_timestamp: u64 = match maps::FOLLOWERS.get(&source_addr) {
-                        Some(value) => *value,
-                        None => {
-                            0
-                        }
-                    };
-
-                    let heartbeat_request_latency: u64 = bpf_ktime_get_ns() - heartbeat_request_timestamp;
-
-                    match maps::HEARTBEAT_LATENCY.insert(&source_addr, &heartbeat_request_latency, 0) {
-                        Ok(()) => {
-                            debug!(&ctx, "[XDP] Received a heartbeat from {},{},{}", source_addr, bpf_ktime_get_ns(), heartbeat_request_latency);
-                        },
-                        Err(_) => todo!()
-                    }
-                    
-                }
+        (Protocol::Udp, HEARTBEAT_RESPONSE_PORT) => {
+            if !packet::is_message_in_payload(&packet, mac_key.trailer_len(), HEARTBEAT_WORDS) {
+                return Ok(xdp_action::XDP_DROP);
             }
 
+            let heartbeat = match packet::parse_body(&packet) {
+                Ok(body) => body,
+                Err(_) => return Ok(xdp_action::XDP_DROP)
+            };
+
+            if !helpers_raft::is_authentic(&ctx, &mac_key, source_addr, dest_port, &heartbeat) {
+                warn!(&ctx, "[XDP] [{}] Received heartbeat response from '{}' with invalid MAC; dropping.", execution_id, source_addr);
+                return Ok(xdp_action::XDP_DROP);
+            }
+
+            handler::handle_heartbeat_response(&XdpMaps, source_addr, &heartbeat);
+            debug!(&ctx, "[XDP] Received a heartbeat response from {} for term {}", source_addr, heartbeat[0]);
+
             return Ok(xdp_action::XDP_DROP) // Drop heartbeat response packet.
         },
+        // Leader relinquishing leadership; start an election without waiting for the leader timeout.
+        (Protocol::Udp, LEADER_STEP_DOWN_PORT) => {
+            if !packet::is_term_in_payload(&packet, mac_key.trailer_len()) {
+                return Ok(xdp_action::XDP_DROP);
+            }
+
+            let incoming_term_number: u64 = match packet::parse_term_in_payload(&packet) {
+                Ok(x) => x,
+                Err(_) => return Ok(xdp_action::XDP_DROP)
+            };
+
+            if !helpers_raft::is_authentic(&ctx, &mac_key, source_addr, dest_port, &[incoming_term_number]) {
+                warn!(&ctx, "[XDP] [{}] Received step down from '{}' with invalid MAC; dropping.", execution_id, source_addr);
+                return Ok(xdp_action::XDP_DROP);
+            }
+
+            match handler::handle_step_down(&XdpMaps, source_addr) {
+                Ok(true) => info!(&ctx, "[XDP] [{}] Leader '{}' stepped down in term {}.", execution_id, source_addr, incoming_term_number),
+                _ => debug!(&ctx, "[XDP] [{}] Ignoring step down from '{}', which is not the current leader.", execution_id, source_addr),
+            };
+
+            return Ok(xdp_action::XDP_DROP)
+        },
+        // Key-value reads from clients, answered in place from the KV_STORE mirror.
+        (Protocol::Udp, KV_GET_PORT) => {
+            if !helpers_xdp::ptr_exists::<KvMessage>(&ctx, PAYLOAD_OFFSET)
+                || helpers_xdp::ptr_exists::<[u8; 1]>(&ctx, PAYLOAD_OFFSET + mem::size_of::<KvMessage>()) {
+                return Ok(xdp_action::XDP_DROP);
+            }
+
+            let message: *mut KvMessage = helpers_xdp::ptr_at(&ctx, PAYLOAD_OFFSET)?;
+
+            // Never answer anything but requests, so responses cannot bounce between nodes.
+            if unsafe { (*message).status } != KV_STATUS_REQUEST {
+                return Ok(xdp_action::XDP_DROP);
+            }
+
+            helpers_raft::answer_kv_read(message)?;
+
+            unsafe {
+                let src_addr = (*ipv4hdr).src_addr;
+                let dst_addr = (*ipv4hdr).dst_addr;
+                let src_mac =  (*ethhdr).src_addr;
+                let dst_mac =  (*ethhdr).dst_addr;
+                let src_port = (*udphdr).source;
+                let dst_port = (*udphdr).dest;
+                (*ipv4hdr).dst_addr = src_addr;
+                (*ethhdr).dst_addr = src_mac;
+                (*udphdr).dest = src_port;
+                (*ipv4hdr).src_addr = dst_addr;
+                (*ethhdr).src_addr = dst_mac;
+                (*udphdr).source = dst_port;
+                (*udphdr).check = 0; // Payload changed.
+            }
+
+            debug!(&ctx, "[XDP] [{}] Answered key-value read from '{}' with status {}.", execution_id, source_addr, unsafe { (*message).status });
+
+            return Ok(xdp_action::XDP_TX)
+        },
         (_, _) => {
             debug!(&ctx, "Other traffic which is ignored...");
         },
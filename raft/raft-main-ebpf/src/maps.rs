@@ -1,19 +1,53 @@
 use aya_bpf::{
-    maps::{HashMap, Array},
+    maps::{HashMap, LruHashMap, Array, PerCpuArray},
     macros::map,
 };
-use raft_main_common::{LeaderNode, CurrentNode};
+use raft_main_common::{
+    auth::MacKey,
+    fault::Fault,
+    kv::{KvKey, KvMirrorState, KvValue},
+    LeaderNode, CurrentNode, HeartbeatAck, LogState, COUNTERS_MAX_ENTRIES
+};
 
+// Maps created with `pinned` are pinned by name under the directory passed to the loader
+// (see raft-main --pin), so Raft state survives restarts of the userspace process.
 
 #[map]
 pub static FOLLOWERS: HashMap<u32, u64> = HashMap::with_max_entries(1024, 0);
 #[map]
 pub static HEARTBEAT_LATENCY: HashMap<u32, u64> = HashMap::with_max_entries(1024, 0);
 #[map]
-pub static CURRENT_NODE: Array<CurrentNode> = Array::with_max_entries(1, 0);
+pub static CURRENT_NODE: Array<CurrentNode> = Array::pinned(1, 0);
+#[map]
+pub static LEADER_NODE: Array<LeaderNode> = Array::pinned(1, 0);
+// Terms voted in. When full, the least recently used term is evicted, which lookups refresh, so
+// any term may be forgotten. Votes are not granted twice regardless, as a vote is only granted
+// for a term above VOTED_TERM.
+#[map]
+pub static VOTE_TERMS: LruHashMap<u64, bool> = LruHashMap::pinned(8192, 0);
+#[map]
+pub static VOTED_TERM: Array<u64> = Array::pinned(1, 0); // Highest term voted in or adopted from a refused candidate; never evicted.
+#[map]
+pub static VOTE_CEILING: Array<u64> = Array::pinned(1, 0); // Highest term votes may be granted in, recorded on disk by userspace.
+#[map]
+pub static VOTE_RESULTS: HashMap<u32, u64> = HashMap::with_max_entries(1024, 0);
+#[map]
+pub static MEMBERS: HashMap<u32, u8> = HashMap::with_max_entries(1024, 0);
+#[map]
+pub static FAULTS: HashMap<u32, Fault> = HashMap::with_max_entries(1024, 0); // Injected faults, by peer address.
+#[map]
+pub static COUNTERS: PerCpuArray<u64> = PerCpuArray::with_max_entries(COUNTERS_MAX_ENTRIES, 0);
+#[map]
+pub static MAC_KEY: Array<MacKey> = Array::with_max_entries(1, 0);
+#[map]
+pub static SEQUENCE_BASE: Array<u64> = Array::with_max_entries(1, 0);
+#[map]
+pub static REPLAY_WINDOWS: HashMap<u64, u64> = HashMap::pinned(1024, 0);
+#[map]
+pub static LOG_STATE: Array<LogState> = Array::with_max_entries(1, 0);
 #[map]
-pub static LEADER_NODE: Array<LeaderNode> = Array::with_max_entries(1, 0);
+pub static HEARTBEAT_ACKS: HashMap<u32, HeartbeatAck> = HashMap::with_max_entries(1024, 0);
 #[map]
-pub static VOTE_TERMS: HashMap<u64, bool> = HashMap::with_max_entries(8192, 0);
+pub static KV_STORE: HashMap<KvKey, KvValue> = HashMap::with_max_entries(65536, 0); // Mirror of the applied key-value state.
 #[map]
-pub static VOTE_RESULTS: HashMap<u32, u64> = HashMap::with_max_entries(1024, 0);
\ No newline at end of file
+pub static KV_MIRROR: Array<KvMirrorState> = Array::with_max_entries(1, 0);
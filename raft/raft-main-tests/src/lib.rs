@@ -0,0 +1,252 @@
+// Harness running the XDP program on crafted packets with BPF_PROG_TEST_RUN.
+//
+// Each harness loads its own instance of the compiled eBPF object, with maps pinned in a private
+// directory, so tests can run in parallel and next to a running node. This needs the object built
+// by `cargo xtask build-ebpf` and root (or CAP_BPF), so the tests are ignored by default:
+//
+//     cargo xtask build-ebpf && sudo -E cargo test -p raft-main-tests -- --ignored
+
+use aya::maps::{Array, HashMap, MapData, PerCpuArray};
+use aya::programs::Xdp;
+use aya::{Bpf, BpfLoader, Pod};
+use std::fs;
+use std::os::fd::AsFd;
+use std::os::fd::AsRawFd;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub const NODE_ADDR: u32 = 0x0a00_0001; // 10.0.0.1, node running the program.
+pub const PEER_ADDR: u32 = 0x0a00_0002; // 10.0.0.2, member of the cluster.
+pub const OTHER_ADDR: u32 = 0x0a00_0063; // 10.0.0.99, not a member.
+
+pub const NODE_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x01];
+pub const PEER_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x02];
+pub const CLIENT_PORT: u16 = 40000;
+
+pub const ETH_LEN: usize = 14;
+pub const IPV4_LEN: usize = 20;
+pub const UDP_LEN: usize = 8;
+pub const PAYLOAD_OFFSET: usize = ETH_LEN + IPV4_LEN + UDP_LEN;
+
+pub const XDP_ABORTED: u32 = 0;
+pub const XDP_DROP: u32 = 1;
+pub const XDP_PASS: u32 = 2;
+pub const XDP_TX: u32 = 3;
+
+const BPF_PROG_TEST_RUN: libc::c_long = 10;
+
+// Anonymous struct of union bpf_attr used by BPF_PROG_TEST_RUN.
+#[repr(C)]
+#[derive(Default)]
+struct TestRunAttr {
+    prog_fd: u32,
+    retval: u32,
+    data_size_in: u32,
+    data_size_out: u32,
+    data_in: u64,
+    data_out: u64,
+    repeat: u32,
+    duration: u32,
+    ctx_size_in: u32,
+    ctx_size_out: u32,
+    ctx_in: u64,
+    ctx_out: u64,
+    flags: u32,
+    cpu: u32,
+    batch_size: u32,
+    _pad: u32,
+}
+
+// Loaded instance of the XDP program and its maps.
+pub struct XdpHarness {
+    bpf: Bpf,
+    pin_dir: PathBuf,
+}
+
+impl XdpHarness {
+    // Load the eBPF object, from RAFT_MAIN_EBPF_OBJECT or the debug build in the target directory.
+    pub fn load() -> XdpHarness {
+        let path = match std::env::var("RAFT_MAIN_EBPF_OBJECT") {
+            Ok(path) => PathBuf::from(path),
+            Err(_) => PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                .join("../target/bpfel-unknown-none/debug/raft-main"),
+        };
+        let object = fs::read(&path).unwrap_or_else(|err| {
+            panic!(
+                "failed to read {} (run `cargo xtask build-ebpf`): {}",
+                path.display(),
+                err
+            )
+        });
+
+        static INSTANCE: AtomicU64 = AtomicU64::new(0);
+        let pin_dir = PathBuf::from(format!(
+            "/sys/fs/bpf/raft-main-tests-{}-{}",
+            std::process::id(),
+            INSTANCE.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir_all(&pin_dir).expect("failed to create pin directory (is bpffs mounted?)");
+
+        let mut bpf = BpfLoader::new()
+            .map_pin_path(&pin_dir)
+            .load(&object)
+            .expect("failed to load eBPF object (are you root?)");
+        let program: &mut Xdp = bpf.program_mut("raft_main").unwrap().try_into().unwrap();
+        program.load().expect("failed to load XDP program");
+
+        XdpHarness { bpf, pin_dir }
+    }
+
+    // Run the program on a packet and return its XDP action and the packet as it left the program.
+    pub fn run(&self, packet: &[u8]) -> (u32, Vec<u8>) {
+        let program: &Xdp = self.bpf.program("raft_main").unwrap().try_into().unwrap();
+        let fd = program.fd().unwrap().as_fd().as_raw_fd();
+
+        let mut data_out = vec![0u8; packet.len() + 256];
+        let mut attr = TestRunAttr {
+            prog_fd: fd as u32,
+            data_size_in: packet.len() as u32,
+            data_size_out: data_out.len() as u32,
+            data_in: packet.as_ptr() as u64,
+            data_out: data_out.as_mut_ptr() as u64,
+            repeat: 1,
+            ..Default::default()
+        };
+
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_bpf,
+                BPF_PROG_TEST_RUN,
+                &mut attr as *mut TestRunAttr,
+                std::mem::size_of::<TestRunAttr>() as u32,
+            )
+        };
+        assert_eq!(
+            ret,
+            0,
+            "BPF_PROG_TEST_RUN failed: {}",
+            std::io::Error::last_os_error()
+        );
+
+        data_out.truncate(attr.data_size_out as usize);
+        (attr.retval, data_out)
+    }
+
+    pub fn array<V: Pod>(&mut self, name: &str) -> Array<&mut MapData, V> {
+        Array::try_from(self.bpf.map_mut(name).unwrap()).unwrap()
+    }
+
+    pub fn hash_map<K: Pod, V: Pod>(&mut self, name: &str) -> HashMap<&mut MapData, K, V> {
+        HashMap::try_from(self.bpf.map_mut(name).unwrap()).unwrap()
+    }
+
+    // Get a counter, summed over all CPUs.
+    pub fn counter(&mut self, index: u32) -> u64 {
+        let counters: PerCpuArray<&mut MapData, u64> =
+            PerCpuArray::try_from(self.bpf.map_mut("COUNTERS").unwrap()).unwrap();
+        counters.get(&index, 0).unwrap().iter().sum()
+    }
+
+    // Allow Raft traffic from the given addresses.
+    pub fn add_members(&mut self, addrs: &[u32]) {
+        let mut members = self.hash_map::<u32, u8>("MEMBERS");
+        for addr in addrs {
+            members.insert(addr, 1, 0).unwrap();
+        }
+    }
+}
+
+impl Drop for XdpHarness {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.pin_dir);
+    }
+}
+
+// Big-endian u64 words, as in Raft messages.
+pub fn words(body: &[u64]) -> Vec<u8> {
+    body.iter().flat_map(|word| word.to_be_bytes()).collect()
+}
+
+// Build an Ethernet/IPv4/UDP frame from a peer to the node.
+pub fn udp_packet(source_addr: u32, dest_port: u16, payload: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(PAYLOAD_OFFSET + payload.len());
+
+    packet.extend_from_slice(&NODE_MAC);
+    packet.extend_from_slice(&PEER_MAC);
+    packet.extend_from_slice(&0x0800u16.to_be_bytes());
+
+    let mut ipv4 = [0u8; IPV4_LEN];
+    ipv4[0] = 0x45;
+    ipv4[2..4].copy_from_slice(&((IPV4_LEN + UDP_LEN + payload.len()) as u16).to_be_bytes());
+    ipv4[8] = 64; // TTL
+    ipv4[9] = 17; // UDP
+    ipv4[12..16].copy_from_slice(&source_addr.to_be_bytes());
+    ipv4[16..20].copy_from_slice(&NODE_ADDR.to_be_bytes());
+    let checksum = ipv4_checksum(&ipv4);
+    ipv4[10..12].copy_from_slice(&checksum.to_be_bytes());
+    packet.extend_from_slice(&ipv4);
+
+    packet.extend_from_slice(&CLIENT_PORT.to_be_bytes());
+    packet.extend_from_slice(&dest_port.to_be_bytes());
+    packet.extend_from_slice(&((UDP_LEN + payload.len()) as u16).to_be_bytes());
+    packet.extend_from_slice(&0xffffu16.to_be_bytes()); // Checksum, cleared when payloads change.
+
+    packet.extend_from_slice(payload);
+    packet
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|pair| u16::from_be_bytes([pair[0], pair[1]]) as u32)
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+// Fields of a frame, for assertions on rewritten packets.
+pub fn eth_addrs(packet: &[u8]) -> ([u8; 6], [u8; 6]) {
+    (
+        packet[0..6].try_into().unwrap(),
+        packet[6..12].try_into().unwrap(),
+    )
+}
+
+pub fn ipv4_addrs(packet: &[u8]) -> (u32, u32) {
+    let ip = &packet[ETH_LEN..];
+    (
+        u32::from_be_bytes(ip[12..16].try_into().unwrap()),
+        u32::from_be_bytes(ip[16..20].try_into().unwrap()),
+    )
+}
+
+pub fn udp_ports(packet: &[u8]) -> (u16, u16) {
+    let udp = &packet[ETH_LEN + IPV4_LEN..];
+    (
+        u16::from_be_bytes([udp[0], udp[1]]),
+        u16::from_be_bytes([udp[2], udp[3]]),
+    )
+}
+
+pub fn udp_checksum(packet: &[u8]) -> u16 {
+    let udp = &packet[ETH_LEN + IPV4_LEN..];
+    u16::from_be_bytes([udp[6], udp[7]])
+}
+
+// Big-endian u64 at `offset` in the UDP payload.
+pub fn payload_word(packet: &[u8], offset: usize) -> u64 {
+    let start = PAYLOAD_OFFSET + offset;
+    u64::from_be_bytes(packet[start..start + 8].try_into().unwrap())
+}
+
+// Nanoseconds of the given clock.
+pub fn clock_ns(clock: libc::clockid_t) -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(clock, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
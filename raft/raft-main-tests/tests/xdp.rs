@@ -0,0 +1,643 @@
+// XDP program tests, run with BPF_PROG_TEST_RUN. See src/lib.rs for how to run them.
+
+use raft_main_common::auth::{message_mac, MacKey, MAC_LEN, SEQ_LEN};
+use raft_main_common::fault::{
+    Fault, FAULT_ALL_PEERS, FAULT_DROP_PPM_MAX, FAULT_HEARTBEAT_REQUEST,
+};
+use raft_main_common::kv::{KvMessage, KV_MESSAGE_LEN, KV_STATUS_NOT_LEADER, KV_STATUS_REQUEST};
+use raft_main_common::{
+    CurrentNode, HeartbeatAck, LeaderNode, LogState, NodeState, Vote, APPEND_ENTRIES_PORT,
+    COUNTER_FAULT_DROPS, COUNTER_MAC_FAILURES, COUNTER_NON_MEMBER_DROPS, HEARTBEAT_REQUEST_PORT,
+    HEARTBEAT_RESPONSE_PORT, KV_GET_PORT, SNAPSHOT_PORT, VOTE_REQUEST_PORT, VOTE_RESPONSE_PORT_NO,
+    VOTE_RESPONSE_PORT_YES,
+};
+use raft_main_tests::*;
+
+fn harness(state: NodeState, term: u64) -> XdpHarness {
+    let mut harness = XdpHarness::load();
+    harness.add_members(&[PEER_ADDR]);
+    harness
+        .array::<CurrentNode>("CURRENT_NODE")
+        .set(
+            0,
+            CurrentNode {
+                state,
+                term,
+                peers: [PEER_ADDR, 0],
+                vote: Vote {
+                    in_progress: false,
+                    started_ts: 0,
+                    ended_ts: 0,
+                    election_timeout: 0,
+                },
+            },
+            0,
+        )
+        .unwrap();
+    // Votes in any term are durable, except in vote_above_ceiling_passed_to_userspace.
+    harness
+        .array::<u64>("VOTE_CEILING")
+        .set(0, u64::MAX, 0)
+        .unwrap();
+    harness
+}
+
+// VOTE_TERMS values are bools in the eBPF program, which are not Pod.
+fn voted_for(harness: &mut XdpHarness, term: u64) -> bool {
+    harness
+        .hash_map::<u64, u8>("VOTE_TERMS")
+        .get(&term, 0)
+        .is_ok()
+}
+
+fn assert_reply_to_peer(packet: &[u8], dest_port: u16) {
+    assert_eq!(eth_addrs(packet), (PEER_MAC, NODE_MAC));
+    assert_eq!(ipv4_addrs(packet), (NODE_ADDR, PEER_ADDR));
+    assert_eq!(udp_ports(packet).1, dest_port);
+}
+
+#[test]
+#[ignore = "needs root and the eBPF object"]
+fn vote_granted_for_higher_term() {
+    let mut harness = harness(NodeState::Follower, 3);
+
+    let (action, packet) = harness.run(&udp_packet(
+        PEER_ADDR,
+        VOTE_REQUEST_PORT,
+        &words(&[5, 0, 0]),
+    ));
+
+    assert_eq!(action, XDP_TX);
+    assert_reply_to_peer(&packet, VOTE_RESPONSE_PORT_YES);
+    assert_eq!(payload_word(&packet, 0), 5);
+    assert!(voted_for(&mut harness, 5));
+
+    // The granted term is kept until userspace adopts it.
+    let voted_term: u64 = harness.array("VOTED_TERM").get(&0, 0).unwrap();
+    assert_eq!(voted_term, 5);
+}
+
+#[test]
+#[ignore = "needs root and the eBPF object"]
+fn vote_above_ceiling_passed_to_userspace() {
+    let mut harness = harness(NodeState::Follower, 3);
+    harness.array::<u64>("VOTE_CEILING").set(0, 4, 0).unwrap();
+
+    let (action, _) = harness.run(&udp_packet(
+        PEER_ADDR,
+        VOTE_REQUEST_PORT,
+        &words(&[5, 0, 0]),
+    ));
+
+    // Userspace raises the ceiling on disk, then grants the vote.
+    assert_eq!(action, XDP_PASS);
+    assert!(!voted_for(&mut harness, 5));
+    let voted_term: u64 = harness.array("VOTED_TERM").get(&0, 0).unwrap();
+    assert_eq!(voted_term, 0);
+}
+
+#[test]
+#[ignore = "needs root and the eBPF object"]
+fn vote_denied_for_current_term() {
+    let mut harness = harness(NodeState::Follower, 5);
+
+    let (action, packet) = harness.run(&udp_packet(
+        PEER_ADDR,
+        VOTE_REQUEST_PORT,
+        &words(&[5, 0, 0]),
+    ));
+
+    assert_eq!(action, XDP_TX);
+    assert_reply_to_peer(&packet, VOTE_RESPONSE_PORT_NO);
+    assert!(!voted_for(&mut harness, 5));
+
+    let voted_term: u64 = harness.array("VOTED_TERM").get(&0, 0).unwrap();
+    assert_eq!(voted_term, 0);
+}
+
+#[test]
+#[ignore = "needs root and the eBPF object"]
+fn vote_denied_for_candidate_with_stale_log() {
+    let mut harness = harness(NodeState::Follower, 3);
+    harness
+        .array::<LogState>("LOG_STATE")
+        .set(
+            0,
+            LogState {
+                last_index: 10,
+                last_term: 3,
+            },
+            0,
+        )
+        .unwrap();
+
+    // Older last term.
+    let (action, packet) = harness.run(&udp_packet(
+        PEER_ADDR,
+        VOTE_REQUEST_PORT,
+        &words(&[5, 20, 2]),
+    ));
+    assert_eq!(action, XDP_TX);
+    assert_reply_to_peer(&packet, VOTE_RESPONSE_PORT_NO);
+
+    // Same last term, shorter log.
+    let (action, packet) = harness.run(&udp_packet(
+        PEER_ADDR,
+        VOTE_REQUEST_PORT,
+        &words(&[6, 9, 3]),
+    ));
+    assert_eq!(action, XDP_TX);
+    assert_reply_to_peer(&packet, VOTE_RESPONSE_PORT_NO);
+
+    // The candidate's term is adopted, but no vote is recorded in it.
+    assert!(!voted_for(&mut harness, 6));
+    let voted_term: u64 = harness.array("VOTED_TERM").get(&0, 0).unwrap();
+    assert_eq!(voted_term, 6);
+
+    // Same last term, same length.
+    let (action, packet) = harness.run(&udp_packet(
+        PEER_ADDR,
+        VOTE_REQUEST_PORT,
+        &words(&[7, 10, 3]),
+    ));
+    assert_eq!(action, XDP_TX);
+    assert_reply_to_peer(&packet, VOTE_RESPONSE_PORT_YES);
+}
+
+#[test]
+#[ignore = "needs root and the eBPF object"]
+fn one_vote_per_term() {
+    let harness = harness(NodeState::Follower, 3);
+    let request = udp_packet(PEER_ADDR, VOTE_REQUEST_PORT, &words(&[5, 0, 0]));
+
+    assert_eq!(harness.run(&request).0, XDP_TX);
+    assert_eq!(harness.run(&request).0, XDP_DROP);
+}
+
+#[test]
+#[ignore = "needs root and the eBPF object"]
+fn no_vote_in_term_of_own_candidacy() {
+    let mut harness = harness(NodeState::Follower, 4);
+    // Recorded by userspace when standing as a candidate.
+    harness
+        .hash_map::<u64, u8>("VOTE_TERMS")
+        .insert(5, 1, 0)
+        .unwrap();
+
+    let (action, _) = harness.run(&udp_packet(
+        PEER_ADDR,
+        VOTE_REQUEST_PORT,
+        &words(&[5, 0, 0]),
+    ));
+
+    assert_eq!(action, XDP_DROP);
+}
+
+#[test]
+#[ignore = "needs root and the eBPF object"]
+fn vote_responses_of_previous_terms_ignored() {
+    let mut harness = harness(NodeState::Candidate, 5);
+
+    let (action, _) = harness.run(&udp_packet(
+        PEER_ADDR,
+        VOTE_RESPONSE_PORT_YES,
+        &words(&[4, 0, 0]),
+    ));
+    assert_eq!(action, XDP_DROP);
+    assert!(harness
+        .hash_map::<u32, u64>("VOTE_RESULTS")
+        .get(&PEER_ADDR, 0)
+        .is_err());
+
+    harness.run(&udp_packet(
+        PEER_ADDR,
+        VOTE_RESPONSE_PORT_YES,
+        &words(&[5, 0, 0]),
+    ));
+    assert_eq!(
+        harness
+            .hash_map::<u32, u64>("VOTE_RESULTS")
+            .get(&PEER_ADDR, 0)
+            .unwrap(),
+        1
+    );
+}
+
+#[test]
+#[ignore = "needs root and the eBPF object"]
+fn leader_drops_vote_requests() {
+    let mut harness = harness(NodeState::Leader, 3);
+
+    let (action, _) = harness.run(&udp_packet(
+        PEER_ADDR,
+        VOTE_REQUEST_PORT,
+        &words(&[5, 0, 0]),
+    ));
+
+    assert_eq!(action, XDP_DROP);
+    assert!(!voted_for(&mut harness, 5));
+}
+
+#[test]
+#[ignore = "needs root and the eBPF object"]
+fn vote_requests_dropped_while_leader_is_alive() {
+    let mut harness = harness(NodeState::Follower, 3);
+
+    let heartbeat = udp_packet(PEER_ADDR, HEARTBEAT_REQUEST_PORT, &words(&[3, 1, 0]));
+    assert_eq!(harness.run(&heartbeat).0, XDP_TX);
+
+    let (action, _) = harness.run(&udp_packet(
+        PEER_ADDR,
+        VOTE_REQUEST_PORT,
+        &words(&[5, 0, 0]),
+    ));
+    assert_eq!(action, XDP_DROP);
+    assert!(!voted_for(&mut harness, 5));
+}
+
+#[test]
+#[ignore = "needs root and the eBPF object"]
+fn malformed_vote_requests_dropped() {
+    let mut harness = harness(NodeState::Follower, 3);
+
+    // Too short.
+    let (action, _) = harness.run(&udp_packet(PEER_ADDR, VOTE_REQUEST_PORT, &words(&[5, 0])));
+    assert_eq!(action, XDP_DROP);
+
+    // Trailing data.
+    let mut payload = words(&[5, 0, 0]);
+    payload.push(0);
+    let (action, _) = harness.run(&udp_packet(PEER_ADDR, VOTE_REQUEST_PORT, &payload));
+    assert_eq!(action, XDP_DROP);
+
+    assert!(!voted_for(&mut harness, 5));
+}
+
+#[test]
+#[ignore = "needs root and the eBPF object"]
+fn raft_traffic_from_non_members_dropped() {
+    let mut harness = harness(NodeState::Follower, 3);
+
+    let (action, _) = harness.run(&udp_packet(
+        OTHER_ADDR,
+        VOTE_REQUEST_PORT,
+        &words(&[5, 0, 0]),
+    ));
+
+    assert_eq!(action, XDP_DROP);
+    assert_eq!(harness.counter(COUNTER_NON_MEMBER_DROPS), 1);
+    assert!(!voted_for(&mut harness, 5));
+}
+
+#[test]
+#[ignore = "needs root and the eBPF object"]
+fn replication_from_non_members_dropped() {
+    let mut harness = harness(NodeState::Follower, 3);
+
+    let append_entries = |source_addr| udp_packet(source_addr, APPEND_ENTRIES_PORT, b"{}");
+    assert_eq!(harness.run(&append_entries(OTHER_ADDR)).0, XDP_DROP);
+    assert_eq!(harness.run(&append_entries(PEER_ADDR)).0, XDP_PASS);
+
+    // Snapshots are sent over TCP, whose ports sit where UDP's do.
+    let snapshot = |source_addr| {
+        let mut packet = udp_packet(source_addr, SNAPSHOT_PORT, b"");
+        packet[ETH_LEN + 9] = 6;
+        packet
+    };
+    assert_eq!(harness.run(&snapshot(OTHER_ADDR)).0, XDP_DROP);
+    assert_eq!(harness.run(&snapshot(PEER_ADDR)).0, XDP_PASS);
+    assert_eq!(harness.counter(COUNTER_NON_MEMBER_DROPS), 2);
+}
+
+fn inject_fault(harness: &mut XdpHarness, addr: u32, fault: Fault) {
+    harness
+        .hash_map::<u32, Fault>("FAULTS")
+        .insert(addr, fault, 0)
+        .unwrap();
+}
+
+#[test]
+#[ignore = "needs root and the eBPF object"]
+fn isolated_peer_is_dropped() {
+    let mut harness = harness(NodeState::Follower, 3);
+    inject_fault(
+        &mut harness,
+        PEER_ADDR,
+        Fault {
+            isolated: 1,
+            ..Fault::default()
+        },
+    );
+
+    let (action, _) = harness.run(&udp_packet(
+        PEER_ADDR,
+        VOTE_REQUEST_PORT,
+        &words(&[5, 0, 0]),
+    ));
+    assert_eq!(action, XDP_DROP);
+    assert_eq!(harness.counter(COUNTER_FAULT_DROPS), 1);
+    assert!(!voted_for(&mut harness, 5));
+
+    // Other traffic from the peer still passes.
+    let (action, _) = harness.run(&udp_packet(PEER_ADDR, 53, b"query"));
+    assert_eq!(action, XDP_PASS);
+}
+
+#[test]
+#[ignore = "needs root and the eBPF object"]
+fn fault_drops_selected_message_types() {
+    let mut harness = harness(NodeState::Follower, 3);
+    inject_fault(
+        &mut harness,
+        PEER_ADDR,
+        Fault {
+            drop_messages: FAULT_HEARTBEAT_REQUEST,
+            ..Fault::default()
+        },
+    );
+
+    let (action, _) = harness.run(&udp_packet(
+        PEER_ADDR,
+        HEARTBEAT_REQUEST_PORT,
+        &words(&[7, 1234, 0]),
+    ));
+    assert_eq!(action, XDP_DROP);
+
+    let (action, _) = harness.run(&udp_packet(
+        PEER_ADDR,
+        VOTE_REQUEST_PORT,
+        &words(&[5, 0, 0]),
+    ));
+    assert_eq!(action, XDP_TX);
+    assert_eq!(harness.counter(COUNTER_FAULT_DROPS), 1);
+}
+
+#[test]
+#[ignore = "needs root and the eBPF object"]
+fn fault_for_all_peers_drops_with_probability() {
+    let mut harness = harness(NodeState::Follower, 3);
+    inject_fault(
+        &mut harness,
+        FAULT_ALL_PEERS,
+        Fault {
+            drop_ppm: FAULT_DROP_PPM_MAX,
+            ..Fault::default()
+        },
+    );
+
+    let (action, _) = harness.run(&udp_packet(
+        PEER_ADDR,
+        VOTE_REQUEST_PORT,
+        &words(&[5, 0, 0]),
+    ));
+    assert_eq!(action, XDP_DROP);
+    assert_eq!(harness.counter(COUNTER_FAULT_DROPS), 1);
+
+    inject_fault(&mut harness, FAULT_ALL_PEERS, Fault::default());
+    let (action, _) = harness.run(&udp_packet(
+        PEER_ADDR,
+        VOTE_REQUEST_PORT,
+        &words(&[5, 0, 0]),
+    ));
+    assert_eq!(action, XDP_TX);
+}
+
+#[test]
+#[ignore = "needs root and the eBPF object"]
+fn other_traffic_passes() {
+    let harness = harness(NodeState::Follower, 3);
+
+    let (action, _) = harness.run(&udp_packet(OTHER_ADDR, 53, b"query"));
+
+    assert_eq!(action, XDP_PASS);
+}
+
+#[test]
+#[ignore = "needs root and the eBPF object"]
+fn non_udp_traffic_passes() {
+    let harness = harness(NodeState::Follower, 3);
+
+    // ICMP, with a UDP-sized header on a Raft port.
+    let mut packet = udp_packet(PEER_ADDR, VOTE_REQUEST_PORT, &words(&[5, 0, 0]));
+    packet[ETH_LEN + 9] = 1;
+    assert_eq!(harness.run(&packet).0, XDP_PASS);
+
+    // IPv4 fragment.
+    let mut packet = udp_packet(PEER_ADDR, VOTE_REQUEST_PORT, &words(&[5, 0, 0]));
+    packet[ETH_LEN + 6] = 0x20; // More fragments.
+    assert_eq!(harness.run(&packet).0, XDP_PASS);
+}
+
+#[test]
+#[ignore = "needs root and the eBPF object"]
+fn heartbeat_records_leader_and_is_answered() {
+    let mut harness = harness(NodeState::Follower, 3);
+
+    let (action, packet) = harness.run(&udp_packet(
+        PEER_ADDR,
+        HEARTBEAT_REQUEST_PORT,
+        &words(&[7, 1234, 0]),
+    ));
+
+    assert_eq!(action, XDP_TX);
+    assert_reply_to_peer(&packet, HEARTBEAT_RESPONSE_PORT);
+    assert_eq!(payload_word(&packet, 0), 7);
+    assert_eq!(payload_word(&packet, 8), 1234); // Leader's send time is echoed.
+    assert_ne!(payload_word(&packet, 16), 0); // Our clock.
+    assert_eq!(udp_checksum(&packet), 0);
+
+    let leader: LeaderNode = harness.array("LEADER_NODE").get(&0, 0).unwrap();
+    assert_eq!(leader.source_addr_raw, PEER_ADDR);
+    assert_eq!(leader.term_id, 7);
+    assert_eq!(leader.generation, 1);
+    assert_ne!(leader.last_seen, 0);
+
+    // The term is applied by userspace.
+    let node: CurrentNode = harness.array("CURRENT_NODE").get(&0, 0).unwrap();
+    assert_eq!(node.term, 3);
+}
+
+#[test]
+#[ignore = "needs root and the eBPF object"]
+fn stale_heartbeat_answered_with_our_term() {
+    let mut harness = harness(NodeState::Follower, 5);
+
+    let (action, packet) = harness.run(&udp_packet(
+        PEER_ADDR,
+        HEARTBEAT_REQUEST_PORT,
+        &words(&[4, 1234, 0]),
+    ));
+
+    assert_eq!(action, XDP_TX);
+    assert_reply_to_peer(&packet, HEARTBEAT_RESPONSE_PORT);
+    assert_eq!(payload_word(&packet, 0), 5);
+    assert_eq!(payload_word(&packet, 8), 1234);
+
+    let leader: LeaderNode = harness.array("LEADER_NODE").get(&0, 0).unwrap();
+    assert_eq!(leader.generation, 0);
+    assert_eq!(leader.last_seen, 0);
+}
+
+#[test]
+#[ignore = "needs root and the eBPF object"]
+fn heartbeat_response_recorded_by_leader() {
+    let mut harness = harness(NodeState::Leader, 7);
+
+    let response = udp_packet(PEER_ADDR, HEARTBEAT_RESPONSE_PORT, &words(&[7, 1000, 5000]));
+    assert_eq!(harness.run(&response).0, XDP_DROP);
+
+    let ack: HeartbeatAck = harness
+        .hash_map("HEARTBEAT_ACKS")
+        .get(&PEER_ADDR, 0)
+        .unwrap();
+    assert_eq!((ack.term, ack.sent_ns, ack.peer_ns), (7, 1000, 5000));
+
+    // Acks of earlier terms are ignored.
+    let response = udp_packet(PEER_ADDR, HEARTBEAT_RESPONSE_PORT, &words(&[6, 2000, 6000]));
+    assert_eq!(harness.run(&response).0, XDP_DROP);
+
+    let ack: HeartbeatAck = harness
+        .hash_map("HEARTBEAT_ACKS")
+        .get(&PEER_ADDR, 0)
+        .unwrap();
+    assert_eq!((ack.term, ack.sent_ns), (7, 1000));
+
+    // As are responses with trailing data.
+    let mut payload = words(&[7, 3000, 7000]);
+    payload.push(0);
+    let response = udp_packet(PEER_ADDR, HEARTBEAT_RESPONSE_PORT, &payload);
+    assert_eq!(harness.run(&response).0, XDP_DROP);
+
+    let ack: HeartbeatAck = harness
+        .hash_map("HEARTBEAT_ACKS")
+        .get(&PEER_ADDR, 0)
+        .unwrap();
+    assert_eq!((ack.term, ack.sent_ns), (7, 1000));
+
+    // A follower of a later term is recorded for userspace to step down.
+    let response = udp_packet(PEER_ADDR, HEARTBEAT_RESPONSE_PORT, &words(&[8, 4000, 8000]));
+    assert_eq!(harness.run(&response).0, XDP_DROP);
+
+    let ack: HeartbeatAck = harness
+        .hash_map("HEARTBEAT_ACKS")
+        .get(&PEER_ADDR, 0)
+        .unwrap();
+    assert_eq!((ack.term, ack.sent_ns), (8, 4000));
+    let node: CurrentNode = harness.array("CURRENT_NODE").get(&0, 0).unwrap();
+    assert_eq!(node.term, 7);
+}
+
+#[test]
+#[ignore = "needs root and the eBPF object"]
+fn kv_read_answered_with_not_leader_by_follower() {
+    let harness = harness(NodeState::Follower, 3);
+
+    let mut request = vec![0u8; KV_MESSAGE_LEN];
+    request[0] = KV_STATUS_REQUEST;
+    request[2..5].copy_from_slice(b"foo");
+
+    let (action, packet) = harness.run(&udp_packet(OTHER_ADDR, KV_GET_PORT, &request));
+
+    assert_eq!(action, XDP_TX);
+    assert_eq!(ipv4_addrs(&packet), (NODE_ADDR, OTHER_ADDR));
+    assert_eq!(udp_ports(&packet), (KV_GET_PORT, CLIENT_PORT));
+    assert_eq!(packet[PAYLOAD_OFFSET], KV_STATUS_NOT_LEADER);
+    assert_eq!(
+        packet.len(),
+        PAYLOAD_OFFSET + std::mem::size_of::<KvMessage>()
+    );
+}
+
+// Authentication
+
+const KEY: [u8; 16] = *b"0123456789abcdef";
+
+fn authenticated_harness() -> XdpHarness {
+    let mut harness = harness(NodeState::Follower, 3);
+    harness
+        .array::<MacKey>("MAC_KEY")
+        .set(0, MacKey::from_bytes(KEY), 0)
+        .unwrap();
+    let sequence_base = clock_ns(libc::CLOCK_REALTIME) - clock_ns(libc::CLOCK_MONOTONIC);
+    harness
+        .array::<u64>("SEQUENCE_BASE")
+        .set(0, sequence_base, 0)
+        .unwrap();
+    harness
+}
+
+fn signed<const N: usize>(port: u16, body: &[u64; N], seq: u64) -> Vec<u8> {
+    let mac = message_mac(&MacKey::from_bytes(KEY), PEER_ADDR, port, body, seq);
+    let mut payload = words(body);
+    payload.extend_from_slice(&seq.to_be_bytes());
+    payload.extend_from_slice(&mac.to_be_bytes());
+    payload
+}
+
+#[test]
+#[ignore = "needs root and the eBPF object"]
+fn authenticated_vote_request_answered_with_signed_response() {
+    let harness = authenticated_harness();
+    let body = [5, 0, 0];
+    let seq = clock_ns(libc::CLOCK_REALTIME);
+
+    let (action, packet) = harness.run(&udp_packet(
+        PEER_ADDR,
+        VOTE_REQUEST_PORT,
+        &signed(VOTE_REQUEST_PORT, &body, seq),
+    ));
+
+    assert_eq!(action, XDP_TX);
+    assert_reply_to_peer(&packet, VOTE_RESPONSE_PORT_YES);
+    let response_seq = payload_word(&packet, 3 * 8);
+    let response_mac = payload_word(&packet, 3 * 8 + SEQ_LEN);
+    assert_eq!(
+        response_mac,
+        message_mac(
+            &MacKey::from_bytes(KEY),
+            NODE_ADDR,
+            VOTE_RESPONSE_PORT_YES,
+            &body,
+            response_seq
+        )
+    );
+    assert_eq!(packet.len(), PAYLOAD_OFFSET + 3 * 8 + SEQ_LEN + MAC_LEN);
+}
+
+#[test]
+#[ignore = "needs root and the eBPF object"]
+fn vote_request_with_invalid_mac_dropped() {
+    let mut harness = authenticated_harness();
+    let seq = clock_ns(libc::CLOCK_REALTIME);
+    let mut payload = signed(VOTE_REQUEST_PORT, &[5, 0, 0], seq);
+    let last = payload.len() - 1;
+    payload[last] ^= 1;
+
+    let (action, _) = harness.run(&udp_packet(PEER_ADDR, VOTE_REQUEST_PORT, &payload));
+
+    assert_eq!(action, XDP_DROP);
+    assert_eq!(harness.counter(COUNTER_MAC_FAILURES), 1);
+    assert!(!voted_for(&mut harness, 5));
+}
+
+#[test]
+#[ignore = "needs root and the eBPF object"]
+fn replayed_vote_request_dropped() {
+    let mut harness = authenticated_harness();
+    let seq = clock_ns(libc::CLOCK_REALTIME);
+    let request = udp_packet(
+        PEER_ADDR,
+        VOTE_REQUEST_PORT,
+        &signed(VOTE_REQUEST_PORT, &[5, 0, 0], seq),
+    );
+
+    assert_eq!(harness.run(&request).0, XDP_TX);
+
+    // Forget the vote, so only replay protection stops the second request.
+    harness
+        .hash_map::<u64, u8>("VOTE_TERMS")
+        .remove(&5)
+        .unwrap();
+    assert_eq!(harness.run(&request).0, XDP_DROP);
+    assert!(!voted_for(&mut harness, 5));
+}
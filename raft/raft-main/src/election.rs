@@ -0,0 +1,312 @@
+use crate::raft_log::Command;
+use crate::values;
+use log::info;
+use raft_main_common::{
+    CurrentNode, LeaderNode, NodeState, Vote, HEARTBEAT_WORDS, VOTE_REQUEST_WORDS,
+};
+use std::net::Ipv4Addr;
+use std::ops::Range;
+
+// The election state machine (fsm_*) reaches the outside world through the traits below only.
+// AppState implements them with the monotonic clock, thread_rng, the UDP socket and the BPF maps
+// shared with the XDP program; the simulator (see sim.rs) with a virtual clock, a seeded RNG and
+// an in-memory network.
+
+// Monotonic clock.
+pub trait Clock {
+    fn now_ns(&self) -> u64;
+
+    fn now_ms(&self) -> u64 {
+        self.now_ns() / 1_000_000
+    }
+}
+
+// Source of randomized timeouts.
+pub trait Random {
+    fn gen_range(&self, range: Range<u64>) -> u64;
+}
+
+// Sends election messages to the peers.
+// Vote responses and heartbeats are handled on arrival by the XDP program, which records them
+// in storage.
+pub trait Transport {
+    fn local_addr(&self) -> u32;
+
+    fn send_vote_requests(&self, request: [u64; VOTE_REQUEST_WORDS]);
+
+    fn send_heartbeats(&self, heartbeat: [u64; HEARTBEAT_WORDS]);
+}
+
+// Node state shared with the XDP program (CURRENT_NODE, LEADER_NODE, VOTE_RESULTS, VOTE_TERMS,
+// VOTED_TERM and HEARTBEAT_ACKS), and the userspace bookkeeping around it.
+pub trait Storage {
+    fn get_current_node(&self) -> CurrentNode;
+
+    // Read-modify-write of the current node; concurrent updates are not lost.
+    fn update_current_node(&self, update: impl FnOnce(&mut CurrentNode));
+
+    // Get the most recently seen leader.
+    fn get_leader(&self) -> LeaderNode;
+
+    // Generation of the last leader heartbeat applied to the current node.
+    fn applied_leader_generation(&self) -> u64;
+
+    fn set_applied_leader_generation(&self, generation: u64);
+
+    // Time the leader timer was last reset by userspace, in nanoseconds.
+    fn leader_timer_reset_ns(&self) -> u64;
+
+    fn set_leader_timer_reset_ns(&self, ns: u64);
+
+    fn get_current_yes_votes_from_peers(&self) -> u64;
+
+    // Reset vote result map items after each election.
+    fn reset_vote_results(&self);
+
+    // Record this node's vote in `term` (VOTE_TERMS), unless it already voted in that term or
+    // cannot record it on disk. Raises the highest voted term.
+    fn record_vote(&self, term: u64) -> bool;
+
+    // Highest term this node voted in, including votes granted by the eBPF program and terms of
+    // candidates it refused for an outdated log (VOTED_TERM).
+    fn voted_term(&self) -> u64;
+
+    // Highest term of the heartbeat responses recorded from the peers (HEARTBEAT_ACKS).
+    fn heartbeat_response_term(&self) -> u64;
+
+    // Index and term of the last log entry.
+    fn last_log_entry(&self) -> (u64, u64);
+
+    // Append an entry of the current term to the log, if this node is the leader.
+    fn append_entry(&self, command: Command);
+}
+
+// Node state transitions of the election state machine, built on the traits above.
+pub trait Election: Clock + Random + Transport + Storage {
+    // Apply heartbeats recorded by the eBPF program since the last call: transition to
+    // follower state and adopt the leader's term. The eBPF program does not record heartbeats
+    // of earlier terms, but we may have stood as a candidate in a later term since.
+    fn apply_leader_heartbeats(&self) {
+        let leader = self.get_leader();
+
+        if leader.generation == self.applied_leader_generation() {
+            return;
+        }
+
+        let voted_term = self.voted_term();
+        self.update_current_node(|node| {
+            if leader.term_id < node.term.max(voted_term) {
+                return;
+            }
+
+            if node.state != NodeState::Follower {
+                info!(
+                    "Received a new heartbeat from leader '{}' with term '{}', transitioned to Follower state.",
+                    Ipv4Addr::from(leader.source_addr_raw),
+                    leader.term_id
+                );
+            }
+
+            node.state = NodeState::Follower;
+            node.term = leader.term_id;
+            node.vote = Vote {
+                in_progress: false,
+                started_ts: 0,
+                ended_ts: 0,
+                election_timeout: 0,
+            };
+        });
+
+        self.set_applied_leader_generation(leader.generation);
+    }
+
+    // Adopt later terms seen by the eBPF program, which cannot write CURRENT_NODE: of votes it
+    // granted, and of followers answering our heartbeats after voting for another candidate.
+    // A candidate gives up its election and a leader steps down.
+    fn apply_later_terms(&self) {
+        let voted_term = self.voted_term();
+        // As a vote restarts the election timer in Raft: a follower which has not heard from a
+        // leader for a while would otherwise stand in the next term before the candidate it just
+        // voted for sends its first heartbeat.
+        if voted_term > self.current_term_id() {
+            self.update_leader_last_seen_time();
+        }
+        self.adopt_term(voted_term.max(self.heartbeat_response_term()));
+    }
+
+    // Adopt a term later than ours and transition to follower state.
+    fn adopt_term(&self, term: u64) {
+        self.update_current_node(|node| {
+            if term <= node.term {
+                return;
+            }
+
+            if node.state != NodeState::Follower {
+                info!(
+                    "Saw term '{}' above mine ('{}'), transitioned to Follower state.",
+                    term, node.term
+                );
+            }
+
+            node.state = NodeState::Follower;
+            node.term = term;
+            node.vote = Vote {
+                in_progress: false,
+                started_ts: 0,
+                ended_ts: 0,
+                election_timeout: 0,
+            };
+        });
+    }
+
+    // Get current node state.
+    fn get_current_state(&self) -> NodeState {
+        self.get_current_node().state
+    }
+
+    // Get curent node term.
+    fn current_term_id(&self) -> u64 {
+        self.get_current_node().term
+    }
+
+    // Check when leader has last communicated.
+    // Data is populated by eBPF program when receiving heartbeat requests on HEARTBEAT_REQUEST_PORT.
+    fn leader_last_seen(&self) -> u64 {
+        let last_seen = self
+            .get_leader()
+            .last_seen
+            .max(self.leader_timer_reset_ns());
+
+        self.now_ms() - (last_seen / 1_000_000)
+    }
+
+    // Restart the leader timer: after a vote, or when simulating a crash, to avoid becoming the
+    // first node detecting absence of leader and winning the election.
+    // LEADER_NODE is owned by the eBPF program, so the reset is kept in userspace.
+    fn update_leader_last_seen_time(&self) {
+        self.set_leader_timer_reset_ns(self.now_ns());
+    }
+
+    // Random extra time to wait for the leader, so followers do not all become candidates at once.
+    fn get_leader_communication_jitter(&self) -> u64 {
+        self.gen_range(
+            values::LEADER_COMMUNICATION_JITTER_MIN_MS..values::LEADER_COMMUNICATION_JITTER_MAX_MS,
+        )
+    }
+
+    // Increment term number.
+    fn increment_term_number(&self) {
+        self.update_current_node(|node| {
+            node.term += 1;
+        });
+    }
+
+    // Transition to follower state.
+    fn become_follower(&self) {
+        self.update_current_node(|node| {
+            node.state = NodeState::Follower;
+        });
+    }
+
+    // Transition to candidate state.
+    fn become_candidate(&self) {
+        self.update_current_node(|node| {
+            node.state = NodeState::Candidate;
+            node.vote.in_progress = false;
+        });
+    }
+
+    // Transition to leader state.
+    fn become_leader(&self) {
+        self.update_current_node(|node| {
+            node.state = NodeState::Leader;
+        });
+    }
+
+    // Start vote (update metadata).
+    fn start_vote(&self) {
+        let started_ts = self.now_ns();
+        let election_timeout = values::ELECTION_TIMEOUT_NS
+            + self.gen_range(
+                values::ELECTION_TIMEOUT_JITTER_MIN_NS..values::ELECTION_TIMEOUT_JITTER_MAX_NS,
+            );
+
+        self.update_current_node(|node| {
+            node.vote.in_progress = true;
+            node.vote.started_ts = started_ts;
+            node.vote.election_timeout = election_timeout;
+        });
+    }
+
+    // Get current election timeout.
+    fn get_election_timeout(&self) -> u64 {
+        self.get_current_node().vote.election_timeout
+    }
+
+    // Check if election has timed out.
+    fn election_timed_out(&self) -> bool {
+        let node = self.get_current_node();
+        let time_elapsed = self.now_ns() - node.vote.started_ts;
+        node.vote.in_progress && (time_elapsed > node.vote.election_timeout)
+    }
+
+    // Stops vote and records its ending time.
+    fn stop_vote(&self) {
+        let ended_ts = self.now_ns();
+        self.update_current_node(|node| {
+            node.vote.in_progress = false;
+            node.vote.ended_ts = ended_ts;
+        });
+    }
+
+    // Aborts vote and resets voting fields.
+    fn abort_vote(&self) {
+        self.update_current_node(|node| {
+            node.vote.in_progress = false;
+            node.vote.started_ts = 0;
+        });
+    }
+
+    // Resets vote data after a successful election.
+    fn reset_vote_data(&self) {
+        self.update_current_node(|node| {
+            node.vote.in_progress = false;
+            node.vote.ended_ts = 0;
+            node.vote.started_ts = 0;
+            node.vote.election_timeout = 0;
+        });
+    }
+
+    // Calculates vote duration.
+    fn get_vote_duration(&self) -> u64 {
+        let node = self.get_current_node();
+        node.vote.ended_ts - node.vote.started_ts
+    }
+
+    // Returns vote state.
+    fn vote_in_progress(&self) -> bool {
+        self.get_current_node().vote.in_progress
+    }
+
+    // Send vote request RPCs.
+    // Requests carry the last log entry, as peers only vote for candidates whose log is up-to-date.
+    fn send_request_vote_rpcs(&self) {
+        let (last_index, last_term) = self.last_log_entry();
+        self.send_vote_requests([self.current_term_id(), last_index, last_term]);
+    }
+
+    // Send heartbeat RPCs.
+    // The send time is echoed by followers and extends the leader lease (see holds_lease in eBPF).
+    // Followers fill in the last word with their own clock.
+    fn send_heartbeat_rpcs(&self) {
+        self.send_heartbeats([self.current_term_id(), self.now_ns(), 0]);
+    }
+
+    // Quorum checks.
+    fn quorum_reached(&self) -> bool {
+        let positive_votes = self.get_current_yes_votes_from_peers() + 1; // Candidate votes for itself.
+        positive_votes >= values::QUORUM
+    }
+}
+
+impl<T: Clock + Random + Transport + Storage> Election for T {}
@@ -0,0 +1,127 @@
+use crate::election::Storage;
+use crate::state;
+use crate::values;
+use axum::http::{header, HeaderMap, Method, StatusCode, Uri};
+use axum::response::{IntoResponse, Json, Response};
+use clap::ValueEnum;
+use hyper::{Body, Client, Request};
+use log::warn;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+// Set on forwarded requests, which are never forwarded again (e.g. while leadership changes).
+pub const FORWARDED_HEADER: &str = "x-raft-forwarded";
+
+// How followers handle key-value requests, which must be served by the leader.
+#[derive(Debug, Copy, Clone, PartialEq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ForwardMode {
+    // Proxy the request to the leader and relay its response.
+    Proxy,
+    // Answer 307 Temporary Redirect to the same path on the leader, preserving method and body.
+    Redirect,
+    // Answer 421 Misdirected Request, with the leader's address in the body.
+    Misdirected,
+}
+
+// Error returned when a key-value request reaches a node which is not the leader.
+pub fn not_leader(state: &state::AppState) -> Json<Value> {
+    let leader = state.get_leader();
+
+    Json(json!({
+        "error": "not the leader",
+        "leader": Ipv4Addr::from(leader.source_addr_raw).to_string(),
+    }))
+}
+
+fn leader_url(leader: u32, uri: &Uri) -> String {
+    let path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    format!(
+        "http://{}:{}{}",
+        Ipv4Addr::from(leader),
+        values::HTTP_PORT,
+        path
+    )
+}
+
+// Handle a key-value request reaching a follower according to its ForwardMode.
+// Requests already forwarded, and requests while the leader is unknown, get a not-the-leader error.
+pub async fn to_leader(
+    state: &state::AppState,
+    headers: &HeaderMap,
+    method: Method,
+    uri: &Uri,
+    body: Option<Value>,
+) -> Response {
+    let leader = state.get_leader().source_addr_raw;
+    if leader == 0 || headers.contains_key(FORWARDED_HEADER) {
+        return not_leader(state).into_response();
+    }
+
+    match state.forward_mode {
+        ForwardMode::Proxy => forward_to_leader(state, method, uri, body)
+            .await
+            .into_response(),
+        ForwardMode::Redirect => (
+            StatusCode::TEMPORARY_REDIRECT,
+            [(header::LOCATION, leader_url(leader, uri))],
+            not_leader(state),
+        )
+            .into_response(),
+        ForwardMode::Misdirected => {
+            (StatusCode::MISDIRECTED_REQUEST, not_leader(state)).into_response()
+        }
+    }
+}
+
+// Forward a client request to the HTTP API of the leader in LEADER_NODE and relay its response.
+pub async fn forward_to_leader(
+    state: &state::AppState,
+    method: Method,
+    uri: &Uri,
+    body: Option<Value>,
+) -> Json<Value> {
+    let leader = state.get_leader().source_addr_raw;
+    if leader == 0 {
+        return Json(json!({ "error": "leader unknown" }));
+    }
+
+    let url = leader_url(leader, uri);
+
+    let body = match body {
+        Some(value) => Body::from(value.to_string()),
+        None => Body::empty(),
+    };
+    let request = match Request::builder()
+        .method(method)
+        .uri(&url)
+        .header(FORWARDED_HEADER, "1")
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(body)
+    {
+        Ok(request) => request,
+        Err(err) => return Json(json!({ "error": err.to_string() })),
+    };
+
+    let timeout = Duration::from_millis(values::FORWARD_TIMEOUT_MS);
+    let response = match tokio::time::timeout(timeout, Client::new().request(request)).await {
+        Ok(Ok(response)) => response,
+        Ok(Err(err)) => {
+            warn!("Failed to forward request to {}: {}", url, err);
+            return Json(json!({ "error": format!("failed to reach the leader: {}", err) }));
+        }
+        Err(_) => return Json(json!({ "error": "timed out waiting for the leader" })),
+    };
+
+    match hyper::body::to_bytes(response.into_body()).await {
+        Ok(bytes) => match serde_json::from_slice(&bytes) {
+            Ok(value) => Json(value),
+            Err(err) => {
+                Json(json!({ "error": format!("invalid response from the leader: {}", err) }))
+            }
+        },
+        Err(err) => Json(json!({ "error": err.to_string() })),
+    }
+}
@@ -1,55 +1,78 @@
-use crate::helpers;
+use crate::election::Election;
+use crate::raft_log::Command;
 use crate::state;
 use crate::values;
-use local_ip_address::local_ip;
 use log::info;
 use raft_main_common::NodeState;
+use std::net::Ipv4Addr;
+use std::thread::sleep;
+use std::time::Duration;
 
-pub fn candidate(state: &state::AppState) {
-    let local_ip = local_ip().unwrap();
+// Returns the time to wait before the next step, in milliseconds.
+pub fn candidate(node: &impl Election) -> u64 {
+    let local_ip = Ipv4Addr::from(node.local_addr());
 
-    if state.vote_in_progress() && !state.election_timed_out() && state.quorum_reached() {
-        state.stop_vote();
+    if node.vote_in_progress() && !node.election_timed_out() && node.quorum_reached() {
+        node.stop_vote();
         info!(
             "[candidate] Quorum reached after {},{},{}, becoming leader with term: {}",
             local_ip,
-            helpers::get_current_clock_ns(),
-            state.get_vote_duration(),
-            state.current_term_id()
+            node.now_ns(),
+            node.get_vote_duration(),
+            node.current_term_id()
         );
-        state.reset_vote_results().unwrap_or_default();
-        state.become_leader();
-        return;
+        node.reset_vote_results();
+        node.become_leader();
+        // Entries of previous terms are only committed along with one of the current term.
+        node.append_entry(Command::Noop);
+        return 0;
     }
 
-    if !state.vote_in_progress() {
-        state.reset_vote_data();
-        state.increment_term_number();
-        state.send_request_vote_rpcs();
-        state.start_vote();
-        info!("[candidate] no vote in progress, starting vote at {} with term: {} and election timeout: {}", helpers::get_current_clock_ns(), state.current_term_id(), state.get_election_timeout());
-        return;
+    if !node.vote_in_progress() {
+        node.reset_vote_data();
+        // Votes of an election interrupted by a heartbeat are left over.
+        node.reset_vote_results();
+        node.increment_term_number();
+        // Candidates vote for themselves, which they cannot do in a term in which they already
+        // granted their vote; that term is skipped.
+        if !node.record_vote(node.current_term_id()) {
+            info!(
+                "[candidate] already voted in term {}, skipping it",
+                node.current_term_id()
+            );
+            return 0;
+        }
+        node.send_request_vote_rpcs();
+        node.start_vote();
+        info!("[candidate] no vote in progress, starting vote at {} with term: {} and election timeout: {}", node.now_ns(), node.current_term_id(), node.get_election_timeout());
+        return 0;
     }
 
-    if state.election_timed_out() {
+    if node.election_timed_out() {
         info!(
             "[candidate] election timed out, aborting with {} out of {} needed votes for term {}",
-            state.get_current_yes_votes_from_peers(),
+            node.get_current_yes_votes_from_peers(),
             values::QUORUM,
-            state.current_term_id()
+            node.current_term_id()
         );
-        state.reset_vote_data();
-        state.abort_vote();
-        state.reset_vote_results().unwrap_or_default();
+        node.reset_vote_data();
+        node.abort_vote();
+        node.reset_vote_results();
     }
+
+    0
 }
 
 pub fn candidate_loop(state: &state::AppState) {
-    loop {
+    while !state.is_shutting_down() {
+        state.apply_leader_heartbeats();
+        state.apply_later_terms();
+
         if state.get_current_state() != NodeState::Candidate {
+            state.wait_for_state(NodeState::Candidate);
             continue;
         }
 
-        candidate(state)
+        sleep(Duration::from_millis(candidate(state)));
     }
 }
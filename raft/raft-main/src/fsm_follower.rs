@@ -1,27 +1,36 @@
-use crate::helpers;
+use crate::election::Election;
 use crate::state;
 use crate::values;
 use log::info;
 use raft_main_common::NodeState;
+use std::thread::sleep;
+use std::time::Duration;
 
-pub fn follower(state: &state::AppState) {
-    let jitter = helpers::get_leader_communication_jitter();
+// Returns the time to wait before the next step, in milliseconds.
+pub fn follower(node: &impl Election) -> u64 {
+    let jitter = node.get_leader_communication_jitter();
 
-    if state.leader_last_seen() > values::LEADER_COMMUNICATION_TIMEOUT_MS + jitter {
+    if node.leader_last_seen() > values::LEADER_COMMUNICATION_TIMEOUT_MS + jitter {
         info!(
             "[follower] No communication received from the leader in {} ms; becoming a candidate",
             values::LEADER_COMMUNICATION_TIMEOUT_MS + jitter
         );
-        state.become_candidate();
+        node.become_candidate();
     }
+
+    0
 }
 
 pub fn follower_loop(state: &state::AppState) {
-    loop {
+    while !state.is_shutting_down() {
+        state.apply_leader_heartbeats();
+        state.apply_later_terms();
+
         if state.get_current_state() != NodeState::Follower {
+            state.wait_for_state(NodeState::Follower);
             continue;
         }
 
-        follower(state)
+        sleep(Duration::from_millis(follower(state)));
     }
 }
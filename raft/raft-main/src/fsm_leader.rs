@@ -1,39 +1,49 @@
+use crate::election::Election;
 use crate::state;
 use crate::values;
 use log::info;
 use raft_main_common::NodeState;
-use std::sync::{Arc, Mutex};
 use std::thread::sleep;
 use std::time::Duration;
 
-pub fn leader(state: &state::AppState, c: Arc<Mutex<i32>>) {
-    state.send_heartbeat_rpcs();
-    let mut current_cyle_counter = c.lock().unwrap();
-    *current_cyle_counter += 1;
-
-    if *current_cyle_counter > values::LEADER_HEARTBEAT_CYCLES_BEFORE_CRASH {
-        info!("[leader] Simulating crash and becoming a follower");
-        // sleeping to avoid being the first node to detect leader absence.
-        sleep(Duration::from_millis(
-            values::LEADER_COMMUNICATION_TIMEOUT_MS + 1,
-        ));
-
-        *current_cyle_counter = 0; // reset cycle counter.
-        state.update_leader_last_seen_time(); // hack; see fn comment.
-        state.become_follower();
-    } else {
-        sleep(Duration::from_millis(values::LEADER_HEARTBEAT_FREQUENCY_MS));
-    }
+// Returns the time to wait before the next step, in milliseconds.
+pub fn leader(node: &impl Election) -> u64 {
+    node.send_heartbeat_rpcs();
+    values::LEADER_HEARTBEAT_FREQUENCY_MS
+}
+
+// Stop sending heartbeats for a while, then become a follower.
+pub fn simulate_crash(state: &state::AppState) {
+    info!("[leader] Simulating crash and becoming a follower");
+    // sleeping to avoid being the first node to detect leader absence.
+    sleep(Duration::from_millis(
+        values::LEADER_COMMUNICATION_TIMEOUT_MS + 1,
+    ));
+
+    state.update_leader_last_seen_time(); // hack; see fn comment.
+    state.become_follower();
 }
 
-pub fn leader_loop(state: &state::AppState) {
-    let c = Arc::new(Mutex::new(0));
+pub fn leader_loop(state: &state::AppState, simulate_crashes: bool) {
+    let mut cycles = 0; // counter to simulate a failure after LEADER_HEARTBEAT_CYCLES_BEFORE_CRASH.
+
+    while !state.is_shutting_down() {
+        state.apply_leader_heartbeats();
+        state.apply_later_terms();
 
-    loop {
         if state.get_current_state() != NodeState::Leader {
+            state.wait_for_state(NodeState::Leader);
             continue;
         }
 
-        leader(state, c.clone())
+        let delay = leader(state);
+        cycles += 1;
+
+        if simulate_crashes && cycles > values::LEADER_HEARTBEAT_CYCLES_BEFORE_CRASH {
+            cycles = 0;
+            simulate_crash(state);
+        } else {
+            sleep(Duration::from_millis(delay));
+        }
     }
 }
@@ -1,22 +1,83 @@
+use crate::election::Election;
 use crate::fsm_candidate;
 use crate::fsm_follower;
 use crate::fsm_leader;
 use crate::state;
+use crate::values;
+use clap::ValueEnum;
 use raft_main_common::NodeState;
-use std::sync::{Arc, Mutex};
+use serde::Serialize;
+use std::thread::{sleep, JoinHandle};
+use std::time::Duration;
 
-pub fn shared_loop(state: &state::AppState) {
-    let counter = Arc::new(Mutex::new(0)); // counter to simulate a failure after LEADER_HEARTBEAT_CYCLES_BEFORE_CRASH.
+// Threads running the election state machine.
+#[derive(Debug, Copy, Clone, PartialEq, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ThreadModel {
+    // One thread acting on the node's current state (shared_loop).
+    SingleThread,
+    // A thread per state (fsm_leader, fsm_follower and fsm_candidate loops), each waiting for the
+    // node to enter its state.
+    MultiThread,
+}
+
+// Start the election state machine threads. With `simulate_crashes`, the leader stops sending
+// heartbeats every LEADER_HEARTBEAT_CYCLES_BEFORE_CRASH heartbeats and the node exits at
+// SIMULATED_CRASHES_LAST_TERM, as in the thesis measurements.
+pub fn spawn(
+    state: &state::AppState,
+    thread_model: ThreadModel,
+    simulate_crashes: bool,
+) -> Vec<JoinHandle<()>> {
+    let state = state.clone();
+
+    match thread_model {
+        ThreadModel::SingleThread => {
+            vec![std::thread::spawn(move || {
+                shared_loop(&state, simulate_crashes)
+            })]
+        }
+        ThreadModel::MultiThread => {
+            let leader_state = state.clone();
+            let follower_state = state.clone();
+            vec![
+                std::thread::spawn(move || {
+                    fsm_leader::leader_loop(&leader_state, simulate_crashes)
+                }),
+                std::thread::spawn(move || fsm_follower::follower_loop(&follower_state)),
+                std::thread::spawn(move || fsm_candidate::candidate_loop(&state)),
+            ]
+        }
+    }
+}
+
+pub fn shared_loop(state: &state::AppState, simulate_crashes: bool) {
+    let mut cycles = 0; // counter to simulate a failure after LEADER_HEARTBEAT_CYCLES_BEFORE_CRASH.
+
+    while !state.is_shutting_down() {
+        state.apply_leader_heartbeats();
+        state.apply_later_terms();
 
-    loop {
-        if state.current_term_id() == 100 {
+        if simulate_crashes && state.current_term_id() >= values::SIMULATED_CRASHES_LAST_TERM {
             std::process::exit(0)
         }
 
-        match state.get_current_state() {
-            NodeState::Leader => fsm_leader::leader(state, counter.clone()),
+        let delay = match state.get_current_state() {
+            NodeState::Leader => {
+                let delay = fsm_leader::leader(state);
+                cycles += 1;
+
+                if simulate_crashes && cycles > values::LEADER_HEARTBEAT_CYCLES_BEFORE_CRASH {
+                    cycles = 0;
+                    fsm_leader::simulate_crash(state);
+                    continue;
+                }
+                delay
+            }
             NodeState::Candidate => fsm_candidate::candidate(state),
             NodeState::Follower => fsm_follower::follower(state),
-        }
+        };
+
+        sleep(Duration::from_millis(delay));
     }
 }
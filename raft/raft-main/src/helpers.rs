@@ -1,27 +1,22 @@
-use crate::values;
 use nix::time::clock_gettime;
-use rand::{thread_rng, Rng};
 use std::net::Ipv4Addr;
 use std::time::Duration;
 
-pub fn get_election_timeout_jitter_ns() -> u64 {
-    let mut rng = thread_rng();
-    rng.gen_range(values::ELECTION_TIMEOUT_JITTER_MIN_NS..values::ELECTION_TIMEOUT_JITTER_MAX_NS)
-}
-
-pub fn get_leader_communication_jitter() -> u64 {
-    let mut rng = thread_rng();
-    rng.gen_range(
-        values::LEADER_COMMUNICATION_JITTER_MIN_MS..values::LEADER_COMMUNICATION_JITTER_MAX_MS,
-    )
+pub fn get_current_clock_ns() -> u64 {
+    Duration::from(clock_gettime(nix::time::ClockId::CLOCK_MONOTONIC).unwrap()).as_nanos() as u64
 }
 
-pub fn get_current_clock_ms() -> u64 {
-    Duration::from(clock_gettime(nix::time::ClockId::CLOCK_MONOTONIC).unwrap()).as_millis() as u64
+// Wall clock time, used for timestamps in log entries, which must be comparable across nodes.
+pub fn get_wall_clock_ns() -> u64 {
+    Duration::from(clock_gettime(nix::time::ClockId::CLOCK_REALTIME).unwrap()).as_nanos() as u64
 }
 
-pub fn get_current_clock_ns() -> u64 {
-    Duration::from(clock_gettime(nix::time::ClockId::CLOCK_MONOTONIC).unwrap()).as_nanos() as u64
+// Offset from the monotonic clock (used by bpf_ktime_get_ns) to the Unix epoch.
+// Sequence numbers are monotonic clock readings plus this offset.
+pub fn get_sequence_base() -> u64 {
+    let realtime_ns = Duration::from(clock_gettime(nix::time::ClockId::CLOCK_REALTIME).unwrap())
+        .as_nanos() as u64;
+    realtime_ns - get_current_clock_ns()
 }
 
 // C onvert dot-delimited IP address to u32 representation.
@@ -47,3 +42,23 @@ pub fn ip_string_to_u32(ip_str: &str) -> Result<u32, ()> {
         Err(_) => Err(()),
     }
 }
+
+// Convert hex-encoded 128-bit key to bytes.
+pub fn parse_mac_key(hex_str: &str) -> Result<[u8; 16], ()> {
+    let hex_str = hex_str.trim();
+
+    if hex_str.len() != 32 || !hex_str.is_ascii() {
+        return Err(());
+    }
+
+    let mut key = [0u8; 16];
+
+    for (index, byte) in key.iter_mut().enumerate() {
+        match u8::from_str_radix(&hex_str[index * 2..index * 2 + 2], 16) {
+            Ok(num) => *byte = num,
+            Err(_) => return Err(()),
+        }
+    }
+
+    Ok(key)
+}
@@ -1,34 +1,85 @@
 use anyhow::Context;
 use axum::{
-    routing::{get, post},
+    routing::{get, post, put},
     Router,
 };
-use aya::maps::MapData;
-use aya::programs::{Xdp, XdpFlags};
-use aya::{include_bytes_aligned, maps::Array, maps::HashMap, Bpf};
+use aya::programs::Xdp;
+use aya::{include_bytes_aligned, Bpf, BpfLoader};
 use aya_log::BpfLogger;
 use clap::Parser;
 use log::{debug, info, warn};
 use nix::sys::socket::{setsockopt, sockopt::SndBuf};
-use raft_main_common::{CurrentNode, LeaderNode};
-use std::net::{SocketAddr, UdpSocket};
+use raft_main_common::{
+    auth::MacKey, kv::KvMirrorState, APPEND_ENTRIES_PORT, APPEND_ENTRIES_RESPONSE_PORT,
+    SNAPSHOT_PORT, VOTE_REQUEST_PORT,
+};
+use std::fs;
+use std::net::{IpAddr, SocketAddr, TcpListener, UdpSocket};
 use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64};
 use std::sync::{Arc, Mutex, RwLock};
-use tokio::signal;
+use std::time::Duration;
+use tokio::sync::oneshot;
 
+mod election;
+mod forward;
 mod fsm_candidate;
 mod fsm_follower;
 mod fsm_leader;
 mod fsm_single_thread;
 mod helpers;
+mod maps;
+mod persistence;
+mod raft_log;
+mod read_index;
+mod replication;
 mod routes;
+mod session;
+mod shutdown;
+#[cfg(test)]
+mod sim;
+mod snapshot;
 mod state;
+mod state_machine;
+mod udp;
 mod values;
+mod xdp;
 
 #[derive(Debug, Parser)]
 struct Opt {
     #[clap(short, long, default_value = "eth0")]
     iface: String,
+    #[clap(long, value_enum, default_value = "auto")]
+    xdp_mode: xdp::XdpMode,
+    /// File holding a hex-encoded 128-bit key used to authenticate Raft messages
+    #[clap(long)]
+    mac_key_file: Option<PathBuf>,
+    /// Keep maps and the XDP program pinned after exit and restore them on startup
+    #[clap(long)]
+    pin: bool,
+    /// Directory on a bpffs mount under which maps and the XDP link are pinned, per interface
+    #[clap(long, default_value = values::PIN_ROOT)]
+    pin_root: PathBuf,
+    /// Directory holding the term, votes, log and snapshot, which survive restarts
+    #[clap(long, default_value = values::DATA_DIR)]
+    data_dir: PathBuf,
+    /// Time to wait for the state machine and HTTP server to stop on SIGINT/SIGTERM
+    #[clap(long, default_value = "1000")]
+    drain_timeout_ms: u64,
+    /// How followers handle key-value requests: proxy them to the leader, or answer 307/421
+    #[clap(long, value_enum, default_value = "proxy")]
+    forward_mode: forward::ForwardMode,
+    /// How election messages are received: by the XDP program, or on UDP sockets without eBPF
+    #[clap(long, value_enum, default_value = "xdp")]
+    transport: udp::TransportMode,
+    /// Threads running the election state machine: one, or one per node state
+    #[clap(long, value_enum, default_value = "single-thread")]
+    thread_model: fsm_single_thread::ThreadModel,
+    /// Make the leader stop sending heartbeats every 30 heartbeats, and exit at term 100, as in
+    /// the thesis measurements (`cargo xtask benchmark --failure simulated`)
+    #[clap(long)]
+    simulate_crashes: bool,
 }
 
 #[tokio::main]
@@ -48,37 +99,92 @@ async fn main() -> Result<(), anyhow::Error> {
         debug!("remove limit on locked memory failed, ret is: {}", ret);
     }
 
-    // This will include your eBPF object file as raw bytes at compile-time and load it at
-    // runtime. This approach is recommended for most real-world use cases. If you would
-    // like to specify the eBPF program at runtime rather than at compile-time, you can
-    // reach for `Bpf::load_file` instead.
-    #[cfg(debug_assertions)]
-    let mut bpf = Bpf::load(include_bytes_aligned!(
-        "../../target/bpfel-unknown-none/debug/raft-main"
-    ))?;
-    #[cfg(not(debug_assertions))]
-    let mut bpf = Bpf::load(include_bytes_aligned!(
-        "../../target/bpfel-unknown-none/release/raft-main"
-    ))?;
-    if let Err(e) = BpfLogger::init(&mut bpf) {
-        // This can happen if you remove all log statements from your eBPF program.
-        warn!("failed to initialize eBPF logger: {}", e);
+    // Maps holding Raft state (CURRENT_NODE, LEADER_NODE, VOTE_TERMS, VOTED_TERM, VOTE_CEILING,
+    // REPLAY_WINDOWS) are always pinned. Unless --pin is set, pins from a previous run are discarded
+    // and removed on exit.
+    // Without XDP, maps are in memory and nothing is pinned.
+    let pin_dir = xdp::pin_dir(&opt.pin_root, &opt.iface);
+    let use_xdp = opt.transport == udp::TransportMode::Xdp;
+    if opt.pin && !use_xdp {
+        anyhow::bail!("--pin requires --transport xdp");
     }
-    let program: &mut Xdp = bpf.program_mut("raft_main").unwrap().try_into()?;
-    program.load()?;
-    program.attach(&opt.iface, XdpFlags::SKB_MODE) // TODO: This somehow affects the checksums????
-        .context("failed to attach the XDP program with default flags - try changing XdpFlags::default() to XdpFlags::SKB_MODE")?;
+    if use_xdp && !opt.pin {
+        xdp::remove_pins(&pin_dir)?;
+    }
+    let restore = opt.pin && xdp::has_pinned_maps(&pin_dir);
+
+    // The term, votes, log and snapshot are read back from disk whether or not maps are pinned.
+    let (persistence, restored) = persistence::Persistence::open(&opt.data_dir)?;
+
+    let mut bpf = match opt.transport {
+        udp::TransportMode::Xdp => Some(load_bpf(&pin_dir)?),
+        udp::TransportMode::Udp => {
+            info!("Handling election messages in userspace, without eBPF.");
+            None
+        }
+    };
 
     // Shared maps.
-    let followers: HashMap<_, u32, u64> = HashMap::try_from(bpf.take_map("FOLLOWERS").unwrap())?;
-    let heartbeat_latency: HashMap<_, u32, u64> =
-        HashMap::try_from(bpf.take_map("HEARTBEAT_LATENCY").unwrap())?;
-    let voting_results: HashMap<_, u32, u64> =
-        HashMap::try_from(bpf.take_map("VOTE_RESULTS").unwrap())?;
-    let current_node: Array<MapData, CurrentNode> =
-        Array::try_from(bpf.take_map("CURRENT_NODE").unwrap())?;
-    let leader_node: Array<MapData, LeaderNode> =
-        Array::try_from(bpf.take_map("LEADER_NODE").unwrap())?;
+    let followers = maps::HashMap::take(bpf.as_mut(), "FOLLOWERS")?;
+    let heartbeat_latency = maps::HashMap::take(bpf.as_mut(), "HEARTBEAT_LATENCY")?;
+    let voting_results = maps::HashMap::take(bpf.as_mut(), "VOTE_RESULTS")?;
+    // Values are bools, which are not Pod.
+    let vote_terms: maps::HashMap<u64, u8> = maps::HashMap::take(bpf.as_mut(), "VOTE_TERMS")?;
+    let voted_term: maps::Array<u64> = maps::Array::take(bpf.as_mut(), "VOTED_TERM")?;
+    let vote_ceiling = maps::Array::take(bpf.as_mut(), "VOTE_CEILING")?;
+    let current_node = maps::Array::take(bpf.as_mut(), "CURRENT_NODE")?;
+    let leader_node = maps::Array::take(bpf.as_mut(), "LEADER_NODE")?;
+    let members = maps::HashMap::take(bpf.as_mut(), "MEMBERS")?;
+    let mut mac_key_map: maps::Array<MacKey> = maps::Array::take(bpf.as_mut(), "MAC_KEY")?;
+
+    // Messages are authenticated only if a key has been provided.
+    let mac_key = match &opt.mac_key_file {
+        Some(path) => {
+            let contents = fs::read_to_string(path)
+                .with_context(|| format!("failed to read MAC key from {}", path.display()))?;
+            let key = helpers::parse_mac_key(&contents).map_err(|_| {
+                anyhow::anyhow!("{} must hold a 32 character hex key", path.display())
+            })?;
+            info!(
+                "Authenticating Raft messages with key from {}",
+                path.display()
+            );
+            MacKey::from_bytes(key)
+        }
+        None => MacKey::default(),
+    };
+    mac_key_map.set(0, mac_key, 0)?;
+
+    // Sequence numbers for replay protection are shared with the eBPF program.
+    let sequence_base = helpers::get_sequence_base();
+    let mut sequence_base_map: maps::Array<u64> = maps::Array::take(bpf.as_mut(), "SEQUENCE_BASE")?;
+    sequence_base_map.set(0, sequence_base, 0)?;
+
+    // Messages are sent, and authenticated, with the address of the interface the program serves.
+    let local_addr = local_ip_address::list_afinet_netifas()?
+        .into_iter()
+        .find_map(|(name, addr)| match addr {
+            IpAddr::V4(addr) if name == opt.iface => Some(u32::from(addr)),
+            _ => None,
+        })
+        .with_context(|| format!("{} has no IPv4 address", opt.iface))?;
+    let counters = maps::PerCpuArray::take(bpf.as_mut(), "COUNTERS")?;
+    let log_state = maps::Array::take(bpf.as_mut(), "LOG_STATE")?;
+
+    // Mirror of the applied key-value state, starting empty like the state machine.
+    let kv_mirror = maps::HashMap::take(bpf.as_mut(), "KV_STORE")?;
+    let mut kv_mirror_state: maps::Array<KvMirrorState> =
+        maps::Array::take(bpf.as_mut(), "KV_MIRROR")?;
+    kv_mirror_state.set(
+        0,
+        KvMirrorState {
+            applied_term: 0,
+            complete: 1,
+        },
+        0,
+    )?;
+    let heartbeat_acks = maps::HashMap::take(bpf.as_mut(), "HEARTBEAT_ACKS")?;
+    let faults = maps::HashMap::take(bpf.as_mut(), "FAULTS")?;
 
     // Create a UDP socket to be shared across multiple threads.
     let udp_socket = UdpSocket::bind("0.0.0.0:0").expect("Failed to create socket");
@@ -86,48 +192,194 @@ async fn main() -> Result<(), anyhow::Error> {
     setsockopt(fd, SndBuf, &4096).expect("Failed to set send buffer size");
 
     // State object holds all relevant data for a single Raft node.
-    let state = state::AppState {
+    let mut state = state::AppState {
         followers: Arc::new(Mutex::new(followers)),
         heartbeat_latency: Arc::new(Mutex::new(heartbeat_latency)),
         voting_results: Arc::new(RwLock::new(voting_results)),
+        vote_terms: Arc::new(Mutex::new(vote_terms)),
+        voted_term: Arc::new(Mutex::new(voted_term)),
+        vote_ceiling: Arc::new(Mutex::new(vote_ceiling)),
         current_node: Arc::new(RwLock::new(current_node)),
         leader_node: Arc::new(RwLock::new(leader_node)),
+        members: Arc::new(Mutex::new(members)),
+        counters: Arc::new(Mutex::new(counters)),
         udp_socket: Arc::new(Mutex::new(udp_socket)),
+        transport: opt.transport,
+        xdp_mode: opt.xdp_mode, // Replaced by the active mode once attached.
+        forward_mode: opt.forward_mode,
+        thread_model: opt.thread_model,
+        mac_key,
+        local_addr,
+        sequence_base,
+        applied_leader_generation: Arc::new(AtomicU64::new(0)),
+        leader_timer_reset_ns: Arc::new(AtomicU64::new(0)),
+        shutting_down: Arc::new(AtomicBool::new(false)),
+        state_changed: Arc::new((Mutex::new(()), std::sync::Condvar::new())),
+        raft_log: Arc::new(Mutex::new(raft_log::RaftLog::new())),
+        log_state: Arc::new(Mutex::new(log_state)),
+        replication: Arc::new(Mutex::new(replication::Progress::default())),
+        state_machine: Arc::new(Mutex::new(Box::new(session::SessionStateMachine::new(
+            Box::new(state_machine::KvStore::new()),
+        )))),
+        kv_mirror: Arc::new(Mutex::new(kv_mirror)),
+        kv_mirror_state: Arc::new(Mutex::new(kv_mirror_state)),
+        kv_mirror_missing: Arc::new(Mutex::new(std::collections::HashSet::new())),
+        heartbeat_acks: Arc::new(Mutex::new(heartbeat_acks)),
+        faults: Arc::new(Mutex::new(faults)),
+        snapshot: Arc::new(Mutex::new(Arc::new(snapshot::Snapshot::default()))),
+        persistence: Arc::new(Mutex::new(persistence)),
+        responses: Arc::new(Mutex::new(std::collections::HashMap::new())),
+        replay_windows: Arc::new(Mutex::new(std::collections::HashMap::new())),
     };
 
-    // Initialise the (follower) node with term ID 0.
-    state.initialise_node();
+    // Initialise the (follower) node with term ID 0, or restore it from pinned maps, then
+    // restore what was written to disk.
+    state.initialise_node(restore);
+    state.restore_from_disk(restored, restore)?;
 
-    // let leader_state = state.clone();
-    // std::thread::spawn(move || fsm_leader::leader_loop(&leader_state));
+    // Attach only once maps are populated, as a pinned program may be replaced in-place.
+    // A pinned program is left attached on exit.
+    let mut link_id = None;
+    if let Some(bpf) = bpf.as_mut() {
+        let program: &mut Xdp = bpf.program_mut("raft_main").unwrap().try_into()?;
+        state.xdp_mode = if opt.pin {
+            xdp::attach_pinned(program, &opt.iface, opt.xdp_mode, &pin_dir)?
+        } else {
+            let (id, mode) = xdp::attach(program, &opt.iface, opt.xdp_mode)?;
+            link_id = Some(id);
+            mode
+        };
+    }
 
-    // let follower_state = state.clone();
-    // std::thread::spawn(move || fsm_follower::follower_loop(&follower_state));
+    // Without XDP, election messages are received on sockets. With XDP, only vote requests
+    // above the vote ceiling are, which need a disk write before they are granted.
+    let election_ports: &[u16] = if use_xdp {
+        &[VOTE_REQUEST_PORT]
+    } else {
+        &udp::ELECTION_PORTS
+    };
+    for socket in udp::bind(election_ports)? {
+        let udp_state = state.clone();
+        std::thread::spawn(move || udp::listener(&udp_state, &socket));
+    }
+
+    // A single thread by default, which performs better than a thread per state.
+    let fsm_threads = fsm_single_thread::spawn(&state, opt.thread_model, opt.simulate_crashes);
 
-    // let candidate_state = state.clone();
-    // std::thread::spawn(move || fsm_candidate::candidate_loop(&candidate_state));
+    // Log replication. Listeners time out periodically to notice shutdown.
+    let append_entries_socket = UdpSocket::bind(("0.0.0.0", APPEND_ENTRIES_PORT))
+        .context("failed to bind the AppendEntries port")?;
+    let response_socket = UdpSocket::bind(("0.0.0.0", APPEND_ENTRIES_RESPONSE_PORT))
+        .context("failed to bind the AppendEntries response port")?;
+    append_entries_socket.set_read_timeout(Some(Duration::from_millis(100)))?;
+    response_socket.set_read_timeout(Some(Duration::from_millis(100)))?;
+    let leader_socket = response_socket.try_clone()?;
+    let snapshot_listener = TcpListener::bind(("0.0.0.0", SNAPSHOT_PORT))
+        .context("failed to bind the snapshot port")?;
+    snapshot_listener.set_nonblocking(true)?;
 
-    // Using single thread as opposed to multiple threads ^ for performance.
-    let shared_state = state.clone();
-    std::thread::spawn(move || fsm_single_thread::shared_loop(&shared_state));
+    let follower_state = state.clone();
+    std::thread::spawn(move || {
+        replication::follower_listener(&follower_state, &append_entries_socket)
+    });
+    let response_state = state.clone();
+    std::thread::spawn(move || replication::response_listener(&response_state, &response_socket));
+    let leader_state = state.clone();
+    std::thread::spawn(move || replication::leader_loop(&leader_state, &leader_socket));
+    let snapshot_state = state.clone();
+    std::thread::spawn(move || snapshot::listener(&snapshot_state, &snapshot_listener));
 
     let app = Router::new()
         .route("/followers/list", get(routes::list_followers))
         .route("/followers/add", post(routes::add_follower))
         .route("/followers/delete", post(routes::delete_follower))
-        .with_state(state);
+        .route("/status", get(routes::status))
+        .route("/sessions", post(routes::register_session))
+        .route(
+            "/faults",
+            get(routes::list_faults).delete(routes::clear_faults),
+        )
+        .route(
+            "/faults/:peer",
+            put(routes::set_fault).delete(routes::clear_fault),
+        )
+        .route(
+            "/kv/:key",
+            get(routes::get_key)
+                .put(routes::put_key)
+                .delete(routes::delete_key),
+        )
+        .with_state(state.clone());
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], 8888));
+    let addr = SocketAddr::from(([0, 0, 0, 0], values::HTTP_PORT));
     info!("Listening on...{}", addr);
-    let server = axum::Server::bind(&addr).serve(app.into_make_service());
+    let (stop_server, server_stopped) = oneshot::channel::<()>();
+    let server = axum::Server::bind(&addr)
+        .serve(app.into_make_service())
+        .with_graceful_shutdown(async {
+            server_stopped.await.ok();
+        });
+    let mut server = tokio::spawn(server);
 
     tokio::select! {
-        _ = server => {
+        _ = &mut server => {
             info!("Server is running...");
         },
-        _ = signal::ctrl_c() => {
-            info!("Ctrl-C received. Exiting...");
+        result = shutdown::wait_for_signal() => {
+            result?;
         }
     }
+
+    let drain_timeout = Duration::from_millis(opt.drain_timeout_ms);
+    shutdown::drain(&state, fsm_threads, drain_timeout).await;
+
+    stop_server.send(()).ok();
+    if tokio::time::timeout(drain_timeout, server).await.is_err() {
+        warn!(
+            "HTTP server did not stop within {} ms.",
+            opt.drain_timeout_ms
+        );
+    }
+
+    if let (Some(bpf), Some(link_id)) = (bpf.as_mut(), link_id) {
+        let program: &mut Xdp = bpf.program_mut("raft_main").unwrap().try_into()?;
+        program.detach(link_id)?;
+        info!("Detached XDP program from {}.", opt.iface);
+    }
+
+    if use_xdp && !opt.pin {
+        xdp::remove_pins(&pin_dir)?;
+    }
     Ok(())
 }
+
+// Load the eBPF program, with its maps pinned under `pin_dir`.
+fn load_bpf(pin_dir: &Path) -> Result<Bpf, anyhow::Error> {
+    fs::create_dir_all(pin_dir)
+        .with_context(|| format!("failed to create {} (is bpffs mounted?)", pin_dir.display()))?;
+
+    // This will include your eBPF object file as raw bytes at compile-time and load it at
+    // runtime. This approach is recommended for most real-world use cases. If you would
+    // like to specify the eBPF program at runtime rather than at compile-time, you can
+    // reach for `Bpf::load_file` instead.
+    #[cfg(debug_assertions)]
+    let mut bpf = BpfLoader::new()
+        .map_pin_path(pin_dir)
+        .load(include_bytes_aligned!(
+            "../../target/bpfel-unknown-none/debug/raft-main"
+        ))?;
+    #[cfg(not(debug_assertions))]
+    let mut bpf = BpfLoader::new()
+        .map_pin_path(pin_dir)
+        .load(include_bytes_aligned!(
+            "../../target/bpfel-unknown-none/release/raft-main"
+        ))?;
+    if let Err(e) = BpfLogger::init(&mut bpf) {
+        // This can happen if you remove all log statements from your eBPF program.
+        warn!("failed to initialize eBPF logger: {}", e);
+    }
+    let program: &mut Xdp = bpf.program_mut("raft_main").unwrap().try_into()?;
+    program.load()?;
+
+    Ok(bpf)
+}
@@ -0,0 +1,165 @@
+use aya::maps::MapData;
+use aya::{Bpf, Pod};
+use std::borrow::Borrow;
+use std::fmt;
+use std::hash::Hash;
+
+pub const BPF_NOEXIST: u64 = 1; // Map update flag: only create new elements.
+
+// Maps holding the node state shared with the XDP program.
+// With --transport udp, no eBPF program is loaded; the election message handlers run in
+// userspace (see udp.rs) and the maps are plain memory with the same interface.
+pub enum HashMap<K: Pod, V: Pod> {
+    Bpf(aya::maps::HashMap<MapData, K, V>),
+    Local(std::collections::HashMap<K, V>),
+}
+
+pub enum Array<V: Pod> {
+    Bpf(aya::maps::Array<MapData, V>),
+    Local(std::collections::HashMap<u32, V>),
+}
+
+pub enum PerCpuArray<V: Pod> {
+    Bpf(aya::maps::PerCpuArray<MapData, V>),
+    Local(std::collections::HashMap<u32, V>), // A single CPU.
+}
+
+#[derive(Debug)]
+pub enum MapError {
+    Bpf(aya::maps::MapError),
+    KeyNotFound,
+    KeyExists,
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MapError::Bpf(err) => err.fmt(f),
+            MapError::KeyNotFound => write!(f, "key not found"),
+            MapError::KeyExists => write!(f, "key already exists"),
+        }
+    }
+}
+
+impl std::error::Error for MapError {}
+
+impl From<aya::maps::MapError> for MapError {
+    fn from(err: aya::maps::MapError) -> MapError {
+        MapError::Bpf(err)
+    }
+}
+
+impl<K: Pod + Eq + Hash, V: Pod> HashMap<K, V> {
+    // Take the map from the loaded eBPF program, or create it in memory if there is none.
+    pub fn take(bpf: Option<&mut Bpf>, name: &str) -> Result<HashMap<K, V>, MapError> {
+        match bpf {
+            Some(bpf) => Ok(HashMap::Bpf(aya::maps::HashMap::try_from(
+                bpf.take_map(name).unwrap(),
+            )?)),
+            None => Ok(HashMap::Local(std::collections::HashMap::new())),
+        }
+    }
+
+    pub fn get(&self, key: &K, flags: u64) -> Result<V, MapError> {
+        match self {
+            HashMap::Bpf(map) => Ok(map.get(key, flags)?),
+            HashMap::Local(map) => map.get(key).copied().ok_or(MapError::KeyNotFound),
+        }
+    }
+
+    pub fn insert(
+        &mut self,
+        key: impl Borrow<K>,
+        value: impl Borrow<V>,
+        flags: u64,
+    ) -> Result<(), MapError> {
+        match self {
+            HashMap::Bpf(map) => Ok(map.insert(key, value, flags)?),
+            HashMap::Local(map) => {
+                if flags & BPF_NOEXIST != 0 && map.contains_key(key.borrow()) {
+                    return Err(MapError::KeyExists);
+                }
+                map.insert(*key.borrow(), *value.borrow());
+                Ok(())
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: &K) -> Result<(), MapError> {
+        match self {
+            HashMap::Bpf(map) => Ok(map.remove(key)?),
+            HashMap::Local(map) => map.remove(key).map(|_| ()).ok_or(MapError::KeyNotFound),
+        }
+    }
+
+    pub fn keys(&self) -> Box<dyn Iterator<Item = Result<K, MapError>> + '_> {
+        match self {
+            HashMap::Bpf(map) => Box::new(map.keys().map(|key| Ok(key?))),
+            HashMap::Local(map) => Box::new(map.keys().map(|key| Ok(*key))),
+        }
+    }
+
+    pub fn iter(&self) -> Box<dyn Iterator<Item = Result<(K, V), MapError>> + '_> {
+        match self {
+            HashMap::Bpf(map) => Box::new(map.iter().map(|entry| Ok(entry?))),
+            HashMap::Local(map) => Box::new(map.iter().map(|(key, value)| Ok((*key, *value)))),
+        }
+    }
+}
+
+impl<V: Pod> Array<V> {
+    pub fn take(bpf: Option<&mut Bpf>, name: &str) -> Result<Array<V>, MapError> {
+        match bpf {
+            Some(bpf) => Ok(Array::Bpf(aya::maps::Array::try_from(
+                bpf.take_map(name).unwrap(),
+            )?)),
+            None => Ok(Array::Local(std::collections::HashMap::new())),
+        }
+    }
+
+    // Elements of local arrays are unset until written.
+    pub fn get(&self, index: &u32, flags: u64) -> Result<V, MapError> {
+        match self {
+            Array::Bpf(map) => Ok(map.get(index, flags)?),
+            Array::Local(map) => map.get(index).copied().ok_or(MapError::KeyNotFound),
+        }
+    }
+
+    pub fn set(&mut self, index: u32, value: impl Borrow<V>, flags: u64) -> Result<(), MapError> {
+        match self {
+            Array::Bpf(map) => Ok(map.set(index, value, flags)?),
+            Array::Local(map) => {
+                map.insert(index, *value.borrow());
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<V: Pod> PerCpuArray<V> {
+    pub fn take(bpf: Option<&mut Bpf>, name: &str) -> Result<PerCpuArray<V>, MapError> {
+        match bpf {
+            Some(bpf) => Ok(PerCpuArray::Bpf(aya::maps::PerCpuArray::try_from(
+                bpf.take_map(name).unwrap(),
+            )?)),
+            None => Ok(PerCpuArray::Local(std::collections::HashMap::new())),
+        }
+    }
+
+    // Get the value of each CPU.
+    pub fn get(&self, index: &u32, flags: u64) -> Result<Vec<V>, MapError> {
+        match self {
+            PerCpuArray::Bpf(map) => Ok(map.get(index, flags)?.iter().copied().collect()),
+            PerCpuArray::Local(map) => Ok(map.get(index).copied().into_iter().collect()),
+        }
+    }
+}
+
+impl PerCpuArray<u64> {
+    // Increment a counter. Counters of BPF maps are only incremented by the eBPF program.
+    pub fn increment(&mut self, index: u32) {
+        if let PerCpuArray::Local(map) = self {
+            *map.entry(index).or_default() += 1;
+        }
+    }
+}
@@ -0,0 +1,328 @@
+use crate::raft_log::LogEntry;
+use crate::snapshot::Snapshot;
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+// Raft state kept on disk (--data-dir), so a restarted node neither votes twice in a term nor
+// forgets entries it acknowledged. Everything is synced to disk before it is acknowledged.
+//
+// The data directory holds:
+// * `hard_state`: HardState as JSON.
+// * `snapshot`: [index (u64 BE)][term (u64 BE)][state machine snapshot].
+// * `log`: the entries following the snapshot, as JSON lines. An entry replaces the one at its
+//   index and all that follow, so entries conflicting with the leader's are overwritten by
+//   appending. The file is rewritten when the log is compacted.
+//
+// `hard_state` and `snapshot` are replaced by renaming a synced temporary file.
+
+const HARD_STATE_FILE: &str = "hard_state";
+const SNAPSHOT_FILE: &str = "snapshot";
+const LOG_FILE: &str = "log";
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HardState {
+    // Highest term of a leader whose entries were accepted.
+    pub term: u64,
+    // Highest term in which votes may have been granted (VOTE_CEILING).
+    pub vote_ceiling: u64,
+}
+
+// State read from the data directory on startup.
+#[derive(Debug, Default)]
+pub struct Restored {
+    pub hard_state: HardState,
+    pub snapshot: Snapshot,
+    pub entries: Vec<LogEntry>,
+}
+
+pub struct Persistence {
+    dir: PathBuf,
+    log: File,
+    hard_state: HardState,
+}
+
+impl Persistence {
+    // Open the data directory, creating it if needed, and read the state it holds.
+    pub fn open(dir: &Path) -> Result<(Persistence, Restored), anyhow::Error> {
+        fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+
+        let hard_state = match fs::read(dir.join(HARD_STATE_FILE)) {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .with_context(|| format!("invalid {} in {}", HARD_STATE_FILE, dir.display()))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => HardState::default(),
+            Err(err) => return Err(err.into()),
+        };
+
+        let snapshot = match File::open(dir.join(SNAPSHOT_FILE)) {
+            Ok(file) => read_snapshot(file)
+                .with_context(|| format!("invalid {} in {}", SNAPSHOT_FILE, dir.display()))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Snapshot::default(),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut log = File::options()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(dir.join(LOG_FILE))?;
+        let entries = read_log(&mut log, snapshot.index)
+            .with_context(|| format!("invalid {} in {}", LOG_FILE, dir.display()))?;
+        sync_dir(dir)?;
+
+        let persistence = Persistence {
+            dir: dir.to_path_buf(),
+            log,
+            hard_state,
+        };
+        let restored = Restored {
+            hard_state,
+            snapshot,
+            entries,
+        };
+        Ok((persistence, restored))
+    }
+
+    pub fn hard_state(&self) -> HardState {
+        self.hard_state
+    }
+
+    pub fn save_hard_state(&mut self, hard_state: HardState) -> io::Result<()> {
+        let contents = serde_json::to_vec(&hard_state)?;
+        self.replace(HARD_STATE_FILE, &contents)?;
+        self.hard_state = hard_state;
+        Ok(())
+    }
+
+    // Append entries to the log, replacing those at their indices and all that follow.
+    pub fn append(&mut self, entries: &[LogEntry]) -> io::Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        let mut buffer = Vec::new();
+        for entry in entries {
+            serde_json::to_writer(&mut buffer, entry)?;
+            buffer.push(b'\n');
+        }
+        self.log.write_all(&buffer)?;
+        self.log.sync_data()
+    }
+
+    // Save a snapshot, followed by the entries of the log after it.
+    pub fn save_snapshot(&mut self, snapshot: &Snapshot, entries: &[LogEntry]) -> io::Result<()> {
+        let mut contents = Vec::with_capacity(16 + snapshot.data.len());
+        contents.extend_from_slice(&snapshot.index.to_be_bytes());
+        contents.extend_from_slice(&snapshot.term.to_be_bytes());
+        contents.extend_from_slice(&snapshot.data);
+        self.replace(SNAPSHOT_FILE, &contents)?;
+
+        // Entries up to the snapshot are skipped when the log is read, so a crash before the
+        // log is rewritten loses nothing.
+        let mut log = Vec::new();
+        for entry in entries {
+            serde_json::to_writer(&mut log, entry)?;
+            log.push(b'\n');
+        }
+        self.replace(LOG_FILE, &log)?;
+        self.log = File::options().append(true).open(self.dir.join(LOG_FILE))?;
+        Ok(())
+    }
+
+    // Replace a file with the given contents, which are on disk once this returns.
+    fn replace(&self, name: &str, contents: &[u8]) -> io::Result<()> {
+        let path = self.dir.join(name);
+        let temporary = self.dir.join(format!("{}.tmp", name));
+
+        let mut file = File::create(&temporary)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+        fs::rename(&temporary, &path)?;
+        sync_dir(&self.dir)
+    }
+}
+
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+fn read_snapshot(mut file: File) -> Result<Snapshot, anyhow::Error> {
+    let mut index = [0u8; 8];
+    let mut term = [0u8; 8];
+    file.read_exact(&mut index)?;
+    file.read_exact(&mut term)?;
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+
+    Ok(Snapshot {
+        index: u64::from_be_bytes(index),
+        term: u64::from_be_bytes(term),
+        data,
+    })
+}
+
+// Read the entries following the snapshot. A last line cut short by a crash was never
+// acknowledged, and is removed.
+fn read_log(log: &mut File, snapshot_index: u64) -> Result<Vec<LogEntry>, anyhow::Error> {
+    let mut entries: Vec<LogEntry> = Vec::new();
+    let mut reader = BufReader::new(&*log);
+    let mut line = Vec::new();
+    let mut valid_len = 0;
+
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        // Only the last line can lack its newline.
+        if !line.ends_with(b"\n") {
+            break;
+        }
+        let entry: LogEntry = serde_json::from_slice(&line)?;
+        valid_len += line.len() as u64;
+
+        if entry.index <= snapshot_index {
+            continue;
+        }
+        let position = (entry.index - snapshot_index - 1) as usize;
+        if position > entries.len() {
+            bail!(
+                "entry {} follows entry {}",
+                entry.index,
+                snapshot_index + entries.len() as u64
+            );
+        }
+        entries.truncate(position);
+        entries.push(entry);
+    }
+
+    if log.metadata()?.len() > valid_len {
+        log.set_len(valid_len)?;
+        log.sync_all()?;
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft_log::Command;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    fn data_dir() -> PathBuf {
+        static INSTANCE: AtomicU64 = AtomicU64::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "raft-main-persistence-{}-{}",
+            std::process::id(),
+            INSTANCE.fetch_add(1, Ordering::SeqCst)
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn entry(index: u64, term: u64) -> LogEntry {
+        LogEntry {
+            index,
+            term,
+            command: Command::Noop,
+        }
+    }
+
+    fn terms(entries: &[LogEntry]) -> Vec<(u64, u64)> {
+        entries
+            .iter()
+            .map(|entry| (entry.index, entry.term))
+            .collect()
+    }
+
+    #[test]
+    fn state_survives_reopening() {
+        let dir = data_dir();
+        let (mut persistence, restored) = Persistence::open(&dir).unwrap();
+        assert_eq!(restored.hard_state, HardState::default());
+        assert!(restored.entries.is_empty());
+
+        let hard_state = HardState {
+            term: 3,
+            vote_ceiling: 13,
+        };
+        persistence.save_hard_state(hard_state).unwrap();
+        persistence
+            .append(&[entry(1, 1), entry(2, 1), entry(3, 2)])
+            .unwrap();
+        drop(persistence);
+
+        let (_, restored) = Persistence::open(&dir).unwrap();
+        assert_eq!(restored.hard_state, hard_state);
+        assert_eq!(terms(&restored.entries), [(1, 1), (2, 1), (3, 2)]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn appended_entries_replace_those_they_conflict_with() {
+        let dir = data_dir();
+        let (mut persistence, _) = Persistence::open(&dir).unwrap();
+        persistence
+            .append(&[entry(1, 1), entry(2, 1), entry(3, 1)])
+            .unwrap();
+        persistence.append(&[entry(2, 2)]).unwrap();
+        drop(persistence);
+
+        let (_, restored) = Persistence::open(&dir).unwrap();
+        assert_eq!(terms(&restored.entries), [(1, 1), (2, 2)]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn snapshot_replaces_the_entries_it_includes() {
+        let dir = data_dir();
+        let (mut persistence, _) = Persistence::open(&dir).unwrap();
+        persistence
+            .append(&[entry(1, 1), entry(2, 1), entry(3, 2)])
+            .unwrap();
+        let snapshot = Snapshot {
+            index: 2,
+            term: 1,
+            data: b"state".to_vec(),
+        };
+        persistence
+            .save_snapshot(&snapshot, &[entry(3, 2)])
+            .unwrap();
+        persistence.append(&[entry(4, 2)]).unwrap();
+        drop(persistence);
+
+        let (_, restored) = Persistence::open(&dir).unwrap();
+        assert_eq!((restored.snapshot.index, restored.snapshot.term), (2, 1));
+        assert_eq!(restored.snapshot.data, b"state");
+        assert_eq!(terms(&restored.entries), [(3, 2), (4, 2)]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn entry_cut_short_is_removed() {
+        let dir = data_dir();
+        let (mut persistence, _) = Persistence::open(&dir).unwrap();
+        persistence.append(&[entry(1, 1), entry(2, 1)]).unwrap();
+        drop(persistence);
+
+        let path = dir.join(LOG_FILE);
+        let len = fs::metadata(&path).unwrap().len();
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 5)
+            .unwrap();
+
+        let (mut persistence, restored) = Persistence::open(&dir).unwrap();
+        assert_eq!(terms(&restored.entries), [(1, 1)]);
+        persistence.append(&[entry(2, 2)]).unwrap();
+        drop(persistence);
+
+        let (_, restored) = Persistence::open(&dir).unwrap();
+        assert_eq!(terms(&restored.entries), [(1, 1), (2, 2)]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
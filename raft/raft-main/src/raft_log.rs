@@ -0,0 +1,322 @@
+use serde::{Deserialize, Serialize};
+
+// Commands replicated through the Raft log and applied to the state machine.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Command {
+    Put {
+        key: String,
+        value: String,
+    },
+    Delete {
+        key: String,
+    },
+    // Appended by a new leader, so entries of previous terms get committed.
+    Noop,
+    // Opens a client session, identified by the index of this entry.
+    RegisterClient {
+        timestamp_ns: u64,
+    },
+    // Command submitted within a client session, applied at most once per sequence number.
+    Client {
+        client_id: u64,
+        sequence: u64,
+        timestamp_ns: u64,
+        command: Box<Command>,
+    },
+    // Appended periodically by the leader to expire idle client sessions.
+    ExpireSessions {
+        timestamp_ns: u64,
+    },
+}
+
+impl Command {
+    // Key modified by this command, if any.
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            Command::Put { key, .. } | Command::Delete { key } => Some(key),
+            Command::Client { command, .. } => command.key(),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LogEntry {
+    pub index: u64,
+    pub term: u64,
+    pub command: Command,
+}
+
+// In-memory Raft log. Indices start at 1; index 0 with term 0 precedes the first entry.
+// Entries up to snapshot_index have been compacted into a snapshot of the state machine.
+#[derive(Debug, Default)]
+pub struct RaftLog {
+    entries: Vec<LogEntry>,
+    snapshot_index: u64,
+    snapshot_term: u64,
+    pub commit_index: u64,
+    pub last_applied: u64,
+}
+
+impl RaftLog {
+    pub fn new() -> RaftLog {
+        RaftLog::default()
+    }
+
+    // Rebuild the log from a snapshot and the entries following it, as read from disk.
+    // Entries after the snapshot are applied once the leader commits them again.
+    pub fn restore(snapshot_index: u64, snapshot_term: u64, entries: Vec<LogEntry>) -> RaftLog {
+        RaftLog {
+            entries,
+            snapshot_index,
+            snapshot_term,
+            commit_index: snapshot_index,
+            last_applied: snapshot_index,
+        }
+    }
+
+    // Get index of the last entry.
+    pub fn last_index(&self) -> u64 {
+        self.snapshot_index + self.entries.len() as u64
+    }
+
+    // Get term of the last entry.
+    pub fn last_term(&self) -> u64 {
+        self.entries
+            .last()
+            .map(|entry| entry.term)
+            .unwrap_or(self.snapshot_term)
+    }
+
+    // Get index of the last entry included in the snapshot.
+    pub fn snapshot_index(&self) -> u64 {
+        self.snapshot_index
+    }
+
+    // Get term of the entry at the given index, if it is in the log or is the last entry
+    // included in the snapshot.
+    pub fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot_index {
+            return Some(self.snapshot_term);
+        }
+        self.get(index).map(|entry| entry.term)
+    }
+
+    // Get entry at the given index, unless it has been compacted.
+    pub fn get(&self, index: u64) -> Option<&LogEntry> {
+        if index <= self.snapshot_index {
+            return None;
+        }
+        self.entries.get((index - self.snapshot_index - 1) as usize)
+    }
+
+    // Get up to `max` entries starting at the given index, which must not be compacted.
+    pub fn entries_from(&self, index: u64, max: usize) -> Vec<LogEntry> {
+        let start = index.max(self.snapshot_index + 1) - self.snapshot_index - 1;
+        self.entries
+            .iter()
+            .skip(start as usize)
+            .take(max)
+            .cloned()
+            .collect()
+    }
+
+    // Append a new entry on the leader and return its index.
+    pub fn append(&mut self, term: u64, command: Command) -> u64 {
+        let index = self.last_index() + 1;
+        self.entries.push(LogEntry {
+            index,
+            term,
+            command,
+        });
+        index
+    }
+
+    // Check if the log contains the entry at `index` with `term`. Entries included in the
+    // snapshot are committed and therefore known to match.
+    pub fn matches(&self, index: u64, term: u64) -> bool {
+        index < self.snapshot_index || self.term_at(index) == Some(term)
+    }
+
+    // Get the position of the first of `entries` which append_entries would write: those before
+    // it are compacted or already in the log.
+    pub fn first_new_entry(&self, entries: &[LogEntry]) -> usize {
+        entries
+            .iter()
+            .position(|entry| {
+                entry.index > self.snapshot_index && self.term_at(entry.index) != Some(entry.term)
+            })
+            .unwrap_or(entries.len())
+    }
+
+    // Append entries received from the leader, following the entry at `prev_index`.
+    // Fails if the log does not contain that entry with `prev_term`. Entries conflicting
+    // with the new ones (same index, different term) are removed along with all that follow.
+    pub fn append_entries(
+        &mut self,
+        prev_index: u64,
+        prev_term: u64,
+        entries: Vec<LogEntry>,
+    ) -> bool {
+        if !self.matches(prev_index, prev_term) {
+            return false;
+        }
+
+        for entry in entries {
+            if entry.index <= self.snapshot_index {
+                continue;
+            }
+
+            match self.term_at(entry.index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => {
+                    // Committed entries never conflict, as the leader has them too.
+                    self.entries
+                        .truncate((entry.index - self.snapshot_index - 1) as usize);
+                    self.entries.push(entry);
+                }
+                None => self.entries.push(entry),
+            }
+        }
+        true
+    }
+
+    // Advance commit index; it never moves backwards.
+    pub fn commit_to(&mut self, index: u64) {
+        self.commit_index = self.commit_index.max(index.min(self.last_index()));
+    }
+
+    // Discard entries up to `index`, which have been applied and included in a snapshot.
+    pub fn compact(&mut self, index: u64) {
+        let term = match self.term_at(index) {
+            Some(term) if index > self.snapshot_index && index <= self.last_applied => term,
+            _ => return,
+        };
+
+        self.entries.drain(..(index - self.snapshot_index) as usize);
+        self.snapshot_index = index;
+        self.snapshot_term = term;
+    }
+
+    // Replace the log up to `index` with a snapshot received from the leader, which has been
+    // restored into the state machine. Entries following it are kept if the logs match.
+    pub fn install_snapshot(&mut self, index: u64, term: u64) {
+        if index > self.snapshot_index && self.matches(index, term) {
+            self.entries.drain(..(index - self.snapshot_index) as usize);
+        } else {
+            self.entries.clear();
+        }
+
+        self.snapshot_index = index;
+        self.snapshot_term = term;
+        self.commit_index = self.commit_index.max(index);
+        self.last_applied = index;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log_with_terms(terms: &[u64]) -> RaftLog {
+        let mut log = RaftLog::new();
+        for term in terms {
+            log.append(*term, Command::Noop);
+        }
+        log
+    }
+
+    fn entry(index: u64, term: u64) -> LogEntry {
+        LogEntry {
+            index,
+            term,
+            command: Command::Noop,
+        }
+    }
+
+    #[test]
+    fn compact_keeps_term_of_last_included_entry() {
+        let mut log = log_with_terms(&[1, 1, 2, 2, 3]);
+        log.commit_to(5);
+        log.last_applied = 3;
+
+        log.compact(3);
+        assert_eq!(log.snapshot_index(), 3);
+        assert_eq!(log.term_at(3), Some(2));
+        assert_eq!(log.term_at(2), None);
+        assert!(log.get(3).is_none());
+        assert_eq!(log.get(4).map(|entry| entry.index), Some(4));
+        assert_eq!((log.last_index(), log.last_term()), (5, 3));
+        assert_eq!(log.entries_from(1, 10).len(), 2);
+
+        // Entries not yet applied, or already compacted, are left alone.
+        log.compact(5);
+        log.compact(2);
+        assert_eq!(log.snapshot_index(), 3);
+
+        log.last_applied = 5;
+        log.compact(5);
+        assert_eq!((log.last_index(), log.last_term()), (5, 3));
+        assert!(log.entries_from(6, 10).is_empty());
+    }
+
+    #[test]
+    fn install_snapshot_keeps_matching_entries() {
+        let mut log = log_with_terms(&[1, 1, 2, 2, 3]);
+
+        log.install_snapshot(3, 2);
+        assert_eq!(log.snapshot_index(), 3);
+        assert_eq!((log.commit_index, log.last_applied), (3, 3));
+        assert_eq!((log.last_index(), log.last_term()), (5, 3));
+        assert_eq!(log.get(4).map(|entry| entry.term), Some(2));
+    }
+
+    #[test]
+    fn install_snapshot_discards_conflicting_entries() {
+        let mut log = log_with_terms(&[1, 1, 2, 2, 3]);
+        log.commit_to(2);
+
+        log.install_snapshot(4, 4);
+        assert_eq!(log.snapshot_index(), 4);
+        assert_eq!((log.commit_index, log.last_applied), (4, 4));
+        assert_eq!((log.last_index(), log.last_term()), (4, 4));
+        assert!(log.get(5).is_none());
+    }
+
+    #[test]
+    fn append_entries_across_snapshot_boundary() {
+        let mut log = log_with_terms(&[1, 1, 2, 2, 3]);
+        log.commit_to(5);
+        log.last_applied = 3;
+        log.compact(3);
+
+        // Compacted entries are committed, so they match whatever the leader sends.
+        assert!(log.append_entries(1, 1, vec![entry(2, 1), entry(3, 2), entry(4, 2)]));
+        assert_eq!((log.last_index(), log.last_term()), (5, 3));
+
+        // The last included entry is checked against the snapshot's term.
+        assert!(!log.append_entries(3, 1, vec![entry(4, 2)]));
+        assert!(log.append_entries(3, 2, vec![entry(4, 2), entry(5, 4), entry(6, 4)]));
+        assert_eq!((log.last_index(), log.last_term()), (6, 4));
+        assert_eq!(log.term_at(5), Some(4));
+
+        // Entries beyond the log are refused.
+        assert!(!log.append_entries(7, 4, vec![entry(8, 4)]));
+    }
+
+    #[test]
+    fn first_new_entry_skips_entries_in_the_log() {
+        let mut log = log_with_terms(&[1, 1, 2]);
+        log.commit_to(3);
+        log.last_applied = 2;
+        log.compact(2);
+
+        let entries = vec![entry(2, 1), entry(3, 2), entry(4, 2)];
+        assert_eq!(log.first_new_entry(&entries), 2);
+        assert_eq!(log.first_new_entry(&entries[..2]), 2);
+
+        // The first conflicting entry and all that follow are written.
+        let entries = vec![entry(3, 3), entry(4, 3)];
+        assert_eq!(log.first_new_entry(&entries), 0);
+    }
+}
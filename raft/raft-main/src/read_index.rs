@@ -0,0 +1,91 @@
+use crate::election::{Election, Storage};
+use crate::helpers;
+use crate::state;
+use crate::values;
+use raft_main_common::NodeState;
+use std::time::{Duration, Instant};
+
+// ReadIndex: linearizable reads without appending to the log.
+// The leader records its commit index, confirms it is still the leader through a round of
+// heartbeats acknowledged by a quorum, and returns once the state machine has applied the
+// recorded index. Reads served afterwards reflect every write completed before the request.
+pub async fn read_index(state: &state::AppState) -> Result<u64, &'static str> {
+    let deadline = Instant::now() + Duration::from_millis(values::CLIENT_REQUEST_TIMEOUT_MS);
+    let term = state.current_term_id();
+
+    if state.get_current_state() != NodeState::Leader {
+        return Err("not the leader");
+    }
+
+    // Until an entry of its own term is committed, the leader may not know the latest commit index.
+    let read_index = match state.commit_index_in_term(term) {
+        Some(index) => index,
+        None => return Err("leader has not committed an entry of its term yet"),
+    };
+
+    // Acks of any heartbeat sent from now on confirm leadership; regular heartbeats count too.
+    // Sending takes the socket lock and fans out on rayon, so it is kept off the async runtime.
+    let started_ns = helpers::get_current_clock_ns();
+    let heartbeat_state = state.clone();
+    tokio::task::spawn_blocking(move || heartbeat_state.send_heartbeat_rpcs())
+        .await
+        .map_err(|_| "failed to send heartbeats")?;
+
+    while state.heartbeat_acks_since(term, started_ns) + 1 < values::QUORUM {
+        // A follower answering from a later term voted for another candidate.
+        if state.get_current_state() != NodeState::Leader
+            || state.current_term_id() != term
+            || state.heartbeat_response_term() > term
+        {
+            return Err("leadership changed while confirming it");
+        }
+        if Instant::now() > deadline {
+            return Err("timed out confirming leadership");
+        }
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+
+    wait_until_applied(state, read_index, deadline).await?;
+
+    Ok(read_index)
+}
+
+// Leader lease read: like ReadIndex, but leadership is confirmed by the leader lease (see
+// AppState::lease_remaining_ns) instead of a round of heartbeats. Refused while the lease does
+// not hold, including while the followers' clock drift is unknown or out of bounds.
+pub async fn lease_read_index(state: &state::AppState) -> Result<u64, &'static str> {
+    let deadline = Instant::now() + Duration::from_millis(values::CLIENT_REQUEST_TIMEOUT_MS);
+    let term = state.current_term_id();
+
+    if state.get_current_state() != NodeState::Leader {
+        return Err("not the leader");
+    }
+
+    let read_index = match state.commit_index_in_term(term) {
+        Some(index) => index,
+        None => return Err("leader has not committed an entry of its term yet"),
+    };
+
+    wait_until_applied(state, read_index, deadline).await?;
+
+    // Checked last, as the read is served right after.
+    if state.lease_remaining_ns() == 0 {
+        return Err("no valid leader lease");
+    }
+
+    Ok(read_index)
+}
+
+async fn wait_until_applied(
+    state: &state::AppState,
+    index: u64,
+    deadline: Instant,
+) -> Result<(), &'static str> {
+    while !state.is_applied(index) {
+        if Instant::now() > deadline {
+            return Err("timed out waiting for the read index to be applied");
+        }
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+    Ok(())
+}
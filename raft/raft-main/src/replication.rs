@@ -0,0 +1,472 @@
+use crate::election::{Election, Storage};
+use crate::helpers::get_wall_clock_ns;
+use crate::raft_log::{Command, LogEntry, RaftLog};
+use crate::snapshot;
+use crate::state;
+use crate::values;
+use log::{debug, info, warn};
+use raft_main_common::auth::{message_mac_bytes, MAC_LEN, SEQ_LEN};
+use raft_main_common::{NodeState, APPEND_ENTRIES_PORT, APPEND_ENTRIES_RESPONSE_PORT};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::time::{Duration, Instant};
+
+// Log replication runs in userspace over UDP; the XDP program passes these ports through.
+// Messages are JSON, followed by a sequence number and a MAC over both if authentication is
+// enabled. Sequence numbers are checked against the sender's high-water mark as in eBPF, as a
+// replayed AppendEntries of the current term could truncate entries appended after it.
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AppendEntries {
+    pub term: u64,
+    pub prev_log_index: u64,
+    pub prev_log_term: u64,
+    pub leader_commit: u64,
+    pub entries: Vec<LogEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AppendEntriesResponse {
+    pub term: u64,
+    pub success: bool,
+    // Last index known to match the leader's log; the follower's last index on failure.
+    pub match_index: u64,
+}
+
+// Leader's view of the followers' logs, reset whenever the term changes.
+#[derive(Debug, Default)]
+pub struct Progress {
+    term: u64,
+    next_index: HashMap<u32, u64>,
+    match_index: HashMap<u32, u64>,
+    // Followers currently receiving a snapshot.
+    snapshots_in_flight: HashSet<u32>,
+}
+
+// Serialize a message sent from this node to `port`.
+fn encode<T: Serialize>(state: &state::AppState, port: u16, message: &T) -> Vec<u8> {
+    let mut buffer = serde_json::to_vec(message).expect("Failed to serialize message");
+
+    if state.mac_key.is_enabled() {
+        buffer.extend_from_slice(&state.sequence_number().to_be_bytes());
+        let mac = message_mac_bytes(&state.mac_key, state.local_addr, port, &buffer);
+        buffer.extend_from_slice(&mac.to_be_bytes());
+    }
+
+    buffer
+}
+
+// Deserialize a message sent from `source_addr` to `port`, verifying its MAC and sequence number
+// if enabled.
+fn decode<T: DeserializeOwned>(
+    state: &state::AppState,
+    source_addr: u32,
+    port: u16,
+    buffer: &[u8],
+) -> Option<T> {
+    let body = if state.mac_key.is_enabled() {
+        if buffer.len() < SEQ_LEN + MAC_LEN {
+            return None;
+        }
+        let (signed, mac) = buffer.split_at(buffer.len() - MAC_LEN);
+        let expected = message_mac_bytes(&state.mac_key, source_addr, port, signed);
+        if mac != expected.to_be_bytes() {
+            return None;
+        }
+
+        let (body, seq) = signed.split_at(signed.len() - SEQ_LEN);
+        let seq = u64::from_be_bytes(seq.try_into().unwrap());
+        if !state.accept_sequence_number(source_addr, port, seq, state.sequence_number()) {
+            return None;
+        }
+        body
+    } else {
+        buffer
+    };
+
+    serde_json::from_slice(body).ok()
+}
+
+// Receive a message from a Raft peer. Returns None on timeout and for invalid messages.
+fn receive<T: DeserializeOwned>(
+    state: &state::AppState,
+    socket: &UdpSocket,
+    port: u16,
+    buffer: &mut [u8],
+) -> Option<(u32, T)> {
+    let (len, source) = match socket.recv_from(buffer) {
+        Ok(x) => x,
+        Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+            return None
+        }
+        Err(err) => {
+            warn!("Failed to receive on port {}: {}", port, err);
+            return None;
+        }
+    };
+
+    let source_addr = match source {
+        SocketAddr::V4(addr) => u32::from(*addr.ip()),
+        SocketAddr::V6(_) => return None,
+    };
+
+    if !state.get_raft_peers().contains(&source_addr) {
+        debug!(
+            "Ignoring message on port {} from non-member {}",
+            port, source
+        );
+        return None;
+    }
+
+    match decode(state, source_addr, port, &buffer[..len]) {
+        Some(message) => Some((source_addr, message)),
+        None => {
+            warn!("Dropping invalid message on port {} from {}", port, source);
+            None
+        }
+    }
+}
+
+// Check if every peer has acknowledged the log up to `index` in the current term.
+pub fn replicated_to_all(state: &state::AppState, index: u64) -> bool {
+    let term = state.current_term_id();
+    let progress = state.replication.lock().unwrap();
+
+    progress.term == term
+        && state
+            .get_raft_peers()
+            .iter()
+            .filter(|ip| **ip != 0)
+            .all(|ip| progress.match_index.get(ip).copied().unwrap_or_default() >= index)
+}
+
+// Send AppendEntries to all followers every REPLICATION_INTERVAL_MS while leader.
+// Entries a follower already has are skipped by next_index, so this doubles as retransmission.
+// Idle client sessions are expired every SESSION_EXPIRY_INTERVAL_MS.
+pub fn leader_loop(state: &state::AppState, socket: &UdpSocket) {
+    let mut last_session_expiry = Instant::now();
+
+    while !state.is_shutting_down() {
+        if state.get_current_state() == NodeState::Leader {
+            if last_session_expiry.elapsed()
+                >= Duration::from_millis(values::SESSION_EXPIRY_INTERVAL_MS)
+            {
+                state.propose(Command::ExpireSessions {
+                    timestamp_ns: get_wall_clock_ns(),
+                });
+                last_session_expiry = Instant::now();
+            }
+            send_append_entries(state, socket);
+        }
+        std::thread::sleep(Duration::from_millis(values::REPLICATION_INTERVAL_MS));
+    }
+}
+
+fn send_append_entries(state: &state::AppState, socket: &UdpSocket) {
+    let term = state.current_term_id();
+    let raft_log = state.raft_log.lock().unwrap();
+    let mut progress = state.replication.lock().unwrap();
+
+    if progress.term != term {
+        *progress = Progress {
+            term,
+            ..Default::default()
+        };
+    }
+
+    for ip in state.get_raft_peers() {
+        if ip == 0 {
+            continue;
+        }
+
+        let next_index = *progress
+            .next_index
+            .entry(ip)
+            .or_insert(raft_log.last_index() + 1);
+
+        // The entries this follower needs have been compacted; send the snapshot instead.
+        if next_index <= raft_log.snapshot_index() {
+            if progress.snapshots_in_flight.insert(ip) {
+                let state = state.clone();
+                std::thread::spawn(move || {
+                    snapshot::send_snapshot(&state, ip);
+                    state
+                        .replication
+                        .lock()
+                        .unwrap()
+                        .snapshots_in_flight
+                        .remove(&ip);
+                });
+            }
+            continue;
+        }
+
+        let prev_log_index = next_index - 1;
+
+        let request = AppendEntries {
+            term,
+            prev_log_index,
+            prev_log_term: raft_log.term_at(prev_log_index).unwrap_or_default(),
+            leader_commit: raft_log.commit_index,
+            entries: batch(raft_log.entries_from(next_index, values::MAX_ENTRIES_PER_APPEND)),
+        };
+
+        let buffer = encode(state, APPEND_ENTRIES_PORT, &request);
+        let dest_socket = SocketAddrV4::new(Ipv4Addr::from(ip), APPEND_ENTRIES_PORT);
+        if let Err(err) = socket.send_to(&buffer, dest_socket) {
+            warn!(
+                "Failed to send {} entries to {}: {}",
+                request.entries.len(),
+                Ipv4Addr::from(ip),
+                err
+            );
+        }
+    }
+}
+
+// Leading entries which fit in a single datagram once serialized, and at least one.
+fn batch(entries: Vec<LogEntry>) -> Vec<LogEntry> {
+    let mut bytes = 0;
+
+    entries
+        .into_iter()
+        .enumerate()
+        .take_while(|(i, entry)| {
+            bytes += serde_json::to_vec(entry).map_or(0, |entry| entry.len() + 1);
+            *i == 0 || bytes <= values::MAX_APPEND_ENTRIES_BYTES
+        })
+        .map(|(_, entry)| entry)
+        .collect()
+}
+
+// Receive AppendEntries responses from followers and advance the commit index.
+pub fn response_listener(state: &state::AppState, socket: &UdpSocket) {
+    let mut buffer = vec![0u8; 65536];
+
+    while !state.is_shutting_down() {
+        let (source_addr, response): (u32, AppendEntriesResponse) =
+            match receive(state, socket, APPEND_ENTRIES_RESPONSE_PORT, &mut buffer) {
+                Some(x) => x,
+                None => continue,
+            };
+
+        handle_append_entries_response(state, source_addr, response);
+    }
+}
+
+pub fn handle_append_entries_response(
+    state: &state::AppState,
+    follower: u32,
+    response: AppendEntriesResponse,
+) {
+    let term = state.current_term_id();
+
+    // The follower voted in a later term, so another leader may have been elected.
+    if response.term > term {
+        info!(
+            "{} answered AppendEntries in term {}, stepping down",
+            Ipv4Addr::from(follower),
+            response.term
+        );
+        state.adopt_term(response.term);
+        return;
+    }
+
+    if state.get_current_state() != NodeState::Leader || response.term != term {
+        return;
+    }
+
+    let mut raft_log = state.raft_log.lock().unwrap();
+    let mut progress = state.replication.lock().unwrap();
+    if progress.term != term {
+        return;
+    }
+
+    if response.success {
+        let match_index = progress.match_index.entry(follower).or_insert(0);
+        *match_index = (*match_index).max(response.match_index);
+        let next_index = *match_index + 1;
+        progress.next_index.insert(follower, next_index);
+    } else {
+        // Walk back towards the follower's last entry until the logs match.
+        let next_index = progress.next_index.entry(follower).or_insert(1);
+        *next_index = (*next_index - 1).min(response.match_index + 1).max(1);
+    }
+
+    advance_commit_index(&mut raft_log, &progress, term);
+    drop(progress);
+    drop(raft_log);
+
+    state.apply_committed_entries();
+}
+
+// Commit the highest entry of the current term stored on a quorum of nodes, and with it
+// all entries before it. Entries of earlier terms are never committed by counting replicas.
+fn advance_commit_index(raft_log: &mut RaftLog, progress: &Progress, term: u64) {
+    let mut index = raft_log.last_index();
+
+    while index > raft_log.commit_index {
+        if raft_log.term_at(index) != Some(term) {
+            break;
+        }
+
+        let replicas = 1 + progress
+            .match_index
+            .values()
+            .filter(|match_index| **match_index >= index)
+            .count() as u64; // Leader stores all of its entries.
+
+        if replicas >= values::QUORUM {
+            raft_log.commit_to(index);
+            return;
+        }
+        index -= 1;
+    }
+}
+
+// Lowest term of a leader whose entries are accepted. Includes votes granted by the eBPF program
+// but not yet adopted: a candidate we voted for was elected with our log as it was, so the leader
+// it replaces must not commit entries through us anymore. The term is returned in refusals, so
+// that leader steps down.
+pub fn accepted_term(state: &state::AppState) -> u64 {
+    state.current_term_id().max(state.voted_term())
+}
+
+// Receive AppendEntries from the leader, append them to the log and apply committed entries.
+pub fn follower_listener(state: &state::AppState, socket: &UdpSocket) {
+    let mut buffer = vec![0u8; 65536];
+
+    while !state.is_shutting_down() {
+        let (source_addr, request): (u32, AppendEntries) =
+            match receive(state, socket, APPEND_ENTRIES_PORT, &mut buffer) {
+                Some(x) => x,
+                None => continue,
+            };
+
+        let response = handle_append_entries(state, request);
+
+        let buffer = encode(state, APPEND_ENTRIES_RESPONSE_PORT, &response);
+        let dest_socket =
+            SocketAddrV4::new(Ipv4Addr::from(source_addr), APPEND_ENTRIES_RESPONSE_PORT);
+        if let Err(err) = socket.send_to(&buffer, dest_socket) {
+            warn!(
+                "Failed to respond to AppendEntries from {}: {}",
+                Ipv4Addr::from(source_addr),
+                err
+            );
+        }
+    }
+}
+
+fn handle_append_entries(state: &state::AppState, request: AppendEntries) -> AppendEntriesResponse {
+    let current_term = accepted_term(state);
+
+    // Terms are adopted from heartbeats; a leader never accepts entries of its own term.
+    if request.term < current_term
+        || (request.term == current_term && state.get_current_state() == NodeState::Leader)
+    {
+        return AppendEntriesResponse {
+            term: current_term,
+            success: false,
+            match_index: 0,
+        };
+    }
+
+    let mut raft_log = state.raft_log.lock().unwrap();
+    let last_new_index = request.prev_log_index + request.entries.len() as u64;
+
+    // The leader's term and the entries are on disk before they are acknowledged.
+    let success = raft_log.matches(request.prev_log_index, request.prev_log_term)
+        && match persist_entries(state, &raft_log, &request) {
+            Ok(()) => true,
+            Err(err) => {
+                warn!("Failed to write entries to disk: {}", err);
+                false
+            }
+        };
+    if success {
+        raft_log.append_entries(
+            request.prev_log_index,
+            request.prev_log_term,
+            request.entries,
+        );
+    }
+
+    let match_index = if success {
+        raft_log.commit_to(request.leader_commit.min(last_new_index));
+        last_new_index
+    } else {
+        raft_log.last_index()
+    };
+    drop(raft_log);
+
+    // Published before acknowledging, so votes are never granted to a candidate missing acknowledged entries.
+    state.publish_log_state();
+    state.apply_committed_entries();
+
+    AppendEntriesResponse {
+        term: request.term,
+        success,
+        match_index,
+    }
+}
+
+// Write the term of an AppendEntries and the entries it adds to the log to disk.
+fn persist_entries(
+    state: &state::AppState,
+    raft_log: &RaftLog,
+    request: &AppendEntries,
+) -> Result<(), anyhow::Error> {
+    state.persist_term(request.term)?;
+
+    let new_entries = &request.entries[raft_log.first_new_entry(&request.entries)..];
+    state.persistence.lock().unwrap().append(new_entries)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn put(index: u64, value_len: usize) -> LogEntry {
+        LogEntry {
+            index,
+            term: 1,
+            command: Command::Put {
+                key: "k".to_owned(),
+                value: "v".repeat(value_len),
+            },
+        }
+    }
+
+    #[test]
+    fn batch_is_bounded_by_size() {
+        let entries: Vec<LogEntry> = (1..=4).map(|i| put(i, 20 * 1024)).collect();
+
+        let batch = batch(entries);
+
+        assert_eq!(
+            batch.iter().map(|entry| entry.index).collect::<Vec<_>>(),
+            [1, 2]
+        );
+    }
+
+    #[test]
+    fn batch_holds_an_entry_larger_than_the_bound() {
+        let entries = vec![put(1, values::MAX_APPEND_ENTRIES_BYTES), put(2, 1)];
+
+        assert_eq!(batch(entries).len(), 1);
+    }
+
+    #[test]
+    fn batch_of_small_entries_is_bounded_by_count() {
+        let entries = (1..=values::MAX_ENTRIES_PER_APPEND as u64)
+            .map(|i| put(i, 10))
+            .collect();
+
+        assert_eq!(batch(entries).len(), values::MAX_ENTRIES_PER_APPEND);
+    }
+}
@@ -1,18 +1,58 @@
-use crate::helpers::ip_string_to_u32;
-use crate::state;
+use crate::election::{Election, Storage};
+use crate::forward;
+use crate::helpers::{get_wall_clock_ns, ip_string_to_u32};
+use crate::raft_log::Command;
+use crate::read_index;
+use crate::state::{self, Outcome};
+use crate::udp::TransportMode;
+use crate::values;
 use axum::extract;
-use axum::extract::State;
-use axum::response::Json;
+use axum::extract::{Path, Query, State};
+use axum::http::{HeaderMap, Method, Uri};
+use axum::response::{IntoResponse, Json, Response};
 use log::{info, warn};
+use raft_main_common::fault::{Fault, FAULT_ALL_PEERS, FAULT_DROP_PPM_MAX, FAULT_MESSAGE_NAMES};
+use raft_main_common::{
+    NodeState, COUNTER_FAULT_DROPS, COUNTER_MAC_FAILURES, COUNTER_MAP_INSERT_FAILURES,
+    COUNTER_NON_MEMBER_DROPS, COUNTER_REPLAYS,
+};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
 
 #[derive(Debug, Deserialize)]
 pub struct IPPayload {
     ip: String,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct ValuePayload {
+    value: String,
+}
+
+// Read consistency, selected with `?consistency=`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Consistency {
+    #[default]
+    ReadIndex,
+    Lease,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReadOptions {
+    #[serde(default)]
+    consistency: Consistency,
+}
+
+// Client session of a write, from `?client_id=&sequence=`. See session.rs.
+#[derive(Debug, Deserialize)]
+pub struct WriteOptions {
+    client_id: Option<u64>,
+    sequence: Option<u64>,
+}
+
 #[derive(Debug, Serialize)]
 pub struct FollowerState {
     ip: String,
@@ -41,9 +81,21 @@ pub async fn add_follower(
     let mut data = follower_data.lock().unwrap();
 
     // Second item is "last_seen" value.
-    match data.insert(ip_address, 0, 0) {
+    if let Err(err) = data.insert(ip_address, 0, 0) {
+        return Json(json!({ "errors": err.to_string() }));
+    }
+
+    // Raft messages from addresses not in MEMBERS are dropped.
+    let mut members = state.members.lock().unwrap();
+    match members.insert(ip_address, 1, 0) {
         Ok(()) => Json(json!({ "errors": "none" })),
-        Err(err) => Json(json!({ "errors": err.to_string() })),
+        Err(err) => {
+            warn!(
+                "add_follower: cannot add {} to MEMBERS, its messages will be dropped: {}",
+                ip_addr_str, err
+            );
+            Json(json!({ "errors": err.to_string() }))
+        }
     }
 }
 
@@ -76,6 +128,15 @@ pub async fn delete_follower(
         Err(err) => warn!("delete_follower: cannot delete a follower: {}", err),
     }
 
+    // Its Raft messages are dropped from now on.
+    let mut members = state.members.lock().unwrap();
+    if let Err(err) = members.remove(&ip_address) {
+        warn!(
+            "delete_follower: cannot remove {} from MEMBERS: {}",
+            ip_addr_str, err
+        );
+    }
+
     Json(json!({ "errors": "none" }))
 }
 
@@ -115,3 +176,381 @@ pub async fn list_followers(State(state): State<state::AppState>) -> Json<Value>
     }
     Json(json!({ "data": response }))
 }
+
+// status returns the node's Raft state, log position, last won election, leader lease and the XDP mode it
+// is running in.
+pub async fn status(State(state): State<state::AppState>) -> Json<Value> {
+    let leader = state.get_leader();
+    let clock_drift_ppm: serde_json::Map<String, Value> = state
+        .peer_clock_drift_ppm()
+        .into_iter()
+        .map(|(ip, drift_ppm)| (Ipv4Addr::from(ip).to_string(), json!(drift_ppm)))
+        .collect();
+    let raft_log = state.raft_log.lock().unwrap();
+    let log = json!({
+        "last_index": raft_log.last_index(),
+        "commit_index": raft_log.commit_index,
+        "last_applied": raft_log.last_applied,
+        "snapshot_index": raft_log.snapshot_index(),
+    });
+    drop(raft_log);
+
+    // Vote timings are kept from winning an election until the next candidacy.
+    let vote = state.get_current_node().vote;
+    let last_election = (vote.ended_ts != 0 && vote.started_ts != 0).then(|| {
+        json!({
+            "ended_ns": vote.ended_ts,
+            "duration_ns": vote.ended_ts.saturating_sub(vote.started_ts),
+        })
+    });
+
+    Json(json!({ "data": {
+        "state": format!("{:?}", state.get_current_state()),
+        "term": state.current_term_id(),
+        "leader": Ipv4Addr::from(leader.source_addr_raw).to_string(),
+        "leader_term": leader.term_id,
+        "transport": state.transport,
+        "xdp_mode": (state.transport == TransportMode::Xdp).then_some(state.xdp_mode),
+        "forward_mode": state.forward_mode,
+        "thread_model": state.thread_model,
+        "authenticated": state.mac_key.is_enabled(),
+        "log": log,
+        "last_election": last_election,
+        "lease_remaining_ns": state.lease_remaining_ns(),
+        "clock_drift_ppm": clock_drift_ppm,
+        "counters": {
+            "non_member_drops": state.get_counter(COUNTER_NON_MEMBER_DROPS),
+            "mac_failures": state.get_counter(COUNTER_MAC_FAILURES),
+            "replays": state.get_counter(COUNTER_REPLAYS),
+            "map_insert_failures": state.get_counter(COUNTER_MAP_INSERT_FAILURES),
+            "fault_drops": state.get_counter(COUNTER_FAULT_DROPS),
+        },
+    }}))
+}
+
+// Propose a command and wait until it has been committed and applied.
+// Returns the entry's index and the state machine's response, unless that is an error.
+async fn replicate(state: &state::AppState, command: Command) -> Result<(u64, Value), Json<Value>> {
+    let (index, term) = match state.submit(command) {
+        Some(x) => x,
+        None => return Err(forward::not_leader(state)),
+    };
+
+    let deadline = Instant::now() + Duration::from_millis(values::CLIENT_REQUEST_TIMEOUT_MS);
+    let outcome = loop {
+        match state.proposal_outcome(index, term) {
+            Outcome::Pending if Instant::now() > deadline => {
+                state.take_response(index);
+                return Err(Json(
+                    json!({ "error": "timed out waiting for the entry to be committed" }),
+                ));
+            }
+            Outcome::Pending => tokio::time::sleep(Duration::from_millis(1)).await,
+            outcome => break outcome,
+        }
+    };
+
+    let response = state.take_response(index).unwrap_or_default();
+    match outcome {
+        Outcome::Replaced => {
+            return Err(Json(
+                json!({ "error": "leadership changed before the entry was committed" }),
+            ));
+        }
+        Outcome::Unknown => {
+            return Err(Json(
+                json!({ "error": "entry replaced by a snapshot from a new leader; it may have been applied" }),
+            ));
+        }
+        Outcome::Pending | Outcome::Applied => {}
+    }
+    if response.get("error").is_some() {
+        return Err(Json(response));
+    }
+    Ok((index, response))
+}
+
+// Wrap a command in the client session given in the query, if any.
+fn with_session(options: &WriteOptions, command: Command) -> Result<Command, Json<Value>> {
+    match (options.client_id, options.sequence) {
+        (None, None) => Ok(command),
+        (Some(client_id), Some(sequence)) if sequence > 0 => Ok(Command::Client {
+            client_id,
+            sequence,
+            timestamp_ns: get_wall_clock_ns(),
+            command: Box::new(command),
+        }),
+        _ => Err(Json(
+            json!({ "error": "client_id and sequence (starting at 1) must be given together" }),
+        )),
+    }
+}
+
+// register_session opens a client session for exactly-once writes.
+pub async fn register_session(
+    State(state): State<state::AppState>,
+    headers: HeaderMap,
+    uri: Uri,
+) -> Response {
+    if state.get_current_state() != NodeState::Leader {
+        return forward::to_leader(&state, &headers, Method::POST, &uri, None).await;
+    }
+
+    let command = Command::RegisterClient {
+        timestamp_ns: get_wall_clock_ns(),
+    };
+
+    match replicate(&state, command).await {
+        Ok((_, response)) => Json(json!({ "data": response })),
+        Err(err) => err,
+    }
+    .into_response()
+}
+
+// get_key reads a key from the leader's state machine, using ReadIndex or the leader lease
+// for linearizability. Followers hand the request to the leader (see ForwardMode).
+pub async fn get_key(
+    State(state): State<state::AppState>,
+    headers: HeaderMap,
+    uri: Uri,
+    Path(key): Path<String>,
+    Query(options): Query<ReadOptions>,
+) -> Response {
+    if state.get_current_state() != NodeState::Leader {
+        return forward::to_leader(&state, &headers, Method::GET, &uri, None).await;
+    }
+
+    let read_index = match options.consistency {
+        Consistency::ReadIndex => read_index::read_index(&state).await,
+        Consistency::Lease => read_index::lease_read_index(&state).await,
+    };
+
+    match read_index {
+        Ok(index) => Json(json!({ "data": {
+            "key": key,
+            "value": state.read_key(&key),
+            "read_index": index,
+        }})),
+        Err(err) => Json(json!({ "error": err })),
+    }
+    .into_response()
+}
+
+// put_key sets a key through the Raft log, within a client session if one is given.
+// Followers hand the request to the leader.
+pub async fn put_key(
+    State(state): State<state::AppState>,
+    headers: HeaderMap,
+    uri: Uri,
+    Path(key): Path<String>,
+    Query(options): Query<WriteOptions>,
+    payload: extract::Json<ValuePayload>,
+) -> Response {
+    if state.get_current_state() != NodeState::Leader {
+        let body = json!({ "value": payload.value });
+        return forward::to_leader(&state, &headers, Method::PUT, &uri, Some(body)).await;
+    }
+
+    let command = Command::Put {
+        key: key.clone(),
+        value: payload.value.clone(),
+    };
+
+    write_key(&state, &key, &options, command).await
+}
+
+// delete_key removes a key through the Raft log, within a client session if one is given.
+// Followers hand the request to the leader.
+pub async fn delete_key(
+    State(state): State<state::AppState>,
+    headers: HeaderMap,
+    uri: Uri,
+    Path(key): Path<String>,
+    Query(options): Query<WriteOptions>,
+) -> Response {
+    if state.get_current_state() != NodeState::Leader {
+        return forward::to_leader(&state, &headers, Method::DELETE, &uri, None).await;
+    }
+
+    let command = Command::Delete { key: key.clone() };
+
+    write_key(&state, &key, &options, command).await
+}
+
+async fn write_key(
+    state: &state::AppState,
+    key: &str,
+    options: &WriteOptions,
+    command: Command,
+) -> Response {
+    let command = match with_session(options, command) {
+        Ok(command) => command,
+        Err(err) => return err.into_response(),
+    };
+
+    // Entries are replicated in single datagrams, so a larger one could never be sent.
+    let command_bytes = serde_json::to_vec(&command).map_or(0, |command| command.len());
+    if command_bytes > values::MAX_COMMAND_BYTES {
+        return Json(json!({ "error": format!(
+            "key and value take {} bytes, more than the {} a log entry may hold",
+            command_bytes,
+            values::MAX_COMMAND_BYTES
+        )}))
+        .into_response();
+    }
+
+    match replicate(state, command).await {
+        Ok((index, response)) => Json(json!({ "data": {
+            "key": key,
+            "index": index,
+            "result": response,
+        }})),
+        Err(err) => err,
+    }
+    .into_response()
+}
+
+// Fault injected for a peer, as set with PUT /faults/:peer. See raft_main_common::fault.
+#[derive(Debug, Deserialize)]
+pub struct FaultPayload {
+    #[serde(default)]
+    isolated: bool,
+    #[serde(default)]
+    drop_probability: f64,
+    #[serde(default)]
+    drop_messages: Vec<String>,
+}
+
+// Faults live in the XDP program, which drops the messages.
+fn check_faults_supported(state: &state::AppState) -> Result<(), Json<Value>> {
+    match state.transport {
+        TransportMode::Xdp => Ok(()),
+        TransportMode::Udp => Err(Json(
+            json!({ "error": "fault injection requires --transport xdp" }),
+        )),
+    }
+}
+
+// A peer's IP address, or "all" for the fault applying to every peer.
+fn parse_fault_peer(peer: &str) -> Result<u32, Json<Value>> {
+    if peer == "all" {
+        return Ok(FAULT_ALL_PEERS);
+    }
+    match ip_string_to_u32(peer) {
+        Ok(addr) if addr != FAULT_ALL_PEERS => Ok(addr),
+        _ => Err(Json(
+            json!({ "error": format!("invalid peer: {}, expected an IP address or \"all\"", peer) }),
+        )),
+    }
+}
+
+fn fault_to_json(addr: u32, fault: &Fault) -> Value {
+    let peer = match addr {
+        FAULT_ALL_PEERS => "all".to_string(),
+        _ => Ipv4Addr::from(addr).to_string(),
+    };
+    let drop_messages: Vec<&str> = FAULT_MESSAGE_NAMES
+        .iter()
+        .filter(|(_, message_type)| fault.drop_messages & message_type != 0)
+        .map(|(name, _)| *name)
+        .collect();
+
+    json!({
+        "peer": peer,
+        "isolated": fault.isolated != 0,
+        "drop_probability": fault.drop_ppm as f64 / FAULT_DROP_PPM_MAX as f64,
+        "drop_messages": drop_messages,
+    })
+}
+
+// list_faults returns the faults injected into the XDP program.
+pub async fn list_faults(State(state): State<state::AppState>) -> Json<Value> {
+    if let Err(err) = check_faults_supported(&state) {
+        return err;
+    }
+
+    let faults = state.faults.lock().unwrap();
+    let response: Vec<Value> = faults
+        .iter()
+        .filter_map(|entry| entry.ok())
+        .map(|(addr, fault)| fault_to_json(addr, &fault))
+        .collect();
+
+    Json(json!({ "data": response }))
+}
+
+// set_fault makes the XDP program drop Raft messages received from a peer: all of them
+// (isolated), each with a probability, or those of the given types.
+pub async fn set_fault(
+    State(state): State<state::AppState>,
+    Path(peer): Path<String>,
+    payload: extract::Json<FaultPayload>,
+) -> Json<Value> {
+    if let Err(err) = check_faults_supported(&state) {
+        return err;
+    }
+    let addr = match parse_fault_peer(&peer) {
+        Ok(addr) => addr,
+        Err(err) => return err,
+    };
+
+    if !(0.0..=1.0).contains(&payload.drop_probability) {
+        return Json(json!({ "error": "drop_probability must be between 0 and 1" }));
+    }
+    let mut drop_messages = 0;
+    for name in &payload.drop_messages {
+        match FAULT_MESSAGE_NAMES.iter().find(|(known, _)| known == name) {
+            Some((_, message_type)) => drop_messages |= message_type,
+            None => {
+                return Json(json!({ "error": format!("unknown message type: {}", name) }));
+            }
+        }
+    }
+
+    let fault = Fault {
+        isolated: payload.isolated as u32,
+        drop_ppm: (payload.drop_probability * FAULT_DROP_PPM_MAX as f64).round() as u32,
+        drop_messages,
+    };
+    if let Err(err) = state.faults.lock().unwrap().insert(addr, fault, 0) {
+        return Json(json!({ "error": err.to_string() }));
+    }
+
+    info!("Injected fault for {}: {:?}", peer, fault);
+    Json(json!({ "data": fault_to_json(addr, &fault) }))
+}
+
+// clear_fault removes the fault injected for a peer.
+pub async fn clear_fault(
+    State(state): State<state::AppState>,
+    Path(peer): Path<String>,
+) -> Json<Value> {
+    if let Err(err) = check_faults_supported(&state) {
+        return err;
+    }
+    let addr = match parse_fault_peer(&peer) {
+        Ok(addr) => addr,
+        Err(err) => return err,
+    };
+
+    // Clearing a peer without a fault is not an error.
+    let _ = state.faults.lock().unwrap().remove(&addr);
+    info!("Cleared fault for {}", peer);
+    Json(json!({ "data": "none" }))
+}
+
+// clear_faults removes all injected faults.
+pub async fn clear_faults(State(state): State<state::AppState>) -> Json<Value> {
+    if let Err(err) = check_faults_supported(&state) {
+        return err;
+    }
+
+    let mut faults = state.faults.lock().unwrap();
+    let addrs: Vec<u32> = faults.keys().filter_map(|key| key.ok()).collect();
+    for addr in &addrs {
+        let _ = faults.remove(addr);
+    }
+
+    info!("Cleared {} faults", addrs.len());
+    Json(json!({ "data": "none" }))
+}
@@ -0,0 +1,120 @@
+use crate::raft_log::Command;
+use crate::state_machine::StateMachine;
+use crate::values;
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+// Client sessions give commands exactly-once semantics. A client registers a session, whose ID
+// is the index of its RegisterClient entry, and numbers its commands from 1. A retried command
+// is not applied again; the response cached for its sequence number is returned instead.
+//
+// Sessions are created, used and expired by log entries, using timestamps chosen by the leader,
+// so all nodes agree on them. They are included in snapshots.
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Session {
+    last_sequence: u64,
+    last_response: Value,
+    last_active_ns: u64,
+}
+
+// Wraps a state machine, deduplicating commands submitted within client sessions.
+pub struct SessionStateMachine {
+    inner: Box<dyn StateMachine>,
+    sessions: HashMap<u64, Session>,
+}
+
+impl SessionStateMachine {
+    pub fn new(inner: Box<dyn StateMachine>) -> SessionStateMachine {
+        SessionStateMachine {
+            inner,
+            sessions: HashMap::new(),
+        }
+    }
+}
+
+impl StateMachine for SessionStateMachine {
+    fn apply(&mut self, index: u64, command: &Command) -> Value {
+        match command {
+            Command::RegisterClient { timestamp_ns } => {
+                self.sessions.insert(
+                    index,
+                    Session {
+                        last_sequence: 0,
+                        last_response: Value::Null,
+                        last_active_ns: *timestamp_ns,
+                    },
+                );
+                json!({ "client_id": index })
+            }
+            Command::Client {
+                client_id,
+                sequence,
+                timestamp_ns,
+                command,
+            } => {
+                let session = match self.sessions.get_mut(client_id) {
+                    Some(session) => session,
+                    None => return json!({ "error": "unknown or expired session" }),
+                };
+                session.last_active_ns = session.last_active_ns.max(*timestamp_ns);
+
+                if *sequence == session.last_sequence {
+                    return session.last_response.clone();
+                }
+                if *sequence < session.last_sequence {
+                    return json!({ "error": "sequence number already used" });
+                }
+
+                let response = self.inner.apply(index, command);
+                session.last_sequence = *sequence;
+                session.last_response = response.clone();
+                response
+            }
+            Command::ExpireSessions { timestamp_ns } => {
+                self.sessions.retain(|_, session| {
+                    session.last_active_ns + values::SESSION_TIMEOUT_NS >= *timestamp_ns
+                });
+                Value::Null
+            }
+            _ => self.inner.apply(index, command),
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<String> {
+        self.inner.get(key)
+    }
+
+    // Sessions are stored as length-prefixed JSON, followed by the wrapped state machine's snapshot.
+    fn snapshot(&self) -> Vec<u8> {
+        let sessions = serde_json::to_vec(&self.sessions).expect("Failed to serialize sessions");
+
+        let mut snapshot = (sessions.len() as u64).to_be_bytes().to_vec();
+        snapshot.extend_from_slice(&sessions);
+        snapshot.extend_from_slice(&self.inner.snapshot());
+        snapshot
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) -> Result<(), anyhow::Error> {
+        if snapshot.len() < 8 {
+            bail!("snapshot is truncated");
+        }
+        let (len, rest) = snapshot.split_at(8);
+        let len = u64::from_be_bytes(len.try_into()?);
+        if len > rest.len() as u64 {
+            bail!("snapshot is truncated");
+        }
+        let (sessions, inner) = rest.split_at(len as usize);
+
+        let sessions = serde_json::from_slice(sessions)?;
+        self.inner.restore(inner)?;
+        self.sessions = sessions;
+        Ok(())
+    }
+
+    fn entries(&self) -> Vec<(String, String)> {
+        self.inner.entries()
+    }
+}
@@ -0,0 +1,65 @@
+use crate::election::Election;
+use crate::replication;
+use crate::state;
+use log::{info, warn};
+use raft_main_common::NodeState;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tokio::signal::unix::{signal, SignalKind};
+
+// Wait for SIGINT (Ctrl-C) or SIGTERM.
+pub async fn wait_for_signal() -> Result<(), anyhow::Error> {
+    let mut sigterm = signal(SignalKind::terminate())?;
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => info!("SIGINT received, shutting down..."),
+        _ = sigterm.recv() => info!("SIGTERM received, shutting down..."),
+    }
+    Ok(())
+}
+
+// Flush the log, stop the Raft state machine threads, hand over leadership if this node is the
+// leader and leave the node in follower state, so pinned maps do not keep a stale role. Waits up
+// to `timeout` in all.
+//
+// The log is not persisted locally (see main), only on the followers: a leader flushes it by
+// waiting for every follower to acknowledge the entries proposed so far, so its successor has
+// them all. Pinned maps are written through and need no flushing.
+pub async fn drain(state: &state::AppState, fsm_threads: Vec<JoinHandle<()>>, timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+
+    if state.get_current_state() == NodeState::Leader {
+        let last_index = state.raft_log.lock().unwrap().last_index();
+        while !replication::replicated_to_all(state, last_index) {
+            if Instant::now() > deadline {
+                warn!(
+                    "Followers did not replicate the log up to index {} within {} ms.",
+                    last_index,
+                    timeout.as_millis()
+                );
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    state.begin_shutdown();
+
+    while !fsm_threads.iter().all(|thread| thread.is_finished()) {
+        if Instant::now() > deadline {
+            warn!(
+                "State machine did not stop within {} ms; stepping down anyway.",
+                timeout.as_millis()
+            );
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+
+    if state.get_current_state() == NodeState::Leader {
+        state.step_down();
+    } else {
+        state.become_follower();
+    }
+    state.reset_vote_data();
+}
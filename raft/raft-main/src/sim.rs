@@ -0,0 +1,657 @@
+// Deterministic simulation of a cluster, checking election safety: at most one leader per term.
+//
+// Each node runs the election state machine (fsm_*) against a virtual clock, an RNG seeded from
+// the history's seed and an in-memory network which loses, delays, reorders and duplicates
+// messages. Election messages are handled by the XDP program's handlers (see
+// raft_main_common::handler), against in-memory maps.
+// Nodes crash and restart, either with the state pinned in BPF maps, as with --pin, or with only
+// what is on disk (the vote ceiling and the log); a crashed node neither steps nor receives.
+//
+// Histories are reproducible from their seed:
+//
+//     SIM_SEEDS=100000 cargo test --release -p raft-main sim
+//     SIM_SEED=1234 cargo test -p raft-main sim
+
+use crate::election::{Clock, Election, Random, Storage, Transport};
+use crate::fsm_candidate;
+use crate::fsm_follower;
+use crate::fsm_leader;
+use crate::raft_log::Command;
+use crate::values::VOTE_CEILING_STEP;
+use raft_main_common::handler::{self, NodeMaps, VoteDecision};
+use raft_main_common::{
+    CurrentNode, HeartbeatAck, LeaderNode, LogState, NodeState, Vote, HEARTBEAT_REQUEST_PORT,
+    HEARTBEAT_RESPONSE_PORT, HEARTBEAT_WORDS, VOTE_REQUEST_PORT, VOTE_REQUEST_WORDS,
+    VOTE_RESPONSE_PORT_NO, VOTE_RESPONSE_PORT_YES,
+};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::Ipv4Addr;
+use std::ops::Range;
+use std::rc::Rc;
+
+const NODES: u32 = 3; // The eBPF program stores two peers.
+const HISTORY_NS: u64 = 10_000_000_000;
+// Shortest time between two steps of a node, whose loop otherwise spins.
+const TICK_NS: u64 = 1_000_000;
+const LATENCY_NS: Range<u64> = 50_000..1_000_000;
+// Delays of reordered messages, long enough to reach later elections.
+const REORDER_DELAY_NS: Range<u64> = 1_000_000..3_000_000_000;
+const CRASH_INTERVAL_NS: Range<u64> = 100_000_000..4_000_000_000;
+const CRASH_DURATION_NS: Range<u64> = 10_000_000..2_000_000_000;
+
+#[derive(Clone, Copy, Debug)]
+struct Message {
+    from: u32,
+    to: u32,
+    port: u16,
+    body: [u64; 3],
+}
+
+// A node, with BPF maps and the UDP socket replaced by memory.
+struct SimNode {
+    addr: u32,
+    clock: Rc<Cell<u64>>,
+    rng: Rc<RefCell<StdRng>>,
+    outbox: RefCell<Vec<Message>>,
+    // CURRENT_NODE, LEADER_NODE, VOTE_RESULTS, VOTE_TERMS, VOTED_TERM, VOTE_CEILING and
+    // HEARTBEAT_ACKS.
+    current_node: RefCell<CurrentNode>,
+    leader_node: RefCell<LeaderNode>,
+    vote_results: RefCell<HashMap<u32, u64>>,
+    vote_terms: RefCell<HashSet<u64>>,
+    voted_term: Cell<u64>,
+    // Also on disk.
+    vote_ceiling: Cell<u64>,
+    heartbeat_acks: RefCell<HashMap<u32, HeartbeatAck>>,
+    applied_leader_generation: Cell<u64>,
+    leader_timer_reset_ns: Cell<u64>,
+    // Terms of the log entries.
+    log: RefCell<Vec<u64>>,
+}
+
+impl SimNode {
+    fn new(addr: u32, peers: [u32; 2], clock: Rc<Cell<u64>>, rng: Rc<RefCell<StdRng>>) -> SimNode {
+        let now = clock.get();
+
+        SimNode {
+            addr,
+            clock,
+            rng,
+            outbox: RefCell::new(Vec::new()),
+            current_node: RefCell::new(CurrentNode {
+                state: NodeState::Follower,
+                peers,
+                term: 0,
+                vote: Vote {
+                    in_progress: false,
+                    started_ts: 0,
+                    ended_ts: 0,
+                    election_timeout: 0,
+                },
+            }),
+            leader_node: RefCell::new(LeaderNode {
+                last_seen: now,
+                source_addr_raw: 0,
+                term_id: 0,
+                generation: 0,
+            }),
+            vote_results: RefCell::new(HashMap::new()),
+            vote_terms: RefCell::new(HashSet::new()),
+            voted_term: Cell::new(0),
+            vote_ceiling: Cell::new(0),
+            heartbeat_acks: RefCell::new(HashMap::new()),
+            applied_leader_generation: Cell::new(0),
+            leader_timer_reset_ns: Cell::new(0),
+            log: RefCell::new(Vec::new()),
+        }
+    }
+
+    // One iteration of fsm_single_thread::shared_loop, without the simulated crashes.
+    // Returns the time to wait before the next step, in milliseconds.
+    fn step(&self) -> u64 {
+        self.apply_leader_heartbeats();
+        self.apply_later_terms();
+
+        match self.get_current_state() {
+            NodeState::Leader => fsm_leader::leader(self),
+            NodeState::Candidate => fsm_candidate::candidate(self),
+            NodeState::Follower => fsm_follower::follower(self),
+        }
+    }
+
+    // Userspace restarts; maps are restored as in AppState::initialise_node, or recreated from
+    // what is on disk as in AppState::restore_from_disk.
+    fn restart(&self, pinned: bool) {
+        self.leader_timer_reset_ns.set(0);
+        if pinned {
+            self.applied_leader_generation
+                .set(self.leader_node.borrow().generation);
+            return;
+        }
+
+        self.vote_results.borrow_mut().clear();
+        self.vote_terms.borrow_mut().clear();
+        self.heartbeat_acks.borrow_mut().clear();
+        self.voted_term.set(self.vote_ceiling.get());
+        let (_, last_term) = self.last_log_entry();
+        let mut node = self.current_node.borrow_mut();
+        node.state = NodeState::Follower;
+        node.term = last_term;
+        node.vote = Vote {
+            in_progress: false,
+            started_ts: 0,
+            ended_ts: 0,
+            election_timeout: 0,
+        };
+        *self.leader_node.borrow_mut() = LeaderNode {
+            last_seen: self.clock.get(),
+            source_addr_raw: 0,
+            term_id: 0,
+            generation: 0,
+        };
+        self.applied_leader_generation.set(0);
+    }
+
+    // Raise the vote ceiling above a term, as AppState::raise_vote_ceiling does.
+    fn raise_vote_ceiling(&self, term: u64) {
+        if self.vote_ceiling.get() < term {
+            self.vote_ceiling.set(term + VOTE_CEILING_STEP);
+        }
+    }
+
+    fn send(&self, to: u32, port: u16, body: [u64; 3]) {
+        self.outbox.borrow_mut().push(Message {
+            from: self.addr,
+            to,
+            port,
+            body,
+        });
+    }
+
+    fn send_to_peers(&self, port: u16, body: [u64; 3]) {
+        for peer in self.get_current_node().peers {
+            self.send(peer, port, body);
+        }
+    }
+
+    // Handle a message as the XDP program does.
+    fn receive(&self, message: Message) {
+        let maps = SimMaps(self);
+
+        match message.port {
+            VOTE_REQUEST_PORT => {
+                let mut decision = handler::handle_vote_request(&maps, &message.body);
+                // Passed to the userspace listener, which raises the ceiling first.
+                if decision == VoteDecision::NotDurable {
+                    self.raise_vote_ceiling(message.body[0]);
+                    decision = handler::handle_vote_request(&maps, &message.body);
+                }
+                if let Some(port) = decision.response_port() {
+                    self.send(message.from, port, message.body);
+                }
+            }
+            VOTE_RESPONSE_PORT_YES | VOTE_RESPONSE_PORT_NO => {
+                let granted = message.port == VOTE_RESPONSE_PORT_YES;
+                handler::handle_vote_response(&maps, message.from, granted, &message.body);
+            }
+            HEARTBEAT_REQUEST_PORT => {
+                if let Ok(response) = handler::handle_heartbeat(&maps, message.from, &message.body)
+                {
+                    self.send(message.from, HEARTBEAT_RESPONSE_PORT, response);
+                }
+            }
+            HEARTBEAT_RESPONSE_PORT => {
+                handler::handle_heartbeat_response(&maps, message.from, &message.body);
+            }
+            _ => {}
+        }
+    }
+}
+
+// The BPF maps of a node, as seen by the XDP program.
+struct SimMaps<'a>(&'a SimNode);
+
+impl NodeMaps for SimMaps<'_> {
+    fn now_ns(&self) -> u64 {
+        self.0.clock.get()
+    }
+
+    fn current_node(&self) -> Option<CurrentNode> {
+        Some(*self.0.current_node.borrow())
+    }
+
+    fn leader_node(&self) -> Option<LeaderNode> {
+        Some(*self.0.leader_node.borrow())
+    }
+
+    fn record_leader(&self, source_addr: u32, term: u64) -> Result<(), ()> {
+        let mut leader = self.0.leader_node.borrow_mut();
+        leader.last_seen = self.0.clock.get();
+        leader.source_addr_raw = source_addr;
+        leader.term_id = term;
+        leader.generation += 1;
+        Ok(())
+    }
+
+    fn expire_leader(&self) -> Result<(), ()> {
+        self.0.leader_node.borrow_mut().last_seen = 0;
+        Ok(())
+    }
+
+    fn log_state(&self) -> LogState {
+        let (last_index, last_term) = self.0.last_log_entry();
+        LogState {
+            last_index,
+            last_term,
+        }
+    }
+
+    fn voted_for_term(&self, term: u64) -> bool {
+        self.0.vote_terms.borrow().contains(&term)
+    }
+
+    fn insert_vote_term(&self, term: u64) -> Result<(), ()> {
+        match self.0.vote_terms.borrow_mut().insert(term) {
+            true => Ok(()),
+            false => Err(()),
+        }
+    }
+
+    fn voted_term(&self) -> u64 {
+        self.0.voted_term.get()
+    }
+
+    fn raise_voted_term(&self, term: u64) -> Result<(), ()> {
+        self.0.voted_term.set(self.0.voted_term.get().max(term));
+        Ok(())
+    }
+
+    fn vote_ceiling(&self) -> u64 {
+        self.0.vote_ceiling.get()
+    }
+
+    fn insert_vote_result(&self, source_addr: u32, granted: u64) -> Result<(), ()> {
+        self.0
+            .vote_results
+            .borrow_mut()
+            .insert(source_addr, granted);
+        Ok(())
+    }
+
+    fn heartbeat_ack(&self, source_addr: u32) -> Option<HeartbeatAck> {
+        self.0.heartbeat_acks.borrow().get(&source_addr).copied()
+    }
+
+    fn insert_heartbeat_ack(&self, source_addr: u32, ack: &HeartbeatAck) -> Result<(), ()> {
+        self.0.heartbeat_acks.borrow_mut().insert(source_addr, *ack);
+        Ok(())
+    }
+
+    // Heartbeat latency is not simulated.
+    fn heartbeat_sent_ns(&self, _source_addr: u32) -> Option<u64> {
+        None
+    }
+
+    fn insert_heartbeat_latency(&self, _source_addr: u32, _latency_ns: u64) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn increment_counter(&self, _index: u32) {}
+}
+
+impl Clock for SimNode {
+    fn now_ns(&self) -> u64 {
+        self.clock.get()
+    }
+}
+
+impl Random for SimNode {
+    fn gen_range(&self, range: Range<u64>) -> u64 {
+        self.rng.borrow_mut().gen_range(range)
+    }
+}
+
+impl Transport for SimNode {
+    fn local_addr(&self) -> u32 {
+        self.addr
+    }
+
+    fn send_vote_requests(&self, request: [u64; VOTE_REQUEST_WORDS]) {
+        self.send_to_peers(VOTE_REQUEST_PORT, request);
+    }
+
+    fn send_heartbeats(&self, heartbeat: [u64; HEARTBEAT_WORDS]) {
+        self.send_to_peers(HEARTBEAT_REQUEST_PORT, heartbeat);
+    }
+}
+
+impl Storage for SimNode {
+    fn get_current_node(&self) -> CurrentNode {
+        *self.current_node.borrow()
+    }
+
+    fn update_current_node(&self, update: impl FnOnce(&mut CurrentNode)) {
+        update(&mut self.current_node.borrow_mut());
+    }
+
+    fn get_leader(&self) -> LeaderNode {
+        *self.leader_node.borrow()
+    }
+
+    fn applied_leader_generation(&self) -> u64 {
+        self.applied_leader_generation.get()
+    }
+
+    fn set_applied_leader_generation(&self, generation: u64) {
+        self.applied_leader_generation.set(generation);
+    }
+
+    fn leader_timer_reset_ns(&self) -> u64 {
+        self.leader_timer_reset_ns.get()
+    }
+
+    fn set_leader_timer_reset_ns(&self, ns: u64) {
+        self.leader_timer_reset_ns.set(ns);
+    }
+
+    fn get_current_yes_votes_from_peers(&self) -> u64 {
+        self.vote_results.borrow().values().sum()
+    }
+
+    fn reset_vote_results(&self) {
+        for vote in self.vote_results.borrow_mut().values_mut() {
+            *vote = 0;
+        }
+    }
+
+    fn record_vote(&self, term: u64) -> bool {
+        self.raise_vote_ceiling(term);
+        if !self.vote_terms.borrow_mut().insert(term) {
+            return false;
+        }
+        self.voted_term.set(self.voted_term.get().max(term));
+        true
+    }
+
+    fn voted_term(&self) -> u64 {
+        self.voted_term.get()
+    }
+
+    fn heartbeat_response_term(&self) -> u64 {
+        let heartbeat_acks = self.heartbeat_acks.borrow();
+        heartbeat_acks
+            .values()
+            .map(|ack| ack.term)
+            .max()
+            .unwrap_or_default()
+    }
+
+    fn last_log_entry(&self) -> (u64, u64) {
+        let log = self.log.borrow();
+        (log.len() as u64, log.last().copied().unwrap_or_default())
+    }
+
+    fn append_entry(&self, _command: Command) {
+        if self.get_current_state() == NodeState::Leader {
+            self.log.borrow_mut().push(self.current_term_id());
+        }
+    }
+}
+
+// Outcome of a history which kept election safety.
+#[derive(Debug, Default)]
+struct Summary {
+    terms_with_leader: u64,
+}
+
+// A seeded cluster history.
+struct Simulation {
+    clock: Rc<Cell<u64>>,
+    rng: Rc<RefCell<StdRng>>,
+    nodes: Vec<SimNode>,
+    up: Vec<bool>,
+    // Whether a crashed node restarts with its pinned maps.
+    pinned: Vec<bool>,
+    next_step_ns: Vec<u64>,
+    // Messages in flight, by delivery time and send order.
+    in_flight: BTreeMap<(u64, u64), Message>,
+    sent: u64,
+    next_crash_ns: u64,
+    // Fault rates, drawn from the seed.
+    loss: f64,
+    reorder: f64,
+    duplicate: f64,
+    // Node which led each term.
+    leaders: HashMap<u64, u32>,
+    trace: Vec<String>,
+}
+
+impl Simulation {
+    fn new(seed: u64) -> Simulation {
+        let clock = Rc::new(Cell::new(0));
+        let rng = Rc::new(RefCell::new(StdRng::seed_from_u64(seed)));
+
+        let addrs: Vec<u32> = (1..=NODES)
+            .map(|i| u32::from(Ipv4Addr::new(10, 0, 0, i as u8)))
+            .collect();
+        let nodes: Vec<SimNode> = addrs
+            .iter()
+            .map(|addr| {
+                let peers: Vec<u32> = addrs.iter().copied().filter(|a| a != addr).collect();
+                SimNode::new(*addr, [peers[0], peers[1]], clock.clone(), rng.clone())
+            })
+            .collect();
+
+        let (loss, reorder, duplicate, next_crash_ns) = {
+            let mut rng = rng.borrow_mut();
+            (
+                rng.gen_range(0.0..0.3),
+                rng.gen_range(0.0..0.1),
+                rng.gen_range(0.0..0.05),
+                rng.gen_range(CRASH_INTERVAL_NS),
+            )
+        };
+
+        Simulation {
+            clock,
+            rng,
+            up: vec![true; nodes.len()],
+            pinned: vec![true; nodes.len()],
+            next_step_ns: vec![0; nodes.len()],
+            nodes,
+            in_flight: BTreeMap::new(),
+            sent: 0,
+            next_crash_ns,
+            loss,
+            reorder,
+            duplicate,
+            leaders: HashMap::new(),
+            trace: Vec::new(),
+        }
+    }
+
+    // Run the history until `end_ns`, failing with its trace if two nodes lead the same term.
+    fn run(mut self, end_ns: u64) -> Result<Summary, String> {
+        loop {
+            let next_delivery = self.in_flight.keys().next().map(|(at, _)| *at);
+            let next_step = self.next_step_ns.iter().min().copied();
+            let now = [next_delivery, next_step, Some(self.next_crash_ns)]
+                .into_iter()
+                .flatten()
+                .min()
+                .unwrap();
+            if now > end_ns {
+                break;
+            }
+            self.clock.set(now);
+
+            if now == self.next_crash_ns {
+                self.crash();
+            }
+
+            while let Some(entry) = self.in_flight.first_entry() {
+                if entry.key().0 > now {
+                    break;
+                }
+                let message = entry.remove();
+                let i = self.index_of(message.to);
+                if self.up[i] {
+                    self.nodes[i].receive(message);
+                    self.flush(i);
+                }
+            }
+
+            for i in 0..self.nodes.len() {
+                if self.next_step_ns[i] > now {
+                    continue;
+                }
+                if !self.up[i] {
+                    self.up[i] = true;
+                    self.nodes[i].restart(self.pinned[i]);
+                    self.log(format!(
+                        "{} restarts {}",
+                        Ipv4Addr::from(self.nodes[i].addr),
+                        if self.pinned[i] {
+                            "with pinned maps"
+                        } else {
+                            "from disk"
+                        }
+                    ));
+                }
+
+                let delay_ns = self.nodes[i].step() * 1_000_000;
+                self.next_step_ns[i] = now + delay_ns.max(TICK_NS);
+                self.flush(i);
+                self.check_election_safety(i)?;
+            }
+        }
+
+        Ok(Summary {
+            terms_with_leader: self.leaders.len() as u64,
+        })
+    }
+
+    fn index_of(&self, addr: u32) -> usize {
+        self.nodes
+            .iter()
+            .position(|node| node.addr == addr)
+            .unwrap()
+    }
+
+    // Crash a random node, which restarts after a while.
+    fn crash(&mut self) {
+        let now = self.clock.get();
+        let mut rng = self.rng.borrow_mut();
+        let i = rng.gen_range(0..self.nodes.len());
+        let restart_ns = now + rng.gen_range(CRASH_DURATION_NS);
+        self.next_crash_ns = restart_ns + rng.gen_range(CRASH_INTERVAL_NS);
+        let pinned = rng.gen_bool(0.5);
+        drop(rng);
+
+        if self.up[i] {
+            self.up[i] = false;
+            self.pinned[i] = pinned;
+            self.next_step_ns[i] = restart_ns;
+            let node = self.nodes[i].get_current_node();
+            self.log(format!(
+                "{} crashes as {:?} of term {}",
+                Ipv4Addr::from(self.nodes[i].addr),
+                node.state,
+                node.term
+            ));
+        }
+    }
+
+    // Put the messages sent by node `i` on the network.
+    fn flush(&mut self, i: usize) {
+        let now = self.clock.get();
+        let outbox: Vec<Message> = self.nodes[i].outbox.borrow_mut().drain(..).collect();
+        let mut rng = self.rng.borrow_mut();
+
+        for message in outbox {
+            if rng.gen_bool(self.loss) {
+                continue;
+            }
+            let copies = if rng.gen_bool(self.duplicate) { 2 } else { 1 };
+
+            for _ in 0..copies {
+                let delay_ns = if rng.gen_bool(self.reorder) {
+                    rng.gen_range(REORDER_DELAY_NS)
+                } else {
+                    rng.gen_range(LATENCY_NS)
+                };
+                self.in_flight.insert((now + delay_ns, self.sent), message);
+                self.sent += 1;
+            }
+        }
+    }
+
+    fn check_election_safety(&mut self, i: usize) -> Result<(), String> {
+        let node = &self.nodes[i];
+        if node.get_current_state() != NodeState::Leader {
+            return Ok(());
+        }
+
+        let term = node.current_term_id();
+        let addr = node.addr;
+        match self.leaders.get(&term) {
+            None => {
+                self.leaders.insert(term, addr);
+                self.log(format!("{} leads term {}", Ipv4Addr::from(addr), term));
+                Ok(())
+            }
+            Some(leader) if *leader == addr => Ok(()),
+            Some(leader) => {
+                let leader = *leader;
+                self.log(format!(
+                    "{} and {} both lead term {}",
+                    Ipv4Addr::from(leader),
+                    Ipv4Addr::from(addr),
+                    term
+                ));
+                Err(self.trace.join("\n"))
+            }
+        }
+    }
+
+    fn log(&mut self, event: String) {
+        self.trace.push(format!(
+            "[{:>5} ms] {}",
+            self.clock.get() / 1_000_000,
+            event
+        ));
+    }
+}
+
+fn env_u64(name: &str) -> Option<u64> {
+    std::env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+}
+
+#[test]
+fn election_safety() {
+    let seeds = match env_u64("SIM_SEED") {
+        Some(seed) => seed..seed + 1,
+        None => 0..env_u64("SIM_SEEDS").unwrap_or(200),
+    };
+
+    let mut elections = 0;
+    for seed in seeds.clone() {
+        match Simulation::new(seed).run(HISTORY_NS) {
+            Ok(summary) => elections += summary.terms_with_leader,
+            Err(trace) => panic!("election safety violated with seed {}:\n{}", seed, trace),
+        }
+    }
+
+    // Histories where nobody is ever elected would pass trivially.
+    assert!(
+        elections >= seeds.end - seeds.start,
+        "only {} leaders elected in {} histories",
+        elections,
+        seeds.end - seeds.start
+    );
+}
@@ -0,0 +1,262 @@
+use crate::election::Election;
+use crate::replication::{self, AppendEntriesResponse};
+use crate::state;
+use crate::values;
+use anyhow::{anyhow, bail};
+use log::{info, warn};
+use raft_main_common::auth::{message_mac_bytes, MAC_LEN, SEQ_LEN};
+use raft_main_common::{NodeState, SNAPSHOT_PORT};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Snapshots are sent to followers whose next entry has been compacted, over a TCP connection
+// to SNAPSHOT_PORT handled in userspace. The follower answers with an AppendEntriesResponse
+// on the same connection.
+//
+// Frames are [header length (u32 BE)][JSON header][data length (u64 BE)][data], followed by a
+// sequence number and a MAC over the whole frame if authentication is enabled. The sequence
+// number is checked against the sender's high-water mark and the time the frame started to
+// arrive, so a slow transfer is not taken for a replay.
+
+// State machine snapshot, including all entries up to `index`.
+#[derive(Debug, Default)]
+pub struct Snapshot {
+    pub index: u64,
+    pub term: u64,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InstallSnapshot {
+    pub term: u64,
+    pub last_included_index: u64,
+    pub last_included_term: u64,
+}
+
+const MAX_HEADER_LEN: u32 = 64 * 1024;
+
+fn write_frame<T: Serialize>(
+    state: &state::AppState,
+    stream: &mut TcpStream,
+    header: &T,
+    data: &[u8],
+) -> Result<(), anyhow::Error> {
+    let header = serde_json::to_vec(header)?;
+
+    let mut frame = Vec::with_capacity(4 + header.len() + 8 + data.len() + SEQ_LEN + MAC_LEN);
+    frame.extend_from_slice(&(header.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&header);
+    frame.extend_from_slice(&(data.len() as u64).to_be_bytes());
+    frame.extend_from_slice(data);
+
+    if state.mac_key.is_enabled() {
+        frame.extend_from_slice(&state.sequence_number().to_be_bytes());
+        let mac = message_mac_bytes(&state.mac_key, state.local_addr, SNAPSHOT_PORT, &frame);
+        frame.extend_from_slice(&mac.to_be_bytes());
+    }
+
+    stream.write_all(&frame)?;
+    Ok(())
+}
+
+fn read_frame<T: DeserializeOwned>(
+    state: &state::AppState,
+    stream: &mut TcpStream,
+    source_addr: u32,
+) -> Result<(T, Vec<u8>), anyhow::Error> {
+    let mut frame = Vec::new();
+
+    let mut header_len = [0u8; 4];
+    stream.read_exact(&mut header_len)?;
+    let started = state.sequence_number();
+    let header_len_value = u32::from_be_bytes(header_len);
+    if header_len_value > MAX_HEADER_LEN {
+        bail!("snapshot header of {} bytes is too large", header_len_value);
+    }
+    let mut header = vec![0u8; header_len_value as usize];
+    stream.read_exact(&mut header)?;
+
+    let mut data_len = [0u8; 8];
+    stream.read_exact(&mut data_len)?;
+    let data_len_value = u64::from_be_bytes(data_len);
+    if data_len_value > values::MAX_SNAPSHOT_BYTES {
+        bail!("snapshot of {} bytes is too large", data_len_value);
+    }
+    let mut data = vec![0u8; data_len_value as usize];
+    stream.read_exact(&mut data)?;
+
+    if state.mac_key.is_enabled() {
+        frame.extend_from_slice(&header_len);
+        frame.extend_from_slice(&header);
+        frame.extend_from_slice(&data_len);
+        frame.extend_from_slice(&data);
+
+        let mut seq = [0u8; SEQ_LEN];
+        stream.read_exact(&mut seq)?;
+        frame.extend_from_slice(&seq);
+
+        let mut mac = [0u8; MAC_LEN];
+        stream.read_exact(&mut mac)?;
+        let expected = message_mac_bytes(&state.mac_key, source_addr, SNAPSHOT_PORT, &frame);
+        if mac != expected.to_be_bytes() {
+            bail!("invalid MAC");
+        }
+
+        let seq = u64::from_be_bytes(seq);
+        if !state.accept_sequence_number(source_addr, SNAPSHOT_PORT, seq, started) {
+            bail!("replayed or stale frame");
+        }
+    }
+
+    Ok((serde_json::from_slice(&header)?, data))
+}
+
+// Send the latest snapshot to a follower and handle its response like an AppendEntries response.
+pub fn send_snapshot(state: &state::AppState, follower: u32) {
+    let snapshot: Arc<Snapshot> = state.snapshot.lock().unwrap().clone();
+    let request = InstallSnapshot {
+        term: state.current_term_id(),
+        last_included_index: snapshot.index,
+        last_included_term: snapshot.term,
+    };
+
+    info!(
+        "Sending snapshot up to index {} ({} bytes) to {}",
+        snapshot.index,
+        snapshot.data.len(),
+        Ipv4Addr::from(follower)
+    );
+
+    let result = (|| -> Result<AppendEntriesResponse, anyhow::Error> {
+        let timeout = Duration::from_millis(values::SNAPSHOT_TIMEOUT_MS);
+        let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::from(follower), SNAPSHOT_PORT));
+        let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+
+        write_frame(state, &mut stream, &request, &snapshot.data)?;
+        let (response, _) = read_frame(state, &mut stream, follower)?;
+        Ok(response)
+    })();
+
+    match result {
+        Ok(response) => replication::handle_append_entries_response(state, follower, response),
+        Err(err) => warn!(
+            "Failed to send snapshot to {}: {}",
+            Ipv4Addr::from(follower),
+            err
+        ),
+    }
+}
+
+// Accept snapshots from the leader. The listener must be non-blocking, so shutdown is noticed.
+pub fn listener(state: &state::AppState, listener: &TcpListener) {
+    while !state.is_shutting_down() {
+        match listener.accept() {
+            Ok((stream, source)) => {
+                if let Err(err) = receive_snapshot(state, stream, source) {
+                    warn!("Failed to receive snapshot from {}: {}", source, err);
+                }
+            }
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                std::thread::sleep(Duration::from_millis(100));
+            }
+            Err(err) => warn!("Failed to accept snapshot connection: {}", err),
+        }
+    }
+}
+
+fn receive_snapshot(
+    state: &state::AppState,
+    mut stream: TcpStream,
+    source: SocketAddr,
+) -> Result<(), anyhow::Error> {
+    let source_addr = match source {
+        SocketAddr::V4(addr) => u32::from(*addr.ip()),
+        SocketAddr::V6(_) => bail!("IPv6 peers are not supported"),
+    };
+
+    if !state.get_raft_peers().contains(&source_addr) {
+        bail!("not a member of the cluster");
+    }
+
+    let timeout = Duration::from_millis(values::SNAPSHOT_TIMEOUT_MS);
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let (request, data): (InstallSnapshot, Vec<u8>) = read_frame(state, &mut stream, source_addr)?;
+    let response = handle_install_snapshot(state, request, data)?;
+    write_frame(state, &mut stream, &response, &[])
+}
+
+fn handle_install_snapshot(
+    state: &state::AppState,
+    request: InstallSnapshot,
+    data: Vec<u8>,
+) -> Result<AppendEntriesResponse, anyhow::Error> {
+    let current_term = replication::accepted_term(state);
+
+    // Same rules as for AppendEntries.
+    if request.term < current_term
+        || (request.term == current_term && state.get_current_state() == NodeState::Leader)
+    {
+        return Ok(AppendEntriesResponse {
+            term: current_term,
+            success: false,
+            match_index: 0,
+        });
+    }
+
+    let mut raft_log = state.raft_log.lock().unwrap();
+
+    state.persist_term(request.term)?;
+
+    // A snapshot up to a committed index adds nothing; the entries before it match the leader's.
+    if request.last_included_index > raft_log.commit_index {
+        let snapshot = Snapshot {
+            index: request.last_included_index,
+            term: request.last_included_term,
+            data,
+        };
+
+        // Written to disk with the entries install_snapshot keeps, before it is restored.
+        let kept_entries = if raft_log.matches(snapshot.index, snapshot.term) {
+            raft_log.entries_from(snapshot.index + 1, usize::MAX)
+        } else {
+            Vec::new()
+        };
+        state
+            .persistence
+            .lock()
+            .unwrap()
+            .save_snapshot(&snapshot, &kept_entries)?;
+
+        let mut state_machine = state.state_machine.lock().unwrap();
+        state_machine
+            .restore(&snapshot.data)
+            .map_err(|err| anyhow!("failed to restore snapshot: {}", err))?;
+        raft_log.install_snapshot(snapshot.index, snapshot.term);
+        state.rebuild_kv_mirror(state_machine.entries(), snapshot.term);
+
+        *state.snapshot.lock().unwrap() = Arc::new(snapshot);
+
+        info!(
+            "Installed snapshot up to index {} from the leader",
+            request.last_included_index
+        );
+    }
+    drop(raft_log);
+
+    state.publish_log_state();
+
+    Ok(AppendEntriesResponse {
+        term: request.term,
+        success: true,
+        match_index: request.last_included_index,
+    })
+}
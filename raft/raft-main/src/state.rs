@@ -1,24 +1,94 @@
+use crate::election::{Clock, Election, Random, Storage, Transport};
+use crate::forward::ForwardMode;
+use crate::fsm_single_thread::ThreadModel;
+use crate::helpers::get_current_clock_ns;
 use crate::helpers::ip_string_to_u32;
-use crate::helpers::{self, get_current_clock_ns};
+use crate::maps::{Array, HashMap, MapError, PerCpuArray, BPF_NOEXIST};
+use crate::persistence::{Persistence, Restored};
+use crate::raft_log::{Command, LogEntry, RaftLog};
+use crate::replication;
+use crate::snapshot::Snapshot;
+use crate::state_machine::StateMachine;
+use crate::udp::TransportMode;
 use crate::values;
-use aya::{maps::Array, maps::HashMap, maps::MapData};
-use local_ip_address::local_ip;
-use log::info;
+use crate::xdp::XdpMode;
+use log::{info, warn};
+use raft_main_common::auth::{is_fresh, message_mac, replay_window_key, MacKey, MAC_LEN, SEQ_LEN};
+use raft_main_common::fault::Fault;
+use raft_main_common::kv::{KvKey, KvMirrorState, KvValue};
 use raft_main_common::{
-    CurrentNode, LeaderNode, NodeState, Vote, HEARTBEAT_REQUEST_PORT, VOTE_REQUEST_PORT,
+    CurrentNode, HeartbeatAck, LeaderNode, LogState, NodeState, Vote, COUNTER_REPLAYS,
+    HEARTBEAT_REQUEST_PORT, HEARTBEAT_WORDS, LEADER_LEASE_NS, LEADER_STEP_DOWN_PORT,
+    LEASE_FOLLOWER_ACKS, TERM_LEN, VOTE_REQUEST_PORT, VOTE_REQUEST_WORDS,
 };
+use rand::{thread_rng, Rng};
 use rayon::prelude::*;
+use serde_json::Value;
+use std::collections::HashSet;
 use std::env;
 use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
-use std::sync::{Arc, Mutex, RwLock};
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::time::Duration;
+
+// Outcome of an entry proposed by this node as leader.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Outcome {
+    Pending,
+    Applied,
+    // Another entry was applied in its place after a change of leader.
+    Replaced,
+    // Applied from a snapshot installed by a later leader, which may or may not include it.
+    Unknown,
+}
+
+// Term and response of the entries applied at indices awaited by clients.
+pub type Responses = std::collections::HashMap<u64, Option<(u64, Value)>>;
+
+// Highest sequence number seen from each sender on each port handled in userspace
+// (replication and snapshots), keyed as REPLAY_WINDOWS in eBPF.
+pub type ReplayWindows = std::collections::HashMap<u64, u64>;
 
 pub struct AppState {
-    pub followers: Arc<Mutex<HashMap<MapData, u32, u64>>>,
-    pub heartbeat_latency: Arc<Mutex<HashMap<MapData, u32, u64>>>,
-    pub voting_results: Arc<RwLock<HashMap<MapData, u32, u64>>>,
-    pub leader_node: Arc<RwLock<Array<MapData, LeaderNode>>>,
-    pub current_node: Arc<RwLock<Array<MapData, CurrentNode>>>,
+    pub followers: Arc<Mutex<HashMap<u32, u64>>>,
+    pub heartbeat_latency: Arc<Mutex<HashMap<u32, u64>>>,
+    pub voting_results: Arc<RwLock<HashMap<u32, u64>>>,
+    pub vote_terms: Arc<Mutex<HashMap<u64, u8>>>,
+    pub voted_term: Arc<Mutex<Array<u64>>>,
+    pub vote_ceiling: Arc<Mutex<Array<u64>>>,
+    pub leader_node: Arc<RwLock<Array<LeaderNode>>>,
+    pub current_node: Arc<RwLock<Array<CurrentNode>>>,
+    pub members: Arc<Mutex<HashMap<u32, u8>>>,
+    pub counters: Arc<Mutex<PerCpuArray<u64>>>,
     pub udp_socket: Arc<Mutex<UdpSocket>>,
+    pub transport: TransportMode,
+    pub xdp_mode: XdpMode,
+    pub forward_mode: ForwardMode,
+    pub thread_model: ThreadModel,
+    pub mac_key: MacKey,
+    pub local_addr: u32,
+    pub sequence_base: u64,
+    pub applied_leader_generation: Arc<AtomicU64>,
+    pub leader_timer_reset_ns: Arc<AtomicU64>,
+    pub shutting_down: Arc<AtomicBool>,
+    // Notified when the node changes state, for state machine threads waiting for their state.
+    pub state_changed: Arc<(Mutex<()>, Condvar)>,
+    pub raft_log: Arc<Mutex<RaftLog>>,
+    pub log_state: Arc<Mutex<Array<LogState>>>,
+    pub replication: Arc<Mutex<replication::Progress>>,
+    pub state_machine: Arc<Mutex<Box<dyn StateMachine>>>,
+    pub kv_mirror: Arc<Mutex<HashMap<KvKey, KvValue>>>,
+    pub kv_mirror_state: Arc<Mutex<Array<KvMirrorState>>>,
+    // Keys whose applied value could not be mirrored; the mirror is complete while empty.
+    pub kv_mirror_missing: Arc<Mutex<HashSet<KvKey>>>,
+    pub heartbeat_acks: Arc<Mutex<HashMap<u32, HeartbeatAck>>>,
+    pub faults: Arc<Mutex<HashMap<u32, Fault>>>,
+    pub snapshot: Arc<Mutex<Arc<Snapshot>>>,
+    // Taken after raft_log when both are needed.
+    pub persistence: Arc<Mutex<Persistence>>,
+    pub responses: Arc<Mutex<Responses>>,
+    pub replay_windows: Arc<Mutex<ReplayWindows>>,
 }
 
 // Clone here makes a copy of the Arc pointer.
@@ -29,24 +99,51 @@ impl Clone for AppState {
             followers: Arc::clone(&self.followers),
             heartbeat_latency: Arc::clone(&self.heartbeat_latency),
             voting_results: Arc::clone(&self.voting_results),
+            vote_terms: Arc::clone(&self.vote_terms),
+            voted_term: Arc::clone(&self.voted_term),
+            vote_ceiling: Arc::clone(&self.vote_ceiling),
             current_node: Arc::clone(&self.current_node),
             leader_node: Arc::clone(&self.leader_node),
+            members: Arc::clone(&self.members),
+            counters: Arc::clone(&self.counters),
             udp_socket: Arc::clone(&self.udp_socket),
+            transport: self.transport,
+            xdp_mode: self.xdp_mode,
+            forward_mode: self.forward_mode,
+            thread_model: self.thread_model,
+            mac_key: self.mac_key,
+            local_addr: self.local_addr,
+            sequence_base: self.sequence_base,
+            applied_leader_generation: Arc::clone(&self.applied_leader_generation),
+            leader_timer_reset_ns: Arc::clone(&self.leader_timer_reset_ns),
+            shutting_down: Arc::clone(&self.shutting_down),
+            state_changed: Arc::clone(&self.state_changed),
+            raft_log: Arc::clone(&self.raft_log),
+            log_state: Arc::clone(&self.log_state),
+            replication: Arc::clone(&self.replication),
+            state_machine: Arc::clone(&self.state_machine),
+            kv_mirror: Arc::clone(&self.kv_mirror),
+            kv_mirror_state: Arc::clone(&self.kv_mirror_state),
+            kv_mirror_missing: Arc::clone(&self.kv_mirror_missing),
+            heartbeat_acks: Arc::clone(&self.heartbeat_acks),
+            faults: Arc::clone(&self.faults),
+            snapshot: Arc::clone(&self.snapshot),
+            persistence: Arc::clone(&self.persistence),
+            responses: Arc::clone(&self.responses),
+            replay_windows: Arc::clone(&self.replay_windows),
         }
     }
 }
 
 impl AppState {
     // TODO: This function is very ugly.
-    pub fn initialise_node(&self) {
+    // When `restore` is set, term, role and leader data pinned by a previous run are kept.
+    pub fn initialise_node(&self, restore: bool) {
         let mut peer_ip_addresses: Vec<u32> = Vec::new();
         let mut peer_ip_addresses_array: [u32; 2] = [0; 2]; // eBPF does not support vectors.
 
         match env::var("PEERS") {
             Ok(peers) => {
-                let local_ip = local_ip().unwrap(); // Get local IP address
-                let local_ip_u32 = ip_string_to_u32(&local_ip.to_string()).unwrap_or_default();
-
                 let ip_list_from_env: Vec<&str> = peers.split(',').collect();
 
                 info!(
@@ -59,7 +156,7 @@ impl AppState {
 
                     // Skip adding current IP address to peer list.
                     // This allows passing the same IP address list to all Raft nodes.
-                    if local_ip_u32 == ip_address {
+                    if self.local_addr == ip_address {
                         continue;
                     }
 
@@ -75,6 +172,33 @@ impl AppState {
 
         info!("Added {} IPs to the hosts...", peer_ip_addresses.len());
 
+        // Only peers are allowed to send Raft traffic to this node.
+        let mut members = self.members.lock().unwrap();
+        for ip in peer_ip_addresses.iter() {
+            if let Err(err) = members.insert(ip, 1, 0) {
+                warn!(
+                    "Failed to add {} to MEMBERS, its messages will be dropped: {}",
+                    Ipv4Addr::from(*ip),
+                    err
+                );
+            }
+        }
+
+        if restore {
+            self.update_current_node(|node| node.peers = peer_ip_addresses_array);
+
+            let leader = self.get_leader();
+            self.applied_leader_generation
+                .store(leader.generation, Ordering::SeqCst);
+
+            let node = self.get_current_node();
+            info!(
+                "Restored pinned node state: {:?} with term {}.",
+                node.state, node.term
+            );
+            return;
+        }
+
         // Initialise node
         let mut current_node = self.current_node.write().unwrap();
         match current_node.set(
@@ -104,6 +228,7 @@ impl AppState {
                 last_seen: get_current_clock_ns(),
                 source_addr_raw: 0,
                 term_id: 0,
+                generation: 0,
             },
             0,
         ) {
@@ -112,212 +237,542 @@ impl AppState {
         };
     }
 
-    // Get current node data.
-    fn get_current_node(&self) -> CurrentNode {
-        let current_node = self.current_node.read().unwrap();
+    // Insert heartbeat timestamp into FOLLOWERS map when sending HEARTBEAT_REQUEST_PORT.
+    fn insert_heartbeat_timestamp(&self, ip: u32, ts: u64) {
+        let follower_data = self.followers.clone();
+        let mut followers = follower_data.lock().unwrap();
 
-        let node: CurrentNode = match current_node.get(&0, 0) {
-            Ok(x) => x,
+        match followers.insert(ip, ts, 0) {
+            Ok(()) => {}
             Err(_err) => todo!(),
-        };
+        }
+    }
 
-        node
+    // Get sum of a per-CPU counter maintained by the eBPF program.
+    pub fn get_counter(&self, index: u32) -> u64 {
+        let counters = self.counters.lock().unwrap();
+
+        match counters.get(&index, 0) {
+            Ok(values) => values.iter().sum(),
+            Err(_err) => 0,
+        }
     }
 
-    // Update current node values.
-    fn update_current_node(&self, node: CurrentNode) {
-        let mut node_data = self.current_node.write().unwrap();
+    // Get the sequence number for a message sent now.
+    pub fn sequence_number(&self) -> u64 {
+        self.sequence_base + get_current_clock_ns()
+    }
 
-        match node_data.set(0, node, 0) {
-            Ok(x) => x,
-            Err(_err) => todo!(),
-        };
+    // Check the sequence number of an authentic message from `source_addr` to `port` against
+    // the sender's high-water mark and advance it. `now` is this node's sequence number when
+    // the message started to arrive.
+    pub fn accept_sequence_number(&self, source_addr: u32, port: u16, seq: u64, now: u64) -> bool {
+        let mut replay_windows = self.replay_windows.lock().unwrap();
+        let highest_seen = replay_windows
+            .entry(replay_window_key(source_addr, port))
+            .or_default();
+
+        if !is_fresh(seq, *highest_seen, now) {
+            self.counters.lock().unwrap().increment(COUNTER_REPLAYS);
+            return false;
+        }
+
+        *highest_seen = seq;
+        true
     }
 
-    // Insert heartbeat timestamp into FOLLOWERS map when sending HEARTBEAT_REQUEST_PORT.
-    fn insert_heartbeat_timestamp(&self, ip: u32, ts: u64) {
-        let follower_data = self.followers.clone();
-        let mut followers = follower_data.lock().unwrap();
+    // Get current term number, represented in bytes and followed by a sequence number and MAC
+    // if authentication is enabled.
+    fn current_term_id_bytes(&self, port: u16) -> Vec<u8> {
+        self.message_bytes(port, &[self.current_term_id()])
+    }
 
-        match followers.insert(ip, ts, 0) {
-            Ok(()) => {}
-            Err(_err) => todo!(),
+    // Get message body (u64 words), represented in bytes and followed by a sequence number and MAC
+    // if authentication is enabled.
+    fn message_bytes<const N: usize>(&self, port: u16, body: &[u64; N]) -> Vec<u8> {
+        // u64 needs 8 bytes
+        let mut buffer = Vec::with_capacity(N * TERM_LEN + SEQ_LEN + MAC_LEN);
+        for word in body {
+            buffer.extend_from_slice(&word.to_be_bytes());
         }
+
+        if self.mac_key.is_enabled() {
+            let seq = self.sequence_number();
+            let mac = message_mac(&self.mac_key, self.local_addr, port, body, seq);
+            buffer.extend_from_slice(&seq.to_be_bytes());
+            buffer.extend_from_slice(&mac.to_be_bytes());
+        }
+
+        buffer
     }
 
-    // Get current node state.
-    pub fn get_current_state(&self) -> NodeState {
+    // Get Raft peer IPs.
+    pub fn get_raft_peers(&self) -> [u32; 2] {
         let node = self.get_current_node();
-        node.state
+        node.peers
     }
 
-    // Check when leader has last communicated.
-    // Data is populated by eBPF program when receiving heartbeat requests on HEARTBEAT_REQUEST_PORT.
-    pub fn leader_last_seen(&self) -> u64 {
-        let leader = self.leader_node.read().unwrap();
+    // Relinquish leadership: every peer is told to start an election right away instead of
+    // waiting for LEADER_COMMUNICATION_TIMEOUT_MS, which also lifts its vote guard for the
+    // others' candidates. This node becomes a follower first, so it votes for them too.
+    pub fn step_down(&self) {
+        self.become_follower();
 
-        let current_leader: LeaderNode = match leader.get(&0, 0) {
-            Ok(x) => x,
-            Err(_err) => todo!(),
-        };
+        let buffer = self.current_term_id_bytes(LEADER_STEP_DOWN_PORT);
+        let udp_socket_data = self.udp_socket.clone();
+        let socket = udp_socket_data.lock().unwrap();
+
+        for ip in self.get_raft_peers().into_iter().filter(|ip| *ip != 0) {
+            info!(
+                "Stepping down as leader of term {}; notifying {}",
+                self.current_term_id(),
+                Ipv4Addr::from(ip)
+            );
+            let dest_socket = SocketAddr::new(Ipv4Addr::from(ip).into(), LEADER_STEP_DOWN_PORT);
+            if let Err(err) = socket.send_to(&buffer, dest_socket) {
+                warn!(
+                    "Failed to notify {} about stepping down: {}",
+                    Ipv4Addr::from(ip),
+                    err
+                );
+            }
+        }
+    }
+
+    // Append a command to the log if this node is the leader.
+    // Returns the index and term of the new entry; it is applied once committed.
+    pub fn propose(&self, command: Command) -> Option<(u64, u64)> {
+        if self.get_current_state() != NodeState::Leader {
+            return None;
+        }
 
-        helpers::get_current_clock_ms() - (current_leader.last_seen / 1_000_000)
+        let term = self.current_term_id();
+        let index = self.append_to_log(&mut self.raft_log.lock().unwrap(), term, command)?;
+        self.publish_log_state();
+        Some((index, term))
     }
 
-    // When simulating crash, update leader last seen to current timestamp to avoid
-    // becoming the first node detecting absence of leader and winning the election.
-    pub fn update_leader_last_seen_time(&self) {
-        let mut leader: std::sync::RwLockWriteGuard<'_, Array<MapData, LeaderNode>> =
-            self.leader_node.write().unwrap();
+    // Propose a command for a client, which collects its response with `take_response` once applied.
+    pub fn submit(&self, command: Command) -> Option<(u64, u64)> {
+        if self.get_current_state() != NodeState::Leader {
+            return None;
+        }
 
-        match leader.set(
-            0,
-            LeaderNode {
-                last_seen: get_current_clock_ns(),
-                term_id: 0,
-                source_addr_raw: 0,
-            },
-            0,
-        ) {
-            Ok(x) => x,
-            Err(_err) => todo!(),
+        let term = self.current_term_id();
+        let mut raft_log = self.raft_log.lock().unwrap();
+        let index = self.append_to_log(&mut raft_log, term, command)?;
+        self.responses.lock().unwrap().insert(index, None);
+        drop(raft_log);
+
+        self.publish_log_state();
+        Some((index, term))
+    }
+
+    // Append an entry of this node's term to the log once it is on disk, and return its index.
+    fn append_to_log(&self, raft_log: &mut RaftLog, term: u64, command: Command) -> Option<u64> {
+        let entry = LogEntry {
+            index: raft_log.last_index() + 1,
+            term,
+            command,
         };
+
+        let mut persistence = self.persistence.lock().unwrap();
+        if let Err(err) = persistence.append(std::slice::from_ref(&entry)) {
+            warn!("Failed to write entry {} to disk: {}", entry.index, err);
+            return None;
+        }
+        Some(raft_log.append(entry.term, entry.command))
     }
 
-    // Get curent node term.
-    pub fn current_term_id(&self) -> u64 {
-        self.get_current_node().term
+    // Remove the response slot of a submitted entry, returning the response if it was applied.
+    pub fn take_response(&self, index: u64) -> Option<Value> {
+        let slot = self.responses.lock().unwrap().remove(&index).flatten();
+        slot.map(|(_, response)| response)
     }
 
-    // Get current term number, represented in bytes.
-    fn current_term_id_bytes(&self) -> [u8; 8] {
-        let term_id = self.current_term_id();
+    // Get the outcome of the entry proposed at `index` in `term`, from the term of the entry
+    // applied at that index. The log may have been compacted since.
+    pub fn proposal_outcome(&self, index: u64, term: u64) -> Outcome {
+        let raft_log = self.raft_log.lock().unwrap();
+        let responses = self.responses.lock().unwrap();
 
-        // u64 needs 8 bytes
-        let mut buffer = [0; 8]; // initialise to 0
-        buffer.copy_from_slice(&term_id.to_be_bytes());
+        if raft_log.last_applied < index {
+            return Outcome::Pending;
+        }
+        match responses.get(&index) {
+            Some(Some((applied_term, _))) if *applied_term == term => Outcome::Applied,
+            Some(Some(_)) => Outcome::Replaced,
+            // Applied from a snapshot installed by a later leader.
+            _ => Outcome::Unknown,
+        }
+    }
 
-        buffer
+    // Get commit index, if the entry at it belongs to the given term.
+    pub fn commit_index_in_term(&self, term: u64) -> Option<u64> {
+        let raft_log = self.raft_log.lock().unwrap();
+
+        if raft_log.term_at(raft_log.commit_index) != Some(term) {
+            return None;
+        }
+        Some(raft_log.commit_index)
     }
 
-    // Increment term number.
-    pub fn increment_term_number(&self) {
-        let mut node = self.get_current_node();
-        node.term += 1;
-        self.update_current_node(node);
+    // Check if the entry at `index` has been applied to the state machine.
+    pub fn is_applied(&self, index: u64) -> bool {
+        self.raft_log.lock().unwrap().last_applied >= index
     }
 
-    // Transition to follower state.
-    pub fn become_follower(&self) {
-        let mut node = self.get_current_node();
-        node.state = NodeState::Follower;
-        self.update_current_node(node);
+    // Count peers which acknowledged a heartbeat of `term` sent at or after `since_ns`.
+    // Acks are recorded by the eBPF program in HEARTBEAT_ACKS.
+    pub fn heartbeat_acks_since(&self, term: u64, since_ns: u64) -> u64 {
+        let heartbeat_acks = self.heartbeat_acks.lock().unwrap();
+
+        self.get_raft_peers()
+            .iter()
+            .filter(|ip| **ip != 0)
+            .filter_map(|ip| heartbeat_acks.get(ip, 0).ok())
+            .filter(|ack| ack.term == term && ack.sent_ns >= since_ns)
+            .count() as u64
     }
 
-    // Transition to candidate state.
-    pub fn become_candidate(&self) {
-        let mut node = self.get_current_node();
-        node.state = NodeState::Candidate;
-        node.vote.in_progress = false;
-        self.update_current_node(node);
+    // Get time left on the leader lease, as checked by holds_lease in the eBPF program: the
+    // LEASE_FOLLOWER_ACKS-th most recent heartbeat of the current term acknowledged by a
+    // follower whose clock drift is within bounds, plus LEADER_LEASE_NS. Zero once a later term
+    // was voted in or answered by a follower.
+    pub fn lease_remaining_ns(&self) -> u64 {
+        if self.get_current_state() != NodeState::Leader {
+            return 0;
+        }
+
+        let term = self.current_term_id();
+        if self.voted_term() > term || self.heartbeat_response_term() > term {
+            return 0;
+        }
+        let heartbeat_acks = self.heartbeat_acks.lock().unwrap();
+
+        let mut sent_ns: Vec<u64> = self
+            .get_raft_peers()
+            .iter()
+            .filter(|ip| **ip != 0)
+            .filter_map(|ip| heartbeat_acks.get(ip, 0).ok())
+            .filter(|ack| ack.term == term && ack.drift_within_bounds())
+            .map(|ack| ack.sent_ns)
+            .collect();
+        sent_ns.sort_unstable_by(|a, b| b.cmp(a));
+
+        match sent_ns.get(LEASE_FOLLOWER_ACKS as usize - 1) {
+            Some(sent_ns) => (sent_ns + LEADER_LEASE_NS).saturating_sub(get_current_clock_ns()),
+            None => 0,
+        }
     }
 
-    // Transition to leader state.
-    pub fn become_leader(&self) {
-        let mut node = self.get_current_node();
-        node.state = NodeState::Leader;
-        self.update_current_node(node);
+    // Get clock drift of each peer relative to this node in parts per million, as measured
+    // from heartbeat acks of the current term. None if not measured (yet).
+    pub fn peer_clock_drift_ppm(&self) -> Vec<(u32, Option<u64>)> {
+        let term = self.current_term_id();
+        let heartbeat_acks = self.heartbeat_acks.lock().unwrap();
+
+        self.get_raft_peers()
+            .iter()
+            .filter(|ip| **ip != 0)
+            .map(|ip| {
+                let drift_ppm = match heartbeat_acks.get(ip, 0) {
+                    Ok(ack) if ack.term == term => ack.drift_ppm(),
+                    _ => None,
+                };
+                (*ip, drift_ppm)
+            })
+            .collect()
     }
 
-    // Start vote (update metadata).
-    pub fn start_vote(&self) {
-        let mut node = self.get_current_node();
-        node.vote.in_progress = true;
-        node.vote.started_ts = get_current_clock_ns();
+    // Apply committed log entries to the state machine, in log order, and mirror them to
+    // the KV_STORE map read by the eBPF program.
+    pub fn apply_committed_entries(&self) {
+        let mut raft_log = self.raft_log.lock().unwrap();
+        let mut state_machine = self.state_machine.lock().unwrap();
 
-        node.vote.election_timeout =
-            values::ELECTION_TIMEOUT_NS + helpers::get_election_timeout_jitter_ns();
+        if raft_log.last_applied == raft_log.commit_index {
+            return;
+        }
 
-        self.update_current_node(node);
+        let mut responses = self.responses.lock().unwrap();
+        while raft_log.last_applied < raft_log.commit_index {
+            let index = raft_log.last_applied + 1;
+            if let Some(entry) = raft_log.get(index) {
+                let response = state_machine.apply(index, &entry.command);
+                if let Some(key) = entry.command.key() {
+                    self.mirror_key(key, state_machine.get(key));
+                }
+                if let Some(slot) = responses.get_mut(&index) {
+                    *slot = Some((entry.term, response));
+                }
+            }
+            raft_log.last_applied = index;
+        }
+        drop(responses);
+
+        let applied_term = raft_log.term_at(raft_log.last_applied).unwrap_or_default();
+        self.update_kv_mirror_state(|mirror| mirror.applied_term = applied_term);
+
+        // Snapshot the state machine and discard the entries it includes.
+        if raft_log.last_applied - raft_log.snapshot_index() >= values::SNAPSHOT_INTERVAL_ENTRIES {
+            let snapshot = Snapshot {
+                index: raft_log.last_applied,
+                term: applied_term,
+                data: state_machine.snapshot(),
+            };
+
+            let entries = raft_log.entries_from(snapshot.index + 1, usize::MAX);
+            let mut persistence = self.persistence.lock().unwrap();
+            if let Err(err) = persistence.save_snapshot(&snapshot, &entries) {
+                warn!(
+                    "Failed to write snapshot up to index {} to disk: {}",
+                    snapshot.index, err
+                );
+                return;
+            }
+            drop(persistence);
+            raft_log.compact(snapshot.index);
+
+            info!(
+                "Compacted log up to index {} ({} byte snapshot)",
+                snapshot.index,
+                snapshot.data.len()
+            );
+            *self.snapshot.lock().unwrap() = Arc::new(snapshot);
+        }
     }
 
-    // Get current election timeout.
-    pub fn get_election_timeout(&self) -> u64 {
-        let node = self.get_current_node();
-        node.vote.election_timeout
+    // Replace the KV_STORE mirror with the given key-value pairs, after restoring a snapshot.
+    pub fn rebuild_kv_mirror(&self, entries: Vec<(String, String)>, applied_term: u64) {
+        // XDP stops answering until the mirror matches the state machine again.
+        self.update_kv_mirror_state(|mirror| mirror.applied_term = 0);
+
+        let mut kv_mirror = self.kv_mirror.lock().unwrap();
+        let mut missing = self.kv_mirror_missing.lock().unwrap();
+        missing.clear();
+
+        let keys: Vec<KvKey> = kv_mirror.keys().filter_map(|key| key.ok()).collect();
+        for key in keys {
+            if let Err(err) = kv_mirror.remove(&key) {
+                warn!("Failed to clear KV_STORE: {}", err);
+                missing.insert(key);
+            }
+        }
+
+        for (key, value) in entries {
+            if let Some(kv_key) = KvKey::from_bytes(key.as_bytes()) {
+                match kv_mirror.insert(kv_key, KvValue::from_bytes(value.as_bytes()), 0) {
+                    Ok(()) => missing.remove(&kv_key),
+                    Err(err) => {
+                        warn!("Failed to mirror {} to KV_STORE: {}", key, err);
+                        missing.insert(kv_key)
+                    }
+                };
+            }
+        }
+        let complete = missing.is_empty();
+        drop(missing);
+        drop(kv_mirror);
+
+        self.update_kv_mirror_state(|mirror| {
+            mirror.applied_term = applied_term;
+            mirror.complete = complete as u64;
+        });
     }
 
-    // Check if election has timed out.
-    pub fn election_timed_out(&self) -> bool {
-        let node = self.get_current_node();
-        let time_elapsed = get_current_clock_ns() - node.vote.started_ts;
-        if node.vote.in_progress && (time_elapsed > node.vote.election_timeout) {
-            return true;
+    // Mirror the applied value of a key to the KV_STORE map. Mirroring the state machine rather
+    // than the command keeps commands deduplicated by client sessions out of the mirror.
+    // Keys longer than KV_KEY_LEN cannot be requested through XDP and are skipped.
+    fn mirror_key(&self, key: &str, value: Option<String>) {
+        let kv_key = match KvKey::from_bytes(key.as_bytes()) {
+            Some(kv_key) => kv_key,
+            None => return,
+        };
+        let mut kv_mirror = self.kv_mirror.lock().unwrap();
+
+        let result = match value {
+            Some(value) => kv_mirror.insert(kv_key, KvValue::from_bytes(value.as_bytes()), 0),
+            None if kv_mirror.get(&kv_key, 0).is_ok() => kv_mirror.remove(&kv_key),
+            None => Ok(()),
+        };
+
+        // Missing keys are not authoritative while any key failed to mirror, so XDP stops
+        // answering NOT_FOUND until each of them has been mirrored since.
+        let mut missing = self.kv_mirror_missing.lock().unwrap();
+        let changed = match result {
+            Ok(()) => missing.remove(&kv_key),
+            Err(err) => {
+                warn!("Failed to mirror {} to KV_STORE: {}", key, err);
+                missing.insert(kv_key)
+            }
+        };
+        if changed {
+            let complete = missing.is_empty();
+            self.update_kv_mirror_state(|mirror| mirror.complete = complete as u64);
         }
-        false
     }
 
-    // Stops vote and records its ending time.
-    pub fn stop_vote(&self) {
-        let mut node = self.get_current_node();
-        node.vote.in_progress = false;
-        node.vote.ended_ts = get_current_clock_ns();
-        self.update_current_node(node);
+    // Update KV_STORE mirror state.
+    fn update_kv_mirror_state(&self, update: impl FnOnce(&mut KvMirrorState)) {
+        let mut kv_mirror_state = self.kv_mirror_state.lock().unwrap();
+
+        let mut mirror: KvMirrorState = match kv_mirror_state.get(&0, 0) {
+            Ok(x) => x,
+            Err(err) => {
+                warn!("Failed to read KV_MIRROR: {}", err);
+                return;
+            }
+        };
+
+        update(&mut mirror);
+
+        if let Err(err) = kv_mirror_state.set(0, mirror, 0) {
+            warn!("Failed to update KV_MIRROR: {}", err);
+        }
     }
 
-    // Aborts vote and resets voting fields.
-    pub fn abort_vote(&self) {
-        let mut node = self.get_current_node();
-        node.vote.in_progress = false;
-        node.vote.started_ts = 0;
-        self.update_current_node(node);
+    // Raise the highest term voted in (VOTED_TERM), unless it is higher already.
+    pub fn raise_voted_term(&self, term: u64) -> Result<(), MapError> {
+        let mut voted_term = self.voted_term.lock().unwrap();
+
+        if voted_term.get(&0, 0).unwrap_or_default() < term {
+            voted_term.set(0, term, 0)?;
+        }
+        Ok(())
     }
 
-    // Resets vote data after a successful election.
-    pub fn reset_vote_data(&self) {
-        let mut node = self.get_current_node();
-        node.vote.in_progress = false;
-        node.vote.ended_ts = 0;
-        node.vote.started_ts = 0;
-        node.vote.election_timeout = 0;
-        self.update_current_node(node);
+    // Record on disk that votes may be granted in terms up to `term`, then let the eBPF program
+    // grant them (VOTE_CEILING). The ceiling is raised VOTE_CEILING_STEP terms further, so most
+    // elections wait for no disk write.
+    pub fn raise_vote_ceiling(&self, term: u64) -> Result<(), anyhow::Error> {
+        let mut persistence = self.persistence.lock().unwrap();
+        let mut hard_state = persistence.hard_state();
+        if hard_state.vote_ceiling >= term {
+            return Ok(());
+        }
+
+        hard_state.vote_ceiling = term.saturating_add(values::VOTE_CEILING_STEP);
+        persistence.save_hard_state(hard_state)?;
+        self.vote_ceiling
+            .lock()
+            .unwrap()
+            .set(0, hard_state.vote_ceiling, 0)?;
+        Ok(())
     }
 
-    // Reset vote result map items after each election.
-    pub fn reset_vote_results(&self) -> Result<(), aya::maps::MapError> {
-        let mut vote_results = self.voting_results.write().unwrap();
+    // Record on disk the term of a leader whose entries are accepted.
+    pub fn persist_term(&self, term: u64) -> Result<(), anyhow::Error> {
+        let mut persistence = self.persistence.lock().unwrap();
+        let mut hard_state = persistence.hard_state();
+        if hard_state.term >= term {
+            return Ok(());
+        }
 
-        for ip in vote_results.keys() {
-            let source_ip = ip.unwrap();
+        hard_state.term = term;
+        persistence.save_hard_state(hard_state)?;
+        Ok(())
+    }
 
-            match vote_results.insert(source_ip, 0, 0) {
-                Ok(()) => return Ok(()),
-                Err(err) => return Err(err),
-            }
+    // Restore the snapshot, log and term read from disk. Without pinned maps, the votes are gone,
+    // so the node takes the vote ceiling as its voted term: it may have voted in any term up to it.
+    pub fn restore_from_disk(&self, restored: Restored, pinned: bool) -> Result<(), anyhow::Error> {
+        let Restored {
+            hard_state,
+            snapshot,
+            entries,
+        } = restored;
+
+        if snapshot.index > 0 {
+            let mut state_machine = self.state_machine.lock().unwrap();
+            state_machine.restore(&snapshot.data)?;
+            self.rebuild_kv_mirror(state_machine.entries(), snapshot.term);
         }
+
+        let raft_log = RaftLog::restore(snapshot.index, snapshot.term, entries);
+        let last_term = raft_log.last_term();
+        info!(
+            "Restored snapshot up to index {} and log up to index {} from disk.",
+            snapshot.index,
+            raft_log.last_index()
+        );
+        *self.raft_log.lock().unwrap() = raft_log;
+        *self.snapshot.lock().unwrap() = Arc::new(snapshot);
+        self.publish_log_state();
+
+        self.vote_ceiling
+            .lock()
+            .unwrap()
+            .set(0, hard_state.vote_ceiling, 0)?;
+        if !pinned {
+            self.raise_voted_term(hard_state.vote_ceiling)?;
+        }
+        self.update_current_node(|node| node.term = node.term.max(hard_state.term).max(last_term));
         Ok(())
     }
 
-    // Calculates vote duration.
-    pub fn get_vote_duration(&self) -> u64 {
-        let node = self.get_current_node();
-        node.vote.ended_ts - node.vote.started_ts
+    // Read a key from the state machine.
+    pub fn read_key(&self, key: &str) -> Option<String> {
+        self.state_machine.lock().unwrap().get(key)
+    }
+
+    // Publish the last log entry to the eBPF program, which decides on votes.
+    pub fn publish_log_state(&self) {
+        let raft_log = self.raft_log.lock().unwrap();
+        let mut log_state = self.log_state.lock().unwrap();
+
+        // Votes are then judged against the previously published log.
+        if let Err(err) = log_state.set(
+            0,
+            LogState {
+                last_index: raft_log.last_index(),
+                last_term: raft_log.last_term(),
+            },
+            0,
+        ) {
+            warn!("Failed to update LOG_STATE: {}", err);
+        }
     }
 
-    // Returns vote state.
-    pub fn vote_in_progress(&self) -> bool {
-        self.get_current_node().vote.in_progress
+    // Stop the state machine loops.
+    pub fn begin_shutdown(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
     }
 
-    // Get Raft peer IPs.
-    pub fn get_raft_peers(&self) -> [u32; 2] {
-        let node = self.get_current_node();
-        node.peers
+    // Check if the node is shutting down.
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    // Block until the node is in the given state, or is shutting down.
+    // The lock is held while checking the state, so a change in between is not missed.
+    pub fn wait_for_state(&self, wanted: NodeState) {
+        let (lock, state_changed) = &*self.state_changed;
+        let mut guard = lock.lock().unwrap();
+
+        while self.get_current_state() != wanted && !self.is_shutting_down() {
+            // Times out to notice shutdown.
+            guard = state_changed
+                .wait_timeout(guard, Duration::from_millis(100))
+                .unwrap()
+                .0;
+        }
+    }
+
+    // Send a message to a node, on the given port.
+    pub fn send_message<const N: usize>(&self, ip: u32, port: u16, body: &[u64; N]) {
+        let buffer = self.message_bytes(port, body);
+        let socket = self.udp_socket.lock().unwrap();
+        let dest_socket = SocketAddr::new(Ipv4Addr::from(ip).into(), port);
+
+        if let Err(err) = socket.send_to(&buffer, dest_socket) {
+            warn!("Failed to send to {}:{}: {}", Ipv4Addr::from(ip), port, err);
+        }
     }
 
-    // Send heartbeat RPCs.
-    pub fn send_heartbeat_rpcs(&self) {
-        let buffer = self.current_term_id_bytes();
+    // Send a message to every peer, on the given port.
+    fn send_to_peers<const N: usize>(&self, port: u16, body: &[u64; N]) {
+        let buffer = self.message_bytes(port, body);
         let udp_socket_data = self.udp_socket.clone();
         let socket = udp_socket_data.lock().unwrap();
 
@@ -325,35 +780,115 @@ impl AppState {
             if ip == 0 {
                 return;
             }
-            let dest_socket = SocketAddr::new(Ipv4Addr::from(ip).into(), HEARTBEAT_REQUEST_PORT);
+            let dest_socket = SocketAddr::new(Ipv4Addr::from(ip).into(), port);
 
-            self.insert_heartbeat_timestamp(ip, helpers::get_current_clock_ns());
-            socket
-                .send_to(&buffer, dest_socket)
-                .expect("Failed to send packet");
+            if port == HEARTBEAT_REQUEST_PORT {
+                self.insert_heartbeat_timestamp(ip, get_current_clock_ns());
+            }
+            if let Err(err) = socket.send_to(&buffer, dest_socket) {
+                warn!("Failed to send to {}:{}: {}", Ipv4Addr::from(ip), port, err);
+            }
         });
     }
+}
 
-    // Send vote request RPCs.
-    pub fn send_request_vote_rpcs(&self) {
-        let buffer = self.current_term_id_bytes();
-        let udp_socket_data = self.udp_socket.clone();
-        let socket = udp_socket_data.lock().unwrap();
+impl Clock for AppState {
+    fn now_ns(&self) -> u64 {
+        get_current_clock_ns()
+    }
+}
 
-        self.get_raft_peers().par_iter().for_each(|&ip| {
-            if ip == 0 {
-                return;
+impl Random for AppState {
+    fn gen_range(&self, range: Range<u64>) -> u64 {
+        thread_rng().gen_range(range)
+    }
+}
+
+impl Transport for AppState {
+    fn local_addr(&self) -> u32 {
+        self.local_addr
+    }
+
+    fn send_vote_requests(&self, request: [u64; VOTE_REQUEST_WORDS]) {
+        self.send_to_peers(VOTE_REQUEST_PORT, &request);
+    }
+
+    fn send_heartbeats(&self, heartbeat: [u64; HEARTBEAT_WORDS]) {
+        self.send_to_peers(HEARTBEAT_REQUEST_PORT, &heartbeat);
+    }
+}
+
+impl Storage for AppState {
+    fn get_current_node(&self) -> CurrentNode {
+        let current_node = self.current_node.read().unwrap();
+
+        let node: CurrentNode = match current_node.get(&0, 0) {
+            Ok(x) => x,
+            Err(_err) => todo!(),
+        };
+
+        node
+    }
+
+    // The lock is held for the whole read-modify-write.
+    // Userspace is the only writer of CURRENT_NODE; the eBPF program reports heartbeats
+    // through LEADER_NODE instead (see apply_leader_heartbeats).
+    fn update_current_node(&self, update: impl FnOnce(&mut CurrentNode)) {
+        let mut node_data = self.current_node.write().unwrap();
+
+        let mut node: CurrentNode = match node_data.get(&0, 0) {
+            Ok(x) => x,
+            Err(_err) => todo!(),
+        };
+
+        let previous_state = node.state;
+        update(&mut node);
+
+        match node_data.set(0, node, 0) {
+            Ok(x) => x,
+            Err(_err) => todo!(),
+        };
+        drop(node_data);
+
+        if node.state != previous_state {
+            let (lock, state_changed) = &*self.state_changed;
+            let _guard = lock.lock().unwrap();
+            state_changed.notify_all();
+        }
+    }
+
+    // A leader that cannot be read is reported as unknown.
+    fn get_leader(&self) -> LeaderNode {
+        let leader = self.leader_node.read().unwrap();
+
+        match leader.get(&0, 0) {
+            Ok(x) => x,
+            Err(err) => {
+                warn!("Failed to read LEADER_NODE: {}", err);
+                LeaderNode::default()
             }
-            let dest_socket = SocketAddr::new(Ipv4Addr::from(ip).into(), VOTE_REQUEST_PORT);
+        }
+    }
 
-            socket
-                .send_to(&buffer, dest_socket)
-                .expect("Failed to send packet");
-        });
+    fn applied_leader_generation(&self) -> u64 {
+        self.applied_leader_generation.load(Ordering::SeqCst)
     }
 
-    // Get current yes votes from peers.
-    pub fn get_current_yes_votes_from_peers(&self) -> u64 {
+    fn set_applied_leader_generation(&self, generation: u64) {
+        self.applied_leader_generation
+            .store(generation, Ordering::SeqCst);
+    }
+
+    fn leader_timer_reset_ns(&self) -> u64 {
+        self.leader_timer_reset_ns.load(Ordering::SeqCst)
+    }
+
+    fn set_leader_timer_reset_ns(&self, ns: u64) {
+        self.leader_timer_reset_ns.store(ns, Ordering::SeqCst);
+    }
+
+    // Votes are recorded by the eBPF program when receiving VOTE_RESPONSE_PORT_YES/NO.
+    fn get_current_yes_votes_from_peers(&self) -> u64 {
         let vote_results = self.voting_results.read().unwrap();
         let mut total_positive_votes_for: u64 = 0;
 
@@ -371,15 +906,65 @@ impl AppState {
         total_positive_votes_for
     }
 
-    // Quorum checks.
-    pub fn quorum_reached(&self) -> bool {
-        let mut positive_votes = self.get_current_yes_votes_from_peers();
-        positive_votes += 1; // Candidate votes for itself.
+    // Every voter is reset, so no vote carries over to the next election.
+    fn reset_vote_results(&self) {
+        let mut vote_results = self.voting_results.write().unwrap();
+        let voters: Vec<u32> = vote_results.keys().filter_map(|ip| ip.ok()).collect();
+
+        for voter in voters {
+            if let Err(err) = vote_results.insert(voter, 0, 0) {
+                warn!("Failed to reset vote of {}: {}", Ipv4Addr::from(voter), err);
+            }
+        }
+    }
+
+    // The insert fails if the term is already in the map, so a vote granted concurrently by the
+    // eBPF program is never overridden (see VOTE_REQUEST_PORT in eBPF). The vote is on disk
+    // before it is requested from the peers.
+    fn record_vote(&self, term: u64) -> bool {
+        if let Err(err) = self.raise_vote_ceiling(term) {
+            warn!("Failed to write vote ceiling to disk: {}", err);
+            return false;
+        }
+
+        let mut vote_terms = self.vote_terms.lock().unwrap();
+        if vote_terms.insert(term, 1, BPF_NOEXIST).is_err() {
+            return false;
+        }
+        drop(vote_terms);
 
-        if positive_votes >= values::QUORUM {
-            return true;
+        if let Err(err) = self.raise_voted_term(term) {
+            warn!("Failed to record vote in term {}: {}", term, err);
         }
+        true
+    }
+
+    fn voted_term(&self) -> u64 {
+        self.voted_term
+            .lock()
+            .unwrap()
+            .get(&0, 0)
+            .unwrap_or_default()
+    }
+
+    fn heartbeat_response_term(&self) -> u64 {
+        let heartbeat_acks = self.heartbeat_acks.lock().unwrap();
+
+        self.get_raft_peers()
+            .iter()
+            .filter(|ip| **ip != 0)
+            .filter_map(|ip| heartbeat_acks.get(ip, 0).ok())
+            .map(|ack| ack.term)
+            .max()
+            .unwrap_or_default()
+    }
+
+    fn last_log_entry(&self) -> (u64, u64) {
+        let raft_log = self.raft_log.lock().unwrap();
+        (raft_log.last_index(), raft_log.last_term())
+    }
 
-        false
+    fn append_entry(&self, command: Command) {
+        self.propose(command);
     }
 }
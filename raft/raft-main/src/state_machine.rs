@@ -0,0 +1,68 @@
+use crate::raft_log::Command;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+// State machine driven by the Raft log. Committed commands are applied exactly once and
+// in log order on every node.
+pub trait StateMachine: Send {
+    // Apply a committed command and return the response for the client which submitted it.
+    fn apply(&mut self, index: u64, command: &Command) -> Value;
+
+    // Read a key from the applied state.
+    fn get(&self, key: &str) -> Option<String>;
+
+    // Serialize the applied state, so the log up to the last applied entry can be compacted.
+    fn snapshot(&self) -> Vec<u8>;
+
+    // Replace the applied state with a snapshot taken by `snapshot`.
+    fn restore(&mut self, snapshot: &[u8]) -> Result<(), anyhow::Error>;
+
+    // All key-value pairs, used to rebuild the KV_STORE mirror after restoring a snapshot.
+    fn entries(&self) -> Vec<(String, String)>;
+}
+
+// Built-in in-memory key-value store.
+#[derive(Debug, Default)]
+pub struct KvStore {
+    data: HashMap<String, String>,
+}
+
+impl KvStore {
+    pub fn new() -> KvStore {
+        KvStore::default()
+    }
+}
+
+impl StateMachine for KvStore {
+    // Responds with the previous value of the key.
+    fn apply(&mut self, _index: u64, command: &Command) -> Value {
+        match command {
+            Command::Put { key, value } => {
+                json!({ "previous": self.data.insert(key.clone(), value.clone()) })
+            }
+            Command::Delete { key } => json!({ "previous": self.data.remove(key) }),
+            // Session commands are handled by SessionStateMachine.
+            _ => Value::Null,
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<String> {
+        self.data.get(key).cloned()
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        serde_json::to_vec(&self.data).expect("Failed to serialize snapshot")
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) -> Result<(), anyhow::Error> {
+        self.data = serde_json::from_slice(snapshot)?;
+        Ok(())
+    }
+
+    fn entries(&self) -> Vec<(String, String)> {
+        self.data
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+}
@@ -0,0 +1,361 @@
+use crate::election::Storage;
+use crate::helpers::get_current_clock_ns;
+use crate::maps::BPF_NOEXIST;
+use crate::state::AppState;
+use anyhow::Context;
+use clap::ValueEnum;
+use log::{debug, info, warn};
+use raft_main_common::auth::{is_fresh, message_mac, replay_window_key, MAC_LEN, SEQ_LEN};
+use raft_main_common::handler::{self, decode_body, NodeMaps, VoteDecision};
+use raft_main_common::{
+    CurrentNode, HeartbeatAck, LeaderNode, LogState, COUNTER_MAC_FAILURES,
+    COUNTER_NON_MEMBER_DROPS, COUNTER_REPLAYS, HEARTBEAT_REQUEST_PORT, HEARTBEAT_RESPONSE_PORT,
+    LEADER_STEP_DOWN_PORT, TERM_LEN, VOTE_REQUEST_PORT, VOTE_RESPONSE_PORT_NO,
+    VOTE_RESPONSE_PORT_YES,
+};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, UdpSocket};
+use std::time::Duration;
+
+// How election messages (votes, heartbeats and step downs) are received.
+#[derive(Debug, Copy, Clone, PartialEq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportMode {
+    // Handled by the XDP program, which answers in place, except for vote requests above the vote
+    // ceiling, which it passes to a socket. Needs root (CAP_BPF and CAP_NET_ADMIN).
+    Xdp,
+    // Received on UDP sockets and handled in userspace by the same code as in the XDP program.
+    // BPF maps are replaced by memory (see maps.rs) and key-value reads on KV_GET_PORT are not
+    // served.
+    Udp,
+}
+
+pub const ELECTION_PORTS: [u16; 6] = [
+    VOTE_REQUEST_PORT,
+    VOTE_RESPONSE_PORT_YES,
+    VOTE_RESPONSE_PORT_NO,
+    HEARTBEAT_REQUEST_PORT,
+    HEARTBEAT_RESPONSE_PORT,
+    LEADER_STEP_DOWN_PORT,
+];
+
+// Bind a socket to each of the given election ports. Sockets time out periodically to notice
+// shutdown.
+pub fn bind(ports: &[u16]) -> Result<Vec<UdpSocket>, anyhow::Error> {
+    ports
+        .iter()
+        .map(|port| {
+            let socket = UdpSocket::bind(("0.0.0.0", *port))
+                .with_context(|| format!("failed to bind election port {}", port))?;
+            socket.set_read_timeout(Some(Duration::from_millis(100)))?;
+            Ok(socket)
+        })
+        .collect()
+}
+
+// Receive election messages on a socket bound by `bind` until shutdown.
+pub fn listener(state: &AppState, socket: &UdpSocket) {
+    let port = socket.local_addr().unwrap().port();
+    // Highest sequence number seen from each sender on this port (REPLAY_WINDOWS in eBPF).
+    let mut replay_windows: HashMap<u64, u64> = HashMap::new();
+    let mut buffer = [0u8; 64];
+
+    info!("Receiving election messages on UDP port {}.", port);
+
+    while !state.is_shutting_down() {
+        let (len, source) = match socket.recv_from(&mut buffer) {
+            Ok(x) => x,
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                continue
+            }
+            Err(err) => {
+                warn!("Failed to receive on port {}: {}", port, err);
+                continue;
+            }
+        };
+
+        let source_addr = match source.ip() {
+            IpAddr::V4(ip) => u32::from(ip),
+            IpAddr::V6(_) => continue,
+        };
+
+        receive(
+            state,
+            &mut replay_windows,
+            source_addr,
+            port,
+            &buffer[..len],
+        );
+    }
+}
+
+// Handle a message as the XDP program does.
+fn receive(
+    state: &AppState,
+    replay_windows: &mut HashMap<u64, u64>,
+    source_addr: u32,
+    port: u16,
+    payload: &[u8],
+) {
+    let maps = UserMaps(state);
+
+    // Drop Raft traffic from addresses which are not members of the cluster.
+    if state.members.lock().unwrap().get(&source_addr, 0).is_err() {
+        maps.increment_counter(COUNTER_NON_MEMBER_DROPS);
+        debug!(
+            "[UDP] Received packet on port {} from non-member '{}'; dropping.",
+            port,
+            Ipv4Addr::from(source_addr)
+        );
+        return;
+    }
+
+    match port {
+        VOTE_REQUEST_PORT => {
+            let request = match authentic_body(state, replay_windows, source_addr, port, payload) {
+                Some(body) => body,
+                None => return,
+            };
+
+            let mut decision = handler::handle_vote_request(&maps, &request);
+            if decision == VoteDecision::NotDurable {
+                match state.raise_vote_ceiling(request[0]) {
+                    Ok(()) => decision = handler::handle_vote_request(&maps, &request),
+                    Err(err) => warn!("[UDP] Failed to write vote ceiling to disk: {}", err),
+                }
+            }
+            debug!(
+                "[UDP] Vote request from '{}' for term {}: {:?}",
+                Ipv4Addr::from(source_addr),
+                request[0],
+                decision
+            );
+            if decision == VoteDecision::NotRecorded {
+                warn!(
+                    "[UDP] Unable to record vote for term '{}' from '{}'; dropping.",
+                    request[0],
+                    Ipv4Addr::from(source_addr)
+                );
+            }
+
+            if let Some(response_port) = decision.response_port() {
+                state.send_message(source_addr, response_port, &request);
+            }
+        }
+        VOTE_RESPONSE_PORT_YES | VOTE_RESPONSE_PORT_NO => {
+            let request = match authentic_body(state, replay_windows, source_addr, port, payload) {
+                Some(body) => body,
+                None => return,
+            };
+
+            let granted = port == VOTE_RESPONSE_PORT_YES;
+            if handler::handle_vote_response(&maps, source_addr, granted, &request) {
+                debug!(
+                    "[UDP] Received '{}' from {} for term {}",
+                    if granted { "YES" } else { "NO" },
+                    Ipv4Addr::from(source_addr),
+                    request[0]
+                );
+            }
+        }
+        HEARTBEAT_REQUEST_PORT => {
+            let heartbeat = match authentic_body(state, replay_windows, source_addr, port, payload)
+            {
+                Some(body) => body,
+                None => return,
+            };
+
+            let current_term = handler::current_term(&maps);
+            if let Ok(response) = handler::handle_heartbeat(&maps, source_addr, &heartbeat) {
+                debug!(
+                    "[UDP] Received heartbeat from leader '{}' with term {} (mine was {}).",
+                    Ipv4Addr::from(source_addr),
+                    heartbeat[0],
+                    current_term
+                );
+                state.send_message(source_addr, HEARTBEAT_RESPONSE_PORT, &response);
+            }
+        }
+        HEARTBEAT_RESPONSE_PORT => {
+            let heartbeat = match authentic_body(state, replay_windows, source_addr, port, payload)
+            {
+                Some(body) => body,
+                None => return,
+            };
+
+            handler::handle_heartbeat_response(&maps, source_addr, &heartbeat);
+        }
+        LEADER_STEP_DOWN_PORT => {
+            let [term] = match authentic_body(state, replay_windows, source_addr, port, payload) {
+                Some(body) => body,
+                None => return,
+            };
+
+            if let Ok(true) = handler::handle_step_down(&maps, source_addr) {
+                info!(
+                    "[UDP] Leader '{}' stepped down in term {}.",
+                    Ipv4Addr::from(source_addr),
+                    term
+                );
+            }
+        }
+        _ => {}
+    }
+}
+
+// Parse a message body of N words which, if authentication is enabled, must be followed by a
+// valid sequence number and MAC (see is_authentic in eBPF).
+fn authentic_body<const N: usize>(
+    state: &AppState,
+    replay_windows: &mut HashMap<u64, u64>,
+    source_addr: u32,
+    port: u16,
+    payload: &[u8],
+) -> Option<[u64; N]> {
+    let body_len = N * TERM_LEN;
+    if payload.len() != body_len + state.mac_key.trailer_len() {
+        return None;
+    }
+
+    let body = decode_body::<N>(payload)?;
+    if !state.mac_key.is_enabled() {
+        return Some(body);
+    }
+
+    let [seq, mac] = decode_body::<2>(&payload[body_len..body_len + SEQ_LEN + MAC_LEN])?;
+    if mac != message_mac(&state.mac_key, source_addr, port, &body, seq) {
+        UserMaps(state).increment_counter(COUNTER_MAC_FAILURES);
+        warn!(
+            "[UDP] Received message on port {} from '{}' with invalid MAC; dropping.",
+            port,
+            Ipv4Addr::from(source_addr)
+        );
+        return None;
+    }
+
+    // Only authentic messages may advance the sender's high-water mark.
+    let window_key = replay_window_key(source_addr, port);
+    let highest_seen = replay_windows.get(&window_key).copied().unwrap_or_default();
+    if !is_fresh(
+        seq,
+        highest_seen,
+        state.sequence_base + get_current_clock_ns(),
+    ) {
+        UserMaps(state).increment_counter(COUNTER_REPLAYS);
+        return None;
+    }
+    replay_windows.insert(window_key, seq);
+
+    Some(body)
+}
+
+// The maps of AppState, as seen by the XDP program.
+struct UserMaps<'a>(&'a AppState);
+
+impl NodeMaps for UserMaps<'_> {
+    fn now_ns(&self) -> u64 {
+        get_current_clock_ns()
+    }
+
+    fn current_node(&self) -> Option<CurrentNode> {
+        self.0.current_node.read().unwrap().get(&0, 0).ok()
+    }
+
+    fn leader_node(&self) -> Option<LeaderNode> {
+        self.0.leader_node.read().unwrap().get(&0, 0).ok()
+    }
+
+    // Userspace applies the transition to follower state once it observes the new generation,
+    // as with the XDP program.
+    fn record_leader(&self, source_addr: u32, term: u64) -> Result<(), ()> {
+        let mut leader_node = self.0.leader_node.write().unwrap();
+
+        let mut leader = leader_node.get(&0, 0).map_err(|_| ())?;
+        leader.last_seen = get_current_clock_ns();
+        leader.source_addr_raw = source_addr;
+        leader.term_id = term;
+        leader.generation += 1;
+
+        leader_node.set(0, leader, 0).map_err(|_| ())
+    }
+
+    fn expire_leader(&self) -> Result<(), ()> {
+        let mut leader_node = self.0.leader_node.write().unwrap();
+
+        let mut leader = leader_node.get(&0, 0).map_err(|_| ())?;
+        leader.last_seen = 0;
+
+        leader_node.set(0, leader, 0).map_err(|_| ())
+    }
+
+    fn log_state(&self) -> LogState {
+        self.0
+            .log_state
+            .lock()
+            .unwrap()
+            .get(&0, 0)
+            .unwrap_or_default()
+    }
+
+    fn voted_for_term(&self, term: u64) -> bool {
+        self.0.vote_terms.lock().unwrap().get(&term, 0).is_ok()
+    }
+
+    fn insert_vote_term(&self, term: u64) -> Result<(), ()> {
+        let mut vote_terms = self.0.vote_terms.lock().unwrap();
+        vote_terms.insert(term, 1, BPF_NOEXIST).map_err(|_| ())
+    }
+
+    fn voted_term(&self) -> u64 {
+        self.0.voted_term()
+    }
+
+    fn raise_voted_term(&self, term: u64) -> Result<(), ()> {
+        self.0.raise_voted_term(term).map_err(|_| ())
+    }
+
+    fn vote_ceiling(&self) -> u64 {
+        self.0
+            .vote_ceiling
+            .lock()
+            .unwrap()
+            .get(&0, 0)
+            .unwrap_or_default()
+    }
+
+    fn insert_vote_result(&self, source_addr: u32, granted: u64) -> Result<(), ()> {
+        let mut vote_results = self.0.voting_results.write().unwrap();
+        vote_results.insert(source_addr, granted, 0).map_err(|_| ())
+    }
+
+    fn heartbeat_ack(&self, source_addr: u32) -> Option<HeartbeatAck> {
+        self.0
+            .heartbeat_acks
+            .lock()
+            .unwrap()
+            .get(&source_addr, 0)
+            .ok()
+    }
+
+    fn insert_heartbeat_ack(&self, source_addr: u32, ack: &HeartbeatAck) -> Result<(), ()> {
+        let mut heartbeat_acks = self.0.heartbeat_acks.lock().unwrap();
+        heartbeat_acks.insert(source_addr, ack, 0).map_err(|_| ())
+    }
+
+    fn heartbeat_sent_ns(&self, source_addr: u32) -> Option<u64> {
+        self.0.followers.lock().unwrap().get(&source_addr, 0).ok()
+    }
+
+    fn insert_heartbeat_latency(&self, source_addr: u32, latency_ns: u64) -> Result<(), ()> {
+        let mut heartbeat_latency = self.0.heartbeat_latency.lock().unwrap();
+        heartbeat_latency
+            .insert(source_addr, latency_ns, 0)
+            .map_err(|_| ())
+    }
+
+    fn increment_counter(&self, index: u32) {
+        self.0.counters.lock().unwrap().increment(index);
+    }
+}
@@ -9,4 +9,24 @@ pub static LEADER_COMMUNICATION_TIMEOUT_MS: u64 = 2 * LEADER_HEARTBEAT_FREQUENCY
 
 pub static LEADER_COMMUNICATION_JITTER_MIN_MS: u64 = 5;
 pub static LEADER_COMMUNICATION_JITTER_MAX_MS: u64 = 50;
-pub static LEADER_HEARTBEAT_CYCLES_BEFORE_CRASH: i32 = 30;
+pub static LEADER_HEARTBEAT_CYCLES_BEFORE_CRASH: i32 = 30; // With --simulate-crashes only.
+pub static SIMULATED_CRASHES_LAST_TERM: u64 = 100; // With --simulate-crashes, nodes exit at this term.
+
+pub static HTTP_PORT: u16 = 8888;
+
+pub static PIN_ROOT: &str = "/sys/fs/bpf/raft"; // Default --pin-root; maps and program link are pinned under <pin root>/<iface>.
+pub static DATA_DIR: &str = "/var/lib/raft-main"; // Default --data-dir, holding the term, votes, log and snapshot.
+pub static VOTE_CEILING_STEP: u64 = 10; // Terms the vote ceiling is raised ahead, so most votes wait for no disk write.
+
+pub static REPLICATION_INTERVAL_MS: u64 = 10;
+pub static MAX_ENTRIES_PER_APPEND: usize = 64;
+pub static MAX_APPEND_ENTRIES_BYTES: usize = 60 * 1024; // Serialized entries per AppendEntries; a UDP datagram holds 65,507 bytes.
+pub static MAX_COMMAND_BYTES: usize = MAX_APPEND_ENTRIES_BYTES - 1024; // Leaves room for the entry's index and term.
+pub static CLIENT_REQUEST_TIMEOUT_MS: u64 = 1000; // Time to wait for a proposed entry to be applied.
+pub static SESSION_TIMEOUT_NS: u64 = 3_600_000_000_000; // 1 h without commands, in log time.
+pub static SESSION_EXPIRY_INTERVAL_MS: u64 = 60_000;
+pub static FORWARD_TIMEOUT_MS: u64 = 2 * CLIENT_REQUEST_TIMEOUT_MS; // Outlasts the leader's own timeout.
+
+pub static SNAPSHOT_INTERVAL_ENTRIES: u64 = 10_000; // Applied entries between snapshots.
+pub static MAX_SNAPSHOT_BYTES: u64 = 256 * 1024 * 1024;
+pub static SNAPSHOT_TIMEOUT_MS: u64 = 10_000; // Read/write timeout of snapshot transfers.
@@ -0,0 +1,124 @@
+use anyhow::Context;
+use aya::programs::links::{FdLink, PinnedLink};
+use aya::programs::xdp::{XdpLink, XdpLinkId};
+use aya::programs::{Xdp, XdpFlags};
+use clap::ValueEnum;
+use log::{info, warn};
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// XDP attach modes.
+// Auto tries driver (native) mode first and falls back to generic (SKB) mode.
+// Hardware offload is not offered: the program uses LRU and per-CPU maps and helpers that
+// offloading NICs do not support, and would have to be loaded for the NIC.
+#[derive(Debug, Copy, Clone, PartialEq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum XdpMode {
+    Skb,
+    Native,
+    Auto,
+    // Mode of a link pinned by a previous run, which is not known to this process.
+    #[value(skip)]
+    Inherited,
+}
+
+impl XdpMode {
+    fn flags(&self) -> XdpFlags {
+        match self {
+            XdpMode::Skb => XdpFlags::SKB_MODE,
+            XdpMode::Native => XdpFlags::DRV_MODE,
+            XdpMode::Auto | XdpMode::Inherited => XdpFlags::default(),
+        }
+    }
+}
+
+// Attach the XDP program to the interface and return the mode it is running in.
+pub fn attach(
+    program: &mut Xdp,
+    iface: &str,
+    mode: XdpMode,
+) -> Result<(XdpLinkId, XdpMode), anyhow::Error> {
+    if mode != XdpMode::Auto {
+        let link_id = program.attach(iface, mode.flags()).with_context(|| {
+            format!(
+                "failed to attach the XDP program to {} in {:?} mode",
+                iface, mode
+            )
+        })?;
+        info!("Attached XDP program to {} in {:?} mode.", iface, mode);
+        return Ok((link_id, mode));
+    }
+
+    match program.attach(iface, XdpMode::Native.flags()) {
+        Ok(link_id) => {
+            info!("Attached XDP program to {} in Native mode.", iface);
+            Ok((link_id, XdpMode::Native))
+        }
+        Err(err) => {
+            warn!(
+                "Driver of {} does not support native XDP ({}); falling back to Skb mode.",
+                iface, err
+            );
+            let link_id = program
+                .attach(iface, XdpMode::Skb.flags())
+                .with_context(|| {
+                    format!("failed to attach the XDP program to {} in Skb mode", iface)
+                })?;
+            info!("Attached XDP program to {} in Skb mode.", iface);
+            Ok((link_id, XdpMode::Skb))
+        }
+    }
+}
+
+// Directory under `pin_root` holding pinned maps and the program link of the given interface.
+pub fn pin_dir(pin_root: &Path, iface: &str) -> PathBuf {
+    pin_root.join(iface)
+}
+
+// Check if a previous run left pinned maps behind.
+pub fn has_pinned_maps(pin_dir: &Path) -> bool {
+    pin_dir.join("CURRENT_NODE").exists()
+}
+
+// Remove maps and program link pinned by a previous run, detaching its XDP program.
+pub fn remove_pins(pin_dir: &Path) -> Result<(), anyhow::Error> {
+    if pin_dir.exists() {
+        fs::remove_dir_all(pin_dir)
+            .with_context(|| format!("failed to remove pins under {}", pin_dir.display()))?;
+        info!("Removed pins under {}.", pin_dir.display());
+    }
+    Ok(())
+}
+
+// Attach the XDP program through a pinned link, so it keeps running when this process exits.
+// If a previous run pinned a link, its program is atomically replaced by this one.
+pub fn attach_pinned(
+    program: &mut Xdp,
+    iface: &str,
+    mode: XdpMode,
+    pin_dir: &Path,
+) -> Result<XdpMode, anyhow::Error> {
+    let link_path = pin_dir.join("link");
+
+    if link_path.exists() {
+        let pinned_link = PinnedLink::from_pin(&link_path)
+            .with_context(|| format!("failed to open link pinned at {}", link_path.display()))?;
+        let link = XdpLink::try_from(FdLink::from(pinned_link))?;
+        program.attach_to_link(link)?;
+        info!(
+            "Replaced XDP program in link pinned at {}.",
+            link_path.display()
+        );
+        return Ok(XdpMode::Inherited);
+    }
+
+    let (link_id, mode) = attach(program, iface, mode)?;
+    let link = FdLink::try_from(program.take_link(link_id)?)
+        .context("pinning requires bpf_link based XDP attachment (Linux 5.9+)")?;
+    link.pin(&link_path)
+        .with_context(|| format!("failed to pin link at {}", link_path.display()))?;
+    info!("Pinned XDP link at {}.", link_path.display());
+
+    Ok(mode)
+}
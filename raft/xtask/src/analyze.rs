@@ -0,0 +1,470 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, Context as _};
+use clap::Parser;
+
+/// Two-sided 95% quantile of the standard normal distribution, for confidence intervals
+const Z_95: f64 = 1.959964;
+
+/// Width of the longest histogram bar, in characters
+const HISTOGRAM_WIDTH: usize = 40;
+
+#[derive(Debug, Parser)]
+pub struct Options {
+    /// Runs to analyze. A run is one or more measurement CSV files joined with commas, e.g. the
+    /// files of each node, optionally named with `name=`. Values are durations in nanoseconds
+    #[clap(required = true)]
+    pub runs: Vec<String>,
+    /// Column holding the values, by header name or zero-based index, instead of the last one
+    #[clap(long)]
+    pub column: Option<String>,
+    /// Number of histogram bins
+    #[clap(long, default_value = "10")]
+    pub bins: usize,
+    /// Space histogram bins logarithmically, which suits long tails
+    #[clap(long)]
+    pub log_bins: bool,
+}
+
+/// Measurements of one run, sorted
+struct Run {
+    name: String,
+    values: Vec<f64>,
+}
+
+#[derive(Debug, PartialEq)]
+struct Summary {
+    samples: usize,
+    min: f64,
+    p50: f64,
+    p90: f64,
+    p99: f64,
+    max: f64,
+    mean: f64,
+    mean_ci: (f64, f64),
+    p50_ci: (f64, f64),
+}
+
+type Statistic = fn(&Summary) -> f64;
+type Interval = fn(&Summary) -> (f64, f64);
+
+/// Print statistics and histograms of the runs side by side. Two runs are also compared with a
+/// Mann-Whitney U test.
+pub fn analyze(opts: Options) -> Result<(), anyhow::Error> {
+    if opts.bins == 0 {
+        bail!("--bins must be at least 1");
+    }
+    let runs = opts
+        .runs
+        .iter()
+        .map(|spec| read_run(spec, opts.column.as_deref()))
+        .collect::<Result<Vec<Run>, _>>()?;
+    let summaries: Vec<Summary> = runs.iter().map(|run| summarize(&run.values)).collect();
+
+    let width = runs
+        .iter()
+        .map(|run| run.name.len())
+        .max()
+        .unwrap_or_default()
+        .max(24);
+    let header: Vec<String> = runs
+        .iter()
+        .map(|run| format!("{:>width$}", run.name))
+        .collect();
+    let compare = runs.len() == 2;
+    println!(
+        "{:<10}{}{}",
+        "",
+        header.join("  "),
+        if compare { "    change" } else { "" }
+    );
+
+    let samples: Vec<String> = summaries
+        .iter()
+        .map(|summary| format!("{:>width$}", summary.samples))
+        .collect();
+    println!("{:<10}{}", "samples", samples.join("  "));
+
+    let rows: [(&str, Statistic); 6] = [
+        ("min", |s| s.min),
+        ("p50", |s| s.p50),
+        ("p90", |s| s.p90),
+        ("p99", |s| s.p99),
+        ("max", |s| s.max),
+        ("mean", |s| s.mean),
+    ];
+    for (label, statistic) in rows {
+        let values: Vec<f64> = summaries.iter().map(statistic).collect();
+        let cells: Vec<String> = values
+            .iter()
+            .map(|value| format!("{:>width$}", format_ns(*value)))
+            .collect();
+        let change = match values[..] {
+            [a, b] if a != 0.0 => format!("{:>+9.1}%", (b - a) / a * 100.0),
+            _ => String::new(),
+        };
+        println!("{label:<10}{}{change}", cells.join("  "));
+    }
+
+    let intervals: [(&str, Interval); 2] = [("mean ci", |s| s.mean_ci), ("p50 ci", |s| s.p50_ci)];
+    for (label, interval) in intervals {
+        let cells: Vec<String> = summaries
+            .iter()
+            .map(|summary| {
+                let (low, high) = interval(summary);
+                format!(
+                    "{:>width$}",
+                    format!("{} - {}", format_ns(low), format_ns(high))
+                )
+            })
+            .collect();
+        println!("{label:<10}{}", cells.join("  "));
+    }
+    println!("(95% confidence intervals)");
+
+    if let [a, b] = &runs[..] {
+        let (p, b_greater) = mann_whitney(&a.values, &b.values);
+        let p = match p {
+            p if p < 1e-4 => "p < 0.0001".to_owned(),
+            p => format!("p = {p:.4}"),
+        };
+        println!();
+        println!("Mann-Whitney U test: {p}");
+        println!(
+            "A value of {} exceeds one of {} with probability {b_greater:.2}",
+            b.name, a.name
+        );
+    }
+
+    let low = runs
+        .iter()
+        .map(|run| run.values[0])
+        .fold(f64::MAX, f64::min);
+    let high = runs
+        .iter()
+        .map(|run| run.values[run.values.len() - 1])
+        .fold(f64::MIN, f64::max);
+    let edges = bin_edges(low, high, opts.bins, opts.log_bins)?;
+    for run in &runs {
+        println!();
+        println!("{}", run.name);
+        print_histogram(&edges, &histogram(&run.values, &edges));
+    }
+    Ok(())
+}
+
+/// Read a run given as `[name=]file[,file...]`
+fn read_run(spec: &str, column: Option<&str>) -> Result<Run, anyhow::Error> {
+    let (name, files) = match spec.split_once('=') {
+        Some((name, files)) => (name.to_owned(), files),
+        None => {
+            let first = spec.split(',').next().unwrap_or_default();
+            let name = Path::new(first)
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_else(|| first.to_owned());
+            match spec.matches(',').count() {
+                0 => (name, spec),
+                more => (format!("{name} (+{more})"), spec),
+            }
+        }
+    };
+
+    let mut values = Vec::new();
+    for file in files.split(',') {
+        let contents =
+            fs::read_to_string(file).with_context(|| format!("failed to read {file}"))?;
+        values.extend(parse_values(&contents, column).with_context(|| format!("in {file}"))?);
+    }
+    if values.is_empty() {
+        bail!("run {name} has no measurements");
+    }
+    values.sort_by(f64::total_cmp);
+    Ok(Run { name, values })
+}
+
+/// Values in a measurement CSV. Files written by `cargo xtask benchmark` start with a header;
+/// older measurements have none.
+fn parse_values(contents: &str, column: Option<&str>) -> Result<Vec<f64>, anyhow::Error> {
+    let mut lines = contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .peekable();
+
+    let header = match lines.peek() {
+        Some((_, line))
+            if line
+                .split(',')
+                .any(|field| field.trim().parse::<f64>().is_err()) =>
+        {
+            lines.next().map(|(_, line)| line)
+        }
+        _ => None,
+    };
+    let index = match column {
+        None => None,
+        Some(column) => match column.parse::<usize>() {
+            Ok(index) => Some(index),
+            Err(_) => match header
+                .and_then(|header| header.split(',').position(|field| field.trim() == column))
+            {
+                Some(index) => Some(index),
+                None => bail!("no column named {column}"),
+            },
+        },
+    };
+
+    lines
+        .map(|(n, line)| {
+            let fields: Vec<&str> = line.split(',').collect();
+            let field = match index {
+                Some(index) => fields.get(index).copied(),
+                None => fields.last().copied(),
+            };
+            match field.map(|field| field.trim().parse::<f64>()) {
+                Some(Ok(value)) => Ok(value),
+                _ => bail!("line {}: no value in {line:?}", n + 1),
+            }
+        })
+        .collect()
+}
+
+fn summarize(sorted: &[f64]) -> Summary {
+    let n = sorted.len() as f64;
+    let mean = sorted.iter().sum::<f64>() / n;
+    let variance = sorted.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (n - 1.0).max(1.0);
+    let margin = Z_95 * (variance / n).sqrt();
+
+    Summary {
+        samples: sorted.len(),
+        min: sorted[0],
+        p50: percentile(sorted, 0.5),
+        p90: percentile(sorted, 0.9),
+        p99: percentile(sorted, 0.99),
+        max: sorted[sorted.len() - 1],
+        mean,
+        mean_ci: (mean - margin, mean + margin),
+        p50_ci: median_ci(sorted),
+    }
+}
+
+/// Nearest-rank percentile: the smallest value at least a fraction `p` of values are at or below
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let rank = (p * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// Distribution-free confidence interval of the median, between the order statistics the
+/// binomial distribution of values below the median (approximated as normal) puts around it
+fn median_ci(sorted: &[f64]) -> (f64, f64) {
+    let n = sorted.len() as f64;
+    let half_width = Z_95 * n.sqrt() / 2.0;
+    let low = (n / 2.0 - half_width).floor() as usize;
+    let high = (1.0 + n / 2.0 + half_width).ceil() as usize;
+    (
+        sorted[low.clamp(1, sorted.len()) - 1],
+        sorted[high.clamp(1, sorted.len()) - 1],
+    )
+}
+
+/// Two-sided p-value of the Mann-Whitney U test of whether values of `b` tend to differ from
+/// those of `a`, with the normal approximation and ties corrected for, and the probability that
+/// a value of `b` exceeds one of `a` (counting ties as half).
+fn mann_whitney(a: &[f64], b: &[f64]) -> (f64, f64) {
+    let mut all: Vec<(f64, bool)> = a
+        .iter()
+        .map(|x| (*x, false))
+        .chain(b.iter().map(|x| (*x, true)))
+        .collect();
+    all.sort_by(|x, y| x.0.total_cmp(&y.0));
+
+    // Ranks of tied values are averaged.
+    let (mut rank_sum_b, mut ties) = (0.0, 0.0);
+    let mut i = 0;
+    while i < all.len() {
+        let j = all[i..].iter().take_while(|x| x.0 == all[i].0).count() + i;
+        let rank = (i + 1 + j) as f64 / 2.0;
+        rank_sum_b += rank * all[i..j].iter().filter(|x| x.1).count() as f64;
+        let t = (j - i) as f64;
+        ties += t * t * t - t;
+        i = j;
+    }
+
+    let (n_a, n_b) = (a.len() as f64, b.len() as f64);
+    let n = n_a + n_b;
+    let u_b = rank_sum_b - n_b * (n_b + 1.0) / 2.0;
+    let mean = n_a * n_b / 2.0;
+    let variance = n_a * n_b / 12.0 * ((n + 1.0) - ties / (n * (n - 1.0)));
+    let p = if variance > 0.0 {
+        let z = (u_b - mean).abs() / variance.sqrt();
+        (2.0 * (1.0 - normal_cdf(z))).min(1.0)
+    } else {
+        1.0
+    };
+    (p, u_b / (n_a * n_b))
+}
+
+/// Standard normal CDF, with erf approximated as in Abramowitz and Stegun 7.1.26 (error below
+/// 1.5e-7)
+fn normal_cdf(z: f64) -> f64 {
+    let x = z.abs() / std::f64::consts::SQRT_2;
+    let t = 1.0 / (1.0 + 0.3275911 * x);
+    let polynomial = t
+        * (0.254829592
+            + t * (-0.284496736 + t * (1.421413741 + t * (-1.453152027 + t * 1.061405429))));
+    let erf = 1.0 - polynomial * (-x * x).exp();
+    if z >= 0.0 {
+        (1.0 + erf) / 2.0
+    } else {
+        (1.0 - erf) / 2.0
+    }
+}
+
+/// Edges of `bins` bins spanning `low` to `high`
+fn bin_edges(low: f64, high: f64, bins: usize, log: bool) -> Result<Vec<f64>, anyhow::Error> {
+    if log && low <= 0.0 {
+        bail!("logarithmic bins need positive values");
+    }
+    Ok((0..=bins)
+        .map(|i| {
+            let fraction = i as f64 / bins as f64;
+            if log {
+                low * (high / low).powf(fraction)
+            } else {
+                low + (high - low) * fraction
+            }
+        })
+        .collect())
+}
+
+/// Counts of values in each bin. The last bin includes its upper edge.
+fn histogram(values: &[f64], edges: &[f64]) -> Vec<usize> {
+    let mut counts = vec![0; edges.len() - 1];
+    for value in values {
+        let bin = edges[1..edges.len() - 1].partition_point(|edge| edge <= value);
+        counts[bin] += 1;
+    }
+    counts
+}
+
+fn print_histogram(edges: &[f64], counts: &[usize]) {
+    let most = counts.iter().copied().max().unwrap_or_default().max(1);
+    for (i, count) in counts.iter().enumerate() {
+        let range = format!("{} - {}", format_ns(edges[i]), format_ns(edges[i + 1]));
+        let bar = "#".repeat((count * HISTOGRAM_WIDTH).div_ceil(most));
+        println!("  {range:>24}  {bar:<HISTOGRAM_WIDTH$}  {count}");
+    }
+}
+
+fn format_ns(ns: f64) -> String {
+    match ns.abs() {
+        x if x < 1e3 => format!("{ns:.0} ns"),
+        x if x < 1e6 => format!("{:.1} us", ns / 1e3),
+        x if x < 1e9 => format!("{:.2} ms", ns / 1e6),
+        _ => format!("{:.2} s", ns / 1e9),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn one_to(n: usize) -> Vec<f64> {
+        (1..=n).map(|x| x as f64).collect()
+    }
+
+    #[test]
+    fn percentiles_use_nearest_rank() {
+        let summary = summarize(&one_to(100));
+
+        assert_eq!(
+            (
+                summary.min,
+                summary.p50,
+                summary.p90,
+                summary.p99,
+                summary.max
+            ),
+            (1.0, 50.0, 90.0, 99.0, 100.0)
+        );
+        assert_eq!(summary.mean, 50.5);
+        assert_eq!(percentile(&[7.0], 0.99), 7.0);
+    }
+
+    #[test]
+    fn confidence_intervals_contain_the_estimates() {
+        let summary = summarize(&one_to(100));
+
+        // Order statistics 40 and 61 of 100.
+        assert_eq!(summary.p50_ci, (40.0, 61.0));
+        // The standard error of the mean of 1..=100 is 2.9.
+        assert!((summary.mean_ci.0 - 44.81).abs() < 0.01);
+        assert!((summary.mean_ci.1 - 56.19).abs() < 0.01);
+
+        let single = summarize(&[3.0]);
+        assert_eq!((single.mean_ci, single.p50_ci), ((3.0, 3.0), (3.0, 3.0)));
+    }
+
+    #[test]
+    fn mann_whitney_tells_shifted_samples_apart() {
+        let a = one_to(50);
+        let shifted: Vec<f64> = a.iter().map(|x| x + 30.0).collect();
+
+        let (p, b_greater) = mann_whitney(&a, &a);
+        assert!(p > 0.99);
+        assert_eq!(b_greater, 0.5);
+
+        let (p, b_greater) = mann_whitney(&a, &shifted);
+        assert!(p < 1e-6);
+        assert!(b_greater > 0.9);
+
+        // All values tied.
+        assert_eq!(mann_whitney(&[1.0, 1.0], &[1.0]), (1.0, 0.5));
+    }
+
+    #[test]
+    fn normal_cdf_matches_tables() {
+        assert!((normal_cdf(0.0) - 0.5).abs() < 1e-7);
+        assert!((normal_cdf(Z_95) - 0.975).abs() < 1e-6);
+        assert!((normal_cdf(-1.0) - 0.158655).abs() < 1e-6);
+    }
+
+    #[test]
+    fn values_are_read_with_or_without_header() {
+        assert_eq!(
+            parse_values("28053862025242,31083\n28054066235851,21000\n", None).unwrap(),
+            vec![31083.0, 21000.0]
+        );
+        assert_eq!(
+            parse_values("3323197736,10300476960162,185624\n", None).unwrap(),
+            vec![185624.0]
+        );
+
+        let benchmark = "node,term,ended_ns,duration_ns\n10.77.0.1,1,1000,57502\n";
+        assert_eq!(parse_values(benchmark, None).unwrap(), vec![57502.0]);
+        assert_eq!(
+            parse_values(benchmark, Some("ended_ns")).unwrap(),
+            vec![1000.0]
+        );
+        assert_eq!(parse_values(benchmark, Some("1")).unwrap(), vec![1.0]);
+
+        assert!(parse_values(benchmark, Some("rtt_ns")).is_err());
+        assert!(parse_values("1,2\n3,x\n", None).is_err());
+    }
+
+    #[test]
+    fn histogram_counts_every_value() {
+        let values = one_to(100);
+        let edges = bin_edges(1.0, 100.0, 10, false).unwrap();
+        let counts = histogram(&values, &edges);
+        assert_eq!(counts.iter().sum::<usize>(), 100);
+        assert_eq!(counts[9], 10); // Including the maximum.
+
+        let edges = bin_edges(1.0, 1000.0, 3, true).unwrap();
+        assert!((edges[1] - 10.0).abs() < 1e-9 && (edges[2] - 100.0).abs() < 1e-9);
+        assert_eq!(histogram(&[1.0, 5.0, 50.0, 1000.0], &edges), vec![2, 1, 1]);
+        assert!(bin_edges(0.0, 1.0, 3, true).is_err());
+    }
+}
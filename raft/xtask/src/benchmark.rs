@@ -0,0 +1,470 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context as _};
+use clap::{Parser, ValueEnum};
+use nix::time::{clock_gettime, ClockId};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::build_ebpf::{build_ebpf, Architecture, Options as BuildOptions};
+use crate::integration_test::{wait_for_leader, without};
+use crate::netns::{http_get, Cluster};
+use crate::run::build;
+use crate::workload::Workload;
+
+/// Keys the load clients operate on
+const LOAD_KEYS: usize = 16;
+
+/// Heartbeats are sent every 50 ms; polling more often catches every response.
+const HEARTBEAT_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+#[derive(Debug, Parser)]
+pub struct Options {
+    /// Set the endianness of the BPF target
+    #[clap(default_value = "bpfel-unknown-none", long)]
+    pub bpf_target: Architecture,
+    /// Build and run the release target
+    #[clap(long)]
+    pub release: bool,
+    /// The command used to wrap privileged commands and nodes
+    #[clap(short, long, default_value = "sudo -E")]
+    pub runner: String,
+    /// Number of nodes, each in its own network namespace. Only 3 are supported.
+    #[clap(long, default_value = "3")]
+    pub nodes: usize,
+    /// Leader failures to measure
+    #[clap(long, default_value = "30")]
+    pub failovers: usize,
+    /// How the leader fails
+    #[clap(long, value_enum, default_value = "kill")]
+    pub failure: Failure,
+    /// Time to measure heartbeats for between failovers, in milliseconds
+    #[clap(long, default_value = "2000")]
+    pub interval_ms: u64,
+    /// Time allowed for a single leader to emerge after each failure, in milliseconds
+    #[clap(long, default_value = "10000")]
+    pub timeout_ms: u64,
+    /// Clients putting load on the cluster through the key-value API
+    #[clap(long, default_value = "0")]
+    pub clients: usize,
+    /// Directory receiving the measurements, their metadata and the nodes' logs
+    #[clap(long, default_value = "target/benchmark")]
+    pub out: PathBuf,
+    /// Arguments to pass to every node
+    #[clap(name = "args", last = true)]
+    pub node_args: Vec<String>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Failure {
+    /// Kill the leader with SIGKILL; followers notice it is gone after the leader timeout
+    Kill,
+    /// Stop the leader with SIGTERM; it tells a follower to start an election right away
+    StepDown,
+    /// Nodes run with --simulate-crashes: the leader stops sending heartbeats every 30
+    /// heartbeats, as in the thesis measurements. Elections are recorded, failover times are not
+    Simulated,
+}
+
+/// Conditions of a run, written to metadata.json
+#[derive(Debug, Serialize)]
+struct Metadata {
+    started_at: u64,
+    commit: Option<String>,
+    kernel: Option<String>,
+    profile: &'static str,
+    cluster_size: usize,
+    transport: Value,
+    xdp_mode: Value,
+    thread_model: Value,
+    failure: Failure,
+    failovers: usize,
+    interval_ms: u64,
+    clients: usize,
+    node_args: Vec<String>,
+}
+
+/// Build raft-main, start a cluster in network namespaces and fail its leader repeatedly,
+/// recording election durations, failover times and heartbeat round trips.
+///
+/// Durations are measured by the nodes. Failover times compare the new leader's clock with
+/// ours, which is the same monotonic clock as all nodes share the host.
+pub fn benchmark(opts: Options) -> Result<(), anyhow::Error> {
+    // CurrentNode holds two peers and a quorum is two votes, so 5-node measurements cannot be
+    // reproduced. The thread model is a node argument (`-- --thread-model multi-thread`).
+    if opts.nodes != 3 {
+        bail!(
+            "raft-main supports clusters of 3 nodes only (CurrentNode holds two peers), not {}",
+            opts.nodes
+        );
+    }
+
+    build_ebpf(BuildOptions {
+        target: opts.bpf_target,
+        release: opts.release,
+    })
+    .context("Error while building eBPF program")?;
+    build(opts.release).context("Error while building userspace application")?;
+
+    let profile = if opts.release { "release" } else { "debug" };
+    let bin_path = std::env::current_dir()?.join(format!("target/{profile}/raft-main"));
+
+    fs::create_dir_all(&opts.out)?;
+    let mut node_args = opts.node_args.clone();
+    if opts.failure == Failure::Simulated {
+        node_args.push("--simulate-crashes".to_owned());
+    }
+    let mut cluster = Cluster::create(
+        opts.nodes,
+        &opts.runner,
+        &bin_path,
+        node_args,
+        &opts.out.join("logs"),
+    )?;
+    for i in 0..opts.nodes {
+        cluster.start(i)?;
+    }
+
+    let all: Vec<usize> = (0..cluster.nodes.len()).collect();
+    let timeout = Duration::from_millis(opts.timeout_ms);
+    let addrs: Vec<Ipv4Addr> = cluster.nodes.iter().map(|node| node.addr).collect();
+    let (mut leader, mut term) = wait_for_leader(&cluster, &all, 1, timeout)?;
+
+    let status = cluster.status(leader)?;
+    write_json(
+        &opts.out.join("metadata.json"),
+        &Metadata {
+            started_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            commit: command_output("git", &["rev-parse", "HEAD"]),
+            kernel: command_output("uname", &["-r"]),
+            profile,
+            cluster_size: opts.nodes,
+            transport: status["transport"].clone(),
+            xdp_mode: status["xdp_mode"].clone(),
+            thread_model: status["thread_model"].clone(),
+            failure: opts.failure,
+            failovers: opts.failovers,
+            interval_ms: opts.interval_ms,
+            clients: opts.clients,
+            node_args: opts.node_args.clone(),
+        },
+    )?;
+
+    let mut elections = csv(
+        &opts.out.join("elections.csv"),
+        "node,term,ended_ns,duration_ns",
+    )?;
+    let mut failovers = csv(
+        &opts.out.join("failovers.csv"),
+        "term,old_leader,new_leader,failover_ns",
+    )?;
+    let sampler = HeartbeatSampler::start(addrs.clone());
+    let workload =
+        (opts.clients > 0).then(|| Workload::start(addrs, opts.clients, LOAD_KEYS, false));
+
+    let result = (|| -> Result<(), anyhow::Error> {
+        let (ended_ns, duration_ns) = last_election(&cluster, leader, term)?;
+        let addr = cluster.nodes[leader].addr;
+        writeln!(elections, "{addr},{term},{ended_ns},{duration_ns}")?;
+
+        for n in 0..opts.failovers {
+            if opts.failure == Failure::Simulated {
+                // The leader fails on its own; nodes are neither stopped nor restarted.
+                println!("Failover {}/{}", n + 1, opts.failovers);
+                (leader, term) = wait_for_leader(&cluster, &all, term + 1, timeout)?;
+                let (ended_ns, duration_ns) = last_election(&cluster, leader, term)?;
+                let addr = cluster.nodes[leader].addr;
+                writeln!(elections, "{addr},{term},{ended_ns},{duration_ns}")?;
+                continue;
+            }
+
+            thread::sleep(Duration::from_millis(opts.interval_ms));
+            println!("Failover {}/{}", n + 1, opts.failovers);
+
+            let failed_ns = monotonic_ns();
+            match opts.failure {
+                Failure::Kill => cluster.kill(leader)?,
+                Failure::StepDown => cluster.stop(leader)?,
+                Failure::Simulated => unreachable!(),
+            }
+            let rest = without(&all, leader);
+            let (new_leader, new_term) = wait_for_leader(&cluster, &rest, term + 1, timeout)?;
+
+            let (ended_ns, duration_ns) = last_election(&cluster, new_leader, new_term)?;
+            if ended_ns < failed_ns {
+                bail!(
+                    "{} was elected before the failure",
+                    cluster.nodes[new_leader].addr
+                );
+            }
+            let addr = cluster.nodes[new_leader].addr;
+            writeln!(elections, "{addr},{new_term},{ended_ns},{duration_ns}")?;
+            writeln!(
+                failovers,
+                "{new_term},{},{addr},{}",
+                cluster.nodes[leader].addr,
+                ended_ns - failed_ns
+            )?;
+
+            cluster.start(leader)?;
+            (leader, term) = wait_for_leader(&cluster, &all, new_term, timeout)?;
+        }
+        Ok(())
+    })();
+
+    if let Some(workload) = workload {
+        workload.stop();
+    }
+    let heartbeats = sampler.stop();
+    let mut file = csv(
+        &opts.out.join("heartbeats.csv"),
+        "leader,follower,sent_ns,rtt_ns",
+    )?;
+    for sample in &heartbeats {
+        writeln!(
+            file,
+            "{},{},{},{}",
+            sample.leader, sample.follower, sample.sent_ns, sample.rtt_ns
+        )?;
+    }
+    file.flush()?;
+    elections.flush()?;
+    failovers.flush()?;
+
+    result.with_context(|| format!("node logs are in {}", opts.out.join("logs").display()))?;
+    println!(
+        "Recorded {} failovers and {} heartbeats in {}",
+        opts.failovers,
+        heartbeats.len(),
+        opts.out.display()
+    );
+    Ok(())
+}
+
+/// End on the node's clock and duration of the election node `i` won for `term`
+fn last_election(cluster: &Cluster, i: usize, term: u64) -> Result<(u64, u64), anyhow::Error> {
+    let status = cluster.status(i)?;
+    let election = &status["last_election"];
+    match (
+        status["term"].as_u64(),
+        election["ended_ns"].as_u64(),
+        election["duration_ns"].as_u64(),
+    ) {
+        (Some(t), Some(ended_ns), Some(duration_ns)) if t == term => Ok((ended_ns, duration_ns)),
+        _ => bail!(
+            "{} reports no election won for term {term}",
+            cluster.nodes[i].addr
+        ),
+    }
+}
+
+/// Same clock as the nodes' vote timestamps
+fn monotonic_ns() -> u64 {
+    Duration::from(clock_gettime(ClockId::CLOCK_MONOTONIC).unwrap()).as_nanos() as u64
+}
+
+fn csv(path: &Path, header: &str) -> Result<BufWriter<File>, anyhow::Error> {
+    let file =
+        File::create(path).with_context(|| format!("failed to create {}", path.display()))?;
+    let mut file = BufWriter::new(file);
+    writeln!(file, "{header}")?;
+    Ok(file)
+}
+
+fn write_json(path: &Path, value: &impl Serialize) -> Result<(), anyhow::Error> {
+    fs::write(path, serde_json::to_vec_pretty(value)?)
+        .with_context(|| format!("failed to write {}", path.display()))
+}
+
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+struct HeartbeatSample {
+    leader: Ipv4Addr,
+    follower: Ipv4Addr,
+    sent_ns: u64,
+    rtt_ns: u64,
+}
+
+/// Latest heartbeat sent by a leader to a follower, with the round trip reported meanwhile
+struct Pending {
+    sent_ns: u64,
+    rtt_ns: u64,
+    /// Whether the round trip was measured for this heartbeat rather than an earlier one
+    answered: bool,
+}
+
+/// Polls every node's /followers/list in the background. A leader reports when it sent the
+/// latest heartbeat to each follower and the round trip of the latest answered one. A heartbeat
+/// is recorded once the next one is sent, with the last round trip reported meanwhile, if that
+/// changed from the previous heartbeat's.
+struct HeartbeatSampler {
+    samples: Arc<Mutex<Vec<HeartbeatSample>>>,
+    stopped: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+}
+
+/// Record a poll of the heartbeat sent by `leader` to `follower`. Once the next heartbeat has
+/// been sent, returns the previous one if its round trip was measured.
+fn observe(
+    pending: &mut HashMap<(Ipv4Addr, Ipv4Addr), Pending>,
+    leader: Ipv4Addr,
+    follower: Ipv4Addr,
+    sent_ns: u64,
+    rtt_ns: u64,
+) -> Option<HeartbeatSample> {
+    let Some(latest) = pending.get_mut(&(leader, follower)) else {
+        // The round trip first seen may be that of a heartbeat answered long ago.
+        pending.insert(
+            (leader, follower),
+            Pending {
+                sent_ns,
+                rtt_ns,
+                answered: false,
+            },
+        );
+        return None;
+    };
+
+    if latest.sent_ns == sent_ns {
+        if latest.rtt_ns != rtt_ns {
+            latest.rtt_ns = rtt_ns;
+            latest.answered = true;
+        }
+        return None;
+    }
+
+    // The next heartbeat went out; it may already have been answered too.
+    let next = Pending {
+        sent_ns,
+        rtt_ns,
+        answered: latest.rtt_ns != rtt_ns,
+    };
+    let previous = std::mem::replace(latest, next);
+    previous.answered.then_some(HeartbeatSample {
+        leader,
+        follower,
+        sent_ns: previous.sent_ns,
+        rtt_ns: previous.rtt_ns,
+    })
+}
+
+impl HeartbeatSampler {
+    fn start(addrs: Vec<Ipv4Addr>) -> HeartbeatSampler {
+        let samples = Arc::new(Mutex::new(Vec::new()));
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        let handle = {
+            let samples = samples.clone();
+            let stopped = stopped.clone();
+            thread::spawn(move || {
+                let mut pending: HashMap<(Ipv4Addr, Ipv4Addr), Pending> = HashMap::new();
+
+                while !stopped.load(Ordering::Relaxed) {
+                    for addr in &addrs {
+                        let followers = match http_get(*addr, "/followers/list") {
+                            Ok(response) => response["data"].clone(),
+                            Err(_) => continue,
+                        };
+                        for follower in followers.as_array().into_iter().flatten() {
+                            let (Some(ip), Some(rtt_ns), Some(sent_ns)) = (
+                                follower["ip"].as_str(),
+                                follower["latency_ns"].as_u64(),
+                                follower["last_seen_epoch"].as_u64(),
+                            ) else {
+                                continue;
+                            };
+                            let Ok(follower) = ip.parse() else {
+                                continue;
+                            };
+
+                            if let Some(sample) =
+                                observe(&mut pending, *addr, follower, sent_ns, rtt_ns)
+                            {
+                                samples.lock().unwrap().push(sample);
+                            }
+                        }
+                    }
+                    thread::sleep(HEARTBEAT_POLL_INTERVAL);
+                }
+            })
+        };
+
+        HeartbeatSampler {
+            samples,
+            stopped,
+            handle,
+        }
+    }
+
+    fn stop(self) -> Vec<HeartbeatSample> {
+        self.stopped.store(true, Ordering::Relaxed);
+        let _ = self.handle.join();
+        Arc::try_unwrap(self.samples)
+            .map(|samples| samples.into_inner().unwrap())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEADER: Ipv4Addr = Ipv4Addr::new(10, 77, 0, 1);
+    const FOLLOWER: Ipv4Addr = Ipv4Addr::new(10, 77, 0, 2);
+
+    fn polls(observations: &[(u64, u64)]) -> Vec<(u64, u64)> {
+        let mut pending = HashMap::new();
+        observations
+            .iter()
+            .filter_map(|(sent_ns, rtt_ns)| {
+                observe(&mut pending, LEADER, FOLLOWER, *sent_ns, *rtt_ns)
+            })
+            .map(|sample| (sample.sent_ns, sample.rtt_ns))
+            .collect()
+    }
+
+    #[test]
+    fn heartbeats_are_sampled_once_answered() {
+        let samples = polls(&[
+            (100, 7),  // Round trip of a heartbeat before the first seen.
+            (200, 7),  // Not answered yet,
+            (200, 30), // now answered.
+            (200, 30),
+            (300, 30), // Sent, not answered.
+            (400, 25), // Sent and answered between polls.
+            (500, 25),
+        ]);
+
+        assert_eq!(samples, vec![(200, 30), (400, 25)]);
+    }
+
+    #[test]
+    fn equal_round_trips_of_distinct_heartbeats_are_kept() {
+        let samples = polls(&[
+            (100, 7),
+            (200, 9),
+            (200, 30),
+            (300, 9),
+            (300, 30),
+            (400, 30),
+        ]);
+
+        assert_eq!(samples, vec![(200, 30), (300, 30)]);
+    }
+}
@@ -0,0 +1,56 @@
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{bail, Context as _};
+use clap::Parser;
+
+use crate::linearizability::{self, Operation};
+
+#[derive(Debug, Parser)]
+pub struct Options {
+    /// History recorded by `integration-test --linearizability`
+    pub history: PathBuf,
+}
+
+/// Check a recorded history for linearizability
+pub fn check_history(opts: Options) -> Result<(), anyhow::Error> {
+    let history =
+        fs::read(&opts.history).with_context(|| format!("failed to read {:?}", opts.history))?;
+    let history: Vec<Operation> = serde_json::from_slice(&history)?;
+
+    check(&history)
+}
+
+/// Check a history, printing the minimal non-linearizable subhistory of each key that is not
+/// linearizable
+pub fn check(history: &[Operation]) -> Result<(), anyhow::Error> {
+    let violations = linearizability::check(history);
+    let pending = history.iter().filter(|op| op.is_pending()).count();
+    println!(
+        "Checked {} operations ({pending} with unknown outcome)",
+        history.len()
+    );
+
+    for violation in &violations {
+        println!(
+            "  {} is not linearizable; minimal subhistory:",
+            violation.key
+        );
+        let mut operations: Vec<(&Operation, bool)> = violation
+            .operations
+            .iter()
+            .map(|operation| (operation, true))
+            .chain(violation.writes.iter().map(|operation| (operation, false)))
+            .collect();
+        operations.sort_by_key(|(operation, _)| operation.call_ns);
+        for (operation, needed) in operations {
+            let note = if needed { "" } else { " (result not needed)" };
+            println!("    {operation}{note}");
+        }
+    }
+    if !violations.is_empty() {
+        let keys: Vec<&str> = violations.iter().map(|v| v.key.as_str()).collect();
+        bail!("history is not linearizable on {}", keys.join(", "));
+    }
+    Ok(())
+}
@@ -0,0 +1,126 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context as _};
+use clap::Parser;
+use raft_main_common::packet::{self, Protocol, PAYLOAD_OFFSET};
+use raft_main_common::{
+    HEARTBEAT_REQUEST_PORT, HEARTBEAT_RESPONSE_PORT, KV_GET_PORT, LEADER_STEP_DOWN_PORT,
+    VOTE_REQUEST_PORT, VOTE_RESPONSE_PORT_NO, VOTE_RESPONSE_PORT_YES,
+};
+
+const PCAP_MAGIC: u32 = 0xa1b2c3d4; // Microsecond timestamps, in the writer's byte order.
+const PCAP_MAGIC_NS: u32 = 0xa1b23c4d;
+const LINKTYPE_ETHERNET: u32 = 1;
+
+/// Ports whose messages the XDP program parses
+const PORTS: [u16; 7] = [
+    VOTE_REQUEST_PORT,
+    VOTE_RESPONSE_PORT_YES,
+    VOTE_RESPONSE_PORT_NO,
+    HEARTBEAT_REQUEST_PORT,
+    HEARTBEAT_RESPONSE_PORT,
+    LEADER_STEP_DOWN_PORT,
+    KV_GET_PORT,
+];
+
+#[derive(Debug, Parser)]
+pub struct Options {
+    /// Capture of Ethernet frames (e.g. `tcpdump -i eth0 -w raft.pcap udp`)
+    pub pcap: PathBuf,
+    /// Directory holding the corpus of each fuzz target
+    #[clap(long, default_value = "fuzz/corpus")]
+    pub corpus_dir: PathBuf,
+    /// Seeds kept per destination port and payload length, as captures repeat heartbeats
+    #[clap(long, default_value = "2")]
+    pub per_kind: usize,
+}
+
+/// Seed the fuzz corpora with the Raft messages of a packet capture: whole frames for the
+/// `packet` target and their UDP payloads for the `codec` target.
+pub fn fuzz_corpus(opts: Options) -> Result<(), anyhow::Error> {
+    let capture =
+        fs::read(&opts.pcap).with_context(|| format!("failed to read {:?}", opts.pcap))?;
+    let frames = pcap_frames(&capture)?;
+
+    let (mut messages, mut seeds) = (0, 0);
+    let mut kinds: HashMap<(u16, usize), usize> = HashMap::new();
+    for frame in &frames {
+        let header = match packet::parse_header(frame.as_slice()) {
+            Ok(Some(header)) => header,
+            _ => continue,
+        };
+        if header.protocol != Protocol::Udp || !PORTS.contains(&header.dest_port) {
+            continue;
+        }
+
+        messages += 1;
+        let kind = kinds
+            .entry((header.dest_port, frame.len() - PAYLOAD_OFFSET))
+            .or_default();
+        if *kind == opts.per_kind {
+            continue;
+        }
+        *kind += 1;
+
+        seeds += write_seed(&opts.corpus_dir.join("packet"), frame)? as usize;
+        write_seed(&opts.corpus_dir.join("codec"), &frame[PAYLOAD_OFFSET..])?;
+    }
+
+    println!(
+        "{messages} of {} frames are Raft messages; {seeds} new seeds written to {:?}",
+        frames.len(),
+        opts.corpus_dir
+    );
+    Ok(())
+}
+
+/// Frames of a classic pcap file with Ethernet link type
+fn pcap_frames(capture: &[u8]) -> Result<Vec<Vec<u8>>, anyhow::Error> {
+    if capture.len() < 24 {
+        bail!("not a pcap file");
+    }
+
+    let magic = u32::from_le_bytes(capture[0..4].try_into()?);
+    let read_u32: fn(&[u8]) -> u32 = match magic {
+        PCAP_MAGIC | PCAP_MAGIC_NS => |bytes| u32::from_le_bytes(bytes.try_into().unwrap()),
+        _ if magic.swap_bytes() == PCAP_MAGIC || magic.swap_bytes() == PCAP_MAGIC_NS => {
+            |bytes| u32::from_be_bytes(bytes.try_into().unwrap())
+        }
+        _ => bail!("not a pcap file (pcapng is not supported)"),
+    };
+    if read_u32(&capture[20..24]) != LINKTYPE_ETHERNET {
+        bail!("capture is not of Ethernet frames");
+    }
+
+    let mut frames = Vec::new();
+    let mut offset = 24;
+    while offset + 16 <= capture.len() {
+        let len = read_u32(&capture[offset + 8..offset + 12]) as usize;
+        let start = offset + 16;
+        let frame = capture
+            .get(start..start + len)
+            .context("capture ends within a frame")?;
+        frames.push(frame.to_vec());
+        offset = start + len;
+    }
+    Ok(frames)
+}
+
+/// Write a seed named after its content; returns whether it is new
+fn write_seed(dir: &Path, seed: &[u8]) -> Result<bool, anyhow::Error> {
+    fs::create_dir_all(dir)?;
+
+    let mut hasher = DefaultHasher::new();
+    seed.hash(&mut hasher);
+    let path = dir.join(format!("{:016x}", hasher.finish()));
+    if path.exists() {
+        return Ok(false);
+    }
+
+    fs::write(&path, seed).with_context(|| format!("failed to write {:?}", path))?;
+    Ok(true)
+}
@@ -0,0 +1,307 @@
+use std::collections::HashMap;
+use std::fs;
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context as _};
+use clap::{Parser, ValueEnum};
+
+use crate::build_ebpf::{build_ebpf, Architecture, Options as BuildOptions};
+use crate::check_history;
+use crate::netns::{http_get, Cluster};
+use crate::run::build;
+use crate::workload::Workload;
+
+#[derive(Debug, Parser)]
+pub struct Options {
+    /// Set the endianness of the BPF target
+    #[clap(default_value = "bpfel-unknown-none", long)]
+    pub bpf_target: Architecture,
+    /// Build and run the release target
+    #[clap(long)]
+    pub release: bool,
+    /// The command used to wrap privileged commands and nodes
+    #[clap(short, long, default_value = "sudo -E")]
+    pub runner: String,
+    /// Number of nodes, each in its own network namespace
+    #[clap(long, default_value = "3")]
+    pub nodes: usize,
+    /// Scenario to run; may be repeated. Runs all scenarios by default
+    #[clap(long, value_enum)]
+    pub scenario: Vec<Scenario>,
+    /// Time allowed for a single leader to emerge after each step, in milliseconds
+    #[clap(long, default_value = "10000")]
+    pub timeout_ms: u64,
+    /// Record a history of client operations during the scenarios and check it is linearizable
+    #[clap(long)]
+    pub linearizability: bool,
+    /// Clients recording the history
+    #[clap(long, default_value = "3")]
+    pub clients: usize,
+    /// Keys the clients operate on
+    #[clap(long, default_value = "4")]
+    pub keys: usize,
+    /// Read with the leader lease rather than ReadIndex
+    #[clap(long)]
+    pub lease_reads: bool,
+    /// Directory receiving the nodes' logs, and the history
+    #[clap(long, default_value = "target/integration-test")]
+    pub log_dir: PathBuf,
+    /// Arguments to pass to every node
+    #[clap(name = "args", last = true)]
+    pub node_args: Vec<String>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, ValueEnum)]
+pub enum Scenario {
+    /// Kill the leader with SIGKILL, then restart it
+    LeaderKill,
+    /// Cut the leader off from the other nodes, then reconnect it
+    LeaderPartition,
+    /// Cut a follower off from the other nodes, then reconnect it
+    FollowerPartition,
+}
+
+/// Build raft-main, start a cluster in network namespaces and run the scenarios against it.
+/// Throughout, no two nodes may claim leadership of the same term, and with `--linearizability`
+/// the history of concurrent clients must be linearizable.
+pub fn integration_test(opts: Options) -> Result<(), anyhow::Error> {
+    // The eBPF program stores two peers and a quorum is two votes.
+    if opts.nodes != 3 {
+        bail!("raft-main supports clusters of 3 nodes");
+    }
+
+    build_ebpf(BuildOptions {
+        target: opts.bpf_target,
+        release: opts.release,
+    })
+    .context("Error while building eBPF program")?;
+    build(opts.release).context("Error while building userspace application")?;
+
+    let profile = if opts.release { "release" } else { "debug" };
+    let bin_path = std::env::current_dir()?.join(format!("target/{profile}/raft-main"));
+
+    let mut cluster = Cluster::create(
+        opts.nodes,
+        &opts.runner,
+        &bin_path,
+        opts.node_args.clone(),
+        &opts.log_dir,
+    )?;
+    for i in 0..opts.nodes {
+        cluster.start(i)?;
+    }
+
+    let addrs: Vec<Ipv4Addr> = cluster.nodes.iter().map(|node| node.addr).collect();
+    let monitor = Monitor::start(addrs.clone());
+    let workload = opts
+        .linearizability
+        .then(|| Workload::start(addrs, opts.clients, opts.keys, opts.lease_reads));
+    let timeout = Duration::from_millis(opts.timeout_ms);
+
+    let scenarios = if opts.scenario.is_empty() {
+        Scenario::value_variants().to_vec()
+    } else {
+        opts.scenario.clone()
+    };
+
+    let mut result = Ok(());
+    for scenario in scenarios {
+        println!("Running {scenario:?}");
+        result = run_scenario(&mut cluster, scenario, timeout).and_then(|_| monitor.check());
+        if let Err(err) = &result {
+            println!("{scenario:?} failed: {err:#}");
+            break;
+        }
+        println!("{scenario:?} passed");
+    }
+
+    monitor.stop();
+    if let Some(workload) = workload {
+        let history = workload.stop();
+        let path = opts.log_dir.join("history.json");
+        fs::write(&path, serde_json::to_vec(&history)?)
+            .with_context(|| format!("failed to write {}", path.display()))?;
+        println!("History written to {}", path.display());
+
+        result = result.and_then(|_| check_history::check(&history));
+    }
+    result
+        .and_then(|_| monitor.check())
+        .with_context(|| format!("node logs are in {}", opts.log_dir.display()))
+}
+
+fn run_scenario(
+    cluster: &mut Cluster,
+    scenario: Scenario,
+    timeout: Duration,
+) -> Result<(), anyhow::Error> {
+    let all: Vec<usize> = (0..cluster.nodes.len()).collect();
+    let (leader, term) = wait_for_leader(cluster, &all, 1, timeout)?;
+
+    match scenario {
+        Scenario::LeaderKill => {
+            println!("  Killing leader {}", cluster.nodes[leader].addr);
+            cluster.kill(leader)?;
+            let rest = without(&all, leader);
+            let (_, new_term) = wait_for_leader(cluster, &rest, term + 1, timeout)?;
+
+            println!("  Restarting {}", cluster.nodes[leader].addr);
+            cluster.start(leader)?;
+            wait_for_leader(cluster, &all, new_term, timeout)?;
+        }
+        Scenario::LeaderPartition => {
+            println!("  Isolating leader {}", cluster.nodes[leader].addr);
+            cluster.isolate(leader)?;
+            let rest = without(&all, leader);
+            let (_, new_term) = wait_for_leader(cluster, &rest, term + 1, timeout)?;
+
+            println!("  Reconnecting {}", cluster.nodes[leader].addr);
+            cluster.rejoin(leader)?;
+            wait_for_leader(cluster, &all, new_term, timeout)?;
+        }
+        Scenario::FollowerPartition => {
+            let follower = (leader + 1) % all.len();
+            println!("  Isolating follower {}", cluster.nodes[follower].addr);
+            cluster.isolate(follower)?;
+            let rest = without(&all, follower);
+            let (_, new_term) = wait_for_leader(cluster, &rest, term, timeout)?;
+
+            println!("  Reconnecting {}", cluster.nodes[follower].addr);
+            cluster.rejoin(follower)?;
+            wait_for_leader(cluster, &all, new_term, timeout)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn without(nodes: &[usize], excluded: usize) -> Vec<usize> {
+    nodes.iter().copied().filter(|i| *i != excluded).collect()
+}
+
+/// Wait until exactly one of `nodes` is leader, in a term of at least `min_term`, and the others
+/// follow it in that term. Returns the leader and its term.
+pub fn wait_for_leader(
+    cluster: &Cluster,
+    nodes: &[usize],
+    min_term: u64,
+    timeout: Duration,
+) -> Result<(usize, u64), anyhow::Error> {
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        let statuses: Vec<Option<(String, u64)>> = nodes
+            .iter()
+            .map(|i| {
+                let status = cluster.status(*i).ok()?;
+                Some((
+                    status["state"].as_str()?.to_owned(),
+                    status["term"].as_u64()?,
+                ))
+            })
+            .collect();
+
+        let leaders: Vec<(usize, u64)> = nodes
+            .iter()
+            .zip(&statuses)
+            .filter_map(|(i, status)| match status {
+                Some((state, term)) if state == "Leader" && *term >= min_term => Some((*i, *term)),
+                _ => None,
+            })
+            .collect();
+
+        if let [(leader, term)] = leaders[..] {
+            let converged = statuses.iter().all(|status| {
+                matches!(status, Some((state, t)) if *t == term && (state == "Follower" || state == "Leader"))
+            });
+            if converged {
+                println!("  {} leads term {}", cluster.nodes[leader].addr, term);
+                return Ok((leader, term));
+            }
+        }
+
+        if Instant::now() > deadline {
+            let addrs: Vec<String> = nodes
+                .iter()
+                .zip(&statuses)
+                .map(|(i, status)| format!("{} {:?}", cluster.nodes[*i].addr, status))
+                .collect();
+            bail!(
+                "no single leader in term {} or later: {}",
+                min_term,
+                addrs.join(", ")
+            );
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// Polls every node's /status in the background, recording which node claimed leadership of
+/// each term. Two leaders in one term violate election safety.
+struct Monitor {
+    violations: Arc<Mutex<Vec<String>>>,
+    stopped: Arc<AtomicBool>,
+    handle: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Monitor {
+    fn start(addrs: Vec<Ipv4Addr>) -> Monitor {
+        let violations = Arc::new(Mutex::new(Vec::new()));
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        let handle = {
+            let violations = violations.clone();
+            let stopped = stopped.clone();
+            thread::spawn(move || {
+                let mut leaders: HashMap<u64, Ipv4Addr> = HashMap::new();
+
+                while !stopped.load(Ordering::Relaxed) {
+                    for addr in &addrs {
+                        let status = match http_get(*addr, "/status") {
+                            Ok(response) => response["data"].clone(),
+                            Err(_) => continue,
+                        };
+                        if status["state"] != "Leader" {
+                            continue;
+                        }
+                        let term = status["term"].as_u64().unwrap_or_default();
+
+                        let leader = leaders.entry(term).or_insert(*addr);
+                        if leader != addr {
+                            violations
+                                .lock()
+                                .unwrap()
+                                .push(format!("{leader} and {addr} both lead term {term}"));
+                        }
+                    }
+                    thread::sleep(Duration::from_millis(20));
+                }
+            })
+        };
+
+        Monitor {
+            violations,
+            stopped,
+            handle: Mutex::new(Some(handle)),
+        }
+    }
+
+    fn check(&self) -> Result<(), anyhow::Error> {
+        let violations = self.violations.lock().unwrap();
+        if !violations.is_empty() {
+            bail!("election safety violated: {}", violations.join("; "));
+        }
+        Ok(())
+    }
+
+    fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.lock().unwrap().take() {
+            let _ = handle.join();
+        }
+    }
+}
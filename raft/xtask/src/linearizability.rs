@@ -0,0 +1,596 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// Operation of a client on one key of the key-value store
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Input {
+    Read,
+    /// Put, or delete for `None`
+    Write(Option<String>),
+}
+
+/// Value read, or the previous value returned by a write
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Output {
+    Value(Option<String>),
+    /// The request failed after it was sent, so a write may or may not have been applied
+    Unknown,
+}
+
+/// Operation of a history, with the times it was called and returned. Operations whose output
+/// is unknown never return.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Operation {
+    pub client: usize,
+    pub key: String,
+    pub input: Input,
+    pub output: Output,
+    pub call_ns: u64,
+    pub return_ns: u64,
+}
+
+impl Operation {
+    pub fn is_pending(&self) -> bool {
+        self.output == Output::Unknown
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let input = match &self.input {
+            Input::Read => "read".to_owned(),
+            Input::Write(Some(value)) => format!("put {value:?}"),
+            Input::Write(None) => "delete".to_owned(),
+        };
+        let output = match (&self.input, &self.output) {
+            (_, Output::Unknown) => "unknown".to_owned(),
+            (Input::Read, Output::Value(value)) => format!("{value:?}"),
+            (Input::Write(_), Output::Value(value)) => format!("previous {value:?}"),
+        };
+        let returned = if self.is_pending() {
+            "never returned".to_owned()
+        } else {
+            format!("{:.3}s", self.return_ns as f64 / 1e9)
+        };
+
+        write!(
+            f,
+            "client {} {} {} -> {} ({:.3}s to {})",
+            self.client,
+            self.key,
+            input,
+            output,
+            self.call_ns as f64 / 1e9,
+            returned
+        )
+    }
+}
+
+/// Non-linearizable operations on a key: every operation is needed to show the violation
+#[derive(Debug)]
+pub struct Violation {
+    pub key: String,
+    pub operations: Vec<Operation>,
+    /// Other writes of the values the operations observe. They take effect between their call
+    /// and return, but what they returned is not needed to show the violation.
+    pub writes: Vec<Operation>,
+}
+
+/// Check that a history of the key-value store is linearizable. Keys are independent registers,
+/// so each is checked on its own; for each key that is not linearizable, the violation is
+/// reduced to a minimal subhistory.
+pub fn check(history: &[Operation]) -> Vec<Violation> {
+    let mut keys: HashMap<&str, Vec<&Operation>> = HashMap::new();
+    for operation in history {
+        keys.entry(&operation.key).or_default().push(operation);
+    }
+
+    let mut violations: Vec<Violation> = keys
+        .into_iter()
+        .filter(|(_, operations)| !linearizable(operations, &[]))
+        .map(|(key, operations)| {
+            let (operations, writes) = minimize(&operations);
+            Violation {
+                key: key.to_owned(),
+                operations,
+                writes,
+            }
+        })
+        .collect();
+    violations.sort_by(|a, b| a.key.cmp(&b.key));
+    violations
+}
+
+/// Check if operations on a single key, and writes whose results are ignored, can be ordered so
+/// that each takes effect at some point between its call and return, and sees the effects of
+/// those before it.
+///
+/// This is the algorithm of Wing and Gong with the state cache of Lowe, as in Knossos and
+/// Porcupine: operations are linearized in turn, in an order consistent with real time, and
+/// the search backtracks when an operation returns before it could be linearized.
+fn linearizable(operations: &[&Operation], writes: &[&Operation]) -> bool {
+    let all: Vec<&Operation> = operations.iter().chain(writes).copied().collect();
+    let steps = steps(&all, operations.len());
+    let mut events = Events::new(&all);
+
+    let mut stack: Vec<(usize, u32)> = Vec::new(); // Linearized call events, and the state before.
+    let mut linearized = Bitset::new(steps.len());
+    let mut cache: HashSet<(Bitset, u32)> = HashSet::new();
+    let mut state = 0; // Absent.
+
+    let mut entry = events.first();
+    loop {
+        let event = match entry {
+            Some(event) => event,
+            None => return true,
+        };
+
+        let (operation, is_call) = events.events[event];
+        if !is_call {
+            // The operation returned without being linearized; undo the last choice.
+            let (call, previous_state) = match stack.pop() {
+                Some(x) => x,
+                None => return false,
+            };
+            let (operation, _) = events.events[call];
+            linearized.remove(operation);
+            state = previous_state;
+            events.unlift(call);
+            entry = events.next[call];
+            continue;
+        }
+
+        if let Some(next_state) = steps[operation].apply(state) {
+            let mut next_linearized = linearized.clone();
+            next_linearized.insert(operation);
+            if cache.insert((next_linearized.clone(), next_state)) {
+                stack.push((event, state));
+                linearized = next_linearized;
+                state = next_state;
+                events.lift(event);
+                entry = events.first();
+                continue;
+            }
+        }
+        entry = events.next[event];
+    }
+}
+
+/// Reduce the non-linearizable operations of a key to a subset which is linearizable without
+/// any one of its operations (delta debugging). Returns the subset, and the other writes of the
+/// values it observes: without those, reads would fail to linearize for the wrong reason.
+fn minimize(history: &[&Operation]) -> (Vec<Operation>, Vec<Operation>) {
+    let mut operations: Vec<usize> = (0..history.len()).collect();
+    let mut chunks = 2;
+    while operations.len() >= 2 {
+        let chunk_len = operations.len().div_ceil(chunks);
+
+        let reduced = (0..operations.len()).step_by(chunk_len).find_map(|start| {
+            let mut rest = operations.clone();
+            rest.drain(start..(start + chunk_len).min(operations.len()));
+            is_violation(history, &rest).then_some(rest)
+        });
+
+        match reduced {
+            Some(rest) => {
+                operations = rest;
+                chunks = (chunks - 1).max(2);
+            }
+            None if chunks >= operations.len() => break,
+            None => chunks = (chunks * 2).min(operations.len()),
+        }
+    }
+
+    let writes = observed_writes(history, &operations);
+    let sorted = |indices: Vec<usize>| {
+        let mut operations: Vec<Operation> = indices.iter().map(|i| history[*i].clone()).collect();
+        operations.sort_by_key(|operation| operation.call_ns);
+        operations
+    };
+    (sorted(operations), sorted(writes))
+}
+
+/// Whether some of the operations of a key are not linearizable, together with the writes of
+/// the values they observe
+fn is_violation(history: &[&Operation], operations: &[usize]) -> bool {
+    let writes: Vec<&Operation> = observed_writes(history, operations)
+        .into_iter()
+        .map(|i| history[i])
+        .collect();
+    let operations: Vec<&Operation> = operations.iter().map(|i| history[*i]).collect();
+    !linearizable(&operations, &writes)
+}
+
+/// Writes of the values the operations observe, including deletes when they observe an absent
+/// value, called before an operation observing the value returned. Removing other writes and
+/// reads from a linearizable history, and ignoring what these writes returned, leaves it
+/// linearizable.
+fn observed_writes(history: &[&Operation], operations: &[usize]) -> Vec<usize> {
+    let mut observed: HashMap<&Option<String>, u64> = HashMap::new(); // Last return.
+    for i in operations {
+        if let Output::Value(value) = &history[*i].output {
+            let return_ns = observed.entry(value).or_default();
+            *return_ns = history[*i].return_ns.max(*return_ns);
+        }
+    }
+    let included: HashSet<usize> = operations.iter().copied().collect();
+
+    (0..history.len())
+        .filter(|i| !included.contains(i))
+        .filter(|i| match &history[*i].input {
+            Input::Write(value) => observed
+                .get(value)
+                .is_some_and(|return_ns| history[*i].call_ns < *return_ns),
+            Input::Read => false,
+        })
+        .collect()
+}
+
+/// Operation on a register holding interned values, 0 being the absent value
+enum Step {
+    Read(u32),
+    Write {
+        value: u32,
+        previous: Option<u32>,
+    },
+    /// Read whose value is unknown, which has no effect
+    Any,
+}
+
+impl Step {
+    /// State after the operation, if it can be applied to `state`
+    fn apply(&self, state: u32) -> Option<u32> {
+        match *self {
+            Step::Read(value) => (value == state).then_some(state),
+            Step::Write { value, previous } => match previous {
+                Some(previous) if previous != state => None,
+                _ => Some(value),
+            },
+            Step::Any => Some(state),
+        }
+    }
+}
+
+/// Steps of the operations, with their values interned. Results are ignored after the first
+/// `checked` operations.
+fn steps(operations: &[&Operation], checked: usize) -> Vec<Step> {
+    let mut values: HashMap<&Option<String>, u32> = HashMap::from([(&None, 0)]);
+    let mut intern = |value| {
+        let next = values.len() as u32;
+        *values.entry(value).or_insert(next)
+    };
+
+    operations
+        .iter()
+        .enumerate()
+        .map(
+            |(i, operation)| match (&operation.input, &operation.output) {
+                (Input::Write(value), _) if i >= checked => Step::Write {
+                    value: intern(value),
+                    previous: None,
+                },
+                (Input::Read, Output::Value(value)) => Step::Read(intern(value)),
+                (Input::Read, Output::Unknown) => Step::Any,
+                (Input::Write(value), Output::Value(previous)) => Step::Write {
+                    value: intern(value),
+                    previous: Some(intern(previous)),
+                },
+                (Input::Write(value), Output::Unknown) => Step::Write {
+                    value: intern(value),
+                    previous: None,
+                },
+            },
+        )
+        .collect()
+}
+
+/// Call and return events of operations ordered by time, as a doubly linked list from which
+/// linearized operations are lifted
+struct Events {
+    events: Vec<(usize, bool)>, // Operation, and whether this is its call.
+    prev: Vec<Option<usize>>,
+    next: Vec<Option<usize>>,
+    head: Option<usize>,
+    returns: Vec<usize>, // Return event of each operation.
+}
+
+impl Events {
+    fn new(operations: &[&Operation]) -> Events {
+        // Calls sort before returns at the same time, so the operations overlap.
+        let mut order: Vec<(u64, bool, usize)> = operations
+            .iter()
+            .enumerate()
+            .flat_map(|(i, operation)| {
+                let return_ns = if operation.is_pending() {
+                    u64::MAX
+                } else {
+                    operation.return_ns
+                };
+                [(operation.call_ns, false, i), (return_ns, true, i)]
+            })
+            .collect();
+        order.sort();
+
+        let len = order.len();
+        let mut returns = vec![0; operations.len()];
+        for (event, (_, is_return, operation)) in order.iter().enumerate() {
+            if *is_return {
+                returns[*operation] = event;
+            }
+        }
+
+        Events {
+            events: order
+                .iter()
+                .map(|(_, is_return, operation)| (*operation, !is_return))
+                .collect(),
+            prev: (0..len).map(|i| i.checked_sub(1)).collect(),
+            next: (0..len)
+                .map(|i| Some(i + 1).filter(|next| *next < len))
+                .collect(),
+            head: (len > 0).then_some(0),
+            returns,
+        }
+    }
+
+    fn first(&self) -> Option<usize> {
+        self.head
+    }
+
+    /// Remove a call event and the matching return event from the list
+    fn lift(&mut self, call: usize) {
+        let (operation, _) = self.events[call];
+        self.unlink(call);
+        self.unlink(self.returns[operation]);
+    }
+
+    /// Undo `lift`, in reverse order
+    fn unlift(&mut self, call: usize) {
+        let (operation, _) = self.events[call];
+        self.relink(self.returns[operation]);
+        self.relink(call);
+    }
+
+    fn unlink(&mut self, event: usize) {
+        let (prev, next) = (self.prev[event], self.next[event]);
+        match prev {
+            Some(prev) => self.next[prev] = next,
+            None => self.head = next,
+        }
+        if let Some(next) = next {
+            self.prev[next] = prev;
+        }
+    }
+
+    fn relink(&mut self, event: usize) {
+        let (prev, next) = (self.prev[event], self.next[event]);
+        match prev {
+            Some(prev) => self.next[prev] = Some(event),
+            None => self.head = Some(event),
+        }
+        if let Some(next) = next {
+            self.prev[next] = Some(event);
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct Bitset(Vec<u64>);
+
+impl Bitset {
+    fn new(len: usize) -> Bitset {
+        Bitset(vec![0; len.div_ceil(64)])
+    }
+
+    fn insert(&mut self, i: usize) {
+        self.0[i / 64] |= 1 << (i % 64);
+    }
+
+    fn remove(&mut self, i: usize) {
+        self.0[i / 64] &= !(1 << (i % 64));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn operation(input: Input, output: Output, call_ns: u64, return_ns: u64) -> Operation {
+        Operation {
+            client: 0,
+            key: "k".to_owned(),
+            input,
+            output,
+            call_ns,
+            return_ns,
+        }
+    }
+
+    fn is_linearizable(operations: &[Operation]) -> bool {
+        linearizable(&operations.iter().collect::<Vec<_>>(), &[])
+    }
+
+    fn put(value: &str) -> Input {
+        Input::Write(Some(value.to_owned()))
+    }
+
+    fn value(value: &str) -> Output {
+        Output::Value(Some(value.to_owned()))
+    }
+
+    #[test]
+    fn stale_read_reduced_to_reads() {
+        let history = vec![
+            operation(put("a"), Output::Value(None), 0, 10),
+            operation(Input::Read, value("a"), 20, 30),
+            operation(put("b"), value("a"), 40, 50),
+            operation(Input::Read, value("a"), 60, 70), // Stale.
+            operation(Input::Read, value("b"), 80, 90),
+        ];
+
+        let violations = check(&history);
+        assert_eq!(violations.len(), 1);
+        assert_eq!(
+            violations[0].operations,
+            vec![history[3].clone(), history[4].clone()]
+        );
+        assert_eq!(
+            violations[0].writes,
+            vec![history[0].clone(), history[2].clone()]
+        );
+    }
+
+    #[test]
+    fn concurrent_read_sees_either_value() {
+        for seen in [value("a"), value("b")] {
+            let history = vec![
+                operation(put("a"), Output::Value(None), 0, 10),
+                operation(put("b"), value("a"), 20, 40),
+                operation(Input::Read, seen, 30, 50),
+            ];
+            assert!(is_linearizable(&history));
+        }
+    }
+
+    #[test]
+    fn pending_write_may_take_effect_or_not() {
+        let pending = operation(put("a"), Output::Unknown, 0, 0);
+        assert!(is_linearizable(&[
+            pending.clone(),
+            operation(Input::Read, Output::Value(None), 10, 20)
+        ]));
+        assert!(is_linearizable(&[
+            pending.clone(),
+            operation(Input::Read, value("a"), 10, 20)
+        ]));
+
+        // But once seen, it cannot be undone.
+        assert!(!is_linearizable(&[
+            pending,
+            operation(Input::Read, value("a"), 10, 20),
+            operation(Input::Read, Output::Value(None), 30, 40),
+        ]));
+    }
+
+    /// Operation of a generated history: client, write or read, call time, delay until it takes
+    /// effect, delay until it returns, and whether the response is lost
+    type Generated = (usize, bool, u64, u64, u64, bool);
+
+    /// Run generated operations against a register, each taking effect between its call and
+    /// return, so the history is linearizable. Writes whose response is lost may or may not
+    /// take effect.
+    fn history(generated: &[Generated]) -> Vec<Operation> {
+        let mut order: Vec<usize> = (0..generated.len()).collect();
+        order.sort_by_key(|i| generated[*i].2 + generated[*i].3);
+
+        let mut history = vec![None; generated.len()];
+        let mut register: Option<String> = None;
+        for i in order {
+            let (client, write, call_ns, effect_ns, return_ns, lost) = generated[i];
+            let return_ns = call_ns + effect_ns + return_ns;
+
+            let (input, output) = if write {
+                let value = Some(format!("{client}-{i}"));
+                if lost {
+                    if i % 2 == 0 {
+                        register = value.clone();
+                    }
+                    (Input::Write(value), Output::Unknown)
+                } else {
+                    let previous = std::mem::replace(&mut register, value.clone());
+                    (Input::Write(value), Output::Value(previous))
+                }
+            } else {
+                (Input::Read, Output::Value(register.clone()))
+            };
+
+            history[i] = Some(Operation {
+                client,
+                key: "k".to_owned(),
+                input,
+                output,
+                call_ns,
+                return_ns,
+            });
+        }
+        history.into_iter().map(Option::unwrap).collect()
+    }
+
+    fn generated() -> impl Strategy<Value = Vec<Generated>> {
+        prop::collection::vec(
+            (
+                0..4usize,
+                any::<bool>(),
+                0..1000u64,
+                0..100u64,
+                0..100u64,
+                prop::bool::weighted(0.1),
+            ),
+            1..40,
+        )
+    }
+
+    proptest! {
+        #[test]
+        fn histories_of_a_register_are_linearizable(generated in generated()) {
+            prop_assert!(is_linearizable(&history(&generated)));
+        }
+
+        #[test]
+        fn read_of_a_value_never_written_is_reported_alone(generated in generated(), read in any::<prop::sample::Index>()) {
+            let mut history = history(&generated);
+            let reads: Vec<usize> = (0..history.len()).filter(|i| history[*i].input == Input::Read).collect();
+            prop_assume!(!reads.is_empty());
+            let read = reads[read.index(reads.len())];
+            history[read].output = value("never written");
+
+            let violations = check(&history);
+            prop_assert_eq!(violations.len(), 1);
+            prop_assert_eq!(&violations[0].operations, &vec![history[read].clone()]);
+            prop_assert!(violations[0].writes.is_empty());
+        }
+
+        #[test]
+        fn stale_read_is_reported_minimally(generated in generated(), stale in any::<prop::sample::Index>()) {
+            // A read of a value overwritten before it was called.
+            let mut history = history(&generated);
+            let returned = |i: usize| !history[i].is_pending();
+            let stale_reads: Vec<(usize, usize)> = (0..history.len())
+                .filter(|r| history[*r].input == Input::Read)
+                .flat_map(|r| (0..history.len()).map(move |w| (r, w)))
+                .filter(|(r, w)| {
+                    matches!(history[*w].input, Input::Write(Some(_)))
+                        && returned(*w)
+                        && history.iter().enumerate().any(|(o, overwrite)| {
+                            matches!(overwrite.input, Input::Write(_))
+                                && returned(o)
+                                && overwrite.call_ns > history[*w].return_ns
+                                && overwrite.return_ns < history[*r].call_ns
+                        })
+                })
+                .collect();
+            prop_assume!(!stale_reads.is_empty());
+            let (read, write) = stale_reads[stale.index(stale_reads.len())];
+            let Input::Write(written) = history[write].input.clone() else { unreachable!() };
+            history[read].output = Output::Value(written);
+
+            let violations = check(&history);
+            prop_assert_eq!(violations.len(), 1);
+            let all: Vec<&Operation> = history.iter().collect();
+            let operations: Vec<usize> = violations[0]
+                .operations
+                .iter()
+                .map(|operation| history.iter().position(|o| o == operation).unwrap())
+                .collect();
+            prop_assert!(is_violation(&all, &operations));
+            for i in 0..operations.len() {
+                let mut rest = operations.clone();
+                rest.remove(i);
+                prop_assert!(!is_violation(&all, &rest));
+            }
+        }
+    }
+}
@@ -1,5 +1,13 @@
+mod analyze;
+mod benchmark;
 mod build_ebpf;
+mod check_history;
+mod fuzz_corpus;
+mod integration_test;
+mod linearizability;
+mod netns;
 mod run;
+mod workload;
 
 use std::process::exit;
 
@@ -15,6 +23,11 @@ pub struct Options {
 enum Command {
     BuildEbpf(build_ebpf::Options),
     Run(run::Options),
+    IntegrationTest(integration_test::Options),
+    FuzzCorpus(fuzz_corpus::Options),
+    CheckHistory(check_history::Options),
+    Benchmark(benchmark::Options),
+    Analyze(analyze::Options),
 }
 
 fn main() {
@@ -24,6 +37,11 @@ fn main() {
     let ret = match opts.command {
         BuildEbpf(opts) => build_ebpf::build_ebpf(opts),
         Run(opts) => run::run(opts),
+        IntegrationTest(opts) => integration_test::integration_test(opts),
+        FuzzCorpus(opts) => fuzz_corpus::fuzz_corpus(opts),
+        CheckHistory(opts) => check_history::check_history(opts),
+        Benchmark(opts) => benchmark::benchmark(opts),
+        Analyze(opts) => analyze::analyze(opts),
     };
 
     if let Err(e) = ret {
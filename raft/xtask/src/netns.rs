@@ -0,0 +1,299 @@
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use anyhow::{bail, Context as _};
+use serde_json::Value;
+
+/// Bridge joining the namespaces, in the host namespace so nodes are reachable from xtask
+const BRIDGE: &str = "raft-test-br";
+const BRIDGE_ADDR: &str = "10.77.0.254/24";
+const HTTP_PORT: u16 = 8888;
+/// Pins of each node go to a directory of their own, as bpffs is shared by the namespaces
+const PIN_ROOT: &str = "/sys/fs/bpf/raft-test";
+/// As do their term, votes, log and snapshot, which survive restarts of the node
+const DATA_ROOT: &str = "/var/tmp/raft-test";
+
+/// A raft-main node running in its own network namespace
+pub struct Node {
+    pub namespace: String,
+    pub addr: Ipv4Addr,
+    /// Host end of the veth pair, attached to the bridge; the node's end is eth0
+    host_veth: String,
+    /// Passed as --pin-root
+    pin_root: String,
+    /// Passed as --data-dir
+    data_dir: String,
+    process: Option<Child>,
+}
+
+/// Cluster of raft-main nodes, one per network namespace, joined by veth pairs and a bridge.
+/// The namespaces and the bridge are removed when the cluster is dropped.
+pub struct Cluster {
+    pub nodes: Vec<Node>,
+    runner: Vec<String>,
+    bin_path: PathBuf,
+    node_args: Vec<String>,
+    log_dir: PathBuf,
+}
+
+impl Cluster {
+    /// Create the namespaces and links of a cluster of `size` nodes
+    pub fn create(
+        size: usize,
+        runner: &str,
+        bin_path: &Path,
+        node_args: Vec<String>,
+        log_dir: &Path,
+    ) -> Result<Cluster, anyhow::Error> {
+        let nodes = (0..size)
+            .map(|i| Node {
+                namespace: format!("raft-test-{i}"),
+                addr: Ipv4Addr::new(10, 77, 0, i as u8 + 1),
+                host_veth: format!("raft-test-v{i}"),
+                pin_root: format!("{PIN_ROOT}/raft-test-{i}"),
+                data_dir: format!("{DATA_ROOT}/raft-test-{i}"),
+                process: None,
+            })
+            .collect();
+
+        fs::create_dir_all(log_dir)?;
+        let cluster = Cluster {
+            nodes,
+            runner: runner.split_whitespace().map(String::from).collect(),
+            bin_path: bin_path.to_path_buf(),
+            node_args,
+            log_dir: log_dir.to_path_buf(),
+        };
+
+        // Leftovers of an interrupted run.
+        cluster.teardown();
+
+        cluster.privileged(&["ip", "link", "add", BRIDGE, "type", "bridge"])?;
+        cluster.privileged(&["ip", "addr", "add", BRIDGE_ADDR, "dev", BRIDGE])?;
+        cluster.privileged(&["ip", "link", "set", BRIDGE, "up"])?;
+
+        for node in &cluster.nodes {
+            let namespace = node.namespace.as_str();
+            let addr = format!("{}/24", node.addr);
+
+            cluster.privileged(&["ip", "netns", "add", namespace])?;
+            cluster.privileged(&[
+                "ip",
+                "link",
+                "add",
+                &node.host_veth,
+                "type",
+                "veth",
+                "peer",
+                "name",
+                "eth0",
+                "netns",
+                namespace,
+            ])?;
+            cluster.privileged(&["ip", "link", "set", &node.host_veth, "master", BRIDGE])?;
+            cluster.privileged(&["ip", "link", "set", &node.host_veth, "up"])?;
+            cluster.privileged(&[
+                "ip", "netns", "exec", namespace, "ip", "addr", "add", &addr, "dev", "eth0",
+            ])?;
+            cluster.privileged(&[
+                "ip", "netns", "exec", namespace, "ip", "link", "set", "eth0", "up",
+            ])?;
+            cluster.privileged(&[
+                "ip", "netns", "exec", namespace, "ip", "link", "set", "lo", "up",
+            ])?;
+        }
+
+        Ok(cluster)
+    }
+
+    /// Start raft-main in the namespace of node `i`, attaching XDP in generic mode to its veth
+    pub fn start(&mut self, i: usize) -> Result<(), anyhow::Error> {
+        if self.nodes[i].process.is_some() {
+            bail!("{} is already running", self.nodes[i].namespace);
+        }
+
+        let peers = self
+            .nodes
+            .iter()
+            .map(|node| node.addr.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        let log_path = self
+            .log_dir
+            .join(format!("{}.log", self.nodes[i].namespace));
+        let log = File::options().create(true).append(true).open(&log_path)?;
+
+        let mut command = self.command(&[
+            "ip",
+            "netns",
+            "exec",
+            &self.nodes[i].namespace,
+            "env",
+            &format!("PEERS={peers}"),
+            &format!(
+                "RUST_LOG={}",
+                std::env::var("RUST_LOG").unwrap_or_else(|_| "info".to_owned())
+            ),
+        ]);
+        command
+            .arg(&self.bin_path)
+            .args(["--iface", "eth0", "--xdp-mode", "skb"])
+            .args(["--pin-root", &self.nodes[i].pin_root])
+            .args(["--data-dir", &self.nodes[i].data_dir])
+            .args(&self.node_args)
+            .stdin(Stdio::null())
+            .stdout(log.try_clone()?)
+            .stderr(log);
+
+        let process = command
+            .spawn()
+            .with_context(|| format!("failed to start {}", self.nodes[i].namespace))?;
+        self.nodes[i].process = Some(process);
+        Ok(())
+    }
+
+    /// Kill every process in the namespace of node `i` with SIGKILL
+    pub fn kill(&mut self, i: usize) -> Result<(), anyhow::Error> {
+        self.signal(i, "KILL")?;
+        if let Some(mut process) = self.nodes[i].process.take() {
+            process.wait()?;
+        }
+        Ok(())
+    }
+
+    /// Stop node `i` with SIGTERM, letting a leader step down, and wait for it to exit
+    pub fn stop(&mut self, i: usize) -> Result<(), anyhow::Error> {
+        self.signal(i, "TERM")?;
+        if let Some(mut process) = self.nodes[i].process.take() {
+            process.wait()?;
+        }
+        Ok(())
+    }
+
+    /// Cut node `i` off from the bridge
+    pub fn isolate(&self, i: usize) -> Result<(), anyhow::Error> {
+        self.privileged(&["ip", "link", "set", &self.nodes[i].host_veth, "down"])
+    }
+
+    /// Reconnect node `i` to the bridge
+    pub fn rejoin(&self, i: usize) -> Result<(), anyhow::Error> {
+        self.privileged(&["ip", "link", "set", &self.nodes[i].host_veth, "up"])
+    }
+
+    /// Get the `data` of node `i`'s /status response
+    pub fn status(&self, i: usize) -> Result<Value, anyhow::Error> {
+        http_get(self.nodes[i].addr, "/status").map(|response| response["data"].clone())
+    }
+
+    fn signal(&self, i: usize, signal: &str) -> Result<(), anyhow::Error> {
+        let output = self
+            .command(&["ip", "netns", "pids", &self.nodes[i].namespace])
+            .output()?;
+        let pids = String::from_utf8_lossy(&output.stdout).into_owned();
+        let pids: Vec<&str> = pids.split_whitespace().collect();
+        if pids.is_empty() {
+            return Ok(());
+        }
+
+        let signal = format!("-{signal}");
+        let mut args = vec!["kill", signal.as_str()];
+        args.extend(pids);
+        self.privileged(&args)
+    }
+
+    fn teardown(&self) {
+        for i in 0..self.nodes.len() {
+            let _ = self.signal(i, "KILL");
+            let _ = self
+                .command(&["ip", "netns", "del", &self.nodes[i].namespace])
+                .output();
+            let _ = self
+                .command(&["rm", "-rf", &self.nodes[i].pin_root])
+                .output();
+            let _ = self
+                .command(&["rm", "-rf", &self.nodes[i].data_dir])
+                .output();
+        }
+        let _ = self.command(&["ip", "link", "del", BRIDGE]).output();
+    }
+
+    fn command(&self, args: &[&str]) -> Command {
+        let mut argv: Vec<&str> = self.runner.iter().map(String::as_str).collect();
+        argv.extend_from_slice(args);
+
+        let mut command = Command::new(argv[0]);
+        command.args(&argv[1..]);
+        command
+    }
+
+    fn privileged(&self, args: &[&str]) -> Result<(), anyhow::Error> {
+        let output = self
+            .command(args)
+            .stdin(Stdio::null())
+            .output()
+            .with_context(|| format!("failed to run `{}`", args.join(" ")))?;
+        if !output.status.success() {
+            bail!(
+                "`{}` failed: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Cluster {
+    fn drop(&mut self) {
+        for node in &mut self.nodes {
+            if let Some(mut process) = node.process.take() {
+                let _ = process.kill();
+            }
+        }
+        self.teardown();
+    }
+}
+
+/// Send a GET request to the HTTP API of the node at `addr` and parse its JSON response
+pub fn http_get(addr: Ipv4Addr, path: &str) -> Result<Value, anyhow::Error> {
+    let mut stream = http_connect(addr, Duration::from_millis(200))?;
+    http_request(&mut stream, "GET", path, None)
+}
+
+/// Connect to the HTTP API of the node at `addr`. Requests on the stream time out after
+/// `timeout`.
+pub fn http_connect(addr: Ipv4Addr, timeout: Duration) -> Result<TcpStream, anyhow::Error> {
+    let stream = TcpStream::connect_timeout(&SocketAddr::from((addr, HTTP_PORT)), timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    Ok(stream)
+}
+
+/// Send a request with an optional JSON body on a connection and parse the JSON response. The
+/// node closes the connection after responding.
+pub fn http_request(
+    stream: &mut TcpStream,
+    method: &str,
+    path: &str,
+    body: Option<&Value>,
+) -> Result<Value, anyhow::Error> {
+    let addr = stream.peer_addr()?.ip();
+    let body = body.map(Value::to_string).unwrap_or_default();
+    write!(
+        stream,
+        "{method} {path} HTTP/1.0\r\nHost: {addr}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}",
+        body.len()
+    )?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+
+    let body = match response.split_once("\r\n\r\n") {
+        Some((_, body)) => body,
+        None => bail!("malformed response from {addr}"),
+    };
+    Ok(serde_json::from_str(body)?)
+}
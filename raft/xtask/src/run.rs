@@ -22,9 +22,9 @@ pub struct Options {
 }
 
 /// Build the project
-fn build(opts: &Options) -> Result<(), anyhow::Error> {
+pub fn build(release: bool) -> Result<(), anyhow::Error> {
     let mut args = vec!["build"];
-    if opts.release {
+    if release {
         args.push("--release")
     }
     let status = Command::new("cargo")
@@ -43,7 +43,7 @@ pub fn run(opts: Options) -> Result<(), anyhow::Error> {
         release: opts.release,
     })
     .context("Error while building eBPF program")?;
-    build(&opts).context("Error while building userspace application")?;
+    build(opts.release).context("Error while building userspace application")?;
 
     // profile we are building (release or debug)
     let profile = if opts.release { "release" } else { "debug" };
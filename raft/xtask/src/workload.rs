@@ -0,0 +1,141 @@
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde_json::{json, Value};
+
+use crate::linearizability::{Input, Operation, Output};
+use crate::netns::{http_connect, http_request};
+
+/// Outlasts a follower forwarding a request to the leader, which waits for the leader's own
+/// timeout
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Errors after which a write was certainly not applied
+const NOT_APPLIED: [&str; 3] = [
+    "not the leader",
+    "leader unknown",
+    "leadership changed before the entry was committed",
+];
+
+/// Clients reading, putting and deleting keys through the HTTP API of random nodes in the
+/// background, recording a history of their operations for the linearizability checker
+pub struct Workload {
+    history: Arc<Mutex<Vec<Operation>>>,
+    stopped: Arc<AtomicBool>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl Workload {
+    pub fn start(addrs: Vec<Ipv4Addr>, clients: usize, keys: usize, lease_reads: bool) -> Workload {
+        let history = Arc::new(Mutex::new(Vec::new()));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let started = Instant::now();
+
+        let handles = (0..clients)
+            .map(|client| {
+                let (addrs, history, stopped) = (addrs.clone(), history.clone(), stopped.clone());
+                thread::spawn(move || {
+                    let mut rng = rand::thread_rng();
+                    let mut writes = 0;
+
+                    while !stopped.load(Ordering::Relaxed) {
+                        let key = format!("lin-{}", rng.gen_range(0..keys));
+                        let input = match rng.gen_range(0..10) {
+                            0..=4 => Input::Read,
+                            5..=8 => {
+                                writes += 1;
+                                Input::Write(Some(format!("{client}-{writes}")))
+                            }
+                            _ => Input::Write(None),
+                        };
+                        let addr = *addrs.choose(&mut rng).unwrap();
+
+                        let call_ns = started.elapsed().as_nanos() as u64;
+                        if let Some(output) = request(addr, &key, &input, lease_reads) {
+                            history.lock().unwrap().push(Operation {
+                                client,
+                                key,
+                                input,
+                                output,
+                                call_ns,
+                                return_ns: started.elapsed().as_nanos() as u64,
+                            });
+                        }
+                        thread::sleep(Duration::from_millis(rng.gen_range(0..20)));
+                    }
+                })
+            })
+            .collect();
+
+        Workload {
+            history,
+            stopped,
+            handles,
+        }
+    }
+
+    /// Stop the clients and return the history, in the order operations returned
+    pub fn stop(self) -> Vec<Operation> {
+        self.stopped.store(true, Ordering::Relaxed);
+        for handle in self.handles {
+            let _ = handle.join();
+        }
+        Arc::try_unwrap(self.history)
+            .map(|history| history.into_inner().unwrap())
+            .unwrap_or_default()
+    }
+}
+
+/// Send an operation to a node. Returns `None` if it certainly had no effect, so it can be left
+/// out of the history: failed reads, and writes that were not sent or were refused.
+fn request(addr: Ipv4Addr, key: &str, input: &Input, lease_reads: bool) -> Option<Output> {
+    let mut stream = http_connect(addr, REQUEST_TIMEOUT).ok()?;
+
+    let response = match input {
+        Input::Read => {
+            let query = if lease_reads {
+                "?consistency=lease"
+            } else {
+                ""
+            };
+            http_request(&mut stream, "GET", &format!("/kv/{key}{query}"), None)
+        }
+        Input::Write(Some(value)) => http_request(
+            &mut stream,
+            "PUT",
+            &format!("/kv/{key}"),
+            Some(&json!({ "value": value })),
+        ),
+        Input::Write(None) => http_request(&mut stream, "DELETE", &format!("/kv/{key}"), None),
+    };
+
+    let field = match input {
+        Input::Read => "/data/value",
+        Input::Write(_) => "/data/result/previous",
+    };
+    let value = response
+        .as_ref()
+        .ok()
+        .and_then(|response| match response.pointer(field)? {
+            Value::Null => Some(None),
+            Value::String(value) => Some(Some(value.clone())),
+            _ => None,
+        });
+
+    match (input, value) {
+        (_, Some(value)) => Some(Output::Value(value)),
+        (Input::Read, None) => None,
+        (Input::Write(_), None) => {
+            let refused = response
+                .ok()
+                .and_then(|response| response["error"].as_str().map(str::to_owned))
+                .is_some_and(|err| NOT_APPLIED.contains(&err.as_str()));
+            (!refused).then_some(Output::Unknown)
+        }
+    }
+}